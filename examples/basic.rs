@@ -57,10 +57,10 @@ fn main() -> adzdb::Result<()> {
 
     // Check existence
     println!("\n✅ Checking existence...");
-    println!("   Hash [0u8; 32] exists: {}", db.contains(&genesis_hash));
-    println!("   Hash [99u8; 32] exists: {}", db.contains(&[99u8; 32]));
-    println!("   Height 0 exists: {}", db.contains_height(0));
-    println!("   Height 999 exists: {}", db.contains_height(999));
+    println!("   Hash [0u8; 32] exists: {}", db.contains(&genesis_hash)?);
+    println!("   Hash [99u8; 32] exists: {}", db.contains(&[99u8; 32])?);
+    println!("   Height 0 exists: {}", db.contains_height(0)?);
+    println!("   Height 999 exists: {}", db.contains_height(999)?);
 
     // Show statistics
     let stats = db.stats();
@@ -122,11 +122,9 @@ fn main() -> adzdb::Result<()> {
         let data = db.get_by_height(height)?;
         let data_str = String::from_utf8_lossy(&data);
         
-        // Check prev_hash is mentioned (simplified check)
-        if height > 0 {
-            // In a real implementation, we'd deserialize and verify
-            assert!(data_str.contains("prev_hash"));
-        }
+        // Check the block links to its parent (simplified check)
+        // In a real implementation, we'd deserialize and verify
+        assert!(data_str.contains(&format!("{:?}", expected_prev_hash)));
         
         expected_prev_hash = db.get_hash_by_height(height)?;
     }
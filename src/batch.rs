@@ -0,0 +1,78 @@
+//! Write batches: committing many blocks at once
+
+use crate::Hash;
+
+/// A block queued in a `WriteBatch`
+#[derive(Debug, Clone)]
+pub(crate) struct BatchEntry {
+    pub(crate) hash: Hash,
+    pub(crate) height: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+/// A set of blocks written atomically by `Database::write`
+///
+/// Blocks are appended in the order they were added and committed with a
+/// single metadata update and sync. After a crash the database contains
+/// either every block of the batch or none of them.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use adzdb::{Database, Config, WriteBatch};
+///
+/// # fn main() -> adzdb::Result<()> {
+/// let config = Config::new("./blockchain");
+/// let mut db = Database::open_or_create(config)?;
+///
+/// let mut batch = WriteBatch::new();
+/// for height in 0..500u64 {
+///     let hash = [(height % 256) as u8; 32];
+///     batch.put(&hash, height, b"block data");
+/// }
+/// db.write(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<BatchEntry>,
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty batch with room for `capacity` blocks
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Queue a block, with the same meaning as `Database::put`
+    pub fn put(&mut self, hash: &Hash, height: u64, data: &[u8]) {
+        self.entries.push(BatchEntry {
+            hash: *hash,
+            height,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Number of queued blocks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no blocks are queued
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove every queued block
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,712 @@
+//! Compaction: rewriting the data segments without dead records
+//!
+//! The data only ever grows. Deleted blocks, truncated tips and stale side
+//! blocks keep their records until a compaction copies the live blocks, in
+//! height order, into fresh segments and swaps them in. Pruned blocks are
+//! copied as the header-only records marking them pruned. Values are stored
+//! with the codec the database is configured with, so a compaction also
+//! converts the values written before compression was turned on or changed.
+//!
+//! A compaction runs in two phases. [`Compactor::run`] copies the blocks that
+//! were live when the compactor was created; it only reads through its own
+//! handles to the data segments, so it can run on another thread while the
+//! database keeps serving reads and writes. [`Database::finish_compaction`]
+//! then catches up with whatever changed in the meantime and swaps the new
+//! files in.
+//!
+//! The swap is crash-safe. The new files are written beside the old ones with
+//! a `.compact` suffix and committed by creating a marker file, which records
+//! how many segments were staged, before they are renamed into place. Live
+//! segments past the staged ones are removed. If a crash interrupts the
+//! renames, the next open completes them; without the marker, leftover files
+//! are discarded.
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::compress::{self, Compression};
+use crate::heights::{HeightFile, HEIGHT_FILE, HEIGHT_LOG};
+use crate::keyfile::{Checkpoint, KeyFile, KEY_FILE};
+use crate::segments::{self, segment_name, Position, Segments, FIRST_FILE};
+use crate::superblock;
+use crate::vfs::{Dir, File};
+use crate::{Database, Error, Hash, HeightEntry, IndexEntry, RecordHeader, Result, State};
+
+/// Database files replaced by a compaction, in the order they are renamed,
+/// after the data segments
+///
+/// adzdb.dat is the data file of format 7 and earlier, which only a
+/// compaction interrupted before the upgrade has staged.
+const FILES: [&str; 6] = ["adzdb.dat", "adzdb.idx", HEIGHT_FILE, HEIGHT_LOG, KEY_FILE, "adzdb.meta"];
+
+/// Marker whose presence commits the staged files
+const MARKER: &str = "adzdb.compact";
+
+/// Progress callback invoked after each copied block
+pub type ProgressFn = Box<dyn FnMut(&CompactProgress) + Send>;
+
+/// Options for [`Database::compact_with`] and [`Database::compactor`]
+///
+/// # Example
+///
+/// ```rust
+/// use adzdb::compact::CompactOptions;
+///
+/// let options = CompactOptions::new()
+///     .with_side_blocks(true)
+///     .with_progress(|p| println!("{}/{} blocks", p.entries_done, p.entries_total));
+/// ```
+#[derive(Default)]
+pub struct CompactOptions {
+    /// Keep side blocks rather than only the canonical chain (default: false)
+    pub keep_side_blocks: bool,
+    /// Called after each block is copied (default: none)
+    pub progress: Option<ProgressFn>,
+}
+
+impl CompactOptions {
+    /// Create options that keep only the canonical chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether side blocks survive the compaction
+    pub fn with_side_blocks(mut self, keep: bool) -> Self {
+        self.keep_side_blocks = keep;
+        self
+    }
+
+    /// Set a callback to report progress while blocks are copied
+    pub fn with_progress<F: FnMut(&CompactProgress) + Send + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+/// How far a compaction has got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactProgress {
+    /// Blocks copied so far
+    pub entries_done: u64,
+    /// Blocks to copy
+    pub entries_total: u64,
+    /// Value bytes copied so far
+    pub bytes_done: u64,
+    /// Value bytes to copy
+    pub bytes_total: u64,
+}
+
+/// Outcome of a finished compaction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Total size of the data segments before the compaction
+    pub data_file_before: u64,
+    /// Total size of the data segments after the compaction
+    pub data_file_after: u64,
+    /// Blocks kept in the new files
+    pub entries_kept: u64,
+    /// Live side blocks left out of the new files
+    pub side_blocks_dropped: u64,
+}
+
+/// A compaction in progress
+///
+/// Created by [`Database::compactor`]. The compactor is `Send`, so
+/// [`Compactor::run`] can copy blocks on a background thread; the result is
+/// installed with [`Database::finish_compaction`]. Dropping a compactor
+/// abandons the compaction.
+pub struct Compactor {
+    dir: Dir,
+    keep_side_blocks: bool,
+    progress_fn: Option<ProgressFn>,
+    progress: CompactProgress,
+    /// The data segments being compacted
+    source: Segments,
+    /// Blocks that were live when the compactor was created, in height order
+    planned: Vec<IndexEntry>,
+    /// Size limit of the staged segments
+    segment_size: u64,
+    /// Codec the copied values are stored with
+    compression: Compression,
+    /// Staged segment being written
+    data: BufWriter<File>,
+    /// End of the staged data
+    data_end: Position,
+    /// Total size of the staged segments
+    data_len: u64,
+    /// Index entries of the records written to the staged segments
+    written: Vec<IndexEntry>,
+    /// Keeps the database from cutting records off its data segments
+    _guard: Arc<()>,
+}
+
+impl Compactor {
+    /// Copy every planned block into the staged segments
+    pub fn run(&mut self) -> Result<()> {
+        while let Some(entry) = self.planned.get(self.written.len()).copied() {
+            let (header, value) = record_of(&self.source, &entry, self.compression)?;
+            self.append(&header, &value)?;
+
+            self.progress.entries_done += 1;
+            self.progress.bytes_done += entry.size as u64;
+            if let Some(progress) = &mut self.progress_fn {
+                progress(&self.progress);
+            }
+        }
+        self.data.flush()?;
+        Ok(())
+    }
+
+    /// How far `run` has got
+    pub fn progress(&self) -> CompactProgress {
+        self.progress
+    }
+
+    /// Append a record to the staged segments, starting the next one when
+    /// the current one is full
+    fn append(&mut self, header: &RecordHeader, value: &[u8]) -> Result<()> {
+        let len = (RecordHeader::SIZE + value.len()) as u64;
+        let at = self.data_end.place(len, self.segment_size);
+        if at.segment != self.data_end.segment {
+            self.data.flush()?;
+            self.data.get_ref().sync_all()?;
+            let path = staged(self.dir.path(), &segment_name(at.segment));
+            self.data = BufWriter::new(self.dir.create(&path)?);
+        }
+        let entry = IndexEntry {
+            key: header.key,
+            offset: at.offset,
+            size: header.size,
+            height: header.height,
+            flags: header.flags,
+            segment: at.segment,
+            uncompressed_size: compress::uncompressed_size(header.flags, value),
+        };
+        self.data.write_all(&header.to_bytes())?;
+        self.data.write_all(value)?;
+        self.data_end = entry.end();
+        self.data_len += len;
+        self.written.push(entry);
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Rewrite the database files without dead records
+    ///
+    /// Copies the canonical chain, in height order, into fresh files and
+    /// swaps them in. Deleted and truncated blocks are left behind. Equivalent
+    /// to `compact_with(CompactOptions::new())`.
+    ///
+    /// Side blocks are deleted too: by default only the canonical chain
+    /// survives a compaction, and a reorg can no longer switch to a branch
+    /// compacted away. Use `compact_with` and
+    /// [`CompactOptions::with_side_blocks`] to keep them.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open(config)?;
+    ///
+    /// let report = db.compact()?;
+    /// println!("{} -> {} bytes", report.data_file_before, report.data_file_after);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact(&mut self) -> Result<CompactReport> {
+        self.compact_with(CompactOptions::new())
+    }
+
+    /// Rewrite the database files without dead records, with options
+    ///
+    /// See [`Database::compact`].
+    pub fn compact_with(&mut self, options: CompactOptions) -> Result<CompactReport> {
+        let mut compactor = self.compactor(options)?;
+        compactor.run()?;
+        self.finish_compaction(compactor)
+    }
+
+    /// Start a compaction that can run in the background
+    ///
+    /// While the compactor exists the database stays fully usable, except
+    /// that another compaction cannot start.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::CompactionInProgress` if another compactor exists.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    /// use adzdb::compact::CompactOptions;
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open(config)?;
+    ///
+    /// let mut compactor = db.compactor(CompactOptions::new())?;
+    /// let worker = std::thread::spawn(move || compactor.run().map(|_| compactor));
+    ///
+    /// db.put(&[7u8; 32], db.latest_height() + 1, b"new block")?;
+    ///
+    /// let compactor = worker.join().expect("compaction thread panicked")?;
+    /// db.finish_compaction(compactor)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compactor(&self, options: CompactOptions) -> Result<Compactor> {
+        self.check_writable()?;
+        if Arc::strong_count(&self.compactors) > 1 {
+            return Err(Error::CompactionInProgress);
+        }
+
+        let dir = self.config.dir();
+        let planned = self.live_entries(options.keep_side_blocks)?;
+        let progress = CompactProgress {
+            entries_total: planned.len() as u64,
+            bytes_total: planned.iter().map(|entry| entry.size as u64).sum(),
+            ..CompactProgress::default()
+        };
+        let source = self.state.read().segments.clone();
+        let data = dir.create(&staged(dir.path(), &segment_name(0)))?;
+
+        Ok(Compactor {
+            dir,
+            keep_side_blocks: options.keep_side_blocks,
+            progress_fn: options.progress,
+            progress,
+            source,
+            planned,
+            segment_size: self.config.segment_size,
+            compression: self.config.compression,
+            data: BufWriter::new(data),
+            data_end: Position::default(),
+            data_len: 0,
+            written: Vec::new(),
+            _guard: Arc::clone(&self.compactors),
+        })
+    }
+
+    /// Install the result of a compaction
+    ///
+    /// Finishes copying if `run` has not completed, then brings the staged
+    /// files up to date with every change made since the compactor was
+    /// created and swaps them in.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidConfig` if the compactor belongs to a database
+    /// at another path.
+    pub fn finish_compaction(&mut self, mut compactor: Compactor) -> Result<CompactReport> {
+        if compactor.dir.path() != self.config.path {
+            return Err(Error::InvalidConfig(format!(
+                "compactor belongs to {:?}, not {:?}",
+                compactor.dir.path(),
+                self.config.path
+            )));
+        }
+        compactor.run()?;
+
+        // Catch up: blocks removed while the compactor ran get a tombstone,
+        // and blocks stored or made canonical meanwhile are copied now
+        let wanted = self.live_entries(compactor.keep_side_blocks)?;
+        let state = self.state.read();
+        let wanted_keys: HashSet<Hash> = wanted.iter().map(|entry| entry.key).collect();
+        let copied: HashSet<Hash> = compactor.written.iter().map(|entry| entry.key).collect();
+        let removed: Vec<IndexEntry> = compactor
+            .written
+            .iter()
+            .filter(|entry| !wanted_keys.contains(&entry.key))
+            .copied()
+            .collect();
+        for entry in &removed {
+            compactor.append(&RecordHeader::tombstone(&entry.key, entry.height), &[])?;
+        }
+        for entry in wanted.iter().filter(|entry| !copied.contains(&entry.key)) {
+            let (header, value) = record_of(&state.segments, entry, compactor.compression)?;
+            compactor.append(&header, &value)?;
+        }
+        compactor.data.flush()?;
+        compactor.data.get_ref().sync_all()?;
+
+        // The canonical chain as it stands, clearing heights that only kept
+        // side blocks so they are not promoted on replay
+        let kept_heights: BTreeSet<u64> = wanted.iter().map(|entry| entry.height).collect();
+        let mut heights = Vec::new();
+        for &height in &kept_heights {
+            heights.push(match state.height_index.get(height)? {
+                Some(hash) => HeightEntry { height, hash },
+                None => HeightEntry::cleared(height),
+            });
+        }
+
+        // The replayed indexes are only written out, never read through, so
+        // they can carry the live segments
+        let dir = self.config.dir();
+        let path = dir.path();
+        let written = &compactor.written;
+        let replay = State::replay(state.segments.clone(), written, &heights)?;
+        let mut metadata = replay.metadata.clone();
+        metadata.height_len = 0;
+        metadata.journal_generation = state.metadata.journal_generation + 1;
+        metadata.index_len = (written.len() * IndexEntry::SIZE) as u64;
+
+        let index_bytes: Vec<u8> = written.iter().flat_map(|entry| entry.to_bytes()).collect();
+        let checkpoint = Checkpoint {
+            index_len: metadata.index_len,
+            height_len: 0,
+            data_end: compactor.data_end,
+            data_size: metadata.data_size,
+            logical_size: metadata.logical_size,
+            index_tail: compactor.written.last().map_or([0; IndexEntry::SIZE], IndexEntry::to_bytes),
+        };
+        let staged_segments = compactor.data_end.segment + 1;
+        write_synced(&dir, &staged(path, "adzdb.idx"), &index_bytes)?;
+        HeightFile::create(&dir, &staged(path, HEIGHT_FILE), replay.height_index.canonical_from(0)?)?;
+        write_synced(&dir, &staged(path, HEIGHT_LOG), &[])?;
+        KeyFile::create(
+            &dir,
+            &staged(path, KEY_FILE),
+            &replay.hash_index.entries()?,
+            &replay.side_blocks,
+            checkpoint,
+        )?;
+        write_synced(&dir, &staged(path, "adzdb.meta"), &superblock::encode(&metadata))?;
+
+        // Commit point: once the marker exists the swap is completed even
+        // if a crash interrupts it
+        let report = CompactReport {
+            data_file_before: state.segments.len()?,
+            data_file_after: compactor.data_len,
+            entries_kept: wanted.len() as u64,
+            side_blocks_dropped: state.metadata.entry_count - wanted.len() as u64,
+        };
+        drop(state);
+        drop(compactor);
+        drop(replay);
+        // Until the swap completes, and for snapshots after it, reads go to
+        // the segments about to be replaced
+        self.state.read().segments.retire()?;
+        // From here the files may be swapped under the open handles, so a
+        // failure leaves only reopening, which completes the swap
+        let swapped = Self::replace_file(&dir, &path.join(MARKER), &staged_segments.to_le_bytes())
+            .and_then(|()| Ok(dir.sync()?))
+            .and_then(|()| finish_pending(&dir));
+        if let Err(e) = swapped.and_then(|()| self.reopen()) {
+            self.torn = true;
+            return Err(e);
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            "🗜️  ADZDB compacted: {} -> {} bytes",
+            report.data_file_before,
+            report.data_file_after
+        );
+
+        Ok(report)
+    }
+
+    /// The blocks a compaction keeps, in height order with the canonical
+    /// block first at each height
+    fn live_entries(&self, keep_side_blocks: bool) -> Result<Vec<IndexEntry>> {
+        let state = self.state.read();
+        let canonical: HashSet<Hash> = state.height_index.canonical_from(0)?.into_iter().map(|(_, hash)| hash).collect();
+        let is_canonical = |entry: &IndexEntry| canonical.contains(&entry.key);
+        let mut entries: Vec<IndexEntry> = state
+            .hash_index
+            .entries()?
+            .into_iter()
+            .filter(|entry| keep_side_blocks || is_canonical(entry))
+            .collect();
+        entries.sort_by_key(|entry| (entry.height, !is_canonical(entry), entry.position()));
+        Ok(entries)
+    }
+}
+
+/// The record a compaction writes for a block: its value stored with
+/// `compression`, or for a pruned block a header-only record marking it
+/// pruned
+fn record_of(segments: &Segments, entry: &IndexEntry, compression: Compression) -> Result<(RecordHeader, Vec<u8>)> {
+    if entry.is_pruned() {
+        return Ok((RecordHeader::pruned(&entry.key, entry.height), Vec::new()));
+    }
+    let stored = Database::read_value(segments, entry)?;
+    let codec = entry.flags & IndexEntry::CODEC_FLAGS;
+    if codec == compression.flag() {
+        return Ok((RecordHeader::with_flags(&entry.key, entry.height, &stored, codec), stored));
+    }
+    let value = compress::decode(entry, stored)?;
+    let (stored, codec) = compress::encode(compression, &value)?;
+    let stored = stored.into_owned();
+    Ok((RecordHeader::with_flags(&entry.key, entry.height, &stored, codec), stored))
+}
+
+/// Whether a committed compaction is waiting to be swapped in
+pub(crate) fn is_pending(dir: &Dir) -> bool {
+    dir.exists(&dir.join(MARKER))
+}
+
+/// Complete or discard a compaction interrupted by a crash
+///
+/// With the marker present the staged files are committed, so any that were
+/// not renamed yet are moved into place, and live segments past the staged
+/// ones are removed. The staged segments start at 0 again, so adzdb.first
+/// goes last. Without the marker they are leftovers of a compaction that never
+/// committed.
+pub(crate) fn finish_pending(dir: &Dir) -> Result<()> {
+    let marker = dir.join(MARKER);
+    if dir.exists(&marker) {
+        // An empty marker comes from a compaction of format 7 or earlier,
+        // which staged no segments
+        let staged_segments = match dir.read(&marker)?.get(..4) {
+            Some(count) => u32::from_le_bytes(count.try_into().unwrap()),
+            None => 0,
+        };
+        for segment in 0..staged_segments {
+            let staged = staged(dir.path(), &segment_name(segment));
+            if dir.exists(&staged) {
+                dir.rename(&staged, &dir.join(segment_name(segment)))?;
+            }
+        }
+        // After pruning, the live segments may start past the staged ones
+        let first = segments::first_segment(dir)?.max(staged_segments);
+        remove_segments(dir, first, |segment| dir.join(segment_name(segment)))?;
+        dir.remove_if_exists(&dir.join(FIRST_FILE))?;
+        for name in FILES {
+            let staged = staged(dir.path(), name);
+            if dir.exists(&staged) {
+                dir.rename(&staged, &dir.join(name))?;
+            }
+        }
+        dir.sync()?;
+        dir.remove_file(&marker)?;
+    } else {
+        remove_segments(dir, 0, |segment| staged(dir.path(), &segment_name(segment)))?;
+        for name in FILES {
+            dir.remove_if_exists(&staged(dir.path(), name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the segment files at `path`, from `first` up to the last one
+/// present
+///
+/// The last goes first, so a crash part-way leaves the files still to
+/// remove numbered on from `first`.
+fn remove_segments(dir: &Dir, first: u32, path: impl Fn(u32) -> PathBuf) -> Result<()> {
+    let mut end = first;
+    while dir.exists(&path(end)) {
+        end += 1;
+    }
+    for segment in (first..end).rev() {
+        dir.remove_file(&path(segment))?;
+    }
+    Ok(())
+}
+
+/// Path of the staged replacement for a database file
+fn staged(path: &Path, name: &str) -> PathBuf {
+    path.join(format!("{}.compact", name))
+}
+
+/// Write a file and make it durable
+pub(crate) fn write_synced(dir: &Dir, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = dir.create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::fs;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_compact_drops_dead_records() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-compact");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1").unwrap();
+        db.put(&[11u8; 32], 1, b"side 1").unwrap();
+        db.put(&[2u8; 32], 2, b"block 2").unwrap();
+        db.put(&[9u8; 32], 3, b"spam").unwrap();
+        db.put(&[13u8; 32], 3, b"side 3").unwrap();
+        db.delete(&[9u8; 32]).unwrap();
+
+        let report = db.compact().unwrap();
+        let record = (RecordHeader::SIZE + 7) as u64;
+        assert_eq!(report.entries_kept, 3);
+        assert_eq!(report.side_blocks_dropped, 2);
+        assert_eq!(report.data_file_after, 3 * record);
+        assert!(report.data_file_before > report.data_file_after);
+        assert_eq!(fs::metadata(temp_dir.join(segment_name(0))).unwrap().len(), 3 * record);
+
+        assert_eq!(db.entry_count(), 3);
+        assert_eq!(db.stats().data_size, 21);
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2");
+        assert!(!db.contains(&[11u8; 32]).unwrap());
+        assert!(!db.contains_height(3).unwrap());
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+        assert!(!temp_dir.join(MARKER).exists());
+
+        // Appends continue on the compacted files
+        db.put(&[3u8; 32], 3, b"block 3").unwrap();
+        drop(db);
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.iter_heights().unwrap().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(db.get(&[3u8; 32]).unwrap(), b"block 3");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_compact_keeps_side_blocks_and_reports_progress() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-compact-side");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1a").unwrap();
+        db.put(&[2u8; 32], 2, b"block 2a").unwrap();
+        db.put(&[11u8; 32], 1, b"block 1b").unwrap();
+        db.put(&[12u8; 32], 2, b"block 2b").unwrap();
+        db.truncate_to_height(0).unwrap();
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let options = CompactOptions::new()
+            .with_side_blocks(true)
+            .with_progress(move |p| log.lock().unwrap().push(*p));
+        let report = db.compact_with(options).unwrap();
+        assert_eq!(report.entries_kept, 3);
+        assert_eq!(report.side_blocks_dropped, 0);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen[2].entries_done, 3);
+        assert_eq!(seen[2].bytes_done, seen[2].bytes_total);
+
+        // Side blocks above the tip stay off the canonical chain
+        assert_eq!(db.latest_height(), 0);
+        assert!(!db.contains_height(1).unwrap());
+        assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[11u8; 32]]);
+        drop(db);
+
+        let mut db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert!(!db.contains_height(1).unwrap());
+        db.reorg(0, &[[11u8; 32], [12u8; 32]]).unwrap();
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2b");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_compact_in_background() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-compact-background");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        for i in 0..4u8 {
+            db.put(&[i; 32], i as u64, b"block").unwrap();
+        }
+
+        let mut compactor = db.compactor(CompactOptions::new()).unwrap();
+        assert!(matches!(db.compactor(CompactOptions::new()), Err(Error::CompactionInProgress)));
+        let worker = std::thread::spawn(move || compactor.run().map(|_| compactor));
+
+        // The database changes while the compactor copies
+        db.put(&[4u8; 32], 4, b"block").unwrap();
+        db.delete(&[1u8; 32]).unwrap();
+        db.truncate_to_height(2).unwrap();
+
+        let compactor = worker.join().unwrap().unwrap();
+        assert_eq!(compactor.progress().entries_done, 4);
+        db.finish_compaction(compactor).unwrap();
+
+        assert_eq!(db.iter_heights().unwrap().collect::<Vec<_>>(), vec![0, 2]);
+        assert!(!db.contains(&[1u8; 32]).unwrap());
+        assert!(!db.contains(&[3u8; 32]).unwrap());
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+        drop(db);
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 2);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_interrupted_swap_is_completed_on_open() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-compact-crash");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+        }
+
+        // Stage the current files, then let the live ones move on
+        let segment = segment_name(0);
+        for name in FILES[1..].iter().copied().chain([segment.as_str()]) {
+            fs::copy(temp_dir.join(name), staged(&temp_dir, name)).unwrap();
+        }
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            db.put(&[2u8; 32], 2, b"block 2").unwrap();
+        }
+
+        // Without the marker the staged files are discarded
+        {
+            let db = Database::open(config.clone()).unwrap();
+            assert_eq!(db.latest_height(), 2);
+            assert!(!staged(&temp_dir, &segment).exists());
+        }
+
+        // With it, a swap that crashed after renaming the staged segment is
+        // completed, and live segments past it are removed
+        let before: Vec<Vec<u8>> = FILES[1..]
+            .iter()
+            .map(|name| fs::read(temp_dir.join(name)).unwrap())
+            .collect();
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            db.delete(&[2u8; 32]).unwrap();
+        }
+        for (name, contents) in FILES[1..].iter().zip(&before) {
+            fs::write(staged(&temp_dir, name), contents).unwrap();
+        }
+        fs::write(temp_dir.join(segment_name(1)), b"").unwrap();
+        fs::write(temp_dir.join(MARKER), 1u32.to_le_bytes()).unwrap();
+
+        let db = Database::open(config).unwrap();
+        assert!(!temp_dir.join(MARKER).exists());
+        assert!(!temp_dir.join(segment_name(1)).exists());
+        assert!(db.contains(&[2u8; 32]).unwrap());
+        assert_eq!(db.latest_height(), 2);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
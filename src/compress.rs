@@ -0,0 +1,258 @@
+//! Transparent per-value compression
+//!
+//! With a codec selected through
+//! [`Config::with_compression`](crate::Config::with_compression), `put` and
+//! `write` compress every value before appending it, and store it as it is
+//! when that would not make it smaller. The codec is recorded in the flags
+//! of the record and of its index entry, so values written with another
+//! codec, or before compression was turned on, stay readable: `get`
+//! decompresses each according to its own flags.
+//!
+//! A compressed value is stored as its uncompressed length (u32 LE)
+//! followed by the codec output. The record checksum covers the stored
+//! bytes, so damage is reported before anything is decompressed.
+//!
+//! Each codec is behind a cargo feature: `lz4` (pure Rust) and `zstd`
+//! (the zstd C library).
+
+use std::borrow::Cow;
+
+use crate::{to_hex, Error, IndexEntry, Result, MAX_VALUE_SIZE};
+
+/// Length of the uncompressed length before the codec output
+const PREFIX: usize = 4;
+
+/// Codec new values are compressed with
+///
+/// # Example
+///
+/// ```rust
+/// use adzdb::Config;
+/// use adzdb::compress::Compression;
+///
+/// let config = Config::new("./blockchain").with_compression(Compression::Zstd(3));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store values as they are
+    #[default]
+    None,
+    /// LZ4 block format: fast, with a moderate ratio (`lz4` feature)
+    Lz4,
+    /// Zstandard at the given level, 1 to 22 (`zstd` feature)
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Entry flag recording this codec, or 0 for `None`
+    pub fn flag(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => IndexEntry::FLAG_LZ4,
+            Self::Zstd(_) => IndexEntry::FLAG_ZSTD,
+        }
+    }
+
+    /// The codec recorded in entry flags, or `None` if they name more than
+    /// one; the level of zstd is not recorded, and is not needed to
+    /// decompress
+    fn from_flags(flags: u32) -> Option<Self> {
+        match flags & IndexEntry::CODEC_FLAGS {
+            0 => Some(Self::None),
+            IndexEntry::FLAG_LZ4 => Some(Self::Lz4),
+            IndexEntry::FLAG_ZSTD => Some(Self::Zstd(0)),
+            _ => None,
+        }
+    }
+
+    /// Check that this build includes the codec, and that its level is valid
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidConfig` naming the cargo feature to enable, or
+    /// if a zstd level is outside 1 to 22.
+    pub(crate) fn check(self) -> Result<()> {
+        self.check_feature()?;
+        match self {
+            Self::Zstd(level) if !(1..=22).contains(&level) => {
+                Err(Error::InvalidConfig(format!("zstd level {} is not between 1 and 22", level)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that this build includes the codec, whatever the level
+    fn check_feature(self) -> Result<()> {
+        match self {
+            Self::Lz4 if !cfg!(feature = "lz4") => Err(self.unsupported()),
+            Self::Zstd(_) if !cfg!(feature = "zstd") => Err(self.unsupported()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The error for a codec this build does not include
+    fn unsupported(self) -> Error {
+        let (name, feature) = match self {
+            Self::Zstd(_) => ("zstd", "zstd"),
+            _ => ("LZ4", "lz4"),
+        };
+        Error::InvalidConfig(format!("{} compression requires the `{}` feature", name, feature))
+    }
+}
+
+/// Compress a value for storage
+///
+/// Returns the bytes to store and the flag of the codec they were
+/// compressed with. The value itself is returned, with no flag, when
+/// compression is off, or when compressing does not make it smaller.
+pub(crate) fn encode(compression: Compression, value: &[u8]) -> Result<(Cow<'_, [u8]>, u32)> {
+    if compression == Compression::None || value.len() <= PREFIX {
+        return Ok((Cow::Borrowed(value), 0));
+    }
+    let mut stored = (value.len() as u32).to_le_bytes().to_vec();
+    stored.extend_from_slice(&compress(compression, value)?);
+    if stored.len() >= value.len() {
+        return Ok((Cow::Borrowed(value), 0));
+    }
+    Ok((Cow::Owned(stored), compression.flag()))
+}
+
+/// The value stored for an entry, decompressed if its flags name a codec
+///
+/// # Errors
+///
+/// Returns `Error::InvalidConfig` if the codec is not in this build, and
+/// `Error::Corruption` if the stored bytes do not decompress to the size
+/// recorded for them.
+pub(crate) fn decode(entry: &IndexEntry, stored: Vec<u8>) -> Result<Vec<u8>> {
+    let compression = Compression::from_flags(entry.flags).ok_or_else(|| corrupt(entry))?;
+    if compression == Compression::None {
+        return Ok(stored);
+    }
+    compression.check_feature()?;
+    let size = uncompressed_size(entry.flags, &stored);
+    if stored.len() < PREFIX || size != entry.uncompressed_size || size as u64 > MAX_VALUE_SIZE {
+        return Err(corrupt(entry));
+    }
+    decompress(compression, &stored[PREFIX..], size as usize)?
+        .filter(|value| value.len() == size as usize)
+        .ok_or_else(|| corrupt(entry))
+}
+
+/// Run the codec's compressor
+#[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn compress(compression: Compression, value: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::compress(value)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => Ok(zstd::bulk::compress(value, level)?),
+        _ => Err(compression.unsupported()),
+    }
+}
+
+/// Run the codec's decompressor, or return `None` if `body` is not valid
+/// codec output
+#[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(compression: Compression, body: &[u8], size: usize) -> Result<Option<Vec<u8>>> {
+    match compression {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::decompress(body, size).ok()),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(_) => Ok(zstd::bulk::decompress(body, size).ok()),
+        _ => Err(compression.unsupported()),
+    }
+}
+
+/// Uncompressed length at the start of a stored value, or 0 if `flags` name
+/// no codec
+pub(crate) fn uncompressed_size(flags: u32, stored: &[u8]) -> u32 {
+    match stored.get(..PREFIX) {
+        Some(prefix) if flags & IndexEntry::CODEC_FLAGS != 0 => u32::from_le_bytes(prefix.try_into().unwrap()),
+        _ => 0,
+    }
+}
+
+fn corrupt(entry: &IndexEntry) -> Error {
+    Error::Corruption(format!(
+        "Cannot decompress value for key {} at offset {} of segment {}",
+        to_hex(&entry.key),
+        entry.offset,
+        entry.segment
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry for a value stored as `encode` returned it
+    fn entry_for(stored: &[u8], flags: u32) -> IndexEntry {
+        IndexEntry {
+            size: stored.len() as u32,
+            flags,
+            uncompressed_size: uncompressed_size(flags, stored),
+            ..IndexEntry::default()
+        }
+    }
+
+    #[test]
+    fn test_values_that_do_not_shrink_are_stored_as_is() {
+        let (stored, flag) = encode(Compression::None, b"block data").unwrap();
+        assert_eq!((&*stored, flag), (&b"block data"[..], 0));
+        assert_eq!(decode(&entry_for(&stored, flag), stored.to_vec()).unwrap(), b"block data");
+
+        // Too short to gain anything, so no codec is needed either
+        assert_eq!(encode(Compression::Zstd(3), b"abc").unwrap().1, 0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_level_is_checked() {
+        assert!(Compression::Zstd(1).check().is_ok());
+        assert!(Compression::Zstd(22).check().is_ok());
+        for level in [0, 23, -1] {
+            assert!(matches!(Compression::Zstd(level).check(), Err(Error::InvalidConfig(_))));
+        }
+    }
+
+    #[cfg(not(all(feature = "lz4", feature = "zstd")))]
+    #[test]
+    fn test_missing_codec_is_rejected() {
+        let value = vec![7u8; 1024];
+        let missing = if cfg!(feature = "lz4") { Compression::Zstd(3) } else { Compression::Lz4 };
+        assert!(matches!(encode(missing, &value), Err(Error::InvalidConfig(_))));
+
+        let stored = [&1024u32.to_le_bytes()[..], &[0u8; 16]].concat();
+        let entry = entry_for(&stored, missing.flag());
+        assert!(matches!(decode(&entry, stored), Err(Error::InvalidConfig(_))));
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_roundtrip() {
+        let value: Vec<u8> = (0..4096u32).map(|i| (i % 64) as u8).collect();
+        let codecs = [
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        for compression in codecs {
+            let (stored, flag) = encode(compression, &value).unwrap();
+            assert_eq!(flag, compression.flag());
+            assert!(stored.len() < value.len());
+            let entry = entry_for(&stored, flag);
+            assert_eq!(entry.uncompressed_size, 4096);
+            assert_eq!(decode(&entry, stored.to_vec()).unwrap(), value);
+
+            // A wrong recorded size is caught, not trusted
+            let wrong = IndexEntry { uncompressed_size: 4095, ..entry };
+            assert!(matches!(decode(&wrong, stored.to_vec()), Err(Error::Corruption(_))));
+            let mut short = stored.to_vec();
+            short[..PREFIX].copy_from_slice(&5000u32.to_le_bytes());
+            let entry = entry_for(&short, flag);
+            assert!(matches!(decode(&entry, short), Err(Error::Corruption(_))));
+        }
+    }
+}
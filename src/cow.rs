@@ -0,0 +1,152 @@
+//! Copy-on-write maps for the in-memory indexes
+//!
+//! A map is split into a fixed number of shards, each behind an `Arc`.
+//! Cloning the map copies only the shard pointers, and a write copies the
+//! one shard it touches if a clone still shares it. A snapshot of the
+//! indexes therefore costs a few thousand pointer copies, and every write
+//! made while it is alive copies at most one shard per map.
+
+use std::collections::HashMap;
+use std::hash::Hash as StdHash;
+use std::ops::Index;
+use std::sync::Arc;
+
+use crate::Hash;
+
+/// Number of shards per map
+const SHARDS: usize = 1024;
+
+/// A key that picks its own shard
+pub(crate) trait ShardKey: Eq + StdHash + Clone {
+    fn shard(&self) -> usize;
+}
+
+impl ShardKey for Hash {
+    fn shard(&self) -> usize {
+        // Block hashes are uniformly distributed already
+        u16::from_le_bytes([self[0], self[1]]) as usize % SHARDS
+    }
+}
+
+impl ShardKey for u64 {
+    fn shard(&self) -> usize {
+        (*self % SHARDS as u64) as usize
+    }
+}
+
+/// A hash map whose clones share storage until one of them is written
+#[derive(Debug, Clone)]
+pub(crate) struct CowMap<K, V> {
+    shards: Vec<Arc<HashMap<K, V>>>,
+    len: usize,
+}
+
+impl<K: ShardKey, V: Clone> CowMap<K, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Arc::new(HashMap::new())).collect(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.shards[key.shard()].get(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &K) -> bool {
+        self.shards[key.shard()].contains_key(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.shard_mut(&key).insert(key, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.len -= 1;
+        self.shard_mut(key).remove(key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.shard_mut(key).get_mut(key)
+    }
+
+    /// Get the value for `key`, inserting the default value if it is absent
+    pub(crate) fn get_or_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        if !self.contains_key(&key) {
+            self.len += 1;
+        }
+        self.shard_mut(&key).entry(key).or_default()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    /// The shard holding `key`, copied first if a clone shares it
+    fn shard_mut(&mut self, key: &K) -> &mut HashMap<K, V> {
+        Arc::make_mut(&mut self.shards[key.shard()])
+    }
+}
+
+impl<K: ShardKey, V: Clone> Default for CowMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: ShardKey, V: Clone> Index<&K> for CowMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("key not in map")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_is_isolated_from_writes() {
+        let mut map: CowMap<u64, u64> = CowMap::new();
+        for i in 0..5000 {
+            map.insert(i, i);
+        }
+
+        let frozen = map.clone();
+        map.insert(1, 100);
+        map.remove(&2);
+        map.insert(9000, 9000);
+        *map.get_or_default(3) += 1;
+        map.remove(&4);
+
+        assert_eq!(map.len(), 4999);
+        assert_eq!(map[&1], 100);
+        assert_eq!(map[&3], 4);
+        assert!(!map.contains_key(&2) && !map.contains_key(&4));
+
+        assert_eq!(frozen.len(), 5000);
+        assert_eq!(frozen[&1], 1);
+        assert_eq!(frozen[&3], 3);
+        assert!(frozen.contains_key(&2) && frozen.contains_key(&4));
+        assert!(!frozen.contains_key(&9000));
+        assert_eq!(frozen.iter().map(|(_, value)| value).sum::<u64>(), (0..5000).sum());
+    }
+}
@@ -0,0 +1,160 @@
+//! Content hashers for verifying that a key matches its value
+//!
+//! ADZDB is content-addressable: the key of every value is expected to be
+//! the hash of that value. A [`Hasher`] configured through
+//! [`Config::with_hasher`](crate::Config::with_hasher) lets `put` enforce
+//! this, and lets `verify_entry` re-check stored values.
+//!
+//! SHA-256 and double SHA-256 as used by Bitcoin are provided behind the
+//! `sha2` cargo feature, and BLAKE3 behind the `blake3` feature; any other
+//! hash function can be used by implementing [`Hasher`].
+
+use crate::Hash;
+
+/// A hash function mapping values to 256-bit keys
+///
+/// # Example
+///
+/// ```rust
+/// use adzdb::hasher::Hasher;
+/// use adzdb::Hash;
+///
+/// /// Keys blocks by their first 32 bytes
+/// #[derive(Debug)]
+/// struct Prefix;
+///
+/// impl Hasher for Prefix {
+///     fn hash(&self, data: &[u8]) -> Hash {
+///         let mut hash = [0u8; 32];
+///         let len = data.len().min(32);
+///         hash[..len].copy_from_slice(&data[..len]);
+///         hash
+///     }
+/// }
+///
+/// assert_eq!(Prefix.hash(b"abc")[..3], *b"abc");
+/// ```
+pub trait Hasher: std::fmt::Debug + Send + Sync {
+    /// Hash a value
+    fn hash(&self, data: &[u8]) -> Hash;
+}
+
+/// SHA-256 (`sha2` feature)
+///
+/// # Example
+///
+/// ```rust
+/// use adzdb::hasher::{Hasher, Sha256};
+///
+/// let hash = Sha256.hash(b"abc");
+/// assert_eq!(hash[0], 0xba);
+/// ```
+#[cfg(feature = "sha2")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256;
+
+#[cfg(feature = "sha2")]
+impl Hasher for Sha256 {
+    fn hash(&self, data: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(data).into()
+    }
+}
+
+/// Double SHA-256 (`SHA-256(SHA-256(data))`), as used by Bitcoin (`sha2`
+/// feature)
+#[cfg(feature = "sha2")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DoubleSha256;
+
+#[cfg(feature = "sha2")]
+impl Hasher for DoubleSha256 {
+    fn hash(&self, data: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(sha2::Sha256::digest(data)).into()
+    }
+}
+
+/// BLAKE3 with a 256-bit output (`blake3` feature)
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3 {
+    fn hash(&self, data: &[u8]) -> Hash {
+        blake3::hash(data).into()
+    }
+}
+
+#[cfg(all(test, any(feature = "sha2", feature = "blake3")))]
+mod tests {
+    use super::*;
+    use crate::to_hex;
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_sha256_known_vectors() {
+        assert_eq!(
+            to_hex(&Sha256.hash(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&Sha256.hash(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&Sha256.hash(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_double_sha256() {
+        assert_eq!(DoubleSha256.hash(b"abc"), Sha256.hash(&Sha256.hash(b"abc")));
+        assert_eq!(
+            to_hex(&DoubleSha256.hash(b"hello")),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3_known_vectors() {
+        assert_eq!(
+            to_hex(&Blake3.hash(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            to_hex(&Blake3.hash(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3_multi_chunk() {
+        // Inputs follow the official test vectors: byte i is i % 251
+        let vectors = [
+            (1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (64, "4eed7141ea4a5cd4b788606bd23f46e212af9cacebacdc7d1f4c6dc7f2511b98"),
+            (65, "de1e5fa0be70df6d2be8fffd0e99ceaa8eb6e8c93a63f2d8d1c30ecb6b263dee"),
+            (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (3073, "7124b49501012f81cc7f11ca069ec9226cecb8a2c850cfe644e327d22d3e1cd3"),
+            (4096, "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969"),
+            (5120, "9cadc15fed8b5d854562b26a9536d9707cadeda9b143978f319ab34230535833"),
+            (8193, "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b"),
+            (31744, "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47"),
+            (102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+        ];
+        for (len, expected) in vectors {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(to_hex(&Blake3.hash(&data)), expected, "length {}", len);
+        }
+    }
+}
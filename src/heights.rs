@@ -0,0 +1,408 @@
+//! Dense height index: the canonical chain as an array in adzdb.hgt
+//!
+//! Slot `h` of adzdb.hgt, at offset `h * 32`, holds the hash of the
+//! canonical block at height `h`, so a lookup by height is one positioned
+//! read and opening a database loads nothing. Slots store the bitwise
+//! complement of the hash: the zeros of a hole, or of a file extended past
+//! its last write, read as an empty height. A hash of all `0xFF` bytes would
+//! read as empty too, so `put` rejects it as `RESERVED_HASH`. The file ends
+//! at the highest canonical height.
+//!
+//! adzdb.hgt is checkpointed together with the key file. The canonical
+//! chain changes made since are journaled in adzdb.hgt.log as `HeightEntry`
+//! records and replayed on open. A checkpoint rewrites the slots in place
+//! behind the key file's rollback log, so removing the top of the chain is
+//! a file truncate, and once the journal has grown long enough the
+//! checkpoint empties it.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cow::CowMap;
+use crate::keyfile::PAGE_SIZE;
+use crate::vfs::{Dir, File, OpenOptions};
+use crate::{read_exact_at, Error, Hash, Result};
+
+/// Height file name
+pub(crate) const HEIGHT_FILE: &str = "adzdb.hgt";
+
+/// Journal of the canonical chain changes since the last checkpoint
+pub(crate) const HEIGHT_LOG: &str = "adzdb.hgt.log";
+
+/// Size of a slot
+const SLOT_SIZE: u64 = 32;
+
+/// Slots per checkpoint page
+const PAGE_SLOTS: u64 = PAGE_SIZE as u64 / SLOT_SIZE;
+
+/// The dense array of canonical block hashes in adzdb.hgt
+#[derive(Debug)]
+pub(crate) struct HeightFile {
+    file: File,
+}
+
+impl HeightFile {
+    /// Open the height file of the database at `dir`
+    pub(crate) fn open(dir: &Dir, writable: bool) -> Result<Self> {
+        let file = dir.open(&dir.join(HEIGHT_FILE), OpenOptions::new().read(true).write(writable))?;
+        Ok(Self { file })
+    }
+
+    /// Write a complete height file for a canonical chain, given in
+    /// ascending height order, at `path`
+    pub(crate) fn create(dir: &Dir, path: &Path, canonical: impl IntoIterator<Item = (u64, Hash)>) -> Result<()> {
+        let mut writer = BufWriter::new(dir.create(path)?);
+        let mut len = 0;
+        for (height, hash) in canonical {
+            if height * SLOT_SIZE != len {
+                writer.seek(SeekFrom::Start(height * SLOT_SIZE))?;
+            }
+            writer.write_all(&encode(Some(&hash)))?;
+            len = (height + 1) * SLOT_SIZE;
+        }
+        let file = writer.into_inner().map_err(|e| Error::Io(e.into_error()))?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Write a height file for the database at `dir` and open it
+    ///
+    /// The file is written beside the old one and renamed into place, so a
+    /// crash part-way through leaves the previous height file intact.
+    pub(crate) fn build(dir: &Dir, canonical: impl IntoIterator<Item = (u64, Hash)>) -> Result<Self> {
+        let tmp_path = dir.join(format!("{}.tmp", HEIGHT_FILE));
+        Self::create(dir, &tmp_path, canonical)?;
+        dir.rename(&tmp_path, &dir.join(HEIGHT_FILE))?;
+        Self::open(dir, true)
+    }
+
+    /// Number of slots, up to the highest canonical height
+    pub(crate) fn len(&self) -> Result<u64> {
+        Ok(self.file.len()? / SLOT_SIZE)
+    }
+
+    /// The canonical hash at `height`
+    pub(crate) fn get(&self, height: u64) -> Result<Option<Hash>> {
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        match read_exact_at(&self.file, &mut slot, height * SLOT_SIZE) {
+            Ok(()) => Ok(decode(&slot)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every canonical height from `from` up, with its hash, in ascending
+    /// order
+    pub(crate) fn canonical_from(&self, from: u64) -> Result<Vec<(u64, Hash)>> {
+        self.canonical_between(from, u64::MAX)
+    }
+
+    /// Every canonical height in `from..to`, with its hash, in ascending
+    /// order
+    pub(crate) fn canonical_between(&self, from: u64, to: u64) -> Result<Vec<(u64, Hash)>> {
+        let len = self.len()?.min(to);
+        let mut canonical = Vec::new();
+        let mut page = vec![0u8; PAGE_SIZE];
+        let mut height = from;
+        while height < len {
+            // Skip the holes left below blocks stored far above the chain
+            height = height.max(self.file.data_from(height * SLOT_SIZE)? / SLOT_SIZE);
+            if height >= len {
+                break;
+            }
+            let count = (len - height).min(PAGE_SLOTS);
+            let bytes = &mut page[..(count * SLOT_SIZE) as usize];
+            read_exact_at(&self.file, bytes, height * SLOT_SIZE)?;
+            for (h, slot) in (height..).zip(bytes.chunks_exact(SLOT_SIZE as usize)) {
+                if let Some(hash) = decode(slot) {
+                    canonical.push((h, hash));
+                }
+            }
+            height += count;
+        }
+        Ok(canonical)
+    }
+}
+
+/// The height index of a database: its height file, plus the changes made
+/// since the checkpoint
+///
+/// A read-only database leaves the height file to the writer, which
+/// rewrites it in place, and holds the canonical chain in memory as pending
+/// changes instead. Cloning is cheap and yields a snapshot that later
+/// changes don't touch.
+#[derive(Debug, Clone)]
+pub(crate) struct HeightIndex {
+    slots: Option<Arc<HeightFile>>,
+    /// Heights set, or cleared (`None`), since the checkpoint
+    pending: CowMap<u64, Option<Hash>>,
+}
+
+impl HeightIndex {
+    pub(crate) fn new(slots: Option<HeightFile>) -> Self {
+        Self {
+            slots: slots.map(Arc::new),
+            pending: CowMap::new(),
+        }
+    }
+
+    /// An index holding the whole canonical chain of a height file in
+    /// memory
+    pub(crate) fn load(slots: &HeightFile) -> Result<Self> {
+        let mut index = Self::new(None);
+        for (height, hash) in slots.canonical_from(0)? {
+            index.pending.insert(height, Some(hash));
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn get(&self, height: u64) -> Result<Option<Hash>> {
+        match (self.pending.get(&height), &self.slots) {
+            (Some(change), _) => Ok(*change),
+            (None, Some(slots)) => slots.get(height),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub(crate) fn contains(&self, height: u64) -> Result<bool> {
+        Ok(self.get(height)?.is_some())
+    }
+
+    pub(crate) fn insert(&mut self, height: u64, hash: Hash) {
+        self.pending.insert(height, Some(hash));
+    }
+
+    pub(crate) fn remove(&mut self, height: u64) {
+        if self.slots.is_some() {
+            self.pending.insert(height, None);
+        } else {
+            self.pending.remove(&height);
+        }
+    }
+
+    /// The highest canonical height and its hash
+    pub(crate) fn last(&self) -> Result<Option<(u64, Hash)>> {
+        self.last_at_most(u64::MAX)
+    }
+
+    /// The highest canonical height up to `at_most`, and its hash
+    pub(crate) fn last_at_most(&self, at_most: u64) -> Result<Option<(u64, Hash)>> {
+        let mut last = self
+            .pending
+            .iter()
+            .filter(|(&height, _)| height <= at_most)
+            .filter_map(|(&height, change)| change.map(|hash| (height, hash)))
+            .max_by_key(|(height, _)| *height);
+        if let Some(slots) = &self.slots {
+            // The file ends at a canonical height unless it was cleared since
+            let mut height = slots.len()?.min(at_most.saturating_add(1));
+            while height > last.map_or(0, |(h, _)| h + 1) {
+                height -= 1;
+                let hash = match self.pending.get(&height) {
+                    Some(change) => *change,
+                    None => slots.get(height)?,
+                };
+                if let Some(hash) = hash {
+                    last = Some((height, hash));
+                    break;
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    /// Every canonical height from `from` up, with its hash, in ascending
+    /// order
+    pub(crate) fn canonical_from(&self, from: u64) -> Result<Vec<(u64, Hash)>> {
+        self.canonical_between(from, u64::MAX)
+    }
+
+    /// Every canonical height in `from..to`, with its hash, in ascending
+    /// order
+    pub(crate) fn canonical_between(&self, from: u64, to: u64) -> Result<Vec<(u64, Hash)>> {
+        let mut canonical: Vec<(u64, Hash)> = match &self.slots {
+            Some(slots) => slots
+                .canonical_between(from, to)?
+                .into_iter()
+                .filter(|(height, _)| !self.pending.contains_key(height))
+                .collect(),
+            None => Vec::new(),
+        };
+        canonical.extend(
+            self.pending
+                .iter()
+                .filter(|(&height, _)| (from..to).contains(&height))
+                .filter_map(|(&height, change)| change.map(|hash| (height, hash))),
+        );
+        canonical.sort_unstable_by_key(|(height, _)| *height);
+        Ok(canonical)
+    }
+
+    /// Changes not yet written to the height file
+    pub(crate) fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The height file and the changes to write to it, unless a snapshot
+    /// still reads it or there is none
+    pub(crate) fn checkpoint_parts(&mut self) -> Option<(&HeightFile, &CowMap<u64, Option<Hash>>)> {
+        let slots = self.slots.as_mut().and_then(Arc::get_mut)?;
+        Some((slots, &self.pending))
+    }
+
+    /// Forget the changes a checkpoint has written to the height file
+    pub(crate) fn clear_pending(&mut self) {
+        self.pending = CowMap::new();
+    }
+}
+
+/// Slot changes being prepared for a checkpoint, a page at a time, with
+/// the previous contents of every page they overwrite
+pub(crate) struct HeightUpdate<'a> {
+    file: &'a File,
+    /// Length of the height file before the update
+    pub(crate) original_len: u64,
+    len: u64,
+    /// Current contents of every page read or written
+    pages: HashMap<u64, Vec<u8>>,
+    /// Previous contents of the existing pages among them
+    pub(crate) originals: Vec<(u64, Vec<u8>)>,
+}
+
+impl<'a> HeightUpdate<'a> {
+    pub(crate) fn new(slots: &'a HeightFile) -> Result<Self> {
+        let len = slots.file.len()?;
+        Ok(Self {
+            file: &slots.file,
+            original_len: len,
+            len,
+            pages: HashMap::new(),
+            originals: Vec::new(),
+        })
+    }
+
+    /// Set or clear a slot
+    pub(crate) fn set(&mut self, height: u64, hash: Option<&Hash>) -> Result<()> {
+        let at = ((height % PAGE_SLOTS) * SLOT_SIZE) as usize;
+        self.page(height / PAGE_SLOTS)?[at..at + SLOT_SIZE as usize].copy_from_slice(&encode(hash));
+        if hash.is_some() {
+            self.len = self.len.max((height + 1) * SLOT_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Drop the empty slots at the end of the file
+    pub(crate) fn trim(&mut self) -> Result<()> {
+        while self.len >= SLOT_SIZE {
+            let height = self.len / SLOT_SIZE - 1;
+            let at = ((height % PAGE_SLOTS) * SLOT_SIZE) as usize;
+            if decode(&self.page(height / PAGE_SLOTS)?[at..at + SLOT_SIZE as usize]).is_some() {
+                break;
+            }
+            self.len -= SLOT_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Write the changed pages and the new length, and make them durable
+    pub(crate) fn write(&self) -> Result<()> {
+        let mut file = self.file;
+        let mut numbers: Vec<u64> = self.pages.keys().copied().collect();
+        numbers.sort_unstable();
+        for number in numbers {
+            let start = number * PAGE_SIZE as u64;
+            if start >= self.len {
+                break;
+            }
+            let end = (self.len - start).min(PAGE_SIZE as u64) as usize;
+            file.seek(SeekFrom::Start(start))?;
+            file.write_all(&self.pages[&number][..end])?;
+        }
+        file.set_len(self.len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// A page as this update has left it so far
+    fn page(&mut self, number: u64) -> Result<&mut Vec<u8>> {
+        if !self.pages.contains_key(&number) {
+            let mut page = vec![0u8; PAGE_SIZE];
+            let start = number * PAGE_SIZE as u64;
+            if start < self.original_len {
+                let end = (self.original_len - start).min(PAGE_SIZE as u64) as usize;
+                read_exact_at(self.file, &mut page[..end], start)?;
+                self.originals.push((number, page.clone()));
+            }
+            self.pages.insert(number, page);
+        }
+        Ok(self.pages.get_mut(&number).unwrap())
+    }
+}
+
+/// Put back height file pages logged by an interrupted checkpoint
+pub(crate) fn roll_back(dir: &Dir, pages: &[(u64, &[u8])], len: u64) -> Result<()> {
+    let mut file = dir.open(&dir.join(HEIGHT_FILE), OpenOptions::new().write(true))?;
+    for (number, page) in pages {
+        file.seek(SeekFrom::Start(number * PAGE_SIZE as u64))?;
+        file.write_all(page)?;
+    }
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A slot holding `hash`, or an empty one
+fn encode(hash: Option<&Hash>) -> [u8; SLOT_SIZE as usize] {
+    let mut slot = [0u8; SLOT_SIZE as usize];
+    if let Some(hash) = hash {
+        for (byte, &b) in slot.iter_mut().zip(hash) {
+            *byte = !b;
+        }
+    }
+    slot
+}
+
+fn decode(slot: &[u8]) -> Option<Hash> {
+    if slot.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, &b) in hash.iter_mut().zip(slot) {
+        *byte = !b;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_slots_and_trailing_trim() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-heights-slots");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+
+        // The all-zero hash is a valid canonical block
+        let slots = HeightFile::build(&Dir::os(&temp_dir), [(0, [0u8; 32]), (2, [2u8; 32])]).unwrap();
+        assert_eq!(slots.len().unwrap(), 3);
+        assert_eq!(slots.get(0).unwrap(), Some([0u8; 32]));
+        assert_eq!(slots.get(1).unwrap(), None);
+        assert_eq!(slots.get(200).unwrap(), None);
+
+        let mut update = HeightUpdate::new(&slots).unwrap();
+        update.set(300, Some(&[3u8; 32])).unwrap();
+        update.set(300, None).unwrap();
+        update.set(2, None).unwrap();
+        update.trim().unwrap();
+        update.write().unwrap();
+        assert_eq!(slots.len().unwrap(), 1);
+        assert_eq!(slots.canonical_from(0).unwrap(), vec![(0, [0u8; 32])]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
@@ -0,0 +1,1128 @@
+//! On-disk hash index: a linear-hash table in adzdb.key
+//!
+//! adzdb.idx is a log, so finding a key in it means reading all of it. The
+//! key file holds the same mapping as a hash table of fixed-size pages: a
+//! lookup reads one bucket page, or a second one if the bucket overflowed,
+//! and opening a database reads only the header.
+//!
+//! The table grows by linear hashing. Whenever buckets hold more than half a
+//! page of entries on average, the next bucket in turn is split in two, so
+//! the table is never rehashed all at once. Bucket pages are allocated in
+//! groups that double in size, whose first pages are listed in the header; a
+//! full bucket chains overflow pages taken from the end of the file.
+//!
+//! The key file is a checkpoint: its header records how far into adzdb.idx
+//! and the height journal it is up to date, and the changes since then are
+//! replayed from those files on open. The dense height file is checkpointed
+//! along with it. A checkpoint updates pages of both in place, so it first
+//! copies every page it is about to overwrite to adzdb.key.log. If a crash
+//! interrupts it, the next open copies them back, returning both files to
+//! the previous checkpoint.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::checksum::crc32c;
+use crate::cow::CowMap;
+use crate::heights::{self, HeightFile, HeightIndex, HeightUpdate};
+use crate::segments::Position;
+use crate::vfs::{Dir, File, OpenOptions};
+use crate::{read_exact_at, Error, Hash, HeightEntry, IndexEntry, Result};
+
+/// Key file name
+pub(crate) const KEY_FILE: &str = "adzdb.key";
+
+/// Previous contents of the pages a checkpoint is overwriting
+pub(crate) const LOG_FILE: &str = "adzdb.key.log";
+
+/// Pending changes that make the next automatic sync write a checkpoint
+pub(crate) const CHECKPOINT_INTERVAL: usize = 4096;
+
+/// Magic bytes at the start of the key file
+const KEY_MAGIC: &[u8; 4] = b"ADZK";
+
+/// Magic bytes at the start of the log
+const LOG_MAGIC: &[u8; 4] = b"ADZL";
+
+/// Key file layout version
+const KEY_VERSION: u32 = 3;
+
+/// Size of every page: the header, buckets, overflow and side-block pages,
+/// and the height file pages a checkpoint logs
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+/// Record count and next page number at the start of each page
+const PAGE_HEADER: usize = 16;
+
+/// Index entries per bucket or overflow page
+const BUCKET_CAPACITY: usize = (PAGE_SIZE - PAGE_HEADER) / IndexEntry::SIZE;
+
+/// Average entries per bucket above which the next bucket is split; half a
+/// page keeps overflow pages rare
+const SPLIT_LOAD: u64 = BUCKET_CAPACITY as u64 / 2;
+
+/// Bucket page groups, each twice the size of the one before
+const GROUPS: usize = 48;
+
+/// Bytes of the header page in use, checksum included
+const HEADER_SIZE: usize = 168 + GROUPS * 8 + 4;
+
+/// Page number bit marking a logged page of the height file
+const HEIGHT_PAGE: u64 = 1 << 63;
+
+/// The stored blocks that are not canonical, by height
+type SideBlocks = CowMap<u64, Vec<Hash>>;
+
+/// How far into the log files a key file is up to date
+///
+/// The key file and the height file hold exactly what replaying adzdb.idx
+/// up to `index_len` and the height journal up to `height_len` yields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    /// Length of adzdb.idx covered
+    pub(crate) index_len: u64,
+    /// Length of adzdb.hgt.log covered
+    pub(crate) height_len: u64,
+    /// End of the data covered: the end of the record `index_tail` points
+    /// at
+    pub(crate) data_end: Position,
+    /// Total size of the live values, as in `Metadata::data_size`
+    pub(crate) data_size: u64,
+    /// Total size of the live values before compression, as in
+    /// `Metadata::logical_size`
+    pub(crate) logical_size: u64,
+    /// The last index record covered, to tell whether adzdb.idx has been
+    /// rewritten since
+    pub(crate) index_tail: [u8; IndexEntry::SIZE],
+}
+
+impl Checkpoint {
+    /// The checkpoint of an empty database
+    pub(crate) const EMPTY: Self = Self {
+        index_len: 0,
+        height_len: 0,
+        data_end: Position {
+            segment: 0,
+            offset: 0,
+        },
+        data_size: 0,
+        logical_size: 0,
+        index_tail: [0; IndexEntry::SIZE],
+    };
+}
+
+/// Contents of the header page
+#[derive(Debug, Clone)]
+struct Header {
+    /// Identifies this key file, so a log left behind by another one is
+    /// never applied to it
+    id: u64,
+    bucket_count: u64,
+    /// Entries in the table
+    entries: u64,
+    page_count: u64,
+    /// First page of the chain of free pages, or 0
+    free_head: u64,
+    /// First page of the side-block list, or 0
+    side_head: u64,
+    /// CRC32C of the side-block list, to skip rewriting it unchanged
+    side_checksum: u32,
+    checkpoint: Checkpoint,
+    /// First page of each bucket group
+    groups: [u64; GROUPS],
+}
+
+impl Header {
+    fn to_page(&self) -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        page[0..4].copy_from_slice(KEY_MAGIC);
+        page[4..8].copy_from_slice(&KEY_VERSION.to_le_bytes());
+        page[8..16].copy_from_slice(&self.id.to_le_bytes());
+        page[16..24].copy_from_slice(&self.bucket_count.to_le_bytes());
+        page[24..32].copy_from_slice(&self.entries.to_le_bytes());
+        page[32..40].copy_from_slice(&self.page_count.to_le_bytes());
+        page[40..48].copy_from_slice(&self.free_head.to_le_bytes());
+        page[48..56].copy_from_slice(&self.side_head.to_le_bytes());
+        page[56..60].copy_from_slice(&self.side_checksum.to_le_bytes());
+        page[60..64].copy_from_slice(&self.checkpoint.data_end.segment.to_le_bytes());
+        page[64..72].copy_from_slice(&self.checkpoint.index_len.to_le_bytes());
+        page[72..80].copy_from_slice(&self.checkpoint.height_len.to_le_bytes());
+        page[80..88].copy_from_slice(&self.checkpoint.data_end.offset.to_le_bytes());
+        page[88..96].copy_from_slice(&self.checkpoint.data_size.to_le_bytes());
+        page[96..160].copy_from_slice(&self.checkpoint.index_tail);
+        page[160..168].copy_from_slice(&self.checkpoint.logical_size.to_le_bytes());
+        for (chunk, group) in page[168..HEADER_SIZE - 4].chunks_exact_mut(8).zip(&self.groups) {
+            chunk.copy_from_slice(&group.to_le_bytes());
+        }
+        let checksum = crc32c(&page[..HEADER_SIZE - 4]);
+        page[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    /// Decode a header page, or `None` if it is not an intact one
+    fn from_page(page: &[u8]) -> Option<Self> {
+        let u64_at = |at: usize| u64::from_le_bytes(page[at..at + 8].try_into().unwrap());
+        let checksum = u32::from_le_bytes(page[HEADER_SIZE - 4..HEADER_SIZE].try_into().unwrap());
+        if &page[0..4] != KEY_MAGIC
+            || u32::from_le_bytes(page[4..8].try_into().unwrap()) != KEY_VERSION
+            || checksum != crc32c(&page[..HEADER_SIZE - 4])
+        {
+            return None;
+        }
+
+        let mut groups = [0u64; GROUPS];
+        for (group, chunk) in groups.iter_mut().zip(page[168..HEADER_SIZE - 4].chunks_exact(8)) {
+            *group = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Some(Self {
+            id: u64_at(8),
+            bucket_count: u64_at(16),
+            entries: u64_at(24),
+            page_count: u64_at(32),
+            free_head: u64_at(40),
+            side_head: u64_at(48),
+            side_checksum: u32::from_le_bytes(page[56..60].try_into().unwrap()),
+            checkpoint: Checkpoint {
+                index_len: u64_at(64),
+                height_len: u64_at(72),
+                data_end: Position {
+                    segment: u32::from_le_bytes(page[60..64].try_into().unwrap()),
+                    offset: u64_at(80),
+                },
+                data_size: u64_at(88),
+                logical_size: u64_at(160),
+                index_tail: page[96..160].try_into().unwrap(),
+            },
+            groups,
+        })
+    }
+
+    /// The page holding a bucket
+    fn bucket_page(&self, bucket: u64) -> u64 {
+        let group = group_of(bucket);
+        self.groups[group] + bucket - group_first(group)
+    }
+}
+
+/// The on-disk hash table of a database's live index entries
+#[derive(Debug)]
+pub(crate) struct KeyFile {
+    file: File,
+    dir: Dir,
+    log_path: PathBuf,
+    header: Header,
+}
+
+impl KeyFile {
+    /// Open the key file of the database at `dir`
+    ///
+    /// A checkpoint interrupted by a crash is rolled back first. Returns
+    /// `None` if there is no key file or its header is damaged; either way
+    /// it has to be rebuilt from adzdb.idx.
+    pub(crate) fn open(dir: &Dir) -> Result<Option<Self>> {
+        let file = match dir.open(&dir.join(KEY_FILE), OpenOptions::new().read(true).write(true)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let log_path = dir.join(LOG_FILE);
+        roll_back(dir, &file, &log_path)?;
+        Self::with_header(dir, file, log_path)
+    }
+
+    /// Open the key file of the database at `dir` without writing to it, for
+    /// a read-only database
+    ///
+    /// A checkpoint the writer is in the middle of, or that a crash
+    /// interrupted, can't be rolled back here, so then `None` is returned as
+    /// if there were no key file.
+    pub(crate) fn open_read_only(dir: &Dir) -> Result<Option<Self>> {
+        let file = match dir.open_read(&dir.join(KEY_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let log_path = dir.join(LOG_FILE);
+        if !log_is_empty(dir, &log_path)? {
+            return Ok(None);
+        }
+        Self::with_header(dir, file, log_path)
+    }
+
+    fn with_header(dir: &Dir, file: File, log_path: PathBuf) -> Result<Option<Self>> {
+        let Some(header) = read_header(&file)? else {
+            return Ok(None);
+        };
+        if file.len()? < header.page_count * PAGE_SIZE as u64 {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            file,
+            dir: dir.clone(),
+            log_path,
+            header,
+        }))
+    }
+
+    /// Write a complete key file for `entries` at `path`
+    pub(crate) fn create(
+        dir: &Dir,
+        path: &Path,
+        entries: &[IndexEntry],
+        side_blocks: &CowMap<u64, Vec<Hash>>,
+        checkpoint: Checkpoint,
+    ) -> Result<()> {
+        let bucket_count = ((entries.len() as u64 + SPLIT_LOAD - 1) / SPLIT_LOAD).max(1);
+        let mut buckets = vec![Vec::new(); bucket_count as usize];
+        for entry in entries {
+            buckets[bucket_of(hash_key(&entry.key), bucket_count) as usize].push(entry.to_bytes());
+        }
+
+        // Whole groups are laid out back to back after the header, so bucket
+        // `b` is page `b + 1`; overflow and side-block pages follow
+        let last_group = group_of(bucket_count - 1);
+        let mut groups = [0u64; GROUPS];
+        for (group, first_page) in groups.iter_mut().enumerate().take(last_group + 1) {
+            *first_page = 1 + group_first(group);
+        }
+        let bucket_pages = group_first(last_group) + group_size(last_group);
+
+        let mut writer = BufWriter::new(dir.create(path)?);
+        writer.write_all(&[0u8; PAGE_SIZE])?;
+        let mut next_page = 1 + bucket_pages;
+        let mut spilled = Vec::new();
+        for bucket in 0..bucket_pages as usize {
+            let records = buckets.get(bucket).map(Vec::as_slice).unwrap_or(&[]);
+            let mut chunks = records.chunks(BUCKET_CAPACITY);
+            let first = chunks.next().unwrap_or(&[]);
+            let rest: Vec<_> = chunks.collect();
+            let next = if rest.is_empty() { 0 } else { next_page };
+            writer.write_all(&encode_page(first, next))?;
+            for (i, chunk) in rest.iter().enumerate() {
+                let next = if i + 1 < rest.len() { next_page + i as u64 + 1 } else { 0 };
+                spilled.push(encode_page(chunk, next));
+            }
+            next_page += rest.len() as u64;
+        }
+        for page in &spilled {
+            writer.write_all(page)?;
+        }
+
+        let (side, side_checksum) = side_records(side_blocks);
+        let side_head = if side.is_empty() { 0 } else { next_page };
+        let side_chunks: Vec<_> = side.chunks(page_capacity::<{ HeightEntry::SIZE }>()).collect();
+        for (i, chunk) in side_chunks.iter().enumerate() {
+            let next = if i + 1 < side_chunks.len() { next_page + 1 } else { 0 };
+            writer.write_all(&encode_page(chunk, next))?;
+            next_page += 1;
+        }
+
+        let header = Header {
+            id: new_id(),
+            bucket_count,
+            entries: entries.len() as u64,
+            page_count: next_page,
+            free_head: 0,
+            side_head,
+            side_checksum,
+            checkpoint,
+            groups,
+        };
+        let mut file = writer.into_inner().map_err(|e| Error::Io(e.into_error()))?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.to_page())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Write a key file for the database at `dir` and open it
+    ///
+    /// The file is written beside the old one and renamed into place, so a
+    /// crash part-way through leaves the previous key file intact.
+    pub(crate) fn build(
+        dir: &Dir,
+        entries: &[IndexEntry],
+        side_blocks: &CowMap<u64, Vec<Hash>>,
+        checkpoint: Checkpoint,
+    ) -> Result<Self> {
+        let tmp_path = dir.join(format!("{}.tmp", KEY_FILE));
+        Self::create(dir, &tmp_path, entries, side_blocks, checkpoint)?;
+        dir.rename(&tmp_path, &dir.join(KEY_FILE))?;
+        Self::open(dir)?.ok_or_else(|| Error::Corruption("Key file unreadable after writing it".to_string()))
+    }
+
+    /// How far into the log files this key file is up to date
+    pub(crate) fn checkpoint(&self) -> &Checkpoint {
+        &self.header.checkpoint
+    }
+
+    /// Number of entries in the table
+    pub(crate) fn len(&self) -> u64 {
+        self.header.entries
+    }
+
+    /// Look up the entry for `key`
+    pub(crate) fn get(&self, key: &Hash) -> Result<Option<IndexEntry>> {
+        let bucket = bucket_of(hash_key(key), self.header.bucket_count);
+        let mut number = self.header.bucket_page(bucket);
+        let mut page = vec![0u8; PAGE_SIZE];
+        for _ in 0..self.header.page_count {
+            read_page(&self.file, number, &mut page)?;
+            let (count, next) = page_links::<{ IndexEntry::SIZE }>(&page, number)?;
+            if let Some(record) = page_records::<{ IndexEntry::SIZE }>(&page, count).find(|r| r[..32] == key[..]) {
+                return Ok(Some(IndexEntry::from_bytes(record)));
+            }
+            if next == 0 {
+                return Ok(None);
+            }
+            number = next;
+        }
+        Err(cycle(number))
+    }
+
+    /// Every entry in the table, bucket by bucket
+    pub(crate) fn entries(&self) -> Result<Vec<IndexEntry>> {
+        let mut entries = Vec::with_capacity(self.header.entries as usize);
+        for bucket in 0..self.header.bucket_count {
+            let records = read_chain::<{ IndexEntry::SIZE }>(&self.file, &self.header, self.header.bucket_page(bucket))?;
+            entries.extend(records.iter().map(IndexEntry::from_bytes));
+        }
+        Ok(entries)
+    }
+
+    /// The stored blocks that are not canonical at their height
+    pub(crate) fn side_blocks(&self) -> Result<CowMap<u64, Vec<Hash>>> {
+        let mut side_blocks: CowMap<u64, Vec<Hash>> = CowMap::new();
+        if self.header.side_head != 0 {
+            for record in read_chain::<{ HeightEntry::SIZE }>(&self.file, &self.header, self.header.side_head)? {
+                let entry = HeightEntry::from_bytes(&record);
+                side_blocks.get_or_default(entry.height).push(entry.hash);
+            }
+        }
+        Ok(side_blocks)
+    }
+
+    /// Copy the whole table and the side-block list into memory
+    ///
+    /// For a read-only database, which can't keep reading pages the writer
+    /// overwrites in place. Returns `None` if the writer has started a
+    /// checkpoint since the key file was opened, since the copy may then mix
+    /// pages from before and after it.
+    pub(crate) fn load(&self) -> Result<Option<(HashIndex, SideBlocks)>> {
+        let entries = self.entries()?;
+        let side_blocks = self.side_blocks()?;
+        let unchanged = log_is_empty(&self.dir, &self.log_path)?
+            && read_header(&self.file)?.map(|header| header.to_page()) == Some(self.header.to_page());
+        if !unchanged {
+            return Ok(None);
+        }
+        let mut index = HashIndex::new(None);
+        for entry in entries {
+            index.insert(entry);
+        }
+        Ok(Some((index, side_blocks)))
+    }
+
+    /// Apply changes, store a new side-block list and update the height
+    /// file as one checkpoint
+    ///
+    /// `None` removes a key or clears a height. The previous contents of
+    /// every page written, in either file, are logged and synced first, so a
+    /// crash never leaves a mix of both checkpoints behind.
+    pub(crate) fn update<'a>(
+        &mut self,
+        changes: impl Iterator<Item = (&'a Hash, &'a Option<IndexEntry>)>,
+        side_blocks: &CowMap<u64, Vec<Hash>>,
+        heights: &HeightFile,
+        canonical: impl Iterator<Item = (&'a u64, &'a Option<Hash>)>,
+        checkpoint: Checkpoint,
+    ) -> Result<()> {
+        let mut update = Update::new(&self.file, &self.header)?;
+        for (key, change) in changes {
+            match change {
+                Some(entry) => update.insert(entry)?,
+                None => update.remove(key)?,
+            }
+        }
+        update.set_side_blocks(side_blocks)?;
+        update.header.checkpoint = checkpoint;
+
+        let mut slots = HeightUpdate::new(heights)?;
+        for (&height, change) in canonical {
+            slots.set(height, change.as_ref())?;
+        }
+        slots.trim()?;
+
+        update.write_log(&self.dir, &self.log_path, &slots)?;
+        slots.write()?;
+        update.write_pages()?;
+        clear_log(&self.dir, &self.log_path)?;
+        self.header = update.header;
+        Ok(())
+    }
+}
+
+/// The hash index of a database: its key file, plus the changes made since
+/// the key file's checkpoint
+///
+/// A read-only database keeps no key file open, since the writer updates it
+/// in place, and holds its whole index in memory as pending changes instead,
+/// copied from the key file when it opened.
+/// Cloning is cheap and yields a snapshot that later changes don't touch.
+#[derive(Debug, Clone)]
+pub(crate) struct HashIndex {
+    keys: Option<Arc<KeyFile>>,
+    /// Entries stored, or removed (`None`), since the checkpoint
+    pending: CowMap<Hash, Option<IndexEntry>>,
+}
+
+impl HashIndex {
+    pub(crate) fn new(keys: Option<KeyFile>) -> Self {
+        Self {
+            keys: keys.map(Arc::new),
+            pending: CowMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &Hash) -> Result<Option<IndexEntry>> {
+        match (self.pending.get(key), &self.keys) {
+            (Some(change), _) => Ok(*change),
+            (None, Some(keys)) => keys.get(key),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &Hash) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    pub(crate) fn insert(&mut self, entry: IndexEntry) {
+        self.pending.insert(entry.key, Some(entry));
+    }
+
+    pub(crate) fn remove(&mut self, key: &Hash) {
+        if self.keys.is_some() {
+            self.pending.insert(*key, None);
+        } else {
+            self.pending.remove(key);
+        }
+    }
+
+    /// The key file, if this index has one
+    pub(crate) fn key_file(&self) -> Option<&KeyFile> {
+        self.keys.as_deref()
+    }
+
+    /// Changes not yet written to the key file
+    pub(crate) fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Every live entry, in the order their records were appended
+    pub(crate) fn entries(&self) -> Result<Vec<IndexEntry>> {
+        let mut entries: HashMap<Hash, IndexEntry> = match &self.keys {
+            Some(keys) => keys.entries()?.into_iter().map(|entry| (entry.key, entry)).collect(),
+            None => HashMap::new(),
+        };
+        for (key, change) in self.pending.iter() {
+            match change {
+                Some(entry) => entries.insert(*key, *entry),
+                None => entries.remove(key),
+            };
+        }
+        let mut entries: Vec<IndexEntry> = entries.into_values().collect();
+        entries.sort_unstable_by_key(IndexEntry::position);
+        Ok(entries)
+    }
+
+    /// Write the pending changes to the key file, and those of `heights` to
+    /// the height file, as of `checkpoint`
+    ///
+    /// Returns false, writing nothing, if a snapshot still reads either file
+    /// or there is none.
+    pub(crate) fn checkpoint(
+        &mut self,
+        side_blocks: &CowMap<u64, Vec<Hash>>,
+        heights: &mut HeightIndex,
+        checkpoint: Checkpoint,
+    ) -> Result<bool> {
+        let Some(keys) = self.keys.as_mut().and_then(Arc::get_mut) else {
+            return Ok(false);
+        };
+        let Some((slots, canonical)) = heights.checkpoint_parts() else {
+            return Ok(false);
+        };
+        keys.update(self.pending.iter(), side_blocks, slots, canonical.iter(), checkpoint)?;
+        self.pending = CowMap::new();
+        heights.clear_pending();
+        Ok(true)
+    }
+}
+
+/// A checkpoint being prepared: pages are changed in memory, then written
+/// out behind a log of their previous contents
+struct Update<'a> {
+    file: &'a File,
+    header: Header,
+    /// Page count before the update; pages from here on are new
+    original_pages: u64,
+    /// Current contents of every page read or written
+    pages: HashMap<u64, Vec<u8>>,
+    /// Previous contents of the existing pages among them, header included
+    originals: Vec<(u64, Vec<u8>)>,
+}
+
+impl<'a> Update<'a> {
+    fn new(file: &'a File, header: &Header) -> Result<Self> {
+        let mut header_page = vec![0u8; PAGE_SIZE];
+        read_page(file, 0, &mut header_page)?;
+        Ok(Self {
+            file,
+            header: header.clone(),
+            original_pages: header.page_count,
+            pages: HashMap::new(),
+            originals: vec![(0, header_page)],
+        })
+    }
+
+    /// Insert or replace an entry, splitting buckets as the table fills up
+    fn insert(&mut self, entry: &IndexEntry) -> Result<()> {
+        let first = self.header.bucket_page(bucket_of(hash_key(&entry.key), self.header.bucket_count));
+        let (mut records, chain) = self.read_chain::<{ IndexEntry::SIZE }>(first)?;
+        match records.iter().position(|record| record[..32] == entry.key[..]) {
+            Some(i) => records[i] = entry.to_bytes(),
+            None => {
+                records.push(entry.to_bytes());
+                self.header.entries += 1;
+            }
+        }
+        self.write_chain(chain, &records, true)?;
+
+        while self.header.entries > self.header.bucket_count * SPLIT_LOAD {
+            self.split()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Hash) -> Result<()> {
+        let first = self.header.bucket_page(bucket_of(hash_key(key), self.header.bucket_count));
+        let (mut records, chain) = self.read_chain::<{ IndexEntry::SIZE }>(first)?;
+        if let Some(i) = records.iter().position(|record| record[..32] == key[..]) {
+            records.remove(i);
+            self.header.entries -= 1;
+            self.write_chain(chain, &records, true)?;
+        }
+        Ok(())
+    }
+
+    /// Add the next bucket, moving into it the entries of the bucket it
+    /// splits from
+    fn split(&mut self) -> Result<()> {
+        let count = self.header.bucket_count;
+        let modulus = 1u64 << (63 - count.leading_zeros());
+        let from = count - modulus;
+
+        // The new bucket may open a group; its pages come from the file end
+        let group = group_of(count);
+        if group_first(group) == count {
+            self.header.groups[group] = self.header.page_count;
+            self.header.page_count += group_size(group);
+        }
+        self.header.bucket_count += 1;
+
+        let (records, chain) = self.read_chain::<{ IndexEntry::SIZE }>(self.header.bucket_page(from))?;
+        let (moved, kept): (Vec<_>, Vec<_>) = records.into_iter().partition(|record| {
+            bucket_of(hash_key(record[..32].try_into().unwrap()), count + 1) == count
+        });
+        self.write_chain(chain, &kept, true)?;
+        self.write_chain(vec![self.header.bucket_page(count)], &moved, true)?;
+        Ok(())
+    }
+
+    /// Replace the side-block list, unless it is unchanged
+    fn set_side_blocks(&mut self, side_blocks: &CowMap<u64, Vec<Hash>>) -> Result<()> {
+        let (records, checksum) = side_records(side_blocks);
+        if checksum == self.header.side_checksum {
+            return Ok(());
+        }
+        let chain = match self.header.side_head {
+            0 => Vec::new(),
+            head => self.read_chain::<{ HeightEntry::SIZE }>(head)?.1,
+        };
+        self.header.side_head = self.write_chain(chain, &records, false)?;
+        self.header.side_checksum = checksum;
+        Ok(())
+    }
+
+    /// The records of a chain of pages, and the pages
+    fn read_chain<const N: usize>(&mut self, first: u64) -> Result<(Vec<[u8; N]>, Vec<u64>)> {
+        let mut records = Vec::new();
+        let mut chain = Vec::new();
+        let mut number = first;
+        while number != 0 {
+            if chain.len() as u64 > self.header.page_count {
+                return Err(cycle(number));
+            }
+            let page = self.page(number)?;
+            let (count, next) = page_links::<N>(page, number)?;
+            records.extend(page_records::<N>(page, count).copied());
+            chain.push(number);
+            number = next;
+        }
+        Ok((records, chain))
+    }
+
+    /// Store records in a chain, reusing its pages, and return its first
+    /// page, or 0 if the records fit in none
+    ///
+    /// With `keep_first`, the first page is kept even if it ends up empty:
+    /// it is a bucket page, which always exists.
+    fn write_chain<const N: usize>(&mut self, mut chain: Vec<u64>, records: &[[u8; N]], keep_first: bool) -> Result<u64> {
+        let capacity = page_capacity::<N>();
+        let needed = ((records.len() + capacity - 1) / capacity).max(keep_first as usize);
+        while chain.len() < needed {
+            let page = self.allocate()?;
+            chain.push(page);
+        }
+        for number in chain.split_off(needed) {
+            self.free(number)?;
+        }
+
+        let mut chunks = records.chunks(capacity);
+        for (i, &number) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0);
+            *self.page(number)? = encode_page(chunks.next().unwrap_or(&[]), next);
+        }
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    /// Take a page from the free list, or from the end of the file
+    fn allocate(&mut self) -> Result<u64> {
+        match self.header.free_head {
+            0 => {
+                self.header.page_count += 1;
+                Ok(self.header.page_count - 1)
+            }
+            number => {
+                let next = u64::from_le_bytes(self.page(number)?[8..16].try_into().unwrap());
+                self.header.free_head = next;
+                Ok(number)
+            }
+        }
+    }
+
+    fn free(&mut self, number: u64) -> Result<()> {
+        let next = self.header.free_head;
+        *self.page(number)? = encode_page::<{ IndexEntry::SIZE }>(&[], next);
+        self.header.free_head = number;
+        Ok(())
+    }
+
+    /// A page as this update has left it so far
+    fn page(&mut self, number: u64) -> Result<&mut Vec<u8>> {
+        if !self.pages.contains_key(&number) {
+            let mut page = vec![0u8; PAGE_SIZE];
+            if number < self.original_pages {
+                read_page(self.file, number, &mut page)?;
+                self.originals.push((number, page.clone()));
+            }
+            self.pages.insert(number, page);
+        }
+        Ok(self.pages.get_mut(&number).unwrap())
+    }
+
+    /// Save the previous contents of every page about to be overwritten,
+    /// in the key file and in the height file
+    fn write_log(&self, dir: &Dir, path: &Path, heights: &HeightUpdate) -> Result<()> {
+        let count = self.originals.len() + heights.originals.len();
+        let mut log = Vec::with_capacity(40 + count * (8 + PAGE_SIZE));
+        log.extend_from_slice(LOG_MAGIC);
+        log.extend_from_slice(&self.header.id.to_le_bytes());
+        log.extend_from_slice(&self.original_pages.to_le_bytes());
+        log.extend_from_slice(&heights.original_len.to_le_bytes());
+        log.extend_from_slice(&(count as u64).to_le_bytes());
+        for (number, page) in &self.originals {
+            log.extend_from_slice(&number.to_le_bytes());
+            log.extend_from_slice(page);
+        }
+        for (number, page) in &heights.originals {
+            log.extend_from_slice(&(number | HEIGHT_PAGE).to_le_bytes());
+            log.extend_from_slice(page);
+        }
+        let checksum = crc32c(&log);
+        log.extend_from_slice(&checksum.to_le_bytes());
+
+        let mut file = dir.create(path)?;
+        file.write_all(&log)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Write the changed pages and the header, and make them durable
+    fn write_pages(&self) -> Result<()> {
+        let mut file = self.file;
+        let mut numbers: Vec<u64> = self.pages.keys().copied().collect();
+        numbers.sort_unstable();
+        for number in numbers {
+            file.seek(SeekFrom::Start(number * PAGE_SIZE as u64))?;
+            file.write_all(&self.pages[&number])?;
+        }
+        let len = self.header.page_count * PAGE_SIZE as u64;
+        if file.len()? < len {
+            file.set_len(len)?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.header.to_page())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Put back the pages logged by a checkpoint that a crash interrupted, in
+/// the key file and in the height file of the database at `dir`
+///
+/// An incomplete log was still being written, before any page was touched,
+/// and a log naming another key file is left over from one that has since
+/// been replaced; both are discarded.
+fn roll_back(dir: &Dir, file: &File, log_path: &Path) -> Result<()> {
+    let log = match dir.read(log_path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if log.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(log) = Log::parse(&log) {
+        // The header itself may be torn, but then this log is its own
+        let current = read_header(file)?;
+        if current.map_or(true, |header| header.id == log.id) {
+            let (height_pages, key_pages): (Vec<_>, Vec<_>) =
+                log.pages.into_iter().partition(|(number, _)| number & HEIGHT_PAGE != 0);
+            let height_pages: Vec<_> = height_pages
+                .into_iter()
+                .map(|(number, page)| (number & !HEIGHT_PAGE, page))
+                .collect();
+            heights::roll_back(dir, &height_pages, log.height_len)?;
+
+            let mut file = file;
+            for (number, page) in key_pages {
+                file.seek(SeekFrom::Start(number * PAGE_SIZE as u64))?;
+                file.write_all(page)?;
+            }
+            file.set_len(log.page_count * PAGE_SIZE as u64)?;
+            file.sync_all()?;
+
+            #[cfg(feature = "tracing")]
+            tracing::warn!("🩹 ADZDB rolled back an interrupted key file checkpoint");
+        }
+    }
+    clear_log(dir, log_path)
+}
+
+/// The contents of a complete log
+struct Log<'a> {
+    /// Id of the key file it was written for
+    id: u64,
+    /// Page count of the key file before the checkpoint
+    page_count: u64,
+    /// Length of the height file before the checkpoint
+    height_len: u64,
+    /// Page numbers and their previous contents; height file pages have
+    /// the `HEIGHT_PAGE` bit set
+    pages: Vec<(u64, &'a [u8])>,
+}
+
+impl<'a> Log<'a> {
+    /// Decode a log, or `None` if it was not written out completely
+    fn parse(log: &'a [u8]) -> Option<Self> {
+        if log.len() < 40 || &log[0..4] != LOG_MAGIC {
+            return None;
+        }
+        let (body, checksum) = log.split_at(log.len() - 4);
+        if crc32c(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+        let count = u64_at(28) as usize;
+        let records = &body[36..];
+        if records.len() != count * (8 + PAGE_SIZE) {
+            return None;
+        }
+        let pages = records
+            .chunks_exact(8 + PAGE_SIZE)
+            .map(|record| (u64::from_le_bytes(record[..8].try_into().unwrap()), &record[8..]))
+            .collect();
+        Some(Self {
+            id: u64_at(4),
+            page_count: u64_at(12),
+            height_len: u64_at(20),
+            pages,
+        })
+    }
+}
+
+/// Whether no checkpoint is in progress, or was interrupted, according to
+/// the log at `path`
+fn log_is_empty(dir: &Dir, path: &Path) -> Result<bool> {
+    match dir.len(path) {
+        Ok(len) => Ok(len == 0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn clear_log(dir: &Dir, path: &Path) -> Result<()> {
+    let log = dir.open(path, OpenOptions::new().write(true))?;
+    log.set_len(0)?;
+    log.sync_all()?;
+    Ok(())
+}
+
+fn read_header(file: &File) -> Result<Option<Header>> {
+    let mut page = vec![0u8; PAGE_SIZE];
+    match read_exact_at(file, &mut page, 0) {
+        Ok(()) => Ok(Header::from_page(&page)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_page(file: &File, number: u64, page: &mut [u8]) -> Result<()> {
+    read_exact_at(file, page, number * PAGE_SIZE as u64).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Corruption(format!("Key file page {} is past the end of the file", number))
+        } else {
+            Error::Io(e)
+        }
+    })
+}
+
+/// Every record of a chain of pages
+fn read_chain<const N: usize>(file: &File, header: &Header, first: u64) -> Result<Vec<[u8; N]>> {
+    let mut records = Vec::new();
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut number = first;
+    for _ in 0..header.page_count {
+        read_page(file, number, &mut page)?;
+        let (count, next) = page_links::<N>(&page, number)?;
+        records.extend(page_records::<N>(&page, count).copied());
+        if next == 0 {
+            return Ok(records);
+        }
+        number = next;
+    }
+    Err(cycle(number))
+}
+
+fn cycle(number: u64) -> Error {
+    Error::Corruption(format!("Key file page chain loops at page {}", number))
+}
+
+/// Records of `N` bytes that fit in a page
+const fn page_capacity<const N: usize>() -> usize {
+    (PAGE_SIZE - PAGE_HEADER) / N
+}
+
+/// A page's record count and the page its chain continues on, or 0
+fn page_links<const N: usize>(page: &[u8], number: u64) -> Result<(usize, u64)> {
+    let count = u32::from_le_bytes(page[0..4].try_into().unwrap()) as usize;
+    if count > page_capacity::<N>() {
+        return Err(Error::Corruption(format!("Key file page {} holds {} records", number, count)));
+    }
+    Ok((count, u64::from_le_bytes(page[8..16].try_into().unwrap())))
+}
+
+fn page_records<const N: usize>(page: &[u8], count: usize) -> impl Iterator<Item = &[u8; N]> {
+    page[PAGE_HEADER..PAGE_HEADER + count * N]
+        .chunks_exact(N)
+        .map(|record| record.try_into().unwrap())
+}
+
+fn encode_page<const N: usize>(records: &[[u8; N]], next: u64) -> Vec<u8> {
+    let mut page = vec![0u8; PAGE_SIZE];
+    page[0..4].copy_from_slice(&(records.len() as u32).to_le_bytes());
+    page[8..16].copy_from_slice(&next.to_le_bytes());
+    for (slot, record) in page[PAGE_HEADER..].chunks_exact_mut(N).zip(records) {
+        slot.copy_from_slice(record);
+    }
+    page
+}
+
+/// The side-block list as sorted records, and its checksum
+fn side_records(side_blocks: &CowMap<u64, Vec<Hash>>) -> (Vec<[u8; HeightEntry::SIZE]>, u32) {
+    let mut records: Vec<[u8; HeightEntry::SIZE]> = side_blocks
+        .iter()
+        .flat_map(|(&height, hashes)| hashes.iter().map(move |&hash| HeightEntry { height, hash }.to_bytes()))
+        .collect();
+    records.sort_unstable();
+    let checksum = crc32c(&records.concat());
+    (records, checksum)
+}
+
+/// The group holding a bucket: group 0 holds bucket 0, group `g` the
+/// buckets from `2^(g-1)` up to `2^g`
+fn group_of(bucket: u64) -> usize {
+    (64 - bucket.leading_zeros()) as usize
+}
+
+fn group_first(group: usize) -> u64 {
+    if group == 0 {
+        0
+    } else {
+        1 << (group - 1)
+    }
+}
+
+fn group_size(group: usize) -> u64 {
+    group_first(group).max(1)
+}
+
+/// The bucket of a key hash in a table of `count` buckets
+fn bucket_of(hash: u64, count: u64) -> u64 {
+    // Buckets below `count - modulus` have been split already and use one
+    // more bit of the hash
+    let modulus = 1u64 << (63 - count.leading_zeros());
+    let bucket = hash & (2 * modulus - 1);
+    if bucket < count {
+        bucket
+    } else {
+        bucket - modulus
+    }
+}
+
+/// Spread every bit of a key over the bucket hash, so keys that are not
+/// uniformly distributed still fill the buckets evenly
+fn hash_key(key: &Hash) -> u64 {
+    key.chunks_exact(8)
+        .fold(0, |hash, word| mix(hash ^ u64::from_le_bytes(word.try_into().unwrap())))
+}
+
+/// The splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// An id that differs between key files written at different times
+fn new_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0);
+    mix(nanos ^ (std::process::id() as u64) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn entry(i: u32) -> IndexEntry {
+        let mut key = [0u8; 32];
+        key[..4].copy_from_slice(&i.to_le_bytes());
+        IndexEntry {
+            key,
+            offset: i as u64 * 100,
+            size: i,
+            height: i as u64,
+            flags: 0,
+            segment: 0,
+            uncompressed_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_update_splits_buckets_and_reopens() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-keyfile-update");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let dir = Dir::os(&temp_dir);
+
+        let initial: Vec<IndexEntry> = (0..100).map(entry).collect();
+        let side = CowMap::new();
+        KeyFile::build(&dir, &initial, &side, Checkpoint::EMPTY).unwrap();
+        let slots = HeightFile::build(&dir, [(0, [0u8; 32])]).unwrap();
+
+        let mut keys = KeyFile::open(&dir).unwrap().unwrap();
+        let mut changes: CowMap<Hash, Option<IndexEntry>> = CowMap::new();
+        for i in 100..5000 {
+            changes.insert(entry(i).key, Some(entry(i)));
+        }
+        for i in (0..5000).step_by(3) {
+            changes.insert(entry(i).key, None);
+        }
+        let mut side_blocks: CowMap<u64, Vec<Hash>> = CowMap::new();
+        side_blocks.get_or_default(7).push([7u8; 32]);
+        let mut canonical: CowMap<u64, Option<Hash>> = CowMap::new();
+        canonical.insert(1, Some([1u8; 32]));
+        canonical.insert(500, Some([5u8; 32]));
+        let checkpoint = Checkpoint { index_len: IndexEntry::SIZE as u64, ..Checkpoint::EMPTY };
+        keys.update(changes.iter(), &side_blocks, &slots, canonical.iter(), checkpoint).unwrap();
+        assert_eq!(keys.len(), 5000 - 1667);
+        assert_eq!(slots.canonical_from(0).unwrap(), vec![(0, [0u8; 32]), (1, [1u8; 32]), (500, [5u8; 32])]);
+        drop(keys);
+
+        let keys = KeyFile::open(&dir).unwrap().unwrap();
+        assert_eq!(keys.checkpoint(), &checkpoint);
+        assert!(keys.header.bucket_count > 1);
+        for i in 0..5000 {
+            let found = keys.get(&entry(i).key).unwrap();
+            if i % 3 == 0 {
+                assert!(found.is_none());
+            } else {
+                assert_eq!(found.unwrap().offset, i as u64 * 100);
+            }
+        }
+        assert_eq!(keys.entries().unwrap().len(), 5000 - 1667);
+        assert_eq!(keys.side_blocks().unwrap()[&7], vec![[7u8; 32]]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_interrupted_update_is_rolled_back() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-keyfile-rollback");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let dir = Dir::os(&temp_dir);
+
+        let initial: Vec<IndexEntry> = (0..1000).map(entry).collect();
+        let keys = KeyFile::build(&dir, &initial, &CowMap::new(), Checkpoint::EMPTY).unwrap();
+        let chain: Vec<(u64, Hash)> = (0..200).map(|h| (h, [h as u8; 32])).collect();
+        let slots = HeightFile::build(&dir, chain.clone()).unwrap();
+
+        // Log the update, then crash part-way through writing its pages
+        let mut update = Update::new(&keys.file, &keys.header).unwrap();
+        for i in 1000..3000 {
+            update.insert(&entry(i)).unwrap();
+        }
+        let mut heights = HeightUpdate::new(&slots).unwrap();
+        for height in 100..200 {
+            heights.set(height, None).unwrap();
+        }
+        heights.trim().unwrap();
+        update.write_log(&dir, &keys.log_path, &heights).unwrap();
+        heights.write().unwrap();
+        let mut file = &keys.file;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&vec![0xAB; 3 * PAGE_SIZE]).unwrap();
+        drop(update);
+        drop(keys);
+
+        let keys = KeyFile::open(&dir).unwrap().unwrap();
+        assert_eq!(keys.len(), 1000);
+        assert_eq!(keys.get(&entry(999).key).unwrap().unwrap().offset, 99_900);
+        assert!(keys.get(&entry(1000).key).unwrap().is_none());
+        assert_eq!(slots.canonical_from(0).unwrap(), chain);
+        assert_eq!(fs::metadata(temp_dir.join(LOG_FILE)).unwrap().len(), 0);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
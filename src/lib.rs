@@ -14,10 +14,13 @@
 //!
 //! ```text
 //! adzdb/
-//! ├── adzdb.idx     # Hash index (hash → offset)
-//! ├── adzdb.dat     # Data file (append-only block storage)
-//! ├── adzdb.hgt     # Height index (height → hash)
-//! └── adzdb.meta    # Metadata (chain state)
+//! ├── adzdb.idx     # Index log (hash → offset, appended on every write)
+//! ├── adzdb.key     # Hash index (linear hashing, checkpointed from adzdb.idx)
+//! ├── adzdb.00000.dat # Data segments (append-only, checksummed block records)
+//! ├── adzdb.00001.dat # ... a new one whenever the last reaches the segment size
+//! ├── adzdb.hgt     # Height index (slot per height → hash, checkpointed)
+//! ├── adzdb.hgt.log # Height journal (canonical chain changes since then)
+//! └── adzdb.meta    # Metadata (chain state, in two checksummed slots)
 //! ```
 //!
 //! ## Quick Start
@@ -45,9 +48,11 @@
 //!
 //! ## Performance
 //!
-//! ADZDB achieves O(1) lookups for both hash and height queries by maintaining
-//! in-memory indices that are persisted to disk. This makes it ideal for
-//! blockchain verification where fast block lookups are critical.
+//! ADZDB achieves O(1) lookups for both hash and height queries. The hash
+//! index lives on disk in adzdb.key, a linear-hash table read one page per
+//! lookup, so opening a database only replays what was written since its last
+//! checkpoint. This makes it ideal for blockchain verification where fast
+//! block lookups are critical.
 //!
 //! | Operation | Complexity |
 //! |-----------|------------|
@@ -56,29 +61,73 @@
 //! | Put block | O(1) amortized |
 //! | Contains | O(1) |
 
-use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Write, Seek, SeekFrom, BufReader};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod batch;
+mod checksum;
+mod cow;
+mod heights;
+mod keyfile;
+mod lock;
+mod segments;
+mod superblock;
+pub mod compact;
+pub mod compress;
+pub mod hasher;
+pub mod migrate;
+pub mod sim;
+pub mod verify;
+pub mod vfs;
+#[cfg(feature = "mmap")]
+mod mmap;
+
+pub use batch::WriteBatch;
+
+use checksum::{crc32c, crc32c_extend};
+use compress::Compression;
+use hasher::Hasher;
+use cow::CowMap;
+use heights::{HeightFile, HeightIndex, HEIGHT_LOG};
+use keyfile::{Checkpoint, HashIndex, KeyFile, CHECKPOINT_INTERVAL};
+use lock::WriterLock;
+use segments::{Position, Records, Segments};
+use superblock::Superblock;
+use vfs::{Dir, File, MemVfs, OpenOptions, OsVfs, Vfs};
 
 /// Magic bytes for ADZDB files
 pub const MAGIC: &[u8; 4] = b"ADZB";
 
 /// Current file format version
-pub const VERSION: u32 = 1;
+pub const VERSION: u32 = 10;
 
 /// Maximum value size (1 GB)
 pub const MAX_VALUE_SIZE: u64 = 1 << 30;
 
+/// Default size a data segment grows to before the next is started (256 MB)
+pub const DEFAULT_SEGMENT_SIZE: u64 = 256 << 20;
+
+/// Smallest segment size accepted (4 KB); smaller segments would mostly
+/// hold one record each, and take a file per block
+pub const MIN_SEGMENT_SIZE: u64 = 4 << 10;
+
 /// Maximum reasonable block height (corruption detection)
 pub const MAX_REASONABLE_HEIGHT: u64 = 10_000_000;
 
+/// Heights `prune_below` prunes per commit
+const PRUNE_HEIGHTS: u64 = CHECKPOINT_INTERVAL as u64;
+
 /// 256-bit hash type
 pub type Hash = [u8; 32];
 
 /// Zero hash constant
 pub const ZERO_HASH: Hash = [0u8; 32];
 
+/// Hash no block may have: the height file stores it as an empty height
+pub const RESERVED_HASH: Hash = [0xFF; 32];
+
 /// Configuration for ADZDB
 ///
 /// # Example
@@ -95,6 +144,17 @@ pub struct Config {
     pub path: PathBuf,
     /// Sync data to disk after each write (default: true)
     pub sync_on_write: bool,
+    /// Hash function used to check that keys match their values (default: none)
+    pub hasher: Option<Arc<dyn Hasher>>,
+    /// Open the files read-only and reject writes (default: false)
+    pub read_only: bool,
+    /// Filesystem holding the database files (default: `OsVfs`)
+    pub vfs: Arc<dyn Vfs>,
+    /// Size in bytes past which a new data segment is started (default:
+    /// `DEFAULT_SEGMENT_SIZE`)
+    pub segment_size: u64,
+    /// Codec new values are compressed with (default: none)
+    pub compression: Compression,
 }
 
 impl Default for Config {
@@ -102,6 +162,11 @@ impl Default for Config {
         Self {
             path: PathBuf::from("./adzdb"),
             sync_on_write: true,
+            hasher: None,
+            read_only: false,
+            vfs: Arc::new(OsVfs),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compression: Compression::None,
         }
     }
 }
@@ -123,6 +188,30 @@ impl Config {
         }
     }
 
+    /// Create a configuration for a database kept entirely in memory
+    ///
+    /// The files live in a new [`MemVfs`] instead of on disk, and nothing
+    /// touches the filesystem. The database behaves exactly as an on-disk
+    /// one otherwise. Clones of the configuration share the files, so a
+    /// database opened from one sees what another wrote; they are freed
+    /// with the last database and configuration using them.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::{Config, Database};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::in_memory();
+    /// let mut db = Database::open_or_create(config)?;
+    /// db.put(&[1u8; 32], 0, b"genesis")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn in_memory() -> Self {
+        Self::default().with_vfs(Arc::new(MemVfs::new()))
+    }
+
     /// Set whether to sync to disk after each write
     ///
     /// Disabling sync improves performance but risks data loss on crash.
@@ -130,6 +219,128 @@ impl Config {
         self.sync_on_write = sync;
         self
     }
+
+    /// Set the hash function that keys must match
+    ///
+    /// With a hasher configured, `put` rejects values whose hash differs
+    /// from the supplied key, and `verify_entry` can re-check stored values.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "sha2")] {
+    /// use adzdb::Config;
+    /// use adzdb::hasher::DoubleSha256;
+    ///
+    /// let config = Config::new("./blockchain").with_hasher(DoubleSha256);
+    /// # }
+    /// ```
+    pub fn with_hasher<H: Hasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Some(Arc::new(hasher));
+        self
+    }
+
+    /// Set whether to open the database read-only
+    ///
+    /// A read-only database takes no writer lock, so it can be opened while
+    /// another process writes to the same directory. Its files are never
+    /// modified: repairs after a crash apply to the in-memory view only, and
+    /// writes fail with `Error::ReadOnly`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::Config;
+    ///
+    /// let config = Config::new("./blockchain").with_read_only(true);
+    /// ```
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Set the filesystem the database files are kept on
+    ///
+    /// Every file operation goes through it. The default, [`OsVfs`], is the
+    /// operating system's; [`sim::SimVfs`] keeps the files in memory and can
+    /// inject faults and crashes for testing.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::Config;
+    /// use adzdb::sim::SimVfs;
+    /// use std::sync::Arc;
+    ///
+    /// let config = Config::new("/sim/blockchain").with_vfs(Arc::new(SimVfs::new(42)));
+    /// ```
+    pub fn with_vfs(mut self, vfs: Arc<dyn Vfs>) -> Self {
+        self.vfs = vfs;
+        self
+    }
+
+    /// Set the size data segments grow to
+    ///
+    /// Records are appended to the newest segment file until the next one
+    /// would take it past `bytes`, and then to a new segment. Segments
+    /// already written keep their size; the setting only decides when the
+    /// next one starts. Opening a database fails with `Error::InvalidConfig`
+    /// if it is below `MIN_SEGMENT_SIZE`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::Config;
+    ///
+    /// // 64 MB segments, to archive a little at a time
+    /// let config = Config::new("./blockchain").with_segment_size(64 << 20);
+    /// ```
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Set the codec new values are compressed with
+    ///
+    /// Each value is compressed on its own, and stored as it is if that
+    /// does not make it smaller. Reads decompress transparently, whatever
+    /// codec a value was written with, so the setting can change between
+    /// opens. Opening fails with `Error::InvalidConfig` if the codec's cargo
+    /// feature is not enabled. See [`compress`] for the format.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::Config;
+    /// use adzdb::compress::Compression;
+    ///
+    /// let config = Config::new("./blockchain").with_compression(Compression::Lz4);
+    /// ```
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Check the settings a database cannot be opened with
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidConfig` if the segment size is below
+    /// `MIN_SEGMENT_SIZE`, or the codec is not usable.
+    fn check(&self) -> Result<()> {
+        if self.segment_size < MIN_SEGMENT_SIZE {
+            return Err(Error::InvalidConfig(format!(
+                "segment size {} is below the minimum of {} bytes",
+                self.segment_size, MIN_SEGMENT_SIZE
+            )));
+        }
+        self.compression.check()
+    }
+
+    /// The database directory on the configured filesystem
+    pub(crate) fn dir(&self) -> Dir {
+        Dir::new(self.vfs.clone(), &self.path)
+    }
 }
 
 /// Error types for ADZDB operations
@@ -151,6 +362,24 @@ pub enum Error {
     HashMismatch { expected: Hash, actual: Hash },
     /// Height too large (corruption detection)
     HeightTooLarge(u64),
+    /// Chain reorganization request is inconsistent with the stored blocks
+    InvalidReorg(String),
+    /// Another compaction of this database has not finished
+    CompactionInProgress,
+    /// Another writer holds the lock file
+    Locked(PathBuf),
+    /// Write attempted on a database opened read-only
+    ReadOnly,
+    /// Files in an older format version that must be upgraded first
+    MigrationRequired(u32),
+    /// Files in a format version this build cannot read or upgrade
+    UnsupportedVersion(u32),
+    /// Block is stored, but its value was pruned
+    Pruned,
+    /// A failed write could not be undone; reopen the database to write again
+    ReopenRequired,
+    /// Block hash is `RESERVED_HASH`, which cannot be stored
+    ReservedHash,
 }
 
 impl From<io::Error> for Error {
@@ -169,9 +398,22 @@ impl std::fmt::Display for Error {
             Error::AlreadyExists => write!(f, "Database already exists"),
             Error::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
             Error::HashMismatch { expected, actual } => {
-                write!(f, "Hash mismatch: expected {:?}, got {:?}", expected, actual)
+                write!(f, "Hash mismatch: expected {}, got {}", to_hex(expected), to_hex(actual))
             }
             Error::HeightTooLarge(h) => write!(f, "Height {} exceeds maximum {}", h, MAX_REASONABLE_HEIGHT),
+            Error::InvalidReorg(msg) => write!(f, "Invalid reorg: {}", msg),
+            Error::CompactionInProgress => write!(f, "Compaction already in progress"),
+            Error::Locked(path) => write!(f, "Database is locked by another writer: {}", path.display()),
+            Error::ReadOnly => write!(f, "Database is opened read-only"),
+            Error::MigrationRequired(v) => {
+                write!(f, "Format version {} must be migrated to {} before use", v, VERSION)
+            }
+            Error::UnsupportedVersion(v) => {
+                write!(f, "Unsupported format version {} (expected {})", v, VERSION)
+            }
+            Error::Pruned => write!(f, "Block value has been pruned"),
+            Error::ReopenRequired => write!(f, "A failed write left the files torn; reopen the database"),
+            Error::ReservedHash => write!(f, "Hash {} is reserved and cannot be stored", to_hex(&RESERVED_HASH)),
         }
     }
 }
@@ -181,7 +423,7 @@ impl std::error::Error for Error {}
 /// Result type for ADZDB operations
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Index entry - maps hash to data file offset (56 bytes)
+/// Index entry - maps hash to a data segment and offset (64 bytes)
 ///
 /// This is a fixed-size structure that can be directly memory-mapped
 /// for zero-copy access.
@@ -190,19 +432,98 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct IndexEntry {
     /// Full key hash (32 bytes)
     pub key: Hash,
-    /// Offset in data file (8 bytes)
+    /// Offset of the record header in its data segment (8 bytes)
     pub offset: u64,
-    /// Size of value in data file (4 bytes)
+    /// Size of value in the data segment, excluding the record header (4 bytes)
     pub size: u32,
     /// Block height for quick filtering (8 bytes)
     pub height: u64,
-    /// Flags reserved for future use (4 bytes)
+    /// Entry flags, see `FLAG_TOMBSTONE`, `FLAG_BATCH`, `FLAG_PRUNED`,
+    /// `FLAG_LZ4` and `FLAG_ZSTD`; other bits are reserved (4 bytes)
     pub flags: u32,
+    /// Data segment holding the record (4 bytes)
+    pub segment: u32,
+    /// Size of the value before compression, or 0 if it is stored as it is
+    /// (4 bytes)
+    pub uncompressed_size: u32,
 }
 
 impl IndexEntry {
     /// Size of index entry in bytes
-    pub const SIZE: usize = 56;
+    pub const SIZE: usize = 64;
+
+    /// Flag marking an entry that removes its key instead of storing it
+    ///
+    /// A tombstone points at its own header-only record in the data
+    /// segments, so the removal survives a rebuild from them.
+    pub const FLAG_TOMBSTONE: u32 = 1 << 0;
+
+    /// Flag marking an entry written by a `WriteBatch`
+    ///
+    /// Batch entries past the index length committed by the metadata belong
+    /// to a batch that never committed, and are discarded on open together
+    /// with everything after them.
+    pub const FLAG_BATCH: u32 = 1 << 1;
+
+    /// Flag marking an entry that keeps its key but no longer its value
+    ///
+    /// Like a tombstone, it points at its own header-only record, and it
+    /// takes the place of the entry that stored the value.
+    pub const FLAG_PRUNED: u32 = 1 << 2;
+
+    /// Flag marking an entry whose value is stored LZ4-compressed
+    pub const FLAG_LZ4: u32 = 1 << 3;
+
+    /// Flag marking an entry whose value is stored zstd-compressed
+    pub const FLAG_ZSTD: u32 = 1 << 4;
+
+    /// Every flag naming a codec
+    pub(crate) const CODEC_FLAGS: u32 = Self::FLAG_LZ4 | Self::FLAG_ZSTD;
+
+    /// Returns true if this entry removes its key
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & Self::FLAG_TOMBSTONE != 0
+    }
+
+    /// Returns true if this entry keeps its key without a value
+    pub fn is_pruned(&self) -> bool {
+        self.flags & Self::FLAG_PRUNED != 0
+    }
+
+    /// Returns true if this entry's value is stored compressed
+    pub fn is_compressed(&self) -> bool {
+        self.flags & Self::CODEC_FLAGS != 0
+    }
+
+    /// Size of the value as `get` returns it, after decompression
+    pub fn logical_size(&self) -> u64 {
+        if self.is_compressed() {
+            self.uncompressed_size as u64
+        } else {
+            self.size as u64
+        }
+    }
+
+    /// Offset just past the end of this entry's record in its segment
+    pub fn record_end(&self) -> u64 {
+        self.offset + RecordHeader::SIZE as u64 + self.size as u64
+    }
+
+    /// Segment and offset of this entry's record
+    pub(crate) fn position(&self) -> Position {
+        Position {
+            segment: self.segment,
+            offset: self.offset,
+        }
+    }
+
+    /// Segment and offset just past the end of this entry's record
+    pub(crate) fn end(&self) -> Position {
+        Position {
+            segment: self.segment,
+            offset: self.record_end(),
+        }
+    }
 
     /// Serialize to bytes
     pub fn to_bytes(&self) -> [u8; Self::SIZE] {
@@ -212,6 +533,8 @@ impl IndexEntry {
         buf[40..44].copy_from_slice(&self.size.to_le_bytes());
         buf[44..52].copy_from_slice(&self.height.to_le_bytes());
         buf[52..56].copy_from_slice(&self.flags.to_le_bytes());
+        buf[56..60].copy_from_slice(&self.segment.to_le_bytes());
+        buf[60..64].copy_from_slice(&self.uncompressed_size.to_le_bytes());
         buf
     }
 
@@ -223,17 +546,24 @@ impl IndexEntry {
             size: u32::from_le_bytes(bytes[40..44].try_into().unwrap()),
             height: u64::from_le_bytes(bytes[44..52].try_into().unwrap()),
             flags: u32::from_le_bytes(bytes[52..56].try_into().unwrap()),
+            segment: u32::from_le_bytes(bytes[56..60].try_into().unwrap()),
+            uncompressed_size: u32::from_le_bytes(bytes[60..64].try_into().unwrap()),
         }
     }
 }
 
 /// Height index entry - maps height to hash (40 bytes)
+///
+/// adzdb.hgt.log is a journal of canonical chain updates: when it is
+/// replayed over adzdb.hgt, the last entry for a height wins. An entry whose
+/// height has the `CLEARED` bit set removes that height from the canonical
+/// chain.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct HeightEntry {
     /// Block height (8 bytes)
     pub height: u64,
-    /// Block hash at this height (32 bytes)
+    /// Canonical block hash at this height (32 bytes)
     pub hash: Hash,
 }
 
@@ -241,6 +571,27 @@ impl HeightEntry {
     /// Size of height entry in bytes
     pub const SIZE: usize = 40;
 
+    /// Height bit marking an entry that clears its height
+    pub const CLEARED: u64 = 1 << 63;
+
+    /// Build an entry that removes `height` from the canonical chain
+    pub fn cleared(height: u64) -> Self {
+        Self {
+            height: height | Self::CLEARED,
+            hash: ZERO_HASH,
+        }
+    }
+
+    /// Returns true if this entry clears its height
+    pub fn is_cleared(&self) -> bool {
+        self.height & Self::CLEARED != 0
+    }
+
+    /// The height this entry applies to, without the `CLEARED` bit
+    pub fn target_height(&self) -> u64 {
+        self.height & !Self::CLEARED
+    }
+
     /// Serialize to bytes
     pub fn to_bytes(&self) -> [u8; Self::SIZE] {
         let mut buf = [0u8; Self::SIZE];
@@ -258,6 +609,104 @@ impl HeightEntry {
     }
 }
 
+/// Magic bytes at the start of every data file record
+pub const RECORD_MAGIC: &[u8; 4] = b"ADZR";
+
+/// Data record header - precedes every value in the data segments (56 bytes)
+///
+/// Records are self-describing: the header carries the key and height, so
+/// the hash and height indexes can be rebuilt from the segments alone. The
+/// checksum covers the header fields and the value, and is verified on every
+/// read, so bit rot in a data segment is reported as `Error::Corruption`
+/// instead of being returned to the caller.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// Magic bytes ("ADZR") (4 bytes)
+    pub magic: [u8; 4],
+    /// Size of the value that follows, as stored (4 bytes)
+    pub size: u32,
+    /// Full key hash (32 bytes)
+    pub key: Hash,
+    /// Block height (8 bytes)
+    pub height: u64,
+    /// Entry flags, matching the index entry's `flags` (4 bytes)
+    pub flags: u32,
+    /// CRC32C of the preceding header fields and the value (4 bytes)
+    pub checksum: u32,
+}
+
+impl RecordHeader {
+    /// Size of record header in bytes
+    pub const SIZE: usize = 56;
+
+    /// Build the header for a value
+    pub fn new(key: &Hash, height: u64, value: &[u8]) -> Self {
+        Self::with_flags(key, height, value, 0)
+    }
+
+    /// Build the header for a value with the given entry flags
+    pub fn with_flags(key: &Hash, height: u64, value: &[u8], flags: u32) -> Self {
+        let mut header = Self {
+            magic: *RECORD_MAGIC,
+            size: value.len() as u32,
+            key: *key,
+            height,
+            flags,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum(value);
+        header
+    }
+
+    /// Build the header-only record of a tombstone for `key`
+    pub fn tombstone(key: &Hash, height: u64) -> Self {
+        Self::with_flags(key, height, &[], IndexEntry::FLAG_TOMBSTONE)
+    }
+
+    /// Build the header-only record marking the value of `key` as pruned
+    pub fn pruned(key: &Hash, height: u64) -> Self {
+        Self::with_flags(key, height, &[], IndexEntry::FLAG_PRUNED)
+    }
+
+    /// Compute the checksum of this header's fields and `value`
+    pub fn compute_checksum(&self, value: &[u8]) -> u32 {
+        let bytes = self.to_bytes();
+        crc32c_extend(crc32c(&bytes[..Self::SIZE - 4]), value)
+    }
+
+    /// Returns true if the magic is intact and the checksum matches `value`
+    pub fn verify(&self, value: &[u8]) -> bool {
+        &self.magic == RECORD_MAGIC
+            && self.size as usize == value.len()
+            && self.checksum == self.compute_checksum(value)
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[0..4].copy_from_slice(&self.magic);
+        buf[4..8].copy_from_slice(&self.size.to_le_bytes());
+        buf[8..40].copy_from_slice(&self.key);
+        buf[40..48].copy_from_slice(&self.height.to_le_bytes());
+        buf[48..52].copy_from_slice(&self.flags.to_le_bytes());
+        buf[52..56].copy_from_slice(&self.checksum.to_le_bytes());
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self {
+            magic: bytes[0..4].try_into().unwrap(),
+            size: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            key: bytes[8..40].try_into().unwrap(),
+            height: u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+            flags: u32::from_le_bytes(bytes[48..52].try_into().unwrap()),
+            checksum: u32::from_le_bytes(bytes[52..56].try_into().unwrap()),
+        }
+    }
+}
+
 /// Database metadata (stored in adzdb.meta)
 #[derive(Debug, Clone)]
 pub struct Metadata {
@@ -267,7 +716,7 @@ pub struct Metadata {
     pub version: u32,
     /// Number of entries
     pub entry_count: u64,
-    /// Total data size in bytes
+    /// Total size of the live values as stored, after compression
     pub data_size: u64,
     /// Latest block height
     pub latest_height: u64,
@@ -275,6 +724,23 @@ pub struct Metadata {
     pub latest_hash: Hash,
     /// Genesis hash
     pub genesis_hash: Hash,
+    /// Committed length of adzdb.hgt.log in bytes
+    ///
+    /// Height entries past this length were never synced and are discarded
+    /// on open, which makes multi-entry updates such as reorgs atomic.
+    pub height_len: u64,
+    /// Length of adzdb.idx in bytes at the last sync
+    ///
+    /// Index entries past this length are checksum-verified on open.
+    pub index_len: u64,
+    /// Total size of the live values before compression
+    pub logical_size: u64,
+    /// Number of times adzdb.hgt.log has been replaced by an empty one
+    ///
+    /// A read-only database following the writer reads on from the journal
+    /// it has open while this is unchanged, and finishes it before moving
+    /// to the next one once it has gone up by one.
+    pub journal_generation: u64,
 }
 
 impl Default for Metadata {
@@ -287,13 +753,17 @@ impl Default for Metadata {
             latest_height: 0,
             latest_hash: ZERO_HASH,
             genesis_hash: ZERO_HASH,
+            height_len: 0,
+            index_len: 0,
+            logical_size: 0,
+            journal_generation: 0,
         }
     }
 }
 
 impl Metadata {
     /// Size of metadata in bytes
-    pub const SIZE: usize = 96;
+    pub const SIZE: usize = 128;
 
     /// Serialize to bytes
     pub fn to_bytes(&self) -> [u8; Self::SIZE] {
@@ -305,6 +775,10 @@ impl Metadata {
         buf[24..32].copy_from_slice(&self.latest_height.to_le_bytes());
         buf[32..64].copy_from_slice(&self.latest_hash);
         buf[64..96].copy_from_slice(&self.genesis_hash);
+        buf[96..104].copy_from_slice(&self.height_len.to_le_bytes());
+        buf[104..112].copy_from_slice(&self.index_len.to_le_bytes());
+        buf[112..120].copy_from_slice(&self.logical_size.to_le_bytes());
+        buf[120..128].copy_from_slice(&self.journal_generation.to_le_bytes());
         buf
     }
 
@@ -315,14 +789,24 @@ impl Metadata {
             return Err(Error::Corruption("Invalid magic bytes".to_string()));
         }
 
+        // Older layouts place fields differently; never misread them
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(migrate::version_error(version));
+        }
+
         let meta = Self {
             magic,
-            version: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            version,
             entry_count: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
             data_size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
             latest_height: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
             latest_hash: bytes[32..64].try_into().unwrap(),
             genesis_hash: bytes[64..96].try_into().unwrap(),
+            height_len: u64::from_le_bytes(bytes[96..104].try_into().unwrap()),
+            index_len: u64::from_le_bytes(bytes[104..112].try_into().unwrap()),
+            logical_size: u64::from_le_bytes(bytes[112..120].try_into().unwrap()),
+            journal_generation: u64::from_le_bytes(bytes[120..128].try_into().unwrap()),
         };
 
         // Corruption detection
@@ -355,22 +839,40 @@ impl Metadata {
 /// # Ok(())
 /// # }
 /// ```
+///
+/// # Concurrency
+///
+/// `Database` is `Send` and `Sync`. Reads take `&self` and use positioned
+/// reads, so any number of threads can call `get` and friends at once, for
+/// example through an `Arc<Database>`. Writes take `&mut self`; to keep
+/// reading from other threads while one thread writes, hand them a
+/// [`ReadHandle`] from [`Database::read_handle`].
 pub struct Database {
     config: Config,
     /// Hash index file
     index_file: File,
-    /// Data file (append-only)
-    data_file: File,
-    /// Height index file
-    height_file: File,
+    /// Height journal, the canonical chain changes since the checkpoint
+    height_log: File,
     /// Metadata file
-    meta_file: File,
-    /// In-memory hash index (loaded on open)
-    hash_index: HashMap<Hash, IndexEntry>,
-    /// In-memory height index
-    height_index: HashMap<u64, Hash>,
-    /// Current metadata
-    metadata: Metadata,
+    superblock: Superblock,
+    /// Data segments, in-memory indexes and metadata, shared with read handles
+    state: Shared,
+    /// Repairs performed when the database was opened
+    recovery: RecoveryReport,
+    /// Shared with outstanding compactors, which read the data segments by
+    /// offset; while any exist, records are never cut off their tail
+    compactors: Arc<()>,
+    /// Memory mappings of the data segments used by `get_ref`
+    #[cfg(feature = "mmap")]
+    data_map: mmap::DataMap,
+    /// Exclusive lock held while the database is open for writing
+    lock: Option<WriterLock>,
+    /// Last index record in a read-only view, which `refresh` checks is
+    /// still in place before reading on
+    index_tail: Option<[u8; IndexEntry::SIZE]>,
+    /// Set when a failed write could not be cut back off the files; every
+    /// write fails from then on, until the database is reopened
+    torn: bool,
 }
 
 impl Database {
@@ -392,48 +894,39 @@ impl Database {
     /// # }
     /// ```
     pub fn create(config: Config) -> Result<Self> {
-        std::fs::create_dir_all(&config.path)?;
-
-        let index_path = config.path.join("adzdb.idx");
-        let data_path = config.path.join("adzdb.dat");
-        let height_path = config.path.join("adzdb.hgt");
-        let meta_path = config.path.join("adzdb.meta");
+        if config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        config.check()?;
+        let dir = config.dir();
+        dir.create_dir_all()?;
+        let lock = WriterLock::acquire(&dir)?;
 
+        let index_path = dir.join("adzdb.idx");
+        let height_path = dir.join(HEIGHT_LOG);
         // Check if already exists
-        if index_path.exists() || data_path.exists() {
+        if dir.exists(&index_path) || dir.exists(&dir.join(segments::segment_name(0))) {
             return Err(Error::AlreadyExists);
         }
 
         // Create files
-        let index_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&index_path)?;
-
-        let data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&data_path)?;
+        let index_file = dir.open(
+            &index_path,
+            OpenOptions::new().read(true).write(true).create(true).truncate(false),
+        )?;
 
-        let height_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&height_path)?;
+        let segments = Segments::create(&dir)?;
 
-        let mut meta_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&meta_path)?;
+        let height_log = dir.open(
+            &height_path,
+            OpenOptions::new().read(true).write(true).create(true).truncate(false),
+        )?;
 
         // Write initial metadata
         let metadata = Metadata::default();
-        meta_file.write_all(&metadata.to_bytes())?;
-        meta_file.sync_all()?;
+        let superblock = Superblock::create(&dir, &metadata)?;
+        let keys = KeyFile::build(&dir, &[], &CowMap::new(), Checkpoint::EMPTY)?;
+        let slots = HeightFile::build(&dir, [])?;
 
         #[cfg(feature = "tracing")]
         tracing::info!("🗄️  ADZDB created at {:?}", config.path);
@@ -441,20 +934,58 @@ impl Database {
         Ok(Self {
             config,
             index_file,
-            data_file,
-            height_file,
-            meta_file,
-            hash_index: HashMap::new(),
-            height_index: HashMap::new(),
-            metadata,
+            height_log,
+            superblock,
+            state: Shared::new(State {
+                segments,
+                hash_index: HashIndex::new(Some(keys)),
+                height_index: HeightIndex::new(Some(slots)),
+                side_blocks: CowMap::new(),
+                metadata,
+            }),
+            recovery: RecoveryReport::default(),
+            compactors: Arc::new(()),
+            #[cfg(feature = "mmap")]
+            data_map: mmap::DataMap::default(),
+            lock: Some(lock),
+            index_tail: None,
+            torn: false,
         })
     }
 
+    /// Create a new database kept entirely in memory
+    ///
+    /// Shorthand for `Database::create(Config::in_memory())`. Deduplication,
+    /// the height index, forks, metadata and stats work as on disk, but
+    /// nothing touches the filesystem and the blocks are gone once the
+    /// database is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use adzdb::Database;
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let mut db = Database::in_memory()?;
+    /// db.put(&[1u8; 32], 0, b"genesis")?;
+    /// assert_eq!(db.get_by_height(0)?, b"genesis");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn in_memory() -> Result<Self> {
+        Self::create(Config::in_memory())
+    }
+
     /// Open an existing database
     ///
+    /// Unless `Config::read_only` is set, the writer lock is taken first.
+    ///
     /// # Errors
     ///
-    /// Returns an I/O error if the database doesn't exist or is corrupted.
+    /// Returns an I/O error if the database doesn't exist or is corrupted,
+    /// and `Error::Locked` if another writer has it open. A read-only open
+    /// returns `Error::CompactionInProgress` while a writer is swapping in
+    /// compacted files; retry once it is done.
     ///
     /// # Example
     ///
@@ -468,41 +999,85 @@ impl Database {
     /// # }
     /// ```
     pub fn open(config: Config) -> Result<Self> {
-        let index_path = config.path.join("adzdb.idx");
-        let data_path = config.path.join("adzdb.dat");
-        let height_path = config.path.join("adzdb.hgt");
-        let meta_path = config.path.join("adzdb.meta");
+        config.check()?;
+        let mut lock = Self::lock(&config)?;
+        Self::open_locked(config, &mut lock)
+    }
+
+    /// Take the writer lock, unless the database is opened read-only
+    fn lock(config: &Config) -> Result<Option<WriterLock>> {
+        if config.read_only {
+            return Ok(None);
+        }
+        WriterLock::acquire(&config.dir()).map(Some)
+    }
+
+    /// Open with the writer lock already held; the lock moves into the
+    /// database only once it has opened
+    fn open_locked(config: Config, lock: &mut Option<WriterLock>) -> Result<Self> {
+        let dir = config.dir();
+        if config.read_only {
+            // Only a writer may complete a swap; don't read files mid-swap
+            if compact::is_pending(&dir) {
+                return Err(Error::CompactionInProgress);
+            }
+        } else {
+            // Complete or discard a compaction interrupted by a crash, then
+            // bring files written by an older version up to date
+            compact::finish_pending(&dir)?;
+            migrate::upgrade_locked(&dir)?;
+        }
+
+        let index_path = dir.join("adzdb.idx");
+        let height_path = dir.join(HEIGHT_LOG);
+
+        // Load metadata
+        let writable = !config.read_only;
+        let (mut superblock, mut metadata) = Superblock::open(&dir, writable)?;
 
         // Open files
-        let index_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&index_path)?;
+        let index_file = dir.open(&index_path, OpenOptions::new().read(true).write(writable))?;
 
-        let data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(&data_path)?;
+        let segments = Segments::open(&dir, writable)?;
 
-        let height_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&height_path)?;
+        let mut height_log = dir.open(&height_path, OpenOptions::new().read(true).write(writable))?;
 
-        let meta_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&meta_path)?;
+        let slots = HeightFile::open(&dir, writable)?;
 
-        // Load metadata
-        let metadata = Self::load_metadata(&meta_file)?;
+        let keys = if config.read_only {
+            KeyFile::open_read_only(&dir)?
+        } else {
+            KeyFile::open(&dir)?
+        };
 
-        // Load hash index into memory
-        let hash_index = Self::load_hash_index(&index_file)?;
+        // Reconcile the files after a crash and catch the indexes up with them
+        let (mut state, recovery) = Self::recover(
+            &index_file,
+            segments,
+            &mut height_log,
+            slots,
+            &mut superblock,
+            &mut metadata,
+            keys,
+            config.read_only,
+        )?;
+        if !config.read_only && state.hash_index.key_file().is_none() {
+            Self::build_checkpoint(&dir, &index_file, &mut height_log, &mut superblock, &mut state)?;
+            metadata = state.metadata.clone();
+        }
+
+        let index_tail = if config.read_only && metadata.index_len > 0 {
+            let mut tail = [0u8; IndexEntry::SIZE];
+            read_exact_at(&index_file, &mut tail, metadata.index_len - IndexEntry::SIZE as u64)?;
+            Some(tail)
+        } else {
+            None
+        };
 
-        // Load height index into memory
-        let height_index = Self::load_height_index(&height_file)?;
+        #[cfg(feature = "tracing")]
+        if !recovery.is_clean() {
+            tracing::warn!("🩹 ADZDB recovered from unclean shutdown: {:?}", recovery);
+        }
 
         #[cfg(feature = "tracing")]
         tracing::info!(
@@ -514,12 +1089,16 @@ impl Database {
         Ok(Self {
             config,
             index_file,
-            data_file,
-            height_file,
-            meta_file,
-            hash_index,
-            height_index,
-            metadata,
+            height_log,
+            superblock,
+            state: Shared::new(state),
+            recovery,
+            compactors: Arc::new(()),
+            #[cfg(feature = "mmap")]
+            data_map: mmap::DataMap::default(),
+            lock: lock.take(),
+            index_tail,
+            torn: false,
         })
     }
 
@@ -537,76 +1116,653 @@ impl Database {
     /// # }
     /// ```
     pub fn open_or_create(config: Config) -> Result<Self> {
-        let meta_path = config.path.join("adzdb.meta");
-        if meta_path.exists() {
+        let dir = config.dir();
+        if dir.exists(&dir.join("adzdb.meta")) {
             Self::open(config)
         } else {
             Self::create(config)
         }
     }
 
-    fn load_metadata(file: &File) -> Result<Metadata> {
-        let mut reader = BufReader::new(file);
-        let mut buf = [0u8; Metadata::SIZE];
-
-        reader.read_exact(&mut buf).map_err(|e| {
-            if e.kind() == io::ErrorKind::UnexpectedEof {
-                Error::Corruption("Metadata file too small".to_string())
-            } else {
-                Error::Io(e)
-            }
-        })?;
-
-        Metadata::from_bytes(&buf)
+    /// Open a database by regenerating its indexes from the data segments
+    ///
+    /// The data segments are the single source of truth: every record carries
+    /// its key and height. This recreates adzdb.idx, adzdb.hgt, adzdb.hgt.log,
+    /// adzdb.key and adzdb.meta from them, replacing them if present, so a
+    /// database whose index files were lost or damaged can still be opened.
+    /// A torn record at the end of the data is discarded.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./damaged-blockchain");
+    /// let db = Database::rebuild(config)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rebuild(config: Config) -> Result<Self> {
+        if config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        config.check()?;
+        let mut lock = Self::lock(&config)?;
+        Self::rebuild_files(&config.dir())?;
+        Self::open_locked(config, &mut lock)
     }
 
-    fn load_hash_index(file: &File) -> Result<HashMap<Hash, IndexEntry>> {
-        let mut index = HashMap::new();
-        let mut reader = BufReader::new(file);
-        let mut buf = [0u8; IndexEntry::SIZE];
-
-        loop {
-            match reader.read_exact(&mut buf) {
-                Ok(()) => {
-                    let entry = IndexEntry::from_bytes(&buf);
-                    if entry.key != ZERO_HASH {
-                        index.insert(entry.key, entry);
-                    }
-                }
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(Error::Io(e)),
-            }
+    /// Regenerate the index files of an open database from its data segments
+    ///
+    /// See [`Database::rebuild`].
+    pub fn rebuild_indexes(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if Arc::strong_count(&self.compactors) > 1 {
+            return Err(Error::CompactionInProgress);
         }
+        self.sync()?;
+        Self::rebuild_files(&self.config.dir())?;
+        self.reopen()
+    }
+
+    /// Reopen the database from its files, keeping read handles attached
+    fn reopen(&mut self) -> Result<()> {
+        let mut reopened = Self::open_locked(self.config.clone(), &mut self.lock)?;
+        std::mem::swap(&mut *self.state.write(), &mut *reopened.state.write());
+        reopened.state = self.state.clone();
+        *self = reopened;
+        Ok(())
+    }
 
-        Ok(index)
+    /// Read the height entries of a journal between two offsets
+    fn read_height_entries(height_log: &File, from: u64, to: u64) -> Result<Vec<HeightEntry>> {
+        let count = to.saturating_sub(from) / HeightEntry::SIZE as u64;
+        let mut raw = vec![0u8; (count * HeightEntry::SIZE as u64) as usize];
+        read_exact_at(height_log, &mut raw, from)?;
+        Ok(raw
+            .chunks_exact(HeightEntry::SIZE)
+            .map(|chunk| HeightEntry::from_bytes(chunk.try_into().unwrap()))
+            .collect())
     }
 
-    fn load_height_index(file: &File) -> Result<HashMap<u64, Hash>> {
-        let mut index = HashMap::new();
+    /// Read every complete fixed-size record from a file, from `start` on.
+    ///
+    /// Returns the records and the number of trailing bytes that did not
+    /// form a complete record (a torn write).
+    fn read_records<const N: usize>(file: &File, start: u64) -> Result<(Vec<[u8; N]>, u64)> {
+        let len = file.len()?.saturating_sub(start);
         let mut reader = BufReader::new(file);
-        let mut buf = [0u8; HeightEntry::SIZE];
-
-        loop {
-            match reader.read_exact(&mut buf) {
-                Ok(()) => {
-                    let entry = HeightEntry::from_bytes(&buf);
-                    if entry.hash != ZERO_HASH {
-                        index.insert(entry.height, entry.hash);
-                    }
-                }
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(Error::Io(e)),
-            }
+        reader.seek(SeekFrom::Start(start))?;
+
+        let count = len / N as u64;
+        let mut records = Vec::with_capacity(count as usize);
+        let mut buf = [0u8; N];
+        for _ in 0..count {
+            reader.read_exact(&mut buf)?;
+            records.push(buf);
         }
 
-        Ok(index)
+        Ok((records, len % N as u64))
     }
 
-    /// Store a value by hash (content-addressable)
+    /// Reconcile the data, index and height files after an unclean shutdown.
     ///
-    /// Automatically deduplicates: if the hash already exists, this is a no-op.
+    /// Every `put` appends one value to the active data segment, then one
+    /// `IndexEntry` to adzdb.idx, then, if the block extends the canonical
+    /// chain, one `HeightEntry` to adzdb.hgt.log. A crash can leave any of
+    /// them with a torn tail. The index is truncated to the last entry whose
+    /// record is intact in its segment, the data to the end of that record, and
+    /// the journal to the last entry that refers to a surviving block.
+    /// Canonical height entries that never landed are restored from the index,
+    /// and the metadata is recomputed from the result.
     ///
-    /// # Arguments
+    /// Records appended since the last sync are also checksummed, since the
+    /// filesystem may have extended a segment without persisting its
+    /// contents. Records covered by the stored metadata were synced and are
+    /// only checked when read. Past that point, the first `WriteBatch` entry
+    /// starts a batch that was never committed, so it is discarded along with
+    /// everything after it.
+    ///
+    /// Only tails are repaired: a synced inconsistent entry followed by
+    /// consistent ones is reported as `Error::Corruption` rather than
+    /// discarded.
+    ///
+    /// If the files still extend the checkpoint of the key file `keys`, only
+    /// what was appended since is checked and replayed over it and the height
+    /// file `slots`; otherwise `keys` is dropped and all of adzdb.idx and the
+    /// journal is replayed over `slots`.
+    ///
+    /// With `read_only`, nothing is written: the repairs only shape the
+    /// returned view, which leaves out whatever a writer is still appending.
+    /// The key file is copied into memory, and the height file read into
+    /// memory after the journal, so a change the writer moves from one to
+    /// the other meanwhile is not lost.
+    #[allow(clippy::too_many_arguments)]
+    fn recover(
+        index_file: &File,
+        mut segments: Segments,
+        height_log: &mut File,
+        slots: HeightFile,
+        superblock: &mut Superblock,
+        metadata: &mut Metadata,
+        keys: Option<KeyFile>,
+        read_only: bool,
+    ) -> Result<(State, RecoveryReport)> {
+        let mut report = RecoveryReport::default();
+
+        let keys = match keys {
+            Some(keys) if Self::extends_checkpoint(keys.checkpoint(), index_file, height_log, &segments, metadata)? => {
+                Some(keys)
+            }
+            _ => None,
+        };
+        // A read-only view copies the key file, falling back to all of
+        // adzdb.idx if the writer checkpoints in the meantime
+        let (hash_index, side_blocks, checkpoint) = match keys {
+            Some(keys) if read_only => match keys.load()? {
+                Some((hash_index, side_blocks)) => (hash_index, side_blocks, *keys.checkpoint()),
+                None => (HashIndex::new(None), CowMap::new(), Checkpoint::EMPTY),
+            },
+            Some(keys) => {
+                let side_blocks = keys.side_blocks()?;
+                let checkpoint = *keys.checkpoint();
+                (HashIndex::new(Some(keys)), side_blocks, checkpoint)
+            }
+            None => (HashIndex::new(None), CowMap::new(), Checkpoint::EMPTY),
+        };
+        let index_start = (checkpoint.index_len / IndexEntry::SIZE as u64) as usize;
+        let height_start = (checkpoint.height_len / HeightEntry::SIZE as u64) as usize;
+
+        let (raw_index, index_torn) = Self::read_records::<{ IndexEntry::SIZE }>(index_file, checkpoint.index_len)?;
+        let (raw_height, height_torn) = Self::read_records::<{ HeightEntry::SIZE }>(height_log, checkpoint.height_len)?;
+        let index_entries: Vec<IndexEntry> = raw_index.iter().map(IndexEntry::from_bytes).collect();
+        let mut height_entries: Vec<HeightEntry> = raw_height.iter().map(HeightEntry::from_bytes).collect();
+
+        report.torn_bytes = index_torn + height_torn;
+
+        // Height entries past the committed length belong to an update that
+        // never completed; entries appended by `put` are restored below
+        let committed_heights = ((metadata.height_len.saturating_sub(checkpoint.height_len)) as usize
+            / HeightEntry::SIZE)
+            .min(height_entries.len());
+        let uncommitted_heights = (height_entries.len() - committed_heights) as u64;
+        height_entries.truncate(committed_heights);
+
+        // Find the longest prefix of index entries past the checkpoint whose
+        // records are intact. Records are appended back to back, so each must
+        // start where the previous one ended, or at the start of the next
+        // segment.
+        // Entries past the synced length may have been torn or persisted
+        // out of order by a crash, so the first bad one ends the tail.
+        // Records in pruned segments were committed before the segments went,
+        // and cannot be checked any more; past the synced length, an entry
+        // naming one is torn, most likely zero-filled.
+        let synced = (metadata.index_len as usize / IndexEntry::SIZE).saturating_sub(index_start);
+        let mut consistent = 0;
+        let first = Position {
+            segment: segments.first(),
+            offset: 0,
+        };
+        let mut data_end = checkpoint.data_end.max(first);
+        for (entry, raw) in index_entries.iter().zip(&raw_index) {
+            let removed = entry.segment < first.segment;
+            if removed && consistent >= synced {
+                break;
+            }
+            if !removed && !segments.contains(entry)? {
+                break;
+            }
+            if !removed && !data_end.is_followed_by(entry.position()) {
+                // A zero-filled tail is a write the filesystem never persisted
+                if consistent >= synced || raw.iter().all(|&b| b == 0) {
+                    break;
+                }
+                return Err(Error::Corruption(format!(
+                    "Index entry {} for key {} points to offset {} of segment {}, expected {} of segment {}",
+                    index_start + consistent,
+                    to_hex(&entry.key),
+                    entry.offset,
+                    entry.segment,
+                    data_end.offset,
+                    data_end.segment
+                )));
+            }
+            consistent += 1;
+            if !removed {
+                data_end = entry.end();
+            }
+        }
+
+        // Values written after the last sync may be garbage even though the
+        // file length covers them; cut the tail at the first bad checksum.
+        // A batch entry there belongs to a batch that never committed.
+        let synced = synced.min(consistent);
+        let unsynced = &index_entries[synced..consistent];
+        for (i, entry) in (synced..).zip(unsynced) {
+            if entry.flags & IndexEntry::FLAG_BATCH != 0 {
+                consistent = i;
+                data_end = entry.position();
+                break;
+            }
+            match Self::read_value(&segments, entry) {
+                Ok(_) => {}
+                Err(Error::Corruption(_)) => {
+                    consistent = i;
+                    data_end = entry.position();
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Height entries must refer to a surviving block at the same height.
+        // Dangling entries are only a torn tail if nothing valid follows them.
+        let heights: HashMap<Hash, u64> = index_entries[..consistent]
+            .iter()
+            .map(|entry| (entry.key, entry.height))
+            .collect();
+        // A checkpoint that empties the journal lands before the journal is
+        // emptied, so until then the journal can name blocks removed before
+        // the checkpoint; those are looked up in the index entries it covers
+        let mut valid = Vec::with_capacity(height_entries.len());
+        let mut covered: Option<HashMap<Hash, u64>> = None;
+        for entry in &height_entries {
+            let stored = match heights.get(&entry.hash) {
+                Some(&height) => Some(height),
+                None => hash_index.get(&entry.hash)?.map(|block| block.height),
+            };
+            let mut is_valid = entry.is_cleared() || stored == Some(entry.height);
+            if !is_valid && index_start > 0 {
+                if covered.is_none() {
+                    let (raw, _) = Self::read_records::<{ IndexEntry::SIZE }>(index_file, 0)?;
+                    let entries = raw[..index_start.min(raw.len())].iter().map(IndexEntry::from_bytes);
+                    covered = Some(entries.map(|entry| (entry.key, entry.height)).collect());
+                }
+                is_valid = covered.as_ref().and_then(|covered| covered.get(&entry.hash)) == Some(&entry.height);
+            }
+            valid.push(is_valid);
+        }
+        let height_consistent = valid.iter().position(|valid| !valid).unwrap_or(valid.len());
+        if valid[height_consistent..].contains(&true) {
+            let entry = &height_entries[height_consistent];
+            return Err(Error::Corruption(format!(
+                "Height entry {} refers to unknown block {} at height {}",
+                height_start + height_consistent,
+                to_hex(&entry.hash),
+                entry.height
+            )));
+        }
+
+        report.index_entries_dropped = (index_entries.len() - consistent) as u64;
+        report.height_entries_dropped =
+            (height_entries.len() - height_consistent) as u64 + uncommitted_heights;
+        report.data_bytes_dropped = segments.len_after(data_end)?;
+
+        let index_len = checkpoint.index_len + (consistent * IndexEntry::SIZE) as u64;
+        height_entries.truncate(height_consistent);
+        if !read_only {
+            if index_torn > 0 || report.index_entries_dropped > 0 {
+                index_file.set_len(index_len)?;
+            }
+            if height_torn > 0 || report.height_entries_dropped > 0 {
+                height_log.set_len(checkpoint.height_len + (height_consistent * HeightEntry::SIZE) as u64)?;
+            }
+            if report.data_bytes_dropped > 0 {
+                segments.truncate(data_end)?;
+            }
+        }
+
+        // Start from the checkpoint, then replay the surviving entries since
+        let height_index = if read_only {
+            HeightIndex::load(&slots)?
+        } else {
+            HeightIndex::new(Some(slots))
+        };
+        // A journal missing committed entries was emptied by a checkpoint
+        // that crashed before its commit, or lost them; either way it is not
+        // the one the metadata describes, and followers must not read it as
+        // such
+        let journaled_len = checkpoint.height_len + (committed_heights * HeightEntry::SIZE) as u64;
+        let journal_lost = journaled_len < metadata.height_len;
+        let mut base = Metadata {
+            entry_count: hash_index.key_file().map_or(hash_index.pending_len() as u64, KeyFile::len),
+            data_size: checkpoint.data_size,
+            logical_size: checkpoint.logical_size,
+            journal_generation: metadata.journal_generation + u64::from(journal_lost),
+            ..Metadata::default()
+        };
+        if let Some((height, hash)) = height_index.last()? {
+            base.latest_height = height;
+            base.latest_hash = hash;
+        }
+        let mut state = State {
+            segments,
+            hash_index,
+            height_index,
+            side_blocks,
+            metadata: base,
+        };
+        let checkpointed = state.height_index.clone();
+        // Synced blocks are canonical as the journal says, unless it lost
+        // committed entries
+        let journaled = if journal_lost { 0 } else { synced };
+        let touched = state.apply(&index_entries[..consistent], journaled, &height_entries)?;
+
+        // Restore the canonical blocks the journal lost track of: a block
+        // `put` was about to record, or a removed block whose height stayed
+        // set
+        let mut recorded: HashMap<u64, Option<Hash>> = HashMap::new();
+        for entry in height_entries.iter().filter(|entry| touched.contains(&entry.target_height())) {
+            recorded.insert(entry.target_height(), (!entry.is_cleared()).then_some(entry.hash));
+        }
+        let mut restored = Vec::new();
+        for &height in &touched {
+            let recorded = match recorded.get(&height) {
+                Some(&hash) => hash,
+                None => checkpointed.get(height)?,
+            };
+            let canonical = state.height_index.get(height)?;
+            if recorded != canonical {
+                restored.push(match canonical {
+                    Some(hash) => HeightEntry { height, hash },
+                    None => HeightEntry::cleared(height),
+                });
+            }
+        }
+        restored.sort_by_key(HeightEntry::target_height);
+        if !restored.is_empty() {
+            report.height_entries_restored = restored.len() as u64;
+            if !read_only {
+                height_log.seek(SeekFrom::End(0))?;
+                for entry in &restored {
+                    height_log.write_all(&entry.to_bytes())?;
+                }
+            }
+        }
+        // Read-only, these lengths record how much of each file the view covers
+        let written = if read_only { 0 } else { restored.len() };
+        state.metadata.height_len = checkpoint.height_len + ((height_entries.len() + written) * HeightEntry::SIZE) as u64;
+        state.metadata.index_len = index_len;
+
+        if state.metadata.to_bytes() != metadata.to_bytes() {
+            report.metadata_repaired = true;
+            *metadata = state.metadata.clone();
+            if !read_only {
+                superblock.write(metadata)?;
+            }
+        }
+
+        if !report.is_clean() && !read_only {
+            state.segments.sync()?;
+            index_file.sync_all()?;
+            height_log.sync_all()?;
+        }
+
+        Ok((state, report))
+    }
+
+    /// Whether the files still extend a key file's checkpoint, so only what
+    /// was appended since has to be replayed
+    ///
+    /// Compacting, rebuilding or cutting blocks off the tail rewrites the
+    /// files; the last index record the checkpoint covers then no longer
+    /// matches, or lies past the end of adzdb.idx.
+    fn extends_checkpoint(
+        checkpoint: &Checkpoint,
+        index_file: &File,
+        height_log: &File,
+        segments: &Segments,
+        metadata: &Metadata,
+    ) -> Result<bool> {
+        if checkpoint.index_len % IndexEntry::SIZE as u64 != 0
+            || checkpoint.height_len % HeightEntry::SIZE as u64 != 0
+            || checkpoint.index_len > index_file.len()?
+            || checkpoint.height_len > height_log.len()?.min(metadata.height_len)
+            || !segments.covers(checkpoint.data_end)?
+        {
+            return Ok(false);
+        }
+        if checkpoint.index_len == 0 {
+            return Ok(checkpoint.data_end == Position::default());
+        }
+        let mut tail = [0u8; IndexEntry::SIZE];
+        read_exact_at(index_file, &mut tail, checkpoint.index_len - IndexEntry::SIZE as u64)?;
+        Ok(tail == checkpoint.index_tail && IndexEntry::from_bytes(&tail).end() == checkpoint.data_end)
+    }
+
+    /// The checkpoint describing the files as `metadata` commits them
+    fn checkpoint_at(index_file: &File, metadata: &Metadata) -> Result<Checkpoint> {
+        let mut checkpoint = Checkpoint {
+            index_len: metadata.index_len,
+            height_len: metadata.height_len,
+            data_size: metadata.data_size,
+            logical_size: metadata.logical_size,
+            ..Checkpoint::EMPTY
+        };
+        if metadata.index_len > 0 {
+            read_exact_at(index_file, &mut checkpoint.index_tail, metadata.index_len - IndexEntry::SIZE as u64)?;
+            checkpoint.data_end = IndexEntry::from_bytes(&checkpoint.index_tail).end();
+        }
+        Ok(checkpoint)
+    }
+
+    /// Write a key file holding the whole hash index and a height file
+    /// holding the whole canonical chain, replacing any that no longer match
+    /// adzdb.idx, and empty the journal they now cover
+    ///
+    /// This is how a database written before the key file existed, or
+    /// before adzdb.hgt was dense, gets them.
+    fn build_checkpoint(
+        dir: &Dir,
+        index_file: &File,
+        height_log: &mut File,
+        superblock: &mut Superblock,
+        state: &mut State,
+    ) -> Result<()> {
+        // Replaying the journal over the new height file changes nothing, so
+        // a crash before the journal is emptied is harmless
+        let slots = HeightFile::build(dir, state.height_index.canonical_from(0)?)?;
+        let checkpoint = Checkpoint {
+            height_len: 0,
+            ..Self::checkpoint_at(index_file, &state.metadata)?
+        };
+        let keys = KeyFile::build(dir, &state.hash_index.entries()?, &state.side_blocks, checkpoint)?;
+        state.hash_index = HashIndex::new(Some(keys));
+        state.height_index = HeightIndex::new(Some(slots));
+
+        Self::empty_journal(dir, height_log, &mut state.metadata)?;
+        superblock.write(&state.metadata)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!("🗝️  ADZDB built adzdb.key and adzdb.hgt from adzdb.idx");
+
+        Ok(())
+    }
+
+    /// Read and verify the value referenced by an index entry
+    ///
+    /// Uses positioned reads, so it never moves the file cursor and can run
+    /// on many threads at once.
+    fn read_value(segments: &Segments, entry: &IndexEntry) -> Result<Vec<u8>> {
+        let data_file = segments.file_of(entry)?;
+        let mut header_buf = [0u8; RecordHeader::SIZE];
+        read_exact_at(&data_file, &mut header_buf, entry.offset)?;
+        let header = RecordHeader::from_bytes(&header_buf);
+        Self::check_header(entry, &header)?;
+
+        let mut data = vec![0u8; entry.size as usize];
+        read_exact_at(&data_file, &mut data, entry.offset + RecordHeader::SIZE as u64)?;
+        Self::check_value(entry, &header, &data)?;
+
+        Ok(data)
+    }
+
+    /// Check that a record header describes the entry that points at it
+    fn check_header(entry: &IndexEntry, header: &RecordHeader) -> Result<()> {
+        if &header.magic != RECORD_MAGIC
+            || header.key != entry.key
+            || header.size != entry.size
+            || header.height != entry.height
+            || header.flags != entry.flags
+        {
+            return Err(Error::Corruption(format!(
+                "Record header mismatch for key {} at offset {} of segment {}",
+                to_hex(&entry.key),
+                entry.offset,
+                entry.segment
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check a value against the checksum in its record header
+    fn check_value(entry: &IndexEntry, header: &RecordHeader, value: &[u8]) -> Result<()> {
+        if !header.verify(value) {
+            return Err(Error::Corruption(format!(
+                "Checksum mismatch for key {} at offset {} of segment {}",
+                to_hex(&entry.key),
+                entry.offset,
+                entry.segment
+            )));
+        }
+        Ok(())
+    }
+
+    /// Scan the data segments and return an index entry for every intact
+    /// record
+    ///
+    /// Scanning stops at the first record that is truncated or fails its
+    /// checksum; the returned position is where that torn tail begins.
+    fn scan_data_file(segments: &Segments) -> Result<(Vec<IndexEntry>, Position)> {
+        let mut entries = Vec::new();
+        let mut end = Position::default();
+        let mut header_buf = [0u8; RecordHeader::SIZE];
+        let mut value = Vec::new();
+
+        for segment in segments.numbers() {
+            let data_file = segments.get(segment)?;
+            let data_len = data_file.len()?;
+            let mut reader = BufReader::new(&*data_file);
+            reader.seek(SeekFrom::Start(0))?;
+            let mut offset = 0u64;
+
+            while offset + RecordHeader::SIZE as u64 <= data_len {
+                reader.read_exact(&mut header_buf)?;
+                let header = RecordHeader::from_bytes(&header_buf);
+                let record_end = offset + RecordHeader::SIZE as u64 + header.size as u64;
+                if &header.magic != RECORD_MAGIC || record_end > data_len {
+                    break;
+                }
+
+                value.resize(header.size as usize, 0);
+                reader.read_exact(&mut value)?;
+                if !header.verify(&value) {
+                    break;
+                }
+
+                entries.push(IndexEntry {
+                    key: header.key,
+                    offset,
+                    size: header.size,
+                    height: header.height,
+                    flags: header.flags,
+                    segment,
+                    uncompressed_size: compress::uncompressed_size(header.flags, &value),
+                });
+                offset = record_end;
+            }
+
+            end = Position { segment, offset };
+            if offset < data_len {
+                break;
+            }
+        }
+
+        Ok((entries, end))
+    }
+
+    /// Regenerate adzdb.idx, adzdb.hgt and adzdb.meta from the data segments, and
+    /// empty the height journal
+    ///
+    /// The new index files are written beside the old ones and renamed into
+    /// place, so a crash part-way through leaves the previous files intact.
+    /// The key file is removed first and rebuilt by the next open.
+    fn rebuild_files(dir: &Dir) -> Result<()> {
+        compact::finish_pending(dir)?;
+        // Records of an older format would not scan as current ones
+        if matches!(migrate::version_of(dir), Ok(version) if version != VERSION) {
+            migrate::upgrade_locked(dir)?;
+        }
+
+        let mut segments = Segments::open(dir, true)?;
+
+        let (entries, data_end) = Self::scan_data_file(&segments)?;
+        if segments.len_after(data_end)? > 0 {
+            segments.truncate(data_end)?;
+        }
+        segments.sync()?;
+
+        // With no height entries to replay, the first block stored at each
+        // height becomes canonical, as it was when `put` stored it
+        let replay = State::replay(segments, &entries, &[])?;
+        let mut metadata = replay.metadata.clone();
+        metadata.height_len = 0;
+        metadata.index_len = (entries.len() * IndexEntry::SIZE) as u64;
+
+        let index_bytes: Vec<u8> = entries.iter().flat_map(|entry| entry.to_bytes()).collect();
+
+        dir.remove_if_exists(&dir.join(keyfile::KEY_FILE))?;
+        Self::replace_file(dir, &dir.join("adzdb.idx"), &index_bytes)?;
+        Self::replace_file(dir, &dir.join(HEIGHT_LOG), &[])?;
+        HeightFile::build(dir, replay.height_index.canonical_from(0)?)?;
+        Self::replace_file(dir, &dir.join("adzdb.meta"), &superblock::encode(&metadata))?;
+
+        Ok(())
+    }
+
+    /// Replace the height journal with an empty file, and start the next
+    /// journal generation in `metadata`
+    ///
+    /// A new file rather than a truncated one, so a read-only database
+    /// following the writer can finish reading the old one through its open
+    /// handle, instead of taking entries appended since for a continuation
+    /// of the ones it has read.
+    fn empty_journal(dir: &Dir, height_log: &mut File, metadata: &mut Metadata) -> Result<()> {
+        let log_path = dir.join(HEIGHT_LOG);
+        Self::replace_file(dir, &log_path, &[])?;
+        *height_log = dir.open(&log_path, OpenOptions::new().read(true).write(true))?;
+        metadata.height_len = 0;
+        metadata.journal_generation += 1;
+        Ok(())
+    }
+
+    /// Atomically replace a file's contents via a temporary file and rename
+    fn replace_file(dir: &Dir, path: &Path, contents: &[u8]) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp = dir.create(&tmp_path)?;
+            tmp.write_all(contents)?;
+            tmp.sync_all()?;
+        }
+        dir.rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Store a value by hash (content-addressable)
+    ///
+    /// Automatically deduplicates: if the hash already exists, this is a no-op.
+    ///
+    /// The first block stored at a height becomes the canonical block there.
+    /// Further blocks at the same height are kept as side blocks: they can be
+    /// read by hash and are listed by `get_blocks_at_height`, but
+    /// `get_by_height` keeps returning the canonical block.
+    ///
+    /// If a hasher is configured, the value is hashed first and
+    /// `Error::HashMismatch` is returned when it does not match `hash`. A
+    /// hash of `RESERVED_HASH` is rejected with `Error::ReservedHash`.
+    ///
+    /// # Arguments
     ///
     /// * `hash` - The 256-bit hash key (typically the block hash)
     /// * `height` - The block height for indexing
@@ -627,106 +1783,323 @@ impl Database {
     /// # }
     /// ```
     pub fn put(&mut self, hash: &Hash, height: u64, data: &[u8]) -> Result<()> {
-        // Corruption detection
-        if height > MAX_REASONABLE_HEIGHT {
-            return Err(Error::HeightTooLarge(height));
-        }
+        self.check_writable()?;
+        self.check_block(hash, height, data)?;
+
+        // No slice from `get_ref` outlives `&mut self`
+        #[cfg(feature = "mmap")]
+        self.data_map.release();
+
+        let state = self.state.read();
 
         // Check if already exists (deduplication)
-        if self.hash_index.contains_key(hash) {
+        if state.hash_index.contains(hash)? {
             return Ok(());
         }
 
-        // Get current data file position
-        let offset = self.data_file.seek(SeekFrom::End(0))?;
-
-        // Write data
-        self.data_file.write_all(data)?;
+        // Place the record at the end of the data, in a new segment if it
+        // would take the active one past its size limit
+        let (stored, codec) = compress::encode(self.config.compression, data)?;
+        let record_len = (RecordHeader::SIZE + stored.len()) as u64;
+        let at = state.segments.end()?.place(record_len, self.config.segment_size);
 
         // Create index entry
         let entry = IndexEntry {
             key: *hash,
-            offset,
-            size: data.len() as u32,
+            offset: at.offset,
+            size: stored.len() as u32,
             height,
-            flags: 0,
+            flags: codec,
+            segment: at.segment,
+            uncompressed_size: compress::uncompressed_size(codec, &stored),
         };
 
-        // Write to index file
-        self.index_file.seek(SeekFrom::End(0))?;
-        self.index_file.write_all(&entry.to_bytes())?;
-
-        // Write to height index file
-        let height_entry = HeightEntry {
-            height,
-            hash: *hash,
-        };
-        self.height_file.seek(SeekFrom::End(0))?;
-        self.height_file.write_all(&height_entry.to_bytes())?;
+        // The first block stored at a height joins the canonical chain;
+        // later ones are kept as side blocks until a reorg selects them
+        let canonical = !state.height_index.contains(height)?;
+        let tip = canonical
+            && (height > state.metadata.latest_height || !state.height_index.contains(state.metadata.latest_height)?);
+        drop(state);
+
+        // Write record header and data in one append
+        let mut records = Records::default();
+        let record = records.at(at);
+        record.extend_from_slice(&RecordHeader::with_flags(hash, height, &stored, codec).to_bytes());
+        record.extend_from_slice(&stored);
+        self.appending(|db| {
+            db.append_records(records)?;
+
+            // Write to index file
+            db.index_file.seek(SeekFrom::End(0))?;
+            db.index_file.write_all(&entry.to_bytes())?;
+
+            if canonical {
+                let height_entry = HeightEntry {
+                    height,
+                    hash: *hash,
+                };
+                db.height_log.seek(SeekFrom::End(0))?;
+                db.height_log.write_all(&height_entry.to_bytes())?;
+            }
+            Ok(())
+        })?;
 
         // Update in-memory indices
-        self.hash_index.insert(*hash, entry);
-        self.height_index.insert(height, *hash);
+        let mut state = self.state.write();
+        state.hash_index.insert(entry);
 
         // Update metadata
-        self.metadata.entry_count += 1;
-        self.metadata.data_size += data.len() as u64;
-
-        if height > self.metadata.latest_height {
-            self.metadata.latest_height = height;
-            self.metadata.latest_hash = *hash;
-        }
-
-        if height == 0 {
-            self.metadata.genesis_hash = *hash;
+        state.metadata.entry_count += 1;
+        state.metadata.data_size += entry.size as u64;
+        state.metadata.logical_size += entry.logical_size();
+
+        if canonical {
+            state.height_index.insert(height, *hash);
+            if tip {
+                state.metadata.latest_height = height;
+                state.metadata.latest_hash = *hash;
+            }
+            if height == 0 {
+                state.metadata.genesis_hash = *hash;
+            }
+        } else {
+            state.side_blocks.get_or_default(height).push(*hash);
         }
+        drop(state);
 
         // Sync if configured
         if self.config.sync_on_write {
-            self.sync()?;
+            self.sync_and_checkpoint(false)?;
         }
 
         Ok(())
     }
 
-    /// Get value by hash (O(1) lookup)
+    /// Store a batch of blocks atomically
+    ///
+    /// Every block is stored as if by `put`, in the order it was added to
+    /// the batch: blocks already stored or repeated within the batch are
+    /// skipped, and the first block at a height becomes canonical. All values
+    /// and index entries are appended, then committed with one metadata
+    /// update and one sync, whatever `sync_on_write` says. After a crash the
+    /// database holds either the whole batch or none of it.
     ///
     /// # Errors
     ///
-    /// Returns `Error::NotFound` if the hash doesn't exist.
+    /// The batch is validated before anything is written: if any block is
+    /// rejected, as `put` would reject it, nothing is stored.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use adzdb::{Database, Config};
+    /// use adzdb::{Database, Config, WriteBatch};
     ///
     /// # fn main() -> adzdb::Result<()> {
     /// let config = Config::new("./blockchain");
-    /// let db = Database::open(config)?;
+    /// let mut db = Database::open_or_create(config)?;
     ///
-    /// let hash = [42u8; 32];
-    /// let data = db.get(&hash)?;
+    /// let mut batch = WriteBatch::new();
+    /// batch.put(&[0u8; 32], 0, b"genesis");
+    /// batch.put(&[1u8; 32], 1, b"block 1");
+    /// db.write(batch)?;
+    /// assert_eq!(db.latest_height(), 1);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get(&self, hash: &Hash) -> Result<Vec<u8>> {
-        let entry = self.hash_index.get(hash).ok_or(Error::NotFound)?;
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        for block in &batch.entries {
+            self.check_block(&block.hash, block.height, &block.data)?;
+        }
 
-        let file = &self.data_file;
-        let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(entry.offset))?;
+        #[cfg(feature = "mmap")]
+        self.data_map.release();
+
+        let state = self.state.read();
+        let data_start = state.segments.end()?;
+        let empty = !state.height_index.contains(state.metadata.latest_height)?;
+
+        // Lay out the records back to back, as consecutive puts would
+        let mut entries = Vec::with_capacity(batch.len());
+        let mut canonical = Vec::new();
+        let mut side = Vec::new();
+        let mut records = Records::default();
+        let mut keys = HashSet::with_capacity(batch.len());
+        let mut heights_taken = HashSet::new();
+        let mut end = data_start;
+        for block in &batch.entries {
+            if state.hash_index.contains(&block.hash)? || !keys.insert(block.hash) {
+                continue;
+            }
+            let (stored, codec) = compress::encode(self.config.compression, &block.data)?;
+            let at = end.place((RecordHeader::SIZE + stored.len()) as u64, self.config.segment_size);
+            let entry = IndexEntry {
+                key: block.hash,
+                offset: at.offset,
+                size: stored.len() as u32,
+                height: block.height,
+                flags: IndexEntry::FLAG_BATCH | codec,
+                segment: at.segment,
+                uncompressed_size: compress::uncompressed_size(codec, &stored),
+            };
+            let header = RecordHeader::with_flags(&block.hash, block.height, &stored, entry.flags);
+            let record = records.at(at);
+            record.extend_from_slice(&header.to_bytes());
+            record.extend_from_slice(&stored);
+            end = entry.end();
+
+            if !state.height_index.contains(block.height)? && heights_taken.insert(block.height) {
+                canonical.push(HeightEntry {
+                    height: block.height,
+                    hash: block.hash,
+                });
+            } else {
+                side.push(entry);
+            }
+            entries.push(entry);
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
 
-        let mut data = vec![0u8; entry.size as usize];
-        reader.read_exact(&mut data)?;
+        let index: Vec<u8> = entries.iter().flat_map(|entry| entry.to_bytes()).collect();
+        let heights: Vec<u8> = canonical.iter().flat_map(|entry| entry.to_bytes()).collect();
+
+        let mut metadata = state.metadata.clone();
+        metadata.entry_count += entries.len() as u64;
+        metadata.data_size += entries.iter().map(|entry| entry.size as u64).sum::<u64>();
+        metadata.logical_size += entries.iter().map(IndexEntry::logical_size).sum::<u64>();
+        if let Some(tip) = canonical.iter().max_by_key(|entry| entry.height) {
+            if tip.height > metadata.latest_height || empty {
+                metadata.latest_height = tip.height;
+                metadata.latest_hash = tip.hash;
+            }
+        }
+        if let Some(genesis) = canonical.iter().find(|entry| entry.height == 0) {
+            metadata.genesis_hash = genesis.hash;
+        }
 
-        Ok(data)
+        drop(state);
+
+        // Commit point: the metadata write covers the whole batch
+        self.appending(|db| {
+            db.append_records(records)?;
+            db.index_file.seek(SeekFrom::End(0))?;
+            db.index_file.write_all(&index)?;
+            db.height_log.seek(SeekFrom::End(0))?;
+            db.height_log.write_all(&heights)?;
+            db.commit(&mut metadata)
+        })?;
+
+        // Update in-memory indices
+        let mut state = self.state.write();
+        for entry in entries {
+            state.hash_index.insert(entry);
+        }
+        for entry in canonical {
+            state.height_index.insert(entry.height, entry.hash);
+        }
+        for entry in side {
+            state.side_blocks.get_or_default(entry.height).push(entry.key);
+        }
+        state.metadata = metadata;
+        drop(state);
+
+        self.checkpoint(false)
     }
 
-    /// Get value by height (O(1) with height index)
+    /// Append records to the data, starting the segment they begin if it
+    /// does not exist yet
+    ///
+    /// The files are written under the read lock, so readers carry on
+    /// meanwhile; only adding the new segment to the shared state takes the
+    /// write lock.
+    fn append_records(&self, records: Records) -> Result<()> {
+        for (at, bytes) in records.chunks() {
+            if at.segment > self.state.read().segments.last() {
+                let file = self.state.read().segments.create_next()?;
+                self.state.write().segments.extend(file);
+            }
+            self.state.read().segments.append(at.segment, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Run `append`, which appends to the data, index and journal files,
+    /// and cut the files back to where they ended if it fails
+    ///
+    /// Whatever part of a failed append reached the files would otherwise
+    /// sit between the records before it and the ones appended next, which
+    /// must follow each other back to back. If the files cannot be cut back
+    /// either, every later write fails with `Error::ReopenRequired`.
+    fn appending<T>(&mut self, append: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let data_end = self.state.read().segments.end()?;
+        let index_len = self.index_file.len()?;
+        let height_len = self.height_log.len()?;
+        let result = append(self);
+        if result.is_err() {
+            let cut = self.state.write().segments.truncate(data_end);
+            let cut = cut.is_ok() && self.index_file.set_len(index_len).is_ok();
+            self.torn |= !(cut && self.height_log.set_len(height_len).is_ok());
+        }
+        result
+    }
+
+    /// Reject writes to a database opened read-only, or left torn by a
+    /// failed write
+    fn check_writable(&self) -> Result<()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.torn {
+            return Err(Error::ReopenRequired);
+        }
+        Ok(())
+    }
+
+    /// Check that a block may be stored, before anything is written
+    fn check_block(&self, hash: &Hash, height: u64, data: &[u8]) -> Result<()> {
+        // The height file could not tell the block from an empty height
+        if *hash == RESERVED_HASH {
+            return Err(Error::ReservedHash);
+        }
+
+        // Corruption detection
+        if height > MAX_REASONABLE_HEIGHT {
+            return Err(Error::HeightTooLarge(height));
+        }
+        if data.len() as u64 > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge(data.len() as u64));
+        }
+
+        // Content-addressable check
+        if let Some(hasher) = &self.config.hasher {
+            let actual = hasher.hash(data);
+            if actual != *hash {
+                return Err(Error::HashMismatch {
+                    expected: *hash,
+                    actual,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a stored block by hash
+    ///
+    /// A tombstone is appended rather than rewriting the files: `get`,
+    /// `contains` and `get_blocks_at_height` treat the block as absent from
+    /// then on, and the space is left in the data segments until it is
+    /// compacted. The same block can be stored again later with `put`.
+    ///
+    /// Deleting a canonical block also clears its height, leaving a gap in
+    /// the canonical chain; to discard the tip of the chain, use
+    /// `truncate_to_height` instead.
     ///
     /// # Errors
     ///
-    /// Returns `Error::NotFound` if no block exists at the given height.
+    /// Returns `Error::NotFound` if the hash doesn't exist.
     ///
     /// # Example
     ///
@@ -735,286 +2108,3453 @@ impl Database {
     ///
     /// # fn main() -> adzdb::Result<()> {
     /// let config = Config::new("./blockchain");
-    /// let db = Database::open(config)?;
+    /// let mut db = Database::open_or_create(config)?;
     ///
-    /// let genesis = db.get_by_height(0)?;
+    /// db.put(&[0u8; 32], 0, b"genesis")?;
+    /// db.put(&[1u8; 32], 1, b"block 1")?;
+    /// db.put(&[9u8; 32], 1, b"spam")?; // side block
+    ///
+    /// db.delete(&[9u8; 32])?;
+    /// assert!(!db.contains(&[9u8; 32])?);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get_by_height(&self, height: u64) -> Result<Vec<u8>> {
-        let hash = self.height_index.get(&height).ok_or(Error::NotFound)?;
-        self.get(hash)
+    pub fn delete(&mut self, hash: &Hash) -> Result<()> {
+        self.check_writable()?;
+        let state = self.state.read();
+        let entry = state.hash_index.get(hash)?.ok_or(Error::NotFound)?;
+        let canonical = state.height_index.get(entry.height)? == Some(*hash);
+        drop(state);
+
+        #[cfg(feature = "mmap")]
+        self.data_map.release();
+
+        // The tombstone goes first: if a crash loses the height entry, the
+        // canonical height of a removed block is cleared on open anyway
+        self.appending(|db| {
+            db.append_markers(&[entry], IndexEntry::FLAG_TOMBSTONE)?;
+            if canonical {
+                db.height_log.seek(SeekFrom::End(0))?;
+                db.height_log.write_all(&HeightEntry::cleared(entry.height).to_bytes())?;
+            }
+            Ok(())
+        })?;
+
+        // Update in-memory indices
+        let mut guard = self.state.write();
+        let state = &mut *guard;
+        state.hash_index.remove(hash);
+        if !canonical {
+            remove_side_block(&mut state.side_blocks, entry.height, hash);
+        }
+
+        // Update metadata
+        state.metadata.entry_count -= 1;
+        state.metadata.data_size -= entry.size as u64;
+        state.metadata.logical_size -= entry.logical_size();
+
+        if canonical {
+            state.height_index.remove(entry.height);
+            if entry.height == state.metadata.latest_height {
+                let (height, hash) = state.height_index.last()?.unwrap_or((0, ZERO_HASH));
+                state.metadata.latest_height = height;
+                state.metadata.latest_hash = hash;
+            }
+            if entry.height == 0 {
+                state.metadata.genesis_hash = ZERO_HASH;
+            }
+        }
+        drop(guard);
+
+        // Sync if configured
+        if self.config.sync_on_write {
+            self.sync_and_checkpoint(false)?;
+        }
+
+        Ok(())
     }
 
-    /// Get hash by height
+    /// Switch the canonical chain to a competing branch
+    ///
+    /// `new_branch` lists the blocks that become canonical at heights
+    /// `fork_point + 1`, `fork_point + 2`, and so on; `fork_point` is the
+    /// height of the last block both branches share. Canonical heights above
+    /// the new tip are cleared, and the latest height and hash move to the
+    /// last block of `new_branch`, even if that is lower than the old tip.
+    ///
+    /// The switch is crash-atomic: the new height entries are appended and
+    /// synced, then committed by a single metadata write. After a crash the
+    /// database reopens on either the old branch or the new one, never a mix.
     ///
     /// # Errors
     ///
-    /// Returns `Error::NotFound` if no block exists at the given height.
-    pub fn get_hash_by_height(&self, height: u64) -> Result<Hash> {
-        self.height_index.get(&height).copied().ok_or(Error::NotFound)
-    }
+    /// Returns `Error::NotFound` if a block in `new_branch` is not stored,
+    /// and `Error::InvalidReorg` if `new_branch` is empty, `fork_point` is not
+    /// on the canonical chain, or a block is stored at the wrong height.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open_or_create(config)?;
+    ///
+    /// db.put(&[0u8; 32], 0, b"genesis")?;
+    /// db.put(&[1u8; 32], 1, b"block 1a")?;
+    /// db.put(&[2u8; 32], 1, b"block 1b")?; // side block
+    /// db.put(&[3u8; 32], 2, b"block 2b")?;
+    ///
+    /// // Switch to the b-branch from the genesis block upward
+    /// db.reorg(0, &[[2u8; 32], [3u8; 32]])?;
+    /// assert_eq!(db.get_by_height(1)?, b"block 1b");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reorg(&mut self, fork_point: u64, new_branch: &[Hash]) -> Result<()> {
+        self.check_writable()?;
+        if new_branch.is_empty() {
+            return Err(Error::InvalidReorg("new branch is empty".to_string()));
+        }
+        let state = self.state.read();
+        if !state.height_index.contains(fork_point)? {
+            return Err(Error::InvalidReorg(format!(
+                "fork point {} is not on the canonical chain",
+                fork_point
+            )));
+        }
+        for (height, hash) in (fork_point + 1..).zip(new_branch) {
+            let entry = state.hash_index.get(hash)?.ok_or(Error::NotFound)?;
+            if entry.height != height {
+                return Err(Error::InvalidReorg(format!(
+                    "block {} is stored at height {}, expected {}",
+                    to_hex(hash),
+                    entry.height,
+                    height
+                )));
+            }
+        }
+
+        let new_tip = fork_point + new_branch.len() as u64;
+        let old_tip = state.metadata.latest_height;
+
+        // Rewrite the canonical mapping from the fork point upward, clearing
+        // whatever the old branch had above the new tip
+        let mut updates = Vec::new();
+        for (height, hash) in (fork_point + 1..).zip(new_branch) {
+            if state.height_index.get(height)? != Some(*hash) {
+                updates.push(HeightEntry { height, hash: *hash });
+            }
+        }
+        for height in new_tip + 1..=old_tip {
+            if state.height_index.contains(height)? {
+                updates.push(HeightEntry::cleared(height));
+            }
+        }
+
+        let records: Vec<u8> = updates.iter().flat_map(|entry| entry.to_bytes()).collect();
+        let mut metadata = state.metadata.clone();
+        metadata.latest_height = new_tip;
+        metadata.latest_hash = *new_branch.last().unwrap();
+        drop(state);
+
+        // Commit point: the metadata write covers the new entries
+        self.appending(|db| {
+            db.height_log.seek(SeekFrom::End(0))?;
+            db.height_log.write_all(&records)?;
+            db.commit(&mut metadata)
+        })?;
+
+        // Readers switch branches in one step
+        let mut state = self.state.write();
+        for entry in updates {
+            if entry.is_cleared() {
+                state.clear_canonical(entry.target_height())?;
+            } else {
+                state.set_canonical(entry.height, entry.hash)?;
+            }
+        }
+        state.metadata = metadata;
+        drop(state);
+
+        self.checkpoint(false)
+    }
+
+    /// Discard the canonical chain above `height`
+    ///
+    /// Every canonical block above `height` is removed: it can no longer be
+    /// read by hash or by height, and the latest height and hash move back to
+    /// the highest remaining canonical block. Side blocks above `height` are
+    /// kept, off the canonical chain. Returns the hashes of the removed
+    /// blocks in ascending height order.
+    ///
+    /// If the removed blocks were the last ones appended, nothing else is
+    /// stored above `height` and no compactor or snapshot still reads them,
+    /// their records are cut from the end of the data, index and height
+    /// files. Otherwise a tombstone is appended for each
+    /// block, highest first, followed by height entries clearing their
+    /// heights. Either way an interrupted truncation reopens at the old tip
+    /// or at a lower one, never with a gap in the chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error if the files cannot be written. If the records
+    /// were being cut off the file tails, later writes fail with
+    /// `Error::ReopenRequired` until the database is reopened.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open_or_create(config)?;
+    ///
+    /// db.put(&[0u8; 32], 0, b"genesis")?;
+    /// db.put(&[1u8; 32], 1, b"block 1")?;
+    /// db.put(&[2u8; 32], 2, b"invalid block 2")?;
+    ///
+    /// let removed = db.truncate_to_height(1)?;
+    /// assert_eq!(removed, vec![[2u8; 32]]);
+    /// assert_eq!(db.latest_height(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn truncate_to_height(&mut self, height: u64) -> Result<Vec<Hash>> {
+        self.check_writable()?;
+        // Nothing can be stored above the highest height
+        let Some(from) = height.checked_add(1) else {
+            return Ok(Vec::new());
+        };
+        let state = self.state.read();
+        let mut removed = Vec::new();
+        for (_, hash) in state.height_index.canonical_from(from)? {
+            let entry = state.hash_index.get(&hash)?.ok_or_else(|| {
+                Error::Corruption(format!("Canonical block {} is not in the hash index", to_hex(&hash)))
+            })?;
+            removed.push(entry);
+        }
+        if removed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut metadata = state.metadata.clone();
+        for entry in &removed {
+            metadata.entry_count -= 1;
+            metadata.data_size -= entry.size as u64;
+            metadata.logical_size -= entry.logical_size();
+        }
+        let (latest_height, latest_hash) = state.height_index.last_at_most(height)?.unwrap_or((0, ZERO_HASH));
+        metadata.latest_height = latest_height;
+        metadata.latest_hash = latest_hash;
+        drop(state);
+
+        // Readers must stop finding the blocks before their records go, and
+        // no compactor or snapshot may still read them
+        let at_tail = Arc::strong_count(&self.compactors) == 1
+            && self.is_file_tail(&removed)?
+            && self.remove_truncated(&removed, metadata.clone(), true)?;
+        if at_tail {
+            // The blocks are gone from the indexes already, so if cutting
+            // fails only reopening reconciles the files with them
+            if let Err(e) = self.cut_file_tails(&removed, &mut metadata) {
+                self.torn = true;
+                return Err(e);
+            }
+            self.commit(&mut metadata)?;
+            self.state.write().metadata = metadata;
+        } else {
+            let descending: Vec<IndexEntry> = removed.iter().rev().copied().collect();
+            let clears: Vec<u8> = descending
+                .iter()
+                .flat_map(|entry| HeightEntry::cleared(entry.height).to_bytes())
+                .collect();
+            self.appending(|db| {
+                db.append_markers(&descending, IndexEntry::FLAG_TOMBSTONE)?;
+                db.height_log.seek(SeekFrom::End(0))?;
+                db.height_log.write_all(&clears)?;
+                db.commit(&mut metadata)
+            })?;
+            self.remove_truncated(&removed, metadata, false)?;
+            self.checkpoint(false)?;
+        }
+
+        Ok(removed.iter().map(|entry| entry.key).collect())
+    }
+
+    /// Drop truncated blocks from the indexes and publish the metadata
+    /// describing the shortened chain
+    ///
+    /// With `exclusive`, nothing is changed and false is returned if a
+    /// snapshot still shares the active segment. Otherwise the records are
+    /// about to be cut off the file tails, so the key file and the height
+    /// file are checkpointed first at the point they will be cut at: once
+    /// they are gone, a checkpoint covering them would no longer match
+    /// adzdb.idx. The checkpoint covers none of the journal, which is emptied
+    /// next. The records it keeps are synced first, since the last commit may
+    /// not cover them. If the checkpoint fails, the indexes no longer match
+    /// the files and only reopening reconciles them.
+    fn remove_truncated(&mut self, removed: &[IndexEntry], metadata: Metadata, exclusive: bool) -> Result<bool> {
+        let cut = if exclusive {
+            self.state.read().segments.sync()?;
+            self.index_file.sync_all()?;
+            let index_len = self.index_file.len()? - (removed.len() * IndexEntry::SIZE) as u64;
+            let committed = Metadata {
+                index_len,
+                height_len: 0,
+                ..metadata.clone()
+            };
+            Some(Self::checkpoint_at(&self.index_file, &committed)?)
+        } else {
+            None
+        };
+
+        let mut guard = self.state.write();
+        let state = &mut *guard;
+        if exclusive && state.segments.is_shared() {
+            return Ok(false);
+        }
+        for entry in removed {
+            state.hash_index.remove(&entry.key);
+            state.height_index.remove(entry.height);
+        }
+        state.metadata = metadata;
+        if let Some(checkpoint) = cut {
+            if let Err(e) = state.hash_index.checkpoint(&state.side_blocks, &mut state.height_index, checkpoint) {
+                self.torn = true;
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether `removed` are the last records of the index file, and all in
+    /// the active segment
+    ///
+    /// Only then can they be cut off the file tails; sealed segments are
+    /// never written again. The height file is checkpointed without them
+    /// first, so the journal never needs cutting. Pruned blocks are never
+    /// cut, since earlier entries still name them.
+    fn is_file_tail(&self, removed: &[IndexEntry]) -> Result<bool> {
+        let active = self.state.read().segments.last();
+        if removed.iter().any(|entry| entry.segment != active || entry.is_pruned()) {
+            return Ok(false);
+        }
+        let keys: HashSet<Hash> = removed.iter().map(|entry| entry.key).collect();
+
+        let index_len = self.index_file.len()?;
+        let tail_len = (removed.len() * IndexEntry::SIZE) as u64;
+        if index_len % IndexEntry::SIZE as u64 != 0 || index_len < tail_len {
+            return Ok(false);
+        }
+        let mut reader = BufReader::new(&self.index_file);
+        reader.seek(SeekFrom::Start(index_len - tail_len))?;
+        let mut buf = [0u8; IndexEntry::SIZE];
+        for _ in 0..removed.len() {
+            reader.read_exact(&mut buf)?;
+            let entry = IndexEntry::from_bytes(&buf);
+            if entry.is_tombstone() || !keys.contains(&entry.key) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Empty the journal and cut the records of `removed` off the end of
+    /// the active segment and the index file
+    ///
+    /// The journal goes first, since the checkpoint already left the removed
+    /// heights out of adzdb.hgt. If a crash keeps the index entries, their
+    /// blocks are restored as canonical on open and the truncation is undone;
+    /// once the index entries are gone, the rest is a torn tail.
+    fn cut_file_tails(&mut self, removed: &[IndexEntry], metadata: &mut Metadata) -> Result<()> {
+        let data_end = Position {
+            segment: self.state.read().segments.last(),
+            offset: removed.iter().map(|entry| entry.offset).min().unwrap_or(0),
+        };
+        let index_len = self.index_file.len()? - (removed.len() * IndexEntry::SIZE) as u64;
+
+        // Accessing a mapping past the end of the file would fault
+        #[cfg(feature = "mmap")]
+        self.data_map.clear();
+
+        Self::empty_journal(&self.config.dir(), &mut self.height_log, metadata)?;
+        self.index_file.set_len(index_len)?;
+        self.state.write().segments.truncate(data_end)?;
+
+        Ok(())
+    }
+
+    /// Append a header-only record with `flags` for each entry, in order, to
+    /// the data and index files, and return the new entries
+    ///
+    /// With `FLAG_TOMBSTONE` the records remove their blocks, with
+    /// `FLAG_PRUNED` they mark their values as pruned.
+    fn append_markers(&mut self, entries: &[IndexEntry], flags: u32) -> Result<Vec<IndexEntry>> {
+        let mut end = self.state.read().segments.end()?;
+        let mut records = Records::default();
+        let mut markers = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let at = end.place(RecordHeader::SIZE as u64, self.config.segment_size);
+            let marker = IndexEntry {
+                key: entry.key,
+                offset: at.offset,
+                size: 0,
+                height: entry.height,
+                flags,
+                segment: at.segment,
+                uncompressed_size: 0,
+            };
+            let header = RecordHeader::with_flags(&entry.key, entry.height, &[], flags);
+            records.at(at).extend_from_slice(&header.to_bytes());
+            markers.push(marker);
+            end = marker.end();
+        }
+
+        self.append_records(records)?;
+        let index: Vec<u8> = markers.iter().flat_map(IndexEntry::to_bytes).collect();
+        self.index_file.seek(SeekFrom::End(0))?;
+        self.index_file.write_all(&index)?;
+
+        Ok(markers)
+    }
+
+    /// Discard the values of every block below `height`, keeping the blocks
+    ///
+    /// Pruned blocks stay in the hash and height indexes: `contains`,
+    /// `get_hash_by_height`, `get_blocks_at_height` and the latest and
+    /// genesis hashes see no difference, but reading a pruned value fails
+    /// with `Error::Pruned`. Storing a pruned block again is a no-op, as for
+    /// any block already stored.
+    ///
+    /// Each block pruned gets a header-only record flagged `FLAG_PRUNED`,
+    /// which replaces the entry of its value, and the records are committed
+    /// like tombstones, a few thousand heights at a time so memory stays
+    /// bounded however many blocks are pruned. The data segments before the
+    /// first one still holding a value, other than the active segment, are
+    /// then deleted. Pruned records in them are written again first, so a
+    /// rebuild from the data segments still finds every block. If a write
+    /// fails, the heights committed so far stay pruned. Space in the
+    /// remaining segments is reclaimed by a later call once they are deleted
+    /// too, or by a compaction. Snapshots taken before keep reading the
+    /// pruned values, and the space of a deleted segment is only freed once
+    /// they are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::CompactionInProgress` while a compactor exists, and an
+    /// I/O error if the files cannot be written.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config, Error};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open_or_create(config)?;
+    ///
+    /// db.put(&[0u8; 32], 0, b"genesis")?;
+    /// db.put(&[1u8; 32], 1, b"block 1")?;
+    ///
+    /// let report = db.prune_below(1)?;
+    /// assert_eq!(report.blocks_pruned, 1);
+    /// assert!(db.contains(&[0u8; 32])?);
+    /// assert!(matches!(db.get_by_height(0), Err(Error::Pruned)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prune_below(&mut self, height: u64) -> Result<PruneReport> {
+        self.check_writable()?;
+        if Arc::strong_count(&self.compactors) > 1 {
+            return Err(Error::CompactionInProgress);
+        }
+        let (end, mut first, lowest) = {
+            let state = self.state.read();
+            (state.height_end()?, state.segments.last(), state.segments.first())
+        };
+
+        // The first segment holding a value that stays
+        let mut from = height;
+        while from < end && first > lowest {
+            let to = from.saturating_add(PRUNE_HEIGHTS).min(end);
+            let blocks = self.state.read().blocks_between(from, to)?;
+            for entry in blocks.iter().filter(|entry| !entry.is_pruned()) {
+                first = first.min(entry.segment);
+            }
+            from = to;
+        }
+
+        // Prune the blocks below `height`, and write the records of pruned
+        // blocks in the segments about to go again. In height order, as a
+        // rebuild from the data segments makes the first block canonical.
+        let mut report = PruneReport::default();
+        let mut from = 0;
+        while from < end {
+            let to = from.saturating_add(PRUNE_HEIGHTS).min(end);
+            let state = self.state.read();
+            let pruned: Vec<IndexEntry> = state
+                .blocks_between(from, to)?
+                .into_iter()
+                .filter(|entry| if entry.is_pruned() { entry.segment < first } else { entry.height < height })
+                .collect();
+            let mut metadata = state.metadata.clone();
+            for entry in pruned.iter().filter(|entry| !entry.is_pruned()) {
+                report.blocks_pruned += 1;
+                metadata.data_size -= entry.size as u64;
+                metadata.logical_size -= entry.logical_size();
+            }
+            drop(state);
+            from = to;
+            if pruned.is_empty() {
+                continue;
+            }
+
+            #[cfg(feature = "mmap")]
+            self.data_map.release();
+
+            let markers = self.appending(|db| {
+                let markers = db.append_markers(&pruned, IndexEntry::FLAG_PRUNED)?;
+                db.commit(&mut metadata)?;
+                Ok(markers)
+            })?;
+
+            let mut state = self.state.write();
+            for marker in markers {
+                state.hash_index.insert(marker);
+            }
+            state.metadata = metadata;
+            drop(state);
+            self.checkpoint(false)?;
+        }
+
+        // Committed, so no index entry needs the segments before `first`
+        let mut segments = self.state.read().segments.clone();
+        let len = segments.len()?;
+        let removed = first.saturating_sub(segments.first());
+        if removed > 0 {
+            // A mapping would keep the space of a deleted segment in use
+            #[cfg(feature = "mmap")]
+            self.data_map.clear();
+
+            segments.remove_before(first)?;
+            report.segments_removed = removed as u64;
+            report.bytes_reclaimed = len - segments.len()?;
+            self.state.write().segments = segments;
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            "✂️  ADZDB pruned {} blocks below height {}, removing {} segments",
+            report.blocks_pruned,
+            height,
+            report.segments_removed
+        );
+
+        self.checkpoint(false)?;
+        Ok(report)
+    }
+
+    /// Get value by hash (O(1) lookup)
+    ///
+    /// A value stored compressed is decompressed, whatever codec the
+    /// database is configured with now.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NotFound` if the hash doesn't exist, `Error::Pruned`
+    /// if its value was pruned, or `Error::Corruption` if the stored record
+    /// fails its checksum or does not decompress. A value compressed with a
+    /// codec whose feature is disabled fails with `Error::InvalidConfig`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// let hash = [42u8; 32];
+    /// let data = db.get(&hash)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get(&self, hash: &Hash) -> Result<Vec<u8>> {
+        self.state.read().get(hash)
+    }
+
+    /// Get value by hash without copying it (requires the `mmap` feature)
+    ///
+    /// The returned value borrows straight from a memory-mapped data segment.
+    /// The checksum is verified as by `get`. A segment is mapped again when
+    /// it has grown past the current mapping, so blocks stored after earlier
+    /// calls are readable too.
+    ///
+    /// The value is an owned copy, as from `get`, when it is stored
+    /// compressed, when the segment is not an operating system file, or when
+    /// the database is read-only: a writer in another process may truncate
+    /// the segments, and a mapping must not outlive the bytes it covers.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NotFound` if the hash doesn't exist, `Error::Pruned`
+    /// if its value was pruned, or `Error::Corruption` if the stored record
+    /// fails its checksum.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// let block = db.get_ref(&db.latest_hash())?;
+    /// println!("{} bytes", block.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "mmap")]
+    pub fn get_ref(&self, hash: &Hash) -> Result<std::borrow::Cow<'_, [u8]>> {
+        let state = self.state.read();
+        let entry = state.hash_index.get(hash)?.ok_or(Error::NotFound)?;
+        if entry.is_pruned() {
+            return Err(Error::Pruned);
+        }
+        let segment = state.segments.file_of(&entry)?;
+        let Some(file) = segment.as_std().filter(|_| !self.config.read_only && !entry.is_compressed()) else {
+            return state.get(hash).map(std::borrow::Cow::Owned);
+        };
+        let record = self.data_map.slice(
+            file,
+            entry.segment,
+            entry.offset,
+            RecordHeader::SIZE + entry.size as usize,
+        )?;
+        let (header, value) = record.split_at(RecordHeader::SIZE);
+        let header = RecordHeader::from_bytes(header.try_into().unwrap());
+        Self::check_header(&entry, &header)?;
+        Self::check_value(&entry, &header, value)?;
+        Ok(std::borrow::Cow::Borrowed(value))
+    }
+
+    /// Re-hash a stored value and check it still matches its key
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidConfig` if no hasher is configured,
+    /// `Error::NotFound` if the hash doesn't exist, and
+    /// `Error::HashMismatch` if the stored value hashes to something else.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # #[cfg(feature = "sha2")]
+    /// # fn main() -> adzdb::Result<()> {
+    /// use adzdb::{Database, Config};
+    /// use adzdb::hasher::Sha256;
+    ///
+    /// let config = Config::new("./blockchain").with_hasher(Sha256);
+    /// let db = Database::open(config)?;
+    ///
+    /// db.verify_entry(&db.latest_hash())?;
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "sha2"))]
+    /// # fn main() {}
+    /// ```
+    pub fn verify_entry(&self, hash: &Hash) -> Result<()> {
+        let hasher = self.config.hasher.as_ref().ok_or_else(|| {
+            Error::InvalidConfig("verify_entry requires a configured hasher".to_string())
+        })?;
+
+        let data = self.get(hash)?;
+        let actual = hasher.hash(&data);
+        if actual != *hash {
+            return Err(Error::HashMismatch {
+                expected: *hash,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Get value by height (O(1) with height index)
+    ///
+    /// Follows the canonical chain: if several blocks are stored at `height`,
+    /// the canonical one is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NotFound` if no block exists at the given height, and
+    /// `Error::Pruned` if its value was pruned.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// let genesis = db.get_by_height(0)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_by_height(&self, height: u64) -> Result<Vec<u8>> {
+        self.state.read().get_by_height(height)
+    }
+
+    /// Get the canonical block hash at a height
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NotFound` if no block exists at the given height.
+    pub fn get_hash_by_height(&self, height: u64) -> Result<Hash> {
+        self.state.read().get_hash_by_height(height)
+    }
+
+    /// Get the hashes of every stored block at a height, canonical or not
+    ///
+    /// Hashes are returned in the order the blocks were stored. The result
+    /// is empty if no block exists at the given height.
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error, or `Error::Corruption`, if the indexes cannot
+    /// be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// let tip = db.latest_height();
+    /// for hash in db.get_blocks_at_height(tip)? {
+    ///     let canonical = db.get_hash_by_height(tip)? == hash;
+    ///     println!("{:02x?} canonical={}", &hash[..4], canonical);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_blocks_at_height(&self, height: u64) -> Result<Vec<Hash>> {
+        self.state.read().get_blocks_at_height(height)
+    }
+
+    /// Check if hash exists
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error, or `Error::Corruption`, if the hash index cannot
+    /// be read; a block that is not stored is `Ok(false)`.
+    pub fn contains(&self, hash: &Hash) -> Result<bool> {
+        self.state.read().contains(hash)
+    }
+
+    /// Check if the canonical chain has a block at a height
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error if the height index cannot be read; a height
+    /// with no canonical block is `Ok(false)`.
+    pub fn contains_height(&self, height: u64) -> Result<bool> {
+        self.state.read().contains_height(height)
+    }
+
+    /// Get latest block height
+    pub fn latest_height(&self) -> u64 {
+        self.state.read().metadata.latest_height
+    }
+
+    /// Get latest block hash
+    pub fn latest_hash(&self) -> Hash {
+        self.state.read().metadata.latest_hash
+    }
+
+    /// Get genesis block hash
+    pub fn genesis_hash(&self) -> Hash {
+        self.state.read().metadata.genesis_hash
+    }
+
+    /// Get total entry count
+    pub fn entry_count(&self) -> u64 {
+        self.state.read().metadata.entry_count
+    }
+
+    /// Sync all files to disk
+    ///
+    /// The data and index files and the height journal are made durable
+    /// before the metadata is rewritten, so the metadata never describes
+    /// records that could still be lost in a crash. Writing the metadata
+    /// commits every height entry appended so far.
+    ///
+    /// The hash and height index changes since the last checkpoint are then
+    /// written to adzdb.key and adzdb.hgt, so the next open has nothing to
+    /// replay. Syncs made by `sync_on_write` only do so every few thousand
+    /// changes.
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.sync_and_checkpoint(true)
+    }
+
+    /// Commit everything appended so far and publish the committed
+    /// metadata, then checkpoint the key file if it is due, or with `force`
+    /// whenever anything changed
+    fn sync_and_checkpoint(&mut self, force: bool) -> Result<()> {
+        let mut metadata = self.state.read().metadata.clone();
+        self.commit(&mut metadata)?;
+        self.state.write().metadata = metadata;
+        self.checkpoint(force)
+    }
+
+    /// Write the hash and height index changes made since the last
+    /// checkpoint to the key file and the height file, once
+    /// `CHECKPOINT_INTERVAL` of them have piled up
+    ///
+    /// Must directly follow a commit, so the checkpoint describes exactly the
+    /// committed files. The pages are rewritten in place, so readers wait
+    /// until it is done, and it is skipped while a snapshot reads either
+    /// file.
+    ///
+    /// Once the journal holds `CHECKPOINT_INTERVAL` entries, the checkpoint
+    /// covers none of it and the journal is emptied afterwards: replaying it
+    /// over the new height file changes nothing, so a crash in between is
+    /// harmless.
+    fn checkpoint(&mut self, force: bool) -> Result<()> {
+        let journal_len = self.state.read().metadata.height_len;
+        let rebase = journal_len >= (CHECKPOINT_INTERVAL * HeightEntry::SIZE) as u64;
+        let pending = {
+            let state = self.state.read();
+            state.hash_index.pending_len() + state.height_index.pending_len()
+        };
+        if !rebase && (pending == 0 || (!force && pending < CHECKPOINT_INTERVAL)) {
+            return Ok(());
+        }
+
+        let mut guard = self.state.write();
+        let state = &mut *guard;
+        let mut checkpoint = Self::checkpoint_at(&self.index_file, &state.metadata)?;
+        if rebase {
+            checkpoint.height_len = 0;
+        }
+        let done = state
+            .hash_index
+            .checkpoint(&state.side_blocks, &mut state.height_index, checkpoint)?;
+        let mut metadata = state.metadata.clone();
+        drop(guard);
+
+        if done && rebase {
+            Self::empty_journal(&self.config.dir(), &mut self.height_log, &mut metadata)?;
+            self.commit(&mut metadata)?;
+            self.state.write().metadata = metadata;
+        }
+        Ok(())
+    }
+
+    /// Make every appended record durable, then commit them by writing
+    /// `metadata`, updated with the committed index and journal lengths
+    ///
+    /// The in-memory metadata is left alone, so callers publish a change to
+    /// readers only once it is committed.
+    fn commit(&mut self, metadata: &mut Metadata) -> Result<()> {
+        // Sync record files
+        self.state.read().segments.sync()?;
+        self.index_file.sync_all()?;
+        self.height_log.sync_all()?;
+        metadata.height_len = self.height_log.len()?;
+        metadata.index_len = self.index_file.len()?;
+
+        // Update metadata file
+        self.superblock.write(metadata)?;
+
+        Ok(())
+    }
+
+    /// Pick up blocks another process has written since the database was
+    /// opened or last refreshed
+    ///
+    /// For a read-only database that follows a writer in another process.
+    /// Only what the writer appended is read: the new index entries, checked
+    /// as on open, and the height entries its last sync committed. Blocks are
+    /// published to read handles in one step, like a write. A writable
+    /// database is always current, so this does nothing there.
+    ///
+    /// The writer empties the height journal every few thousand blocks; a
+    /// follower finishes the old journal through its open handle and reads
+    /// on from the new one, as long as it refreshes at least once per
+    /// journal. If the writer has replaced or shortened the files otherwise,
+    /// by compacting, rebuilding, pruning or cutting blocks off the tail, or
+    /// has emptied the journal more than once since, the database is
+    /// reopened instead, and the report says so. While a compaction is being
+    /// swapped in, nothing is read; try again later.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    /// use std::time::Duration;
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain").with_read_only(true);
+    /// let mut db = Database::open(config)?;
+    ///
+    /// let mut indexed = db.latest_height();
+    /// loop {
+    ///     db.refresh()?;
+    ///     while indexed < db.latest_height() {
+    ///         indexed += 1;
+    ///         let block = db.get_by_height(indexed)?;
+    ///         println!("Block {}: {} bytes", indexed, block.len());
+    ///     }
+    ///     std::thread::sleep(Duration::from_millis(500));
+    /// }
+    /// # }
+    /// ```
+    pub fn refresh(&mut self) -> Result<RefreshReport> {
+        let dir = self.config.dir();
+        if !self.config.read_only || compact::is_pending(&dir) {
+            return Ok(RefreshReport::default());
+        }
+
+        // What the writer has committed so far
+        let committed = self.superblock.read()?;
+
+        let state = self.state.read();
+        let index_pos = state.metadata.index_len;
+        let height_pos = state.metadata.height_len;
+        let generation = state.metadata.journal_generation;
+        let index_len = self.index_file.len()?;
+        // Once the writer has moved on to the next journal, the one open here
+        // is complete, and is read to its end before moving on too
+        let next_journal = committed.journal_generation == generation + 1;
+        let height_len = if next_journal {
+            self.height_log.len()?
+        } else {
+            self.height_log.len()?.min(committed.height_len)
+        };
+
+        // Records this view already covers must still be where they were
+        let mut rewritten = !self.index_file.is_same_file(&dir.join("adzdb.idx"))?
+            || !(next_journal || committed.journal_generation == generation)
+            || !state.segments.is_current()?
+            || index_len < index_pos
+            || height_len < height_pos;
+        let mut data_end = Position::default();
+        if let (false, Some(tail)) = (rewritten, self.index_tail) {
+            let mut current = [0u8; IndexEntry::SIZE];
+            read_exact_at(&self.index_file, &mut current, index_pos - IndexEntry::SIZE as u64)?;
+            data_end = IndexEntry::from_bytes(&tail).end();
+            rewritten = current != tail || !state.segments.covers(data_end)?;
+        }
+        let started = if rewritten { None } else { state.segments.open_started()? };
+        drop(state);
+
+        // No slice from `get_ref` outlives `&mut self`, and no mapping may
+        // outlive a segment cut back under it
+        #[cfg(feature = "mmap")]
+        if rewritten {
+            self.data_map.clear();
+        } else {
+            self.data_map.release();
+        }
+        if rewritten {
+            self.reopen()?;
+            let state = self.state.read();
+            return Ok(RefreshReport {
+                index_entries: state.metadata.index_len / IndexEntry::SIZE as u64,
+                height_entries: state.metadata.height_len / HeightEntry::SIZE as u64,
+                reopened: true,
+            });
+        }
+
+        // Segments the writer started since
+        if let Some(started) = started {
+            self.state.write().segments.extend(started);
+        }
+
+        // New index entries, up to the first one whose record is incomplete
+        // or belongs to an uncommitted batch
+        let segments = self.state.read().segments.clone();
+        let count = (index_len - index_pos) / IndexEntry::SIZE as u64;
+        let mut raw = vec![0u8; (count * IndexEntry::SIZE as u64) as usize];
+        read_exact_at(&self.index_file, &mut raw, index_pos)?;
+        let synced = committed.index_len / IndexEntry::SIZE as u64;
+        let mut entries = Vec::new();
+        let mut index_tail = self.index_tail;
+        for (i, chunk) in (index_pos / IndexEntry::SIZE as u64..).zip(raw.chunks_exact(IndexEntry::SIZE)) {
+            let chunk: &[u8; IndexEntry::SIZE] = chunk.try_into().unwrap();
+            let entry = IndexEntry::from_bytes(chunk);
+            if !data_end.is_followed_by(entry.position()) || !segments.contains(&entry)? {
+                break;
+            }
+            if i >= synced {
+                if entry.flags & IndexEntry::FLAG_BATCH != 0 {
+                    break;
+                }
+                match Self::read_value(&segments, &entry) {
+                    Ok(_) => {}
+                    Err(Error::Corruption(_)) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            data_end = entry.end();
+            index_tail = Some(*chunk);
+            entries.push(entry);
+        }
+
+        // Newly committed height entries
+        let mut heights = Self::read_height_entries(&self.height_log, height_pos, height_len)?;
+        let mut height_end = height_pos + (heights.len() * HeightEntry::SIZE) as u64;
+        let mut next_log = None;
+        if next_journal {
+            // Its length is taken before the metadata is read again, so a
+            // journal the writer has only just replaced once more, which stays
+            // empty until that commits, is never mistaken for this one. Until
+            // the writer commits an entry to it, the switch waits.
+            let log = dir.open_read(&dir.join(HEIGHT_LOG))?;
+            let len = log.len()?;
+            let current = self.superblock.read()?;
+            if current.journal_generation == committed.journal_generation
+                && current.height_len > 0
+                && len >= current.height_len
+            {
+                heights.extend(Self::read_height_entries(&log, 0, committed.height_len)?);
+                height_end = committed.height_len;
+                next_log = Some(log);
+            }
+        }
+
+        let report = RefreshReport {
+            index_entries: entries.len() as u64,
+            height_entries: heights.len() as u64,
+            reopened: false,
+        };
+        let journaled = synced.saturating_sub(index_pos / IndexEntry::SIZE as u64) as usize;
+        let mut guard = self.state.write();
+        guard.apply(&entries, journaled, &heights)?;
+        guard.metadata.index_len = index_pos + (entries.len() * IndexEntry::SIZE) as u64;
+        guard.metadata.height_len = height_end;
+        if let Some(log) = next_log {
+            guard.metadata.journal_generation += 1;
+            self.height_log = log;
+        }
+        drop(guard);
+        self.index_tail = index_tail;
+
+        Ok(report)
+    }
+
+    /// Get database statistics
+    pub fn stats(&self) -> DatabaseStats {
+        self.state.read().stats()
+    }
+
+    /// Get the repairs performed when the database was opened
+    ///
+    /// A clean shutdown yields an empty report. After a crash the report
+    /// lists the torn records that were discarded to restore consistency.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    /// Get the database path
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Iterate over all entries by height (ascending)
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// for height in 0..=db.latest_height() {
+    ///     if let Ok(data) = db.get_by_height(height) {
+    ///         println!("Block {}: {} bytes", height, data.len());
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter_heights(&self) -> Result<impl Iterator<Item = u64> + '_> {
+        Ok(self.state.read().heights()?.into_iter())
+    }
+
+    /// Get a handle for reading the database from other threads
+    ///
+    /// The handle is cheap to clone and can be sent to any number of
+    /// threads. It sees every change as soon as the writer makes it, and
+    /// keeps following the database across `compact` and `rebuild_indexes`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let mut db = Database::open_or_create(config)?;
+    ///
+    /// let reader = db.read_handle();
+    /// let verifier = std::thread::spawn(move || {
+    ///     for height in 0..=reader.latest_height() {
+    ///         if let Ok(block) = reader.get_by_height(height) {
+    ///             println!("Block {}: {} bytes", height, block.len());
+    ///         }
+    ///     }
+    /// });
+    ///
+    /// db.put(&[1u8; 32], 1, b"block 1")?;
+    /// verifier.join().unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_handle(&self) -> ReadHandle {
+        ReadHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Take a consistent read-only view of the database as it is now
+    ///
+    /// The snapshot keeps the entry count, the tip and the hash and height
+    /// indexes as they were when it was taken: blocks stored, deleted,
+    /// truncated or reorganized away afterwards don't change what it
+    /// returns, and it can still read them. Taking one is cheap, but every
+    /// write made while it is alive copies part of the in-memory indexes,
+    /// and the key file is not checkpointed until it is gone, so drop it
+    /// when done.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::{Database, Config};
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let config = Config::new("./blockchain");
+    /// let db = Database::open(config)?;
+    ///
+    /// // Serve a range of blocks from one consistent chain
+    /// let snapshot = db.snapshot();
+    /// let tip = snapshot.latest_height();
+    /// for height in tip.saturating_sub(10)..=tip {
+    ///     let block = snapshot.get_by_height(height)?;
+    ///     println!("Block {}: {} bytes", height, block.len());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.state.read().clone(),
+        }
+    }
+}
+
+/// A cloneable handle for reading a database from many threads
+///
+/// Created by [`Database::read_handle`]. Reads behave exactly like the
+/// matching `Database` methods and run concurrently with each other and with
+/// the single writer: each one sees the database either before or after a
+/// write, never part-way through it. The handle stays valid after the
+/// `Database` is dropped, but sees no further changes.
+#[derive(Clone)]
+pub struct ReadHandle {
+    state: Shared,
+}
+
+impl ReadHandle {
+    /// Get value by hash; see [`Database::get`]
+    pub fn get(&self, hash: &Hash) -> Result<Vec<u8>> {
+        self.state.read().get(hash)
+    }
+
+    /// Get value by height; see [`Database::get_by_height`]
+    pub fn get_by_height(&self, height: u64) -> Result<Vec<u8>> {
+        self.state.read().get_by_height(height)
+    }
+
+    /// Get the canonical block hash at a height
+    pub fn get_hash_by_height(&self, height: u64) -> Result<Hash> {
+        self.state.read().get_hash_by_height(height)
+    }
+
+    /// Get the hashes of every stored block at a height, canonical or not
+    pub fn get_blocks_at_height(&self, height: u64) -> Result<Vec<Hash>> {
+        self.state.read().get_blocks_at_height(height)
+    }
+
+    /// Check if hash exists
+    pub fn contains(&self, hash: &Hash) -> Result<bool> {
+        self.state.read().contains(hash)
+    }
+
+    /// Check if the canonical chain has a block at a height
+    pub fn contains_height(&self, height: u64) -> Result<bool> {
+        self.state.read().contains_height(height)
+    }
+
+    /// Get latest block height
+    pub fn latest_height(&self) -> u64 {
+        self.state.read().metadata.latest_height
+    }
+
+    /// Get latest block hash
+    pub fn latest_hash(&self) -> Hash {
+        self.state.read().metadata.latest_hash
+    }
+
+    /// Get genesis block hash
+    pub fn genesis_hash(&self) -> Hash {
+        self.state.read().metadata.genesis_hash
+    }
+
+    /// Get total entry count
+    pub fn entry_count(&self) -> u64 {
+        self.state.read().metadata.entry_count
+    }
+
+    /// Get database statistics
+    pub fn stats(&self) -> DatabaseStats {
+        self.state.read().stats()
+    }
+
+    /// Take a consistent read-only view; see [`Database::snapshot`]
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.state.read().clone(),
+        }
+    }
+}
+
+/// A consistent read-only view of a database at one moment
+///
+/// Created by [`Database::snapshot`] or [`ReadHandle::snapshot`]. Every read
+/// sees the database exactly as it was when the snapshot was taken, however
+/// many blocks the writer has stored, removed or reorganized since. A
+/// snapshot outlives compaction too: it keeps reading the data segments it
+/// was taken from.
+#[derive(Clone)]
+pub struct Snapshot {
+    state: State,
+}
+
+impl Snapshot {
+    /// Get value by hash; see [`Database::get`]
+    pub fn get(&self, hash: &Hash) -> Result<Vec<u8>> {
+        self.state.get(hash)
+    }
+
+    /// Get value by height; see [`Database::get_by_height`]
+    pub fn get_by_height(&self, height: u64) -> Result<Vec<u8>> {
+        self.state.get_by_height(height)
+    }
+
+    /// Get the canonical block hash at a height
+    pub fn get_hash_by_height(&self, height: u64) -> Result<Hash> {
+        self.state.get_hash_by_height(height)
+    }
+
+    /// Get the hashes of every stored block at a height, canonical or not
+    pub fn get_blocks_at_height(&self, height: u64) -> Result<Vec<Hash>> {
+        self.state.get_blocks_at_height(height)
+    }
+
+    /// Check if hash exists
+    pub fn contains(&self, hash: &Hash) -> Result<bool> {
+        self.state.contains(hash)
+    }
+
+    /// Check if the canonical chain has a block at a height
+    pub fn contains_height(&self, height: u64) -> Result<bool> {
+        self.state.contains_height(height)
+    }
+
+    /// Get latest block height
+    pub fn latest_height(&self) -> u64 {
+        self.state.metadata.latest_height
+    }
+
+    /// Get latest block hash
+    pub fn latest_hash(&self) -> Hash {
+        self.state.metadata.latest_hash
+    }
+
+    /// Get genesis block hash
+    pub fn genesis_hash(&self) -> Hash {
+        self.state.metadata.genesis_hash
+    }
+
+    /// Get total entry count
+    pub fn entry_count(&self) -> u64 {
+        self.state.metadata.entry_count
+    }
+
+    /// Get database statistics
+    pub fn stats(&self) -> DatabaseStats {
+        self.state.stats()
+    }
+
+    /// Iterate over canonical heights (ascending)
+    pub fn iter_heights(&self) -> Result<impl Iterator<Item = u64>> {
+        Ok(self.state.heights()?.into_iter())
+    }
+}
+
+/// The part of a database that readers see: the data segments, the indexes
+/// and the metadata describing them
+///
+/// Cloning it is cheap and yields a snapshot that later writes don't touch.
+#[derive(Clone)]
+struct State {
+    /// Data segments (append-only), read with positioned reads; snapshots
+    /// keep them open, so records are never cut off their tail while any
+    /// exist
+    segments: Segments,
+    /// Hash index: the key file, plus the changes since its checkpoint
+    hash_index: HashIndex,
+    /// Height index: the height file, plus the changes since its checkpoint
+    height_index: HeightIndex,
+    /// Stored blocks that are not canonical at their height
+    side_blocks: CowMap<u64, Vec<Hash>>,
+    /// Current metadata
+    metadata: Metadata,
+}
+
+impl State {
+    /// Indexes and metadata rebuilt by replaying index and height entries
+    /// from scratch, without a key file or a height file
+    fn replay(segments: Segments, entries: &[IndexEntry], heights: &[HeightEntry]) -> Result<Self> {
+        let mut state = Self {
+            segments,
+            hash_index: HashIndex::new(None),
+            height_index: HeightIndex::new(None),
+            side_blocks: CowMap::new(),
+            metadata: Metadata::default(),
+        };
+        state.apply(entries, 0, heights)?;
+        Ok(state)
+    }
+
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>> {
+        let entry = self.hash_index.get(hash)?.ok_or(Error::NotFound)?;
+        if entry.is_pruned() {
+            return Err(Error::Pruned);
+        }
+        compress::decode(&entry, Database::read_value(&self.segments, &entry)?)
+    }
+
+    fn get_by_height(&self, height: u64) -> Result<Vec<u8>> {
+        let hash = self.height_index.get(height)?.ok_or(Error::NotFound)?;
+        self.get(&hash)
+    }
+
+    fn get_hash_by_height(&self, height: u64) -> Result<Hash> {
+        self.height_index.get(height)?.ok_or(Error::NotFound)
+    }
+
+    /// Every stored block at heights `from..to`, in the order a rebuild from
+    /// the data segments must find them: by height, the canonical block
+    /// first
+    fn blocks_between(&self, from: u64, to: u64) -> Result<Vec<IndexEntry>> {
+        let mut blocks = Vec::new();
+        for (height, hash) in self.height_index.canonical_between(from, to)? {
+            blocks.extend(self.hash_index.get(&hash)?.map(|entry| (height, false, entry)));
+        }
+        for (&height, hashes) in self.side_blocks.iter().filter(|(height, _)| (from..to).contains(*height)) {
+            for hash in hashes {
+                blocks.extend(self.hash_index.get(hash)?.map(|entry| (height, true, entry)));
+            }
+        }
+        blocks.sort_by_key(|&(height, side, entry)| (height, side, entry.position()));
+        Ok(blocks.into_iter().map(|(_, _, entry)| entry).collect())
+    }
+
+    /// One past the highest height holding a block
+    fn height_end(&self) -> Result<u64> {
+        let canonical = self.height_index.last()?.map_or(0, |(height, _)| height + 1);
+        let side = self.side_blocks.iter().map(|(&height, _)| height + 1).max();
+        Ok(canonical.max(side.unwrap_or(0)))
+    }
+
+    /// Every stored block at a height, in the order they were stored
+    fn get_blocks_at_height(&self, height: u64) -> Result<Vec<Hash>> {
+        let canonical = self.height_index.get(height)?;
+        let mut blocks = Vec::new();
+        for hash in canonical.iter().chain(self.side_blocks.get(&height).into_iter().flatten()) {
+            blocks.extend(self.hash_index.get(hash)?);
+        }
+        blocks.sort_by_key(IndexEntry::position);
+        Ok(blocks.into_iter().map(|entry| entry.key).collect())
+    }
+
+    fn contains(&self, hash: &Hash) -> Result<bool> {
+        self.hash_index.contains(hash)
+    }
+
+    fn contains_height(&self, height: u64) -> Result<bool> {
+        self.height_index.contains(height)
+    }
+
+    /// Canonical heights in ascending order
+    fn heights(&self) -> Result<Vec<u64>> {
+        let canonical = self.height_index.canonical_from(0)?;
+        Ok(canonical.into_iter().map(|(height, _)| height).collect())
+    }
+
+    /// Make a stored block canonical at its height; the block it replaces
+    /// becomes a side block
+    fn set_canonical(&mut self, height: u64, hash: Hash) -> Result<()> {
+        remove_side_block(&mut self.side_blocks, height, &hash);
+        let previous = self.height_index.get(height)?;
+        self.height_index.insert(height, hash);
+        if let Some(previous) = previous.filter(|&previous| previous != hash) {
+            self.side_blocks.get_or_default(height).push(previous);
+        }
+        Ok(())
+    }
+
+    /// Clear a canonical height, keeping its block as a side block
+    fn clear_canonical(&mut self, height: u64) -> Result<()> {
+        if let Some(previous) = self.height_index.get(height)? {
+            self.height_index.remove(height);
+            self.side_blocks.get_or_default(height).push(previous);
+        }
+        Ok(())
+    }
+
+    /// Apply index entries and then height entries, each in the order they
+    /// were appended, and return the heights they touched
+    ///
+    /// The first `journaled` index entries were committed along with the
+    /// height entries recording which of them are canonical, or which a
+    /// checkpoint has since written to the height file. Past those, a new
+    /// block at a height with no canonical block becomes canonical, as `put`
+    /// made it. The height entries then settle the chain. A height entry
+    /// naming a block that is no longer stored clears its height.
+    fn apply(&mut self, entries: &[IndexEntry], journaled: usize, heights: &[HeightEntry]) -> Result<HashSet<u64>> {
+        let mut touched = HashSet::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.is_tombstone() {
+                if let Some(removed) = self.hash_index.get(&entry.key)? {
+                    self.hash_index.remove(&entry.key);
+                    self.metadata.entry_count -= 1;
+                    self.metadata.data_size -= removed.size as u64;
+                    self.metadata.logical_size -= removed.logical_size();
+                    if self.height_index.get(removed.height)? == Some(removed.key) {
+                        self.height_index.remove(removed.height);
+                        touched.insert(removed.height);
+                    } else {
+                        remove_side_block(&mut self.side_blocks, removed.height, &removed.key);
+                    }
+                }
+                continue;
+            }
+            if let Some(stored) = self.hash_index.get(&entry.key)? {
+                // A pruned entry takes the place of the one holding the value
+                if entry.is_pruned() {
+                    self.hash_index.insert(*entry);
+                    self.metadata.data_size -= stored.size as u64;
+                    self.metadata.logical_size -= stored.logical_size();
+                }
+                continue;
+            }
+            self.hash_index.insert(*entry);
+            self.metadata.entry_count += 1;
+            self.metadata.data_size += entry.size as u64;
+            self.metadata.logical_size += entry.logical_size();
+            match self.height_index.get(entry.height)? {
+                // A height file written after the key file can already hold it
+                Some(hash) if hash == entry.key => {}
+                Some(_) => self.side_blocks.get_or_default(entry.height).push(entry.key),
+                None if i < journaled => self.side_blocks.get_or_default(entry.height).push(entry.key),
+                None => {
+                    self.height_index.insert(entry.height, entry.key);
+                    touched.insert(entry.height);
+                }
+            }
+        }
+        for entry in heights {
+            let height = entry.target_height();
+            touched.insert(height);
+            let stored = !entry.is_cleared()
+                && self.hash_index.get(&entry.hash)?.map(|block| block.height) == Some(entry.height);
+            if stored {
+                self.set_canonical(height, entry.hash)?;
+            } else {
+                self.clear_canonical(height)?;
+            }
+        }
+
+        // The tip only needs a full scan if it was cleared or replaced
+        let latest = (self.metadata.latest_height, self.metadata.latest_hash);
+        let tip = if self.height_index.get(latest.0)? == Some(latest.1) {
+            let mut tip = latest;
+            for &height in touched.iter().filter(|&&height| height > latest.0) {
+                match self.height_index.get(height)? {
+                    Some(hash) if height > tip.0 => tip = (height, hash),
+                    _ => {}
+                }
+            }
+            Some(tip)
+        } else {
+            self.height_index.last()?
+        };
+        let (latest_height, latest_hash) = tip.unwrap_or((0, ZERO_HASH));
+        self.metadata.latest_height = latest_height;
+        self.metadata.latest_hash = latest_hash;
+        self.metadata.genesis_hash = self.height_index.get(0)?.unwrap_or(ZERO_HASH);
+
+        Ok(touched)
+    }
+
+    fn stats(&self) -> DatabaseStats {
+        DatabaseStats {
+            entry_count: self.metadata.entry_count,
+            data_size: self.metadata.data_size,
+            logical_size: self.metadata.logical_size,
+            latest_height: self.metadata.latest_height,
+            latest_hash: self.metadata.latest_hash,
+            genesis_hash: self.metadata.genesis_hash,
+        }
+    }
+}
+
+/// `State` behind a lock shared by the writer and its read handles
+///
+/// The writer does its file I/O under the read lock and takes the write
+/// lock only to publish a finished change, so readers wait only for
+/// in-memory updates. Lock poisoning is ignored: the writer publishes no
+/// I/O results it has not already committed.
+#[derive(Clone)]
+struct Shared(Arc<RwLock<State>>);
+
+impl Shared {
+    fn new(state: State) -> Self {
+        Self(Arc::new(RwLock::new(state)))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Remove a block from the side blocks, dropping emptied heights
+fn remove_side_block(side_blocks: &mut CowMap<u64, Vec<Hash>>, height: u64, hash: &Hash) {
+    if let Some(hashes) = side_blocks.get_mut(&height) {
+        hashes.retain(|side| side != hash);
+        if hashes.is_empty() {
+            side_blocks.remove(&height);
+        }
+    }
+}
+
+/// Read exactly `buf.len()` bytes at `offset` without using the file cursor
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.read_exact_at(buf, offset)
+}
+
+/// Format a hash as lowercase hex for error messages
+fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Database statistics
+#[derive(Debug, Clone)]
+pub struct DatabaseStats {
+    /// Total number of entries
+    pub entry_count: u64,
+    /// Bytes the live values take up as stored, after compression
+    pub data_size: u64,
+    /// Bytes the live values take up as `get` returns them
+    pub logical_size: u64,
+    /// Latest block height
+    pub latest_height: u64,
+    /// Latest block hash
+    pub latest_hash: Hash,
+    /// Genesis block hash
+    pub genesis_hash: Hash,
+}
+
+/// What `Database::refresh` picked up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    /// Index entries read: new blocks and tombstones
+    pub index_entries: u64,
+    /// Committed height entries read
+    pub height_entries: u64,
+    /// The files had been rewritten, so the database was reopened and the
+    /// counts cover everything read
+    pub reopened: bool,
+}
+
+/// Outcome of `Database::prune_below`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Blocks whose values were pruned by this call
+    pub blocks_pruned: u64,
+    /// Data segments deleted
+    pub segments_removed: u64,
+    /// Total size of the deleted segments in bytes
+    pub bytes_reclaimed: u64,
+}
+
+/// Repairs performed by `Database::open` after an unclean shutdown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Trailing bytes that did not form a complete index or height record
+    pub torn_bytes: u64,
+    /// Complete index entries discarded because their data record was incomplete
+    pub index_entries_dropped: u64,
+    /// Complete height entries discarded because their block did not survive
+    pub height_entries_dropped: u64,
+    /// Canonical height entries re-appended because the crash lost them
+    pub height_entries_restored: u64,
+    /// Bytes removed from the end of the data segments
+    pub data_bytes_dropped: u64,
+    /// Whether the stored metadata disagreed with the files and was rewritten
+    pub metadata_repaired: bool,
+}
+
+impl RecoveryReport {
+    /// Returns true if no repair was necessary
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::VerifyOptions;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn test_index_entry_roundtrip() {
+        let entry = IndexEntry {
+            key: [1u8; 32],
+            offset: 12345,
+            size: 1000,
+            height: 42,
+            flags: IndexEntry::FLAG_LZ4,
+            segment: 3,
+            uncompressed_size: 4000,
+        };
+
+        let bytes = entry.to_bytes();
+        let recovered = IndexEntry::from_bytes(&bytes);
+
+        assert_eq!(entry.key, recovered.key);
+        assert_eq!(entry.offset, recovered.offset);
+        assert_eq!(entry.size, recovered.size);
+        assert_eq!(entry.height, recovered.height);
+        assert_eq!(entry.segment, recovered.segment);
+        assert_eq!(recovered.logical_size(), 4000);
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let meta = Metadata {
+            magic: *MAGIC,
+            version: VERSION,
+            entry_count: 100,
+            data_size: 50000,
+            latest_height: 42,
+            latest_hash: [1u8; 32],
+            genesis_hash: [2u8; 32],
+            height_len: 400,
+            index_len: 5600,
+            logical_size: 80000,
+            journal_generation: 3,
+        };
+
+        let bytes = meta.to_bytes();
+        let recovered = Metadata::from_bytes(&bytes).unwrap();
+
+        assert_eq!(meta.entry_count, recovered.entry_count);
+        assert_eq!(meta.latest_height, recovered.latest_height);
+        assert_eq!(meta.height_len, recovered.height_len);
+        assert_eq!(meta.index_len, recovered.index_len);
+        assert_eq!(meta.logical_size, recovered.logical_size);
+        assert_eq!(meta.journal_generation, recovered.journal_generation);
+    }
+
+    #[test]
+    fn test_database_create_and_put() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-create");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+
+        let hash = [42u8; 32];
+        let data = b"test block data";
+
+        db.put(&hash, 0, data).unwrap();
+
+        assert!(db.contains(&hash).unwrap());
+        assert_eq!(db.entry_count(), 1);
+
+        let retrieved = db.get(&hash).unwrap();
+        assert_eq!(retrieved, data);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_database_height_index() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-height");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+
+        // Add blocks at different heights
+        let hash0 = [0u8; 32];
+        let hash1 = [1u8; 32];
+        let hash2 = [2u8; 32];
+
+        db.put(&hash0, 0, b"genesis").unwrap();
+        db.put(&hash1, 1, b"block 1").unwrap();
+        db.put(&hash2, 2, b"block 2").unwrap();
+
+        // Retrieve by height
+        assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2");
+
+        // Get hash by height
+        assert_eq!(db.get_hash_by_height(0).unwrap(), hash0);
+        assert_eq!(db.get_hash_by_height(1).unwrap(), hash1);
+        assert_eq!(db.get_hash_by_height(2).unwrap(), hash2);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_corruption_detection() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-corrupt");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+
+        let hash = [42u8; 32];
+
+        // Try to insert with impossibly high height
+        let result = db.put(&hash, MAX_REASONABLE_HEIGHT + 1, b"corrupt");
+        assert!(matches!(result, Err(Error::HeightTooLarge(_))));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_database_reopen() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reopen");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+
+        // Create and populate
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+            db.sync().unwrap();
+        }
+
+        // Reopen and verify
+        {
+            let db = Database::open(config).unwrap();
+            assert_eq!(db.entry_count(), 2);
+            assert_eq!(db.latest_height(), 1);
+            assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
+            assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+        }
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-dedup");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+
+        let hash = [42u8; 32];
+
+        // Insert same hash twice
+        db.put(&hash, 0, b"first").unwrap();
+        db.put(&hash, 0, b"second").unwrap(); // Should be no-op
+
+        // Should still have only one entry with original data
+        assert_eq!(db.entry_count(), 1);
+        assert_eq!(db.get(&hash).unwrap(), b"first");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_clean_shutdown() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-clean");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+        }
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 2);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_torn_tails() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-torn");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+            db.put(&[3u8; 32], 2, b"block 2").unwrap();
+        }
+
+        // Simulate a crash mid-way through the last put: the height entry
+        // never landed and the index entry was only partially written
+        let index_path = temp_dir.join("adzdb.idx");
+        let height_path = temp_dir.join(HEIGHT_LOG);
+        let index_len = fs::metadata(&index_path).unwrap().len();
+        let height_len = fs::metadata(&height_path).unwrap().len();
+        OpenOptions::new().write(true).open(&index_path).unwrap()
+            .set_len(index_len - 10).unwrap();
+        OpenOptions::new().write(true).open(&height_path).unwrap()
+            .set_len(height_len - HeightEntry::SIZE as u64).unwrap();
+
+        {
+            let db = Database::open(config.clone()).unwrap();
+            let report = db.recovery_report();
+            assert_eq!(report.torn_bytes, (IndexEntry::SIZE - 10) as u64);
+            assert_eq!(report.data_bytes_dropped, (RecordHeader::SIZE + 7) as u64);
+            assert!(report.metadata_repaired);
+
+            assert_eq!(db.entry_count(), 2);
+            assert_eq!(db.latest_height(), 1);
+            assert_eq!(db.latest_hash(), [2u8; 32]);
+            assert!(!db.contains(&[3u8; 32]).unwrap());
+            assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+        }
+
+        // The repair is persisted
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 2);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_index_past_data_end() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-data");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+        }
+
+        // The last value never made it to the data file
+        let data_path = temp_dir.join(segments::segment_name(0));
+        let first_record = (RecordHeader::SIZE + 7) as u64;
+        OpenOptions::new().write(true).open(&data_path).unwrap()
+            .set_len(first_record + 20).unwrap();
+
+        let mut db = Database::open(config).unwrap();
+        let report = db.recovery_report().clone();
+        assert_eq!(report.index_entries_dropped, 1);
+        assert_eq!(report.height_entries_dropped, 1);
+        assert_eq!(report.data_bytes_dropped, 20);
+        assert_eq!(db.entry_count(), 1);
+        assert_eq!(db.stats().data_size, 7);
+
+        // Appending continues from the repaired tail
+        db.put(&[2u8; 32], 1, b"block 1").unwrap();
+        assert_eq!(db.get(&[2u8; 32]).unwrap(), b"block 1");
+        assert_eq!(db.get(&[1u8; 32]).unwrap(), b"genesis");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_rejects_inconsistent_records() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-corrupt");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+        }
+
+        // Overwrite the first height entry with a hash that is not indexed
+        let mut height_file = OpenOptions::new()
+            .write(true)
+            .open(temp_dir.join(HEIGHT_LOG))
+            .unwrap();
+        let bogus = HeightEntry { height: 0, hash: [9u8; 32] };
+        height_file.write_all(&bogus.to_bytes()).unwrap();
+
+        let result = Database::open(config);
+        assert!(matches!(result, Err(Error::Corruption(_))));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_checksum_detects_bit_rot() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-checksum");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let hash = [7u8; 32];
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&hash, 1, b"block 1").unwrap();
+        }
+
+        // Flip a bit inside the second value
+        let data_path = temp_dir.join(segments::segment_name(0));
+        let mut bytes = fs::read(&data_path).unwrap();
+        let offset = RecordHeader::SIZE + 7;
+        bytes[offset + RecordHeader::SIZE + 2] ^= 0x04;
+        fs::write(&data_path, &bytes).unwrap();
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.get(&[1u8; 32]).unwrap(), b"genesis");
+        match db.get(&hash) {
+            Err(Error::Corruption(msg)) => {
+                assert!(msg.contains(&to_hex(&hash)));
+                assert!(msg.contains(&format!("offset {}", offset)));
+            }
+            other => panic!("expected corruption, got {:?}", other),
+        }
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_drops_unsynced_bad_checksum() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-checksum");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir).with_sync_on_write(false);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+            db.sync().unwrap();
+            db.put(&[3u8; 32], 2, b"block 2").unwrap();
+        }
+
+        // The unsynced value reached the file length but not its contents
+        let data_path = temp_dir.join(segments::segment_name(0));
+        let mut bytes = fs::read(&data_path).unwrap();
+        let len = bytes.len();
+        bytes[len - 7..].fill(0);
+        fs::write(&data_path, &bytes).unwrap();
+
+        let db = Database::open(config).unwrap();
+        let report = db.recovery_report();
+        assert_eq!(report.index_entries_dropped, 1);
+        assert_eq!(report.height_entries_dropped, 1);
+        assert_eq!(report.data_bytes_dropped, (RecordHeader::SIZE + 7) as u64);
+        assert_eq!(db.entry_count(), 2);
+        assert!(!db.contains(&[3u8; 32]).unwrap());
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_rebuild_from_data_file() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-rebuild");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let expected = {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+            db.put(&[3u8; 32], 2, b"block 2").unwrap();
+            // The rebuilt heights go straight to adzdb.hgt, leaving a new,
+            // empty journal
+            let mut metadata = db.state.read().metadata.clone();
+            metadata.height_len = 0;
+            metadata.journal_generation += 1;
+            metadata.to_bytes()
+        };
+
+        // Lose everything except the data file
+        fs::remove_file(temp_dir.join("adzdb.idx")).unwrap();
+        fs::remove_file(temp_dir.join(heights::HEIGHT_FILE)).unwrap();
+        fs::remove_file(temp_dir.join(HEIGHT_LOG)).unwrap();
+        fs::remove_file(temp_dir.join("adzdb.meta")).unwrap();
+
+        let db = Database::rebuild(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.state.read().metadata.to_bytes(), expected);
+        assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2");
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [2u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_rebuild_indexes_discards_torn_tail() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-rebuild-torn");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+        db.put(&[1u8; 32], 0, b"genesis").unwrap();
+        db.put(&[2u8; 32], 1, b"block 1").unwrap();
+
+        // Half of a record header reaches the data file
+        let header = RecordHeader::new(&[3u8; 32], 2, b"block 2");
+        let mut data_file = OpenOptions::new()
+            .append(true)
+            .open(temp_dir.join(segments::segment_name(0)))
+            .unwrap();
+        data_file.write_all(&header.to_bytes()[..30]).unwrap();
+
+        db.rebuild_indexes().unwrap();
+        assert_eq!(db.entry_count(), 2);
+        assert_eq!(db.latest_height(), 1);
+
+        db.put(&[3u8; 32], 2, b"block 2").unwrap();
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_hasher_rejects_mismatch() {
+        use hasher::Sha256;
+
+        let temp_dir = std::env::temp_dir().join("adzdb-test-hasher");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir).with_hasher(Sha256);
+        let mut db = Database::create(config).unwrap();
+
+        let data = b"genesis";
+        let hash = Sha256.hash(data);
+        db.put(&hash, 0, data).unwrap();
+        db.verify_entry(&hash).unwrap();
+
+        let wrong = Sha256.hash(b"block 2");
+        let result = db.put(&wrong, 1, b"block 1");
+        assert!(matches!(
+            result,
+            Err(Error::HashMismatch { expected, actual })
+                if expected == wrong && actual == Sha256.hash(b"block 1")
+        ));
+        assert!(!db.contains(&wrong).unwrap());
+        assert_eq!(db.entry_count(), 1);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_reserved_hash_is_rejected() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reserved-hash");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+
+        // It would be stored as an empty height and drop out of the chain
+        assert!(matches!(db.put(&RESERVED_HASH, 1, b"block 1"), Err(Error::ReservedHash)));
+        let mut batch = WriteBatch::new();
+        batch.put(&[1u8; 32], 1, b"block 1");
+        batch.put(&RESERVED_HASH, 2, b"block 2");
+        assert!(matches!(db.write(batch), Err(Error::ReservedHash)));
+        assert!(!db.contains(&RESERVED_HASH).unwrap());
+        db.sync().unwrap();
+        drop(db);
+
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.entry_count(), 1);
+        assert_eq!(db.latest_height(), 0);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_verify_entry_detects_wrong_key() {
+        use hasher::DoubleSha256;
+
+        let temp_dir = std::env::temp_dir().join("adzdb-test-verify-entry");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        // Stored without a hasher, so the key was never checked
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            assert!(matches!(db.verify_entry(&[1u8; 32]), Err(Error::InvalidConfig(_))));
+        }
+
+        let db = Database::open(config.with_hasher(DoubleSha256)).unwrap();
+        assert!(matches!(
+            db.verify_entry(&[1u8; 32]),
+            Err(Error::HashMismatch { .. })
+        ));
+        assert!(matches!(db.verify_entry(&[2u8; 32]), Err(Error::NotFound)));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_fork_blocks_at_same_height() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-forks");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1a").unwrap();
+            db.put(&[3u8; 32], 1, b"block 1b").unwrap();
+
+            // The competing block is stored but does not replace the first
+            assert_eq!(db.entry_count(), 3);
+            assert_eq!(db.get(&[3u8; 32]).unwrap(), b"block 1b");
+            assert_eq!(db.get_by_height(1).unwrap(), b"block 1a");
+            assert_eq!(db.latest_hash(), [2u8; 32]);
+            assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[2u8; 32], [3u8; 32]]);
+            assert!(db.get_blocks_at_height(2).unwrap().is_empty());
+        }
+
+        // The canonical chain survives a reopen rather than the last write
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [2u8; 32]);
+        assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[2u8; 32], [3u8; 32]]);
+        assert_eq!(db.latest_height(), 1);
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_restores_lost_height_entry() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-recover-height");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[1u8; 32], 0, b"genesis").unwrap();
+            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+        }
+
+        // Crash after the index entry landed but before the height entry
+        let height_path = temp_dir.join(HEIGHT_LOG);
+        OpenOptions::new().write(true).open(&height_path).unwrap()
+            .set_len(HeightEntry::SIZE as u64).unwrap();
+
+        let db = Database::open(config).unwrap();
+        let report = db.recovery_report();
+        assert_eq!(report.height_entries_restored, 1);
+        assert_eq!(report.index_entries_dropped, 0);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_reorg_switches_branch() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reorg");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1a").unwrap();
+            db.put(&[2u8; 32], 2, b"block 2a").unwrap();
+            db.put(&[3u8; 32], 3, b"block 3a").unwrap();
+            db.put(&[11u8; 32], 1, b"block 1b").unwrap();
+            db.put(&[12u8; 32], 2, b"block 2b").unwrap();
+
+            // A shorter branch replaces the old tip
+            db.reorg(0, &[[11u8; 32], [12u8; 32]]).unwrap();
+            assert_eq!(db.get_by_height(1).unwrap(), b"block 1b");
+            assert_eq!(db.get_by_height(2).unwrap(), b"block 2b");
+            assert!(!db.contains_height(3).unwrap());
+            assert_eq!(db.latest_height(), 2);
+            assert_eq!(db.latest_hash(), [12u8; 32]);
+
+            // Old branch blocks remain readable by hash
+            assert_eq!(db.get(&[3u8; 32]).unwrap(), b"block 3a");
+        }
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [11u8; 32]);
+        assert_eq!(db.get_hash_by_height(2).unwrap(), [12u8; 32]);
+        assert!(!db.contains_height(3).unwrap());
+        assert_eq!(db.latest_height(), 2);
+        assert_eq!(db.latest_hash(), [12u8; 32]);
+        assert_eq!(db.iter_heights().unwrap().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_reorg_replaces_tip_at_same_height() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reorg-tip");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1a").unwrap();
+        db.put(&[2u8; 32], 1, b"block 1b").unwrap();
+
+        db.reorg(0, &[[2u8; 32]]).unwrap();
+        assert_eq!(db.latest_height(), 1);
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1b");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_reorg_rejects_invalid_branch() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reorg-invalid");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1").unwrap();
+        db.put(&[2u8; 32], 2, b"block 2").unwrap();
+
+        assert!(matches!(db.reorg(0, &[]), Err(Error::InvalidReorg(_))));
+        assert!(matches!(db.reorg(5, &[[2u8; 32]]), Err(Error::InvalidReorg(_))));
+        assert!(matches!(db.reorg(0, &[[2u8; 32]]), Err(Error::InvalidReorg(_))));
+        assert!(matches!(db.reorg(0, &[[9u8; 32]]), Err(Error::NotFound)));
+
+        assert_eq!(db.latest_height(), 2);
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [1u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_reorg_interrupted_before_commit() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-reorg-crash");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1a").unwrap();
+            db.put(&[2u8; 32], 2, b"block 2a").unwrap();
+            db.put(&[11u8; 32], 1, b"block 1b").unwrap();
+            db.put(&[12u8; 32], 2, b"block 2b").unwrap();
+        }
+
+        // Half of a reorg's height entries land, but the metadata never does
+        let mut height_file = OpenOptions::new()
+            .append(true)
+            .open(temp_dir.join(HEIGHT_LOG))
+            .unwrap();
+        let entry = HeightEntry { height: 1, hash: [11u8; 32] };
+        height_file.write_all(&entry.to_bytes()).unwrap();
+
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.recovery_report().height_entries_dropped, 1);
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [1u8; 32]);
+        assert_eq!(db.get_hash_by_height(2).unwrap(), [2u8; 32]);
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_truncate_to_height_reclaims_tail() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-truncate");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            for i in 0..5u8 {
+                db.put(&[i; 32], i as u64, format!("block {}", i).as_bytes()).unwrap();
+            }
+
+            // Keeping everything up to the highest height keeps everything
+            assert!(db.truncate_to_height(u64::MAX).unwrap().is_empty());
+            assert_eq!(db.entry_count(), 5);
+            assert_eq!(db.latest_height(), 4);
+
+            let removed = db.truncate_to_height(1).unwrap();
+            assert_eq!(removed, vec![[2u8; 32], [3u8; 32], [4u8; 32]]);
+            assert_eq!(db.latest_height(), 1);
+            assert_eq!(db.latest_hash(), [1u8; 32]);
+            assert_eq!(db.entry_count(), 2);
+            assert!(!db.contains(&[3u8; 32]).unwrap());
+            assert!(!db.contains_height(2).unwrap());
+            assert!(db.truncate_to_height(1).unwrap().is_empty());
+        }
+
+        // The removed records were the last ones appended, so they are gone
+        let record = (RecordHeader::SIZE + 7) as u64;
+        assert_eq!(fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len(), 2 * record);
+        assert_eq!(fs::metadata(temp_dir.join("adzdb.idx")).unwrap().len(), 2 * IndexEntry::SIZE as u64);
+
+        let mut db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.latest_height(), 1);
+
+        // The chain grows again from the new tip
+        db.put(&[12u8; 32], 2, b"block 2'").unwrap();
+        assert_eq!(db.get_by_height(2).unwrap(), b"block 2'");
+        assert_eq!(db.latest_hash(), [12u8; 32]);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_segments_roll_over() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-segments");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        // Values padded so three records make the smallest segment allowed
+        let block = |i: u8| {
+            let mut value = format!("block {}", i).into_bytes();
+            value.resize(1400, b' ');
+            value
+        };
+        let record = (RecordHeader::SIZE + 1400) as u64;
+        let config = Config::new(&temp_dir).with_segment_size(3 * record);
+        let segment_len = |segment| fs::metadata(temp_dir.join(segments::segment_name(segment))).map(|m| m.len()).ok();
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            for i in 0..7u8 {
+                db.put(&[i; 32], i as u64, &block(i)).unwrap();
+            }
+
+            // Three records fill a segment; the next one starts another
+            assert_eq!(segment_len(0), Some(3 * record));
+            assert_eq!(segment_len(1), Some(3 * record));
+            assert_eq!(segment_len(2), Some(record));
+            assert_eq!(segment_len(3), None);
+
+            let mut batch = WriteBatch::new();
+            for i in 7..10u8 {
+                batch.put(&[i; 32], i as u64, &block(i));
+            }
+            db.write(batch).unwrap();
+            assert_eq!(segment_len(2), Some(3 * record));
+            assert_eq!(segment_len(3), Some(record));
+            assert_eq!(db.get_by_height(8).unwrap(), block(8));
+
+            // Blocks in sealed segments get tombstones rather than being cut
+            db.truncate_to_height(5).unwrap();
+            assert_eq!(segment_len(2), Some(3 * record));
+            assert_eq!(db.latest_height(), 5);
+        }
+
+        // A segment started by a write that never committed is discarded
+        let next = (0..).find(|&segment| segment_len(segment).is_none()).unwrap();
+        fs::write(temp_dir.join(segments::segment_name(next)), b"torn").unwrap();
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            assert_eq!(db.recovery_report().data_bytes_dropped, 4);
+            assert_eq!(segment_len(next), None);
+            assert_eq!(db.get_by_height(4).unwrap(), block(4));
+            assert!(!db.contains(&[9u8; 32]).unwrap());
+
+            db.put(&[16u8; 32], 6, b"block 6'").unwrap();
+            assert_eq!(db.get_by_height(6).unwrap(), b"block 6'");
+        }
+
+        // Rebuilding reads every segment in turn
+        let db = Database::rebuild(config).unwrap();
+        assert_eq!(db.entry_count(), 7);
+        assert_eq!(db.get_by_height(1).unwrap(), block(1));
+        assert_eq!(db.get_by_height(6).unwrap(), b"block 6'");
+        assert!(!db.contains(&[9u8; 32]).unwrap());
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_segment_size_has_a_minimum() {
+        let config = Config::in_memory().with_segment_size(MIN_SEGMENT_SIZE - 1);
+        assert!(matches!(Database::create(config.clone()), Err(Error::InvalidConfig(_))));
+        drop(Database::create(config.clone().with_segment_size(MIN_SEGMENT_SIZE)).unwrap());
+        assert!(matches!(Database::open(config), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_prune_below_keeps_blocks() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-prune");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        // Values padded so two records make the smallest segment allowed
+        let pad = |mut value: Vec<u8>| {
+            value.resize(2040, b' ');
+            value
+        };
+        let block = |i: u8| pad(format!("block {}", i).into_bytes());
+        let record = (RecordHeader::SIZE + 2040) as u64;
+        let config = Config::new(&temp_dir).with_segment_size(2 * record);
+        let segment_len = |segment| fs::metadata(temp_dir.join(segments::segment_name(segment))).map(|m| m.len()).ok();
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            for i in 0..6u8 {
+                db.put(&[i; 32], i as u64, &block(i)).unwrap();
+                if i == 2 {
+                    db.put(&[12u8; 32], 2, &pad(b"side 2".to_vec())).unwrap();
+                }
+            }
+
+            // Segments 0 and 1 hold only values below height 4
+            let report = db.prune_below(4).unwrap();
+            assert_eq!(report.blocks_pruned, 5);
+            assert_eq!(report.segments_removed, 2);
+            assert_eq!(report.bytes_reclaimed, 4 * record);
+            assert_eq!(segment_len(0), None);
+            assert_eq!(segment_len(1), None);
+            assert_eq!(segment_len(2), Some(2 * record));
+
+            assert!(matches!(db.get(&[0u8; 32]), Err(Error::Pruned)));
+            assert!(matches!(db.get_by_height(3), Err(Error::Pruned)));
+            #[cfg(feature = "mmap")]
+            assert!(matches!(db.get_ref(&[1u8; 32]), Err(Error::Pruned)));
+            assert_eq!(db.get_by_height(4).unwrap(), block(4));
+            assert_eq!(db.get_hash_by_height(1).unwrap(), [1u8; 32]);
+            assert_eq!(db.get_blocks_at_height(2).unwrap(), vec![[2u8; 32], [12u8; 32]]);
+            assert!(db.contains(&[12u8; 32]).unwrap());
+            assert_eq!(db.entry_count(), 7);
+            assert_eq!(db.stats().data_size, 2 * 2040);
+            assert_eq!(db.latest_height(), 5);
+            assert_eq!(db.genesis_hash(), [0u8; 32]);
+
+            // Pruned blocks count as stored
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            assert!(matches!(db.get(&[0u8; 32]), Err(Error::Pruned)));
+        }
+        assert!(Database::verify(VerifyOptions::new(&temp_dir)).unwrap().is_clean());
+
+        // Replaying adzdb.idx from the start skips the deleted segments
+        fs::remove_file(temp_dir.join(keyfile::KEY_FILE)).unwrap();
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            assert!(db.recovery_report().is_clean());
+            assert_eq!(db.stats().data_size, 2 * 2040);
+            assert!(matches!(db.get(&[2u8; 32]), Err(Error::Pruned)));
+            db.put(&[6u8; 32], 6, &block(6)).unwrap();
+
+            // The records marking blocks 0 to 3 pruned move out of the way
+            let report = db.prune_below(6).unwrap();
+            assert_eq!(report.blocks_pruned, 2);
+            assert_eq!(report.segments_removed, 2);
+            assert!(matches!(db.get_by_height(5), Err(Error::Pruned)));
+            assert_eq!(db.get_by_height(6).unwrap(), block(6));
+        }
+
+        // Rebuilding from the remaining segments still finds every block
+        {
+            let db = Database::rebuild(config.clone()).unwrap();
+            assert_eq!(db.entry_count(), 8);
+            assert_eq!(db.get_hash_by_height(2).unwrap(), [2u8; 32]);
+            assert_eq!(db.get_blocks_at_height(2).unwrap(), vec![[2u8; 32], [12u8; 32]]);
+            assert!(matches!(db.get(&[1u8; 32]), Err(Error::Pruned)));
+            assert_eq!(db.get_by_height(6).unwrap(), block(6));
+        }
+        assert!(Database::verify(VerifyOptions::new(&temp_dir)).unwrap().is_clean());
+
+        // Compacting starts the segments at 0 again
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            db.compact().unwrap();
+            assert!(segment_len(0).is_some());
+            assert!(!temp_dir.join(segments::FIRST_FILE).exists());
+            assert!(matches!(db.get(&[1u8; 32]), Err(Error::Pruned)));
+        }
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 7);
+        assert_eq!(db.get_hash_by_height(0).unwrap(), [0u8; 32]);
+        assert_eq!(db.get_by_height(6).unwrap(), block(6));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_prune_below_spans_many_commits() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-prune-many");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let count = 2 * PRUNE_HEIGHTS + 10;
+        let key = |i: u64| {
+            let mut key = [0u8; 32];
+            key[..8].copy_from_slice(&i.to_le_bytes());
+            key
+        };
+        let config = Config::new(&temp_dir).with_segment_size(64 << 10).with_sync_on_write(false);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            for i in 0..count {
+                db.put(&key(i), i, b"block").unwrap();
+            }
+            db.put(&key(count), count - 1, b"side").unwrap();
+
+            let report = db.prune_below(count - 1).unwrap();
+            assert_eq!(report.blocks_pruned, count - 1);
+            assert!(report.segments_removed > 0);
+            assert!(matches!(db.get_by_height(0), Err(Error::Pruned)));
+            assert!(matches!(db.get_by_height(count - 2), Err(Error::Pruned)));
+            assert_eq!(db.get_by_height(count - 1).unwrap(), b"block");
+            assert_eq!(db.get(&key(count)).unwrap(), b"side");
+            // Each commit checkpoints, so the pruned records don't pile up
+            assert!(db.state.read().hash_index.pending_len() < CHECKPOINT_INTERVAL);
+        }
+        assert!(Database::verify(VerifyOptions::new(&temp_dir)).unwrap().is_clean());
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.entry_count(), count + 1);
+        assert!(matches!(db.get(&key(PRUNE_HEIGHTS)), Err(Error::Pruned)));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compression_is_transparent() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-compression");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let compressed = config.clone().with_compression(Compression::Lz4);
+        let block = |i: u8| [format!("block {} ", i).into_bytes(), vec![i; 1000]].concat();
+        let len = block(0).len() as u64;
+        {
+            // Written before compression was turned on
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, &block(0)).unwrap();
+            assert_eq!(db.stats().data_size, len);
+        }
+        {
+            let mut db = Database::open(compressed.clone()).unwrap();
+            db.put(&[1u8; 32], 1, &block(1)).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(&[2u8; 32], 2, &block(2));
+            batch.put(&[3u8; 32], 3, b"tiny");
+            db.write(batch).unwrap();
+
+            for i in 0..3u8 {
+                assert_eq!(db.get_by_height(i as u64).unwrap(), block(i));
+            }
+            assert_eq!(db.get(&[3u8; 32]).unwrap(), b"tiny");
+            #[cfg(feature = "mmap")]
+            assert_eq!(*db.get_ref(&[1u8; 32]).unwrap(), block(1));
+            let stats = db.stats();
+            assert_eq!(stats.logical_size, 3 * len + 4);
+            assert!(stats.data_size < 2 * len);
+
+            db.delete(&[2u8; 32]).unwrap();
+            assert_eq!(db.stats().logical_size, 2 * len + 4);
+        }
+        assert!(Database::verify(VerifyOptions::new(&temp_dir)).unwrap().is_clean());
+
+        // Reading needs no codec configured, and replaying adzdb.idx or the
+        // data segments from the start counts the same sizes
+        fs::remove_file(temp_dir.join(keyfile::KEY_FILE)).unwrap();
+        let stats = {
+            let db = Database::open(config.clone()).unwrap();
+            assert_eq!(db.get(&[1u8; 32]).unwrap(), block(1));
+            db.stats()
+        };
+        {
+            let db = Database::rebuild(config.clone()).unwrap();
+            assert_eq!((db.stats().data_size, db.stats().logical_size), (stats.data_size, stats.logical_size));
+        }
+
+        // Compacting stores every value with the configured codec
+        {
+            let mut db = Database::open(compressed).unwrap();
+            db.compact().unwrap();
+            assert!(db.stats().data_size < stats.data_size);
+            assert_eq!(db.stats().logical_size, stats.logical_size);
+            assert_eq!(db.get_by_height(0).unwrap(), block(0));
+        }
+        {
+            let mut db = Database::open(config).unwrap();
+            db.compact().unwrap();
+            assert_eq!(db.stats().data_size, db.stats().logical_size);
+            assert_eq!(db.get_by_height(1).unwrap(), block(1));
+        }
+        assert!(Database::verify(VerifyOptions::new(&temp_dir)).unwrap().is_clean());
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_compression_requires_its_feature() {
+        let config = Config::in_memory().with_compression(Compression::Zstd(3));
+        assert!(matches!(Database::create(config.clone()), Err(Error::InvalidConfig(_))));
+        drop(Database::create(config.clone().with_compression(Compression::None)).unwrap());
+        assert!(matches!(Database::open(config.clone()), Err(Error::InvalidConfig(_))));
+        assert!(matches!(Database::rebuild(config), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_truncate_to_height_tombstones() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-truncate-tombstone");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            db.put(&[2u8; 32], 2, b"block 2a").unwrap();
+            db.put(&[3u8; 32], 3, b"block 3a").unwrap();
+            db.put(&[12u8; 32], 2, b"block 2b").unwrap();
+
+            // The side block was appended last, so the removal is logical
+            let removed = db.truncate_to_height(1).unwrap();
+            assert_eq!(removed, vec![[2u8; 32], [3u8; 32]]);
+            assert!(!db.contains(&[2u8; 32]).unwrap());
+            assert!(matches!(db.get(&[3u8; 32]), Err(Error::NotFound)));
+            assert_eq!(db.get(&[12u8; 32]).unwrap(), b"block 2b");
+            assert_eq!(db.get_blocks_at_height(2).unwrap(), vec![[12u8; 32]]);
+            assert!(!db.contains_height(2).unwrap());
+            assert_eq!(db.latest_height(), 1);
+            assert_eq!(db.entry_count(), 3);
+            assert_eq!(db.stats().data_size, 7 + 7 + 8);
+        }
+
+        {
+            let db = Database::open(config.clone()).unwrap();
+            assert!(db.recovery_report().is_clean());
+            assert!(!db.contains(&[2u8; 32]).unwrap());
+            assert!(!db.contains_height(2).unwrap());
+            assert_eq!(db.latest_hash(), [1u8; 32]);
+            assert_eq!(db.entry_count(), 3);
+        }
+
+        // The tombstones live in the data file, so a rebuild keeps them
+        fs::remove_file(temp_dir.join("adzdb.idx")).unwrap();
+        let db = Database::rebuild(config).unwrap();
+        assert!(!db.contains(&[2u8; 32]).unwrap());
+        assert!(!db.contains(&[3u8; 32]).unwrap());
+        assert!(db.contains(&[12u8; 32]).unwrap());
+        assert_eq!(db.entry_count(), 3);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_truncate_to_height_interrupted_before_commit() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-truncate-crash");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let meta_path = temp_dir.join("adzdb.meta");
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            db.put(&[2u8; 32], 2, b"block 2").unwrap();
+            db.put(&[3u8; 32], 3, b"block 3").unwrap();
+            db.put(&[13u8; 32], 3, b"block 3b").unwrap();
+
+            // The tombstones land, but the metadata committing the height
+            // entries that clear heights 2 and 3 does not
+            let old_meta = fs::read(&meta_path).unwrap();
+            db.truncate_to_height(1).unwrap();
+            fs::write(&meta_path, old_meta).unwrap();
+        }
+
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.recovery_report().height_entries_dropped, 2);
+        assert!(db.recovery_report().metadata_repaired);
+        assert!(!db.contains_height(2).unwrap());
+        assert!(!db.contains_height(3).unwrap());
+        assert_eq!(db.latest_height(), 1);
+        assert_eq!(db.latest_hash(), [1u8; 32]);
+        assert!(db.contains(&[13u8; 32]).unwrap());
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_delete_tombstones_block() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-delete");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            db.put(&[9u8; 32], 1, b"spam").unwrap();
+            db.put(&[2u8; 32], 2, b"block 2").unwrap();
+
+            db.delete(&[9u8; 32]).unwrap();
+            assert!(!db.contains(&[9u8; 32]).unwrap());
+            assert!(matches!(db.get(&[9u8; 32]), Err(Error::NotFound)));
+            assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[1u8; 32]]);
+            assert_eq!(db.entry_count(), 3);
+            assert_eq!(db.stats().data_size, 7 + 7 + 7);
+            assert!(matches!(db.delete(&[9u8; 32]), Err(Error::NotFound)));
+
+            // Deleting the canonical tip moves the tip back
+            db.delete(&[2u8; 32]).unwrap();
+            assert!(!db.contains_height(2).unwrap());
+            assert_eq!(db.latest_height(), 1);
+            assert_eq!(db.latest_hash(), [1u8; 32]);
+        }
+
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            assert!(db.recovery_report().is_clean());
+            assert!(!db.contains(&[9u8; 32]).unwrap());
+            assert!(!db.contains(&[2u8; 32]).unwrap());
+            assert_eq!(db.entry_count(), 2);
+            assert_eq!(db.latest_hash(), [1u8; 32]);
+
+            // A deleted block can be stored again
+            db.put(&[9u8; 32], 1, b"spam").unwrap();
+            assert_eq!(db.get(&[9u8; 32]).unwrap(), b"spam");
+            assert_eq!(db.get_hash_by_height(1).unwrap(), [1u8; 32]);
+        }
+
+        let db = Database::rebuild(config).unwrap();
+        assert!(db.contains(&[9u8; 32]).unwrap());
+        assert!(!db.contains(&[2u8; 32]).unwrap());
+        assert_eq!(db.entry_count(), 3);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-batch");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-    /// Check if hash exists
-    pub fn contains(&self, hash: &Hash) -> bool {
-        self.hash_index.contains_key(hash)
-    }
+        let config = Config::new(&temp_dir);
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put(&[1u8; 32], 1, b"block 1");
+            batch.put(&[11u8; 32], 1, b"side 1");
+            batch.put(&[2u8; 32], 2, b"block 2");
+            batch.put(&[2u8; 32], 2, b"again");
+            batch.put(&[0u8; 32], 0, b"stored already");
+            assert_eq!(batch.len(), 5);
+            db.write(batch).unwrap();
+
+            assert_eq!(db.entry_count(), 4);
+            assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+            assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[1u8; 32], [11u8; 32]]);
+            assert_eq!(db.get(&[2u8; 32]).unwrap(), b"block 2");
+            assert_eq!(db.latest_hash(), [2u8; 32]);
+            assert_eq!(db.genesis_hash(), [0u8; 32]);
 
-    /// Check if height exists
-    pub fn contains_height(&self, height: u64) -> bool {
-        self.height_index.contains_key(&height)
-    }
+            db.write(WriteBatch::new()).unwrap();
+        }
 
-    /// Get latest block height
-    pub fn latest_height(&self) -> u64 {
-        self.metadata.latest_height
-    }
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 4);
+        assert_eq!(db.latest_height(), 2);
+        assert_eq!(db.get_hash_by_height(1).unwrap(), [1u8; 32]);
 
-    /// Get latest block hash
-    pub fn latest_hash(&self) -> Hash {
-        self.metadata.latest_hash
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
-    /// Get genesis block hash
-    pub fn genesis_hash(&self) -> Hash {
-        self.metadata.genesis_hash
-    }
+    #[test]
+    fn test_write_batch_rejects_invalid_block() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-batch-invalid");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-    /// Get total entry count
-    pub fn entry_count(&self) -> u64 {
-        self.metadata.entry_count
-    }
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        let data_len = fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len();
 
-    /// Sync all files to disk
-    pub fn sync(&mut self) -> Result<()> {
-        // Update metadata file
-        self.meta_file.seek(SeekFrom::Start(0))?;
-        self.meta_file.write_all(&self.metadata.to_bytes())?;
+        let mut batch = WriteBatch::new();
+        batch.put(&[1u8; 32], 1, b"block 1");
+        batch.put(&[2u8; 32], MAX_REASONABLE_HEIGHT + 1, b"block 2");
+        assert!(matches!(db.write(batch), Err(Error::HeightTooLarge(_))));
 
-        // Sync all files
-        self.data_file.sync_all()?;
-        self.index_file.sync_all()?;
-        self.height_file.sync_all()?;
-        self.meta_file.sync_all()?;
+        assert!(!db.contains(&[1u8; 32]).unwrap());
+        assert_eq!(db.entry_count(), 1);
+        assert_eq!(fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len(), data_len);
 
-        Ok(())
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
-    /// Get database statistics
-    pub fn stats(&self) -> DatabaseStats {
-        DatabaseStats {
-            entry_count: self.metadata.entry_count,
-            data_size: self.metadata.data_size,
-            latest_height: self.metadata.latest_height,
-            latest_hash: self.metadata.latest_hash,
-            genesis_hash: self.metadata.genesis_hash,
+    #[test]
+    fn test_write_batch_interrupted_before_commit() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-batch-crash");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir).with_sync_on_write(false);
+        let meta_path = temp_dir.join("adzdb.meta");
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.sync().unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+
+            // Every record of the batch lands, but the metadata does not
+            let old_meta = fs::read(&meta_path).unwrap();
+            let mut batch = WriteBatch::new();
+            for i in 2..6u8 {
+                batch.put(&[i; 32], i as u64, b"block");
+            }
+            db.write(batch).unwrap();
+            fs::write(&meta_path, old_meta).unwrap();
         }
-    }
 
-    /// Get the database path
-    pub fn path(&self) -> &Path {
-        &self.config.path
-    }
+        // The unsynced put survives; the uncommitted batch is gone
+        let db = Database::open(config).unwrap();
+        let report = db.recovery_report();
+        assert_eq!(report.index_entries_dropped, 4);
+        assert_eq!(report.data_bytes_dropped, 4 * (RecordHeader::SIZE + 5) as u64);
+        assert_eq!(db.entry_count(), 2);
+        assert_eq!(db.latest_height(), 1);
+        assert!(!db.contains(&[2u8; 32]).unwrap());
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
 
-    /// Iterate over all entries by height (ascending)
-    ///
-    /// # Example
-    ///
-    /// ```rust,no_run
-    /// use adzdb::{Database, Config};
-    ///
-    /// # fn main() -> adzdb::Result<()> {
-    /// let config = Config::new("./blockchain");
-    /// let db = Database::open(config)?;
-    ///
-    /// for height in 0..=db.latest_height() {
-    ///     if let Ok(data) = db.get_by_height(height) {
-    ///         println!("Block {}: {} bytes", height, data.len());
-    ///     }
-    /// }
-    /// # Ok(())
-    /// # }
-    /// ```
-    pub fn iter_heights(&self) -> impl Iterator<Item = u64> + '_ {
-        let mut heights: Vec<_> = self.height_index.keys().copied().collect();
-        heights.sort();
-        heights.into_iter()
+        let _ = fs::remove_dir_all(&temp_dir);
     }
-}
 
-/// Database statistics
-#[derive(Debug, Clone)]
-pub struct DatabaseStats {
-    /// Total number of entries
-    pub entry_count: u64,
-    /// Total data size in bytes
-    pub data_size: u64,
-    /// Latest block height
-    pub latest_height: u64,
-    /// Latest block hash
-    pub latest_hash: Hash,
-    /// Genesis block hash
-    pub genesis_hash: Hash,
-}
+    #[test]
+    fn test_torn_metadata_write() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-torn-meta");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
+        let config = Config::new(&temp_dir);
+        let meta_path = temp_dir.join("adzdb.meta");
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+        }
 
-    #[test]
-    fn test_index_entry_roundtrip() {
-        let entry = IndexEntry {
-            key: [1u8; 32],
-            offset: 12345,
-            size: 1000,
-            height: 42,
-            flags: 0,
+        // Tear whichever slot the last sync wrote; the other still holds the
+        // commit before it, and recovery replays the block synced since
+        let mut meta = fs::read(&meta_path).unwrap();
+        let sequence_at = |slot: usize| slot + Metadata::SIZE;
+        let sequence = |slot: usize| {
+            let at = sequence_at(slot);
+            u64::from_le_bytes(meta[at..at + 8].try_into().unwrap())
         };
+        let newest = if sequence(0) > sequence(4096) { 0 } else { 4096 };
+        meta[newest + 40..sequence_at(newest) + 12].fill(0xFF);
+        fs::write(&meta_path, meta).unwrap();
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().metadata_repaired);
+        assert_eq!(db.entry_count(), 2);
+        assert_eq!(db.latest_hash(), [1u8; 32]);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
 
-        let bytes = entry.to_bytes();
-        let recovered = IndexEntry::from_bytes(&bytes);
-
-        assert_eq!(entry.key, recovered.key);
-        assert_eq!(entry.offset, recovered.offset);
-        assert_eq!(entry.size, recovered.size);
-        assert_eq!(entry.height, recovered.height);
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
-    fn test_metadata_roundtrip() {
-        let meta = Metadata {
-            magic: *MAGIC,
-            version: VERSION,
-            entry_count: 100,
-            data_size: 50000,
-            latest_height: 42,
-            latest_hash: [1u8; 32],
-            genesis_hash: [2u8; 32],
-        };
+    fn test_read_handles_while_writing() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Database>();
+        assert_send_sync::<ReadHandle>();
 
-        let bytes = meta.to_bytes();
-        let recovered = Metadata::from_bytes(&bytes).unwrap();
+        let temp_dir = std::env::temp_dir().join("adzdb-test-read-handles");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-        assert_eq!(meta.entry_count, recovered.entry_count);
-        assert_eq!(meta.latest_height, recovered.latest_height);
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config).unwrap();
+        db.put(&[0u8; 32], 0, b"block 0").unwrap();
+
+        // Readers check every block they can see while the writer appends
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = db.read_handle();
+                std::thread::spawn(move || loop {
+                    let tip = reader.latest_height();
+                    for height in 0..=tip {
+                        let data = reader.get_by_height(height).unwrap();
+                        assert_eq!(data, format!("block {}", height).into_bytes());
+                    }
+                    if tip == 200 {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        for height in 1..=200u64 {
+            let mut hash = [0u8; 32];
+            hash[..8].copy_from_slice(&height.to_le_bytes());
+            db.put(&hash, height, format!("block {}", height).as_bytes()).unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // Handles keep following the database when its files are replaced
+        let reader = db.read_handle();
+        db.truncate_to_height(100).unwrap();
+        db.compact().unwrap();
+        assert_eq!(reader.latest_height(), 100);
+        assert_eq!(reader.entry_count(), 101);
+        assert_eq!(reader.get_by_height(100).unwrap(), b"block 100");
+        assert!(matches!(reader.get_by_height(101), Err(Error::NotFound)));
+
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
-    fn test_database_create_and_put() {
-        let temp_dir = std::env::temp_dir().join("adzdb-test-create");
+    fn test_snapshot_is_isolated_from_writes() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-snapshot");
         let _ = fs::remove_dir_all(&temp_dir);
 
         let config = Config::new(&temp_dir);
         let mut db = Database::create(config).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1a").unwrap();
+        db.put(&[2u8; 32], 2, b"block 2a").unwrap();
+        db.put(&[11u8; 32], 1, b"block 1b").unwrap();
+
+        let snapshot = db.read_handle().snapshot();
+
+        // Extend, reorg, delete and truncate underneath the snapshot
+        db.put(&[3u8; 32], 3, b"block 3a").unwrap();
+        db.reorg(0, &[[11u8; 32]]).unwrap();
+        db.delete(&[2u8; 32]).unwrap();
+        let data_len = fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len();
+        db.truncate_to_height(0).unwrap();
+        assert_eq!(db.latest_height(), 0);
+        assert!(!db.contains(&[11u8; 32]).unwrap());
+
+        // The snapshot kept the data file from being cut
+        assert!(fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len() > data_len);
+
+        let check = |snapshot: &Snapshot| {
+            assert_eq!(snapshot.entry_count(), 4);
+            assert_eq!(snapshot.latest_height(), 2);
+            assert_eq!(snapshot.latest_hash(), [2u8; 32]);
+            assert_eq!(snapshot.iter_heights().unwrap().collect::<Vec<_>>(), vec![0, 1, 2]);
+            assert_eq!(snapshot.get_by_height(1).unwrap(), b"block 1a");
+            assert_eq!(snapshot.get(&[2u8; 32]).unwrap(), b"block 2a");
+            assert_eq!(snapshot.get_blocks_at_height(1).unwrap(), vec![[1u8; 32], [11u8; 32]]);
+            assert!(!snapshot.contains(&[3u8; 32]).unwrap());
+            assert!(!snapshot.contains_height(3).unwrap());
+        };
+        check(&snapshot);
 
-        let hash = [42u8; 32];
-        let data = b"test block data";
+        // Compaction replaces the files, but the snapshot reads the old ones
+        db.compact().unwrap();
+        check(&snapshot);
+        assert_eq!(db.get(&[0u8; 32]).unwrap(), b"genesis");
+        assert_eq!(db.entry_count(), 1);
 
-        db.put(&hash, 0, data).unwrap();
+        // Once the snapshot is gone, the tail can be cut again
+        drop(snapshot);
+        db.put(&[4u8; 32], 1, b"block 1c").unwrap();
+        let data_len = fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len();
+        db.truncate_to_height(0).unwrap();
+        assert!(fs::metadata(temp_dir.join(segments::segment_name(0))).unwrap().len() < data_len);
 
-        assert!(db.contains(&hash));
-        assert_eq!(db.entry_count(), 1);
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
 
-        let retrieved = db.get(&hash).unwrap();
-        assert_eq!(retrieved, data);
+    #[test]
+    fn test_read_only_beside_locked_writer() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-read-only");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let read_only = config.clone().with_read_only(true);
+        assert!(matches!(Database::create(read_only.clone()), Err(Error::ReadOnly)));
+
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        db.put(&[1u8; 32], 1, b"block 1").unwrap();
+
+        // A second writer is turned away, readers are not
+        assert!(matches!(Database::open(config.clone()), Err(Error::Locked(_))));
+        let mut reader = Database::open(read_only.clone()).unwrap();
+        assert_eq!(reader.latest_height(), 1);
+        assert_eq!(reader.get(&[1u8; 32]).unwrap(), b"block 1");
+        assert!(matches!(reader.put(&[2u8; 32], 2, b"block 2"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.sync(), Err(Error::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(Error::ReadOnly)));
+
+        // A half-written index entry is left out of the view, not repaired
+        let index_path = temp_dir.join("adzdb.idx");
+        let index_len = fs::metadata(&index_path).unwrap().len();
+        let mut index = OpenOptions::new().append(true).open(&index_path).unwrap();
+        index.write_all(&[7u8; 10]).unwrap();
+        drop(index);
+
+        let reader = Database::open(read_only).unwrap();
+        assert_eq!(reader.recovery_report().torn_bytes, 10);
+        assert_eq!(reader.entry_count(), 2);
+        assert_eq!(fs::metadata(&index_path).unwrap().len(), index_len + 10);
+
+        // The lock is released with the writer
+        drop(db);
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.recovery_report().torn_bytes, 10);
+        assert_eq!(fs::metadata(&index_path).unwrap().len(), index_len);
+        drop(reader);
 
         let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
-    fn test_database_height_index() {
-        let temp_dir = std::env::temp_dir().join("adzdb-test-height");
+    fn test_refresh_follows_writer() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-refresh");
         let _ = fs::remove_dir_all(&temp_dir);
 
         let config = Config::new(&temp_dir);
-        let mut db = Database::create(config).unwrap();
+        let mut db = Database::create(config.clone()).unwrap();
+        for i in 0..3u8 {
+            db.put(&[i; 32], i as u64, &[i; 100]).unwrap();
+        }
+        assert_eq!(db.refresh().unwrap(), RefreshReport::default());
+
+        let mut follower = Database::open(config.with_read_only(true)).unwrap();
+        let reader = follower.read_handle();
+        assert_eq!(follower.latest_height(), 2);
+
+        // New blocks, including a side block, are picked up incrementally
+        db.put(&[3u8; 32], 3, b"block 3").unwrap();
+        db.put(&[4u8; 32], 4, b"block 4a").unwrap();
+        db.put(&[14u8; 32], 4, b"block 4b").unwrap();
+        let report = follower.refresh().unwrap();
+        assert_eq!(report.index_entries, 3);
+        assert_eq!(report.height_entries, 2);
+        assert!(!report.reopened);
+        assert_eq!(reader.latest_height(), 4);
+        assert_eq!(reader.get_by_height(4).unwrap(), b"block 4a");
+        assert_eq!(reader.get_blocks_at_height(4).unwrap(), vec![[4u8; 32], [14u8; 32]]);
+        assert_eq!(follower.refresh().unwrap().index_entries, 0);
+
+        // So are reorgs and deletions
+        db.reorg(3, &[[14u8; 32]]).unwrap();
+        db.delete(&[4u8; 32]).unwrap();
+        db.delete(&[1u8; 32]).unwrap();
+        let report = follower.refresh().unwrap();
+        assert_eq!((report.index_entries, report.reopened), (2, false));
+        assert_eq!(reader.get_by_height(4).unwrap(), b"block 4b");
+        assert!(!reader.contains(&[4u8; 32]).unwrap() && !reader.contains_height(1).unwrap());
+        assert_eq!(reader.entry_count(), db.entry_count());
+        assert_eq!(reader.stats().data_size, db.stats().data_size);
+
+        // Blocks cut off the file tails or compacted away force a reopen
+        db.truncate_to_height(3).unwrap();
+        db.compact().unwrap();
+        db.put(&[24u8; 32], 4, b"block 4c").unwrap();
+        let report = follower.refresh().unwrap();
+        assert!(report.reopened);
+        assert_eq!(reader.get_by_height(4).unwrap(), b"block 4c");
+        assert_eq!(reader.entry_count(), 4);
+        assert_eq!(follower.latest_hash(), db.latest_hash());
 
-        // Add blocks at different heights
-        let hash0 = [0u8; 32];
-        let hash1 = [1u8; 32];
-        let hash2 = [2u8; 32];
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
 
-        db.put(&hash0, 0, b"genesis").unwrap();
-        db.put(&hash1, 1, b"block 1").unwrap();
-        db.put(&hash2, 2, b"block 2").unwrap();
+    #[test]
+    fn test_refresh_follows_journal_checkpoints() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-refresh-checkpoint");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-        // Retrieve by height
-        assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
-        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
-        assert_eq!(db.get_by_height(2).unwrap(), b"block 2");
+        let key = |i: u64| {
+            let mut key = [0u8; 32];
+            key[..8].copy_from_slice(&i.to_le_bytes());
+            key
+        };
+        let config = Config::new(&temp_dir).with_sync_on_write(false);
+        let mut db = Database::create(config.clone()).unwrap();
+        let mut follower = Database::open(config.with_read_only(true)).unwrap();
+
+        // The writer empties the journal twice; the follower reads on through
+        // both without reopening, whether or not it caught the writer between
+        // the replacement and the next commit
+        let blocks = 3 * CHECKPOINT_INTERVAL as u64;
+        for height in 0..blocks {
+            db.put(&key(height), height, &height.to_le_bytes()).unwrap();
+            if height % 1000 == 999 || height + 1 == blocks {
+                db.sync().unwrap();
+                let report = follower.refresh().unwrap();
+                assert!(!report.reopened, "reopened at height {}", height);
+                assert_eq!(follower.latest_height(), height);
+            }
+        }
+        assert_eq!(db.state.read().metadata.journal_generation, 2);
+        assert_eq!(follower.state.read().metadata.journal_generation, 2);
+        for height in [0, CHECKPOINT_INTERVAL as u64, blocks - 1] {
+            assert_eq!(follower.get_by_height(height).unwrap(), height.to_le_bytes());
+        }
 
-        // Get hash by height
-        assert_eq!(db.get_hash_by_height(0).unwrap(), hash0);
-        assert_eq!(db.get_hash_by_height(1).unwrap(), hash1);
-        assert_eq!(db.get_hash_by_height(2).unwrap(), hash2);
+        // Missing a whole journal still reopens
+        for height in blocks..2 * blocks {
+            db.put(&key(height), height, &height.to_le_bytes()).unwrap();
+            if height % 1000 == 999 {
+                db.sync().unwrap();
+            }
+        }
+        db.sync().unwrap();
+        assert!(follower.refresh().unwrap().reopened);
+        assert_eq!(follower.latest_hash(), db.latest_hash());
 
         let _ = fs::remove_dir_all(&temp_dir);
     }
 
+    #[cfg(feature = "mmap")]
     #[test]
-    fn test_corruption_detection() {
-        let temp_dir = std::env::temp_dir().join("adzdb-test-corrupt");
+    fn test_get_ref_follows_growing_file() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-mmap");
         let _ = fs::remove_dir_all(&temp_dir);
 
         let config = Config::new(&temp_dir);
-        let mut db = Database::create(config).unwrap();
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[0u8; 32], 0, b"genesis").unwrap();
+        assert_eq!(*db.get_ref(&[0u8; 32]).unwrap(), b"genesis"[..]);
+
+        // Blocks appended after the first mapping are mapped on demand
+        for i in 1..20u8 {
+            db.put(&[i; 32], i as u64, &vec![i; 4096]).unwrap();
+            assert_eq!(*db.get_ref(&[i; 32]).unwrap(), vec![i; 4096]);
+        }
+        let first = db.get_ref(&[1u8; 32]).unwrap();
+        let last = db.get_ref(&[19u8; 32]).unwrap();
+        assert_eq!(first[0], 1);
+        assert_eq!(last[0], 19);
+        assert!(matches!(db.get_ref(&[99u8; 32]), Err(Error::NotFound)));
+
+        // Truncating drops the mappings before cutting the file
+        db.truncate_to_height(10).unwrap();
+        db.put(&[31u8; 32], 11, b"block 11'").unwrap();
+        assert_eq!(*db.get_ref(&[31u8; 32]).unwrap(), b"block 11'"[..]);
+        drop(db);
+
+        // Bit rot is caught as it is by `get`
+        let data_path = temp_dir.join(segments::segment_name(0));
+        let mut bytes = fs::read(&data_path).unwrap();
+        bytes[RecordHeader::SIZE + 1] ^= 0x01;
+        fs::write(&data_path, &bytes).unwrap();
+
+        let db = Database::open(config).unwrap();
+        assert!(matches!(db.get_ref(&[0u8; 32]), Err(Error::Corruption(_))));
 
-        let hash = [42u8; 32];
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
 
-        // Try to insert with impossibly high height
-        let result = db.put(&hash, MAX_REASONABLE_HEIGHT + 1, b"corrupt");
-        assert!(matches!(result, Err(Error::HeightTooLarge(_))));
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_get_ref_copies_on_read_only() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-mmap-read-only");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        for i in 0..5u8 {
+            db.put(&[i; 32], i as u64, &vec![i; 4096]).unwrap();
+        }
+        assert!(matches!(db.get_ref(&[4u8; 32]).unwrap(), std::borrow::Cow::Borrowed(_)));
+
+        // A follower cannot stop the writer cutting the segment under it
+        let follower = Database::open(config.with_read_only(true)).unwrap();
+        let tip = follower.get_ref(&[4u8; 32]).unwrap();
+        assert!(matches!(tip, std::borrow::Cow::Owned(_)));
+        db.truncate_to_height(1).unwrap();
+        assert_eq!(*tip, vec![4u8; 4096]);
+        assert!(follower.get_ref(&[4u8; 32]).is_err());
+        assert_eq!(*follower.get_ref(&[1u8; 32]).unwrap(), vec![1u8; 4096]);
 
         let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
-    fn test_database_reopen() {
-        let temp_dir = std::env::temp_dir().join("adzdb-test-reopen");
+    fn test_key_file_checkpoint_and_migration() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-key-file");
         let _ = fs::remove_dir_all(&temp_dir);
 
         let config = Config::new(&temp_dir);
-
-        // Create and populate
         {
             let mut db = Database::create(config.clone()).unwrap();
-            db.put(&[1u8; 32], 0, b"genesis").unwrap();
-            db.put(&[2u8; 32], 1, b"block 1").unwrap();
+            for i in 0..10u8 {
+                db.put(&[i; 32], i as u64, &[i; 100]).unwrap();
+            }
+            db.put(&[100u8; 32], 5, b"side 5").unwrap();
             db.sync().unwrap();
+
+            // Past the checkpoint: replayed from adzdb.idx on open
+            db.delete(&[3u8; 32]).unwrap();
+            db.put(&[10u8; 32], 10, b"block 10").unwrap();
         }
 
-        // Reopen and verify
         {
-            let db = Database::open(config).unwrap();
-            assert_eq!(db.entry_count(), 2);
-            assert_eq!(db.latest_height(), 1);
-            assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
-            assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+            let db = Database::open(config.clone()).unwrap();
+            assert!(db.recovery_report().is_clean());
+            assert_eq!(db.state.read().hash_index.pending_len(), 2);
+            assert_eq!(db.entry_count(), 11);
+            assert!(!db.contains(&[3u8; 32]).unwrap());
+            assert!(!db.contains_height(3).unwrap());
+            assert_eq!(db.get(&[9u8; 32]).unwrap(), vec![9u8; 100]);
+            assert_eq!(db.get(&[10u8; 32]).unwrap(), b"block 10");
+            assert_eq!(db.get_blocks_at_height(5).unwrap(), vec![[5u8; 32], [100u8; 32]]);
+        }
+
+        // A database written before the key file existed gets one on open
+        fs::remove_file(temp_dir.join("adzdb.key")).unwrap();
+        let index_len = fs::metadata(temp_dir.join("adzdb.idx")).unwrap().len();
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            assert!(db.recovery_report().is_clean());
+            assert!(temp_dir.join("adzdb.key").exists());
+            assert_eq!(db.entry_count(), 11);
+            assert_eq!(db.latest_hash(), [10u8; 32]);
+            assert_eq!(db.get_blocks_at_height(5).unwrap(), vec![[5u8; 32], [100u8; 32]]);
+
+            // Cutting checkpointed blocks off the tail moves the checkpoint back
+            assert_eq!(db.truncate_to_height(9).unwrap(), vec![[10u8; 32]]);
         }
+        assert_eq!(
+            fs::metadata(temp_dir.join("adzdb.idx")).unwrap().len(),
+            index_len - IndexEntry::SIZE as u64
+        );
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 10);
+        assert_eq!(db.latest_height(), 9);
+        assert!(!db.contains(&[10u8; 32]).unwrap());
+        assert_eq!(db.get_by_height(8).unwrap(), vec![8u8; 100]);
 
         let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
-    fn test_deduplication() {
-        let temp_dir = std::env::temp_dir().join("adzdb-test-dedup");
+    fn test_read_only_open_uses_key_file() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-key-file-read-only");
         let _ = fs::remove_dir_all(&temp_dir);
 
         let config = Config::new(&temp_dir);
-        let mut db = Database::create(config).unwrap();
+        let mut db = Database::create(config.clone()).unwrap();
+        for i in 0..10u8 {
+            db.put(&[i; 32], i as u64, &[i; 100]).unwrap();
+        }
+        db.put(&[100u8; 32], 5, b"side 5").unwrap();
+        db.sync().unwrap();
+        db.delete(&[3u8; 32]).unwrap();
+        db.put(&[10u8; 32], 10, b"block 10").unwrap();
+
+        // Damage an index entry the checkpoint covers: only the entries past
+        // it are read
+        let index_path = temp_dir.join("adzdb.idx");
+        let mut index = fs::read(&index_path).unwrap();
+        index[IndexEntry::SIZE + 32..IndexEntry::SIZE + 40].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        fs::write(&index_path, &index).unwrap();
+
+        let read_only = config.clone().with_read_only(true);
+        let follower = Database::open(read_only.clone()).unwrap();
+        assert!(follower.recovery_report().is_clean());
+        assert_eq!(follower.state.read().hash_index.pending_len(), 11);
+        assert_eq!(follower.entry_count(), db.entry_count());
+        assert!(!follower.contains(&[3u8; 32]).unwrap());
+        assert_eq!(follower.get(&[1u8; 32]).unwrap(), vec![1u8; 100]);
+        assert_eq!(follower.get(&[10u8; 32]).unwrap(), b"block 10");
+        assert_eq!(follower.get_blocks_at_height(5).unwrap(), vec![[5u8; 32], [100u8; 32]]);
+
+        // A checkpoint in progress can't be rolled back read-only, so all of
+        // adzdb.idx is read instead
+        fs::write(temp_dir.join(keyfile::LOG_FILE), b"checkpoint").unwrap();
+        assert!(matches!(Database::open(read_only), Err(Error::Corruption(_))));
+
+        drop(db);
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
 
-        let hash = [42u8; 32];
+    #[test]
+    fn test_dense_height_file_and_conversion() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-dense-heights");
+        let _ = fs::remove_dir_all(&temp_dir);
 
-        // Insert same hash twice
-        db.put(&hash, 0, b"first").unwrap();
-        db.put(&hash, 0, b"second").unwrap(); // Should be no-op
+        let config = Config::new(&temp_dir).with_sync_on_write(false);
+        let height_path = temp_dir.join(heights::HEIGHT_FILE);
+        let hash_of = |i: u64| {
+            let mut hash = [1u8; 32];
+            hash[..8].copy_from_slice(&i.to_le_bytes());
+            hash
+        };
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            for i in 0..CHECKPOINT_INTERVAL as u64 {
+                db.put(&hash_of(i), i, &i.to_le_bytes()).unwrap();
+            }
+            db.sync().unwrap();
 
-        // Should still have only one entry with original data
-        assert_eq!(db.entry_count(), 1);
-        assert_eq!(db.get(&hash).unwrap(), b"first");
+            // A full journal is folded into adzdb.hgt and started over
+            assert_eq!(db.state.read().metadata.height_len, 0);
+            assert_eq!(fs::metadata(&height_path).unwrap().len(), CHECKPOINT_INTERVAL as u64 * 32);
+
+            // Clearing the top heights shrinks the file on the next checkpoint
+            db.truncate_to_height(99).unwrap();
+            db.sync().unwrap();
+            assert_eq!(fs::metadata(&height_path).unwrap().len(), 100 * 32);
+            assert_eq!(db.get_by_height(99).unwrap(), 99u64.to_le_bytes());
+            assert!(!db.contains_height(100).unwrap());
+        }
+
+        // Turn it back into a version 5 database, whose adzdb.hgt was the
+        // height log itself, whose data was all in adzdb.dat and whose index
+        // entries had no segment
+        {
+            let mut db = Database::open(config.clone()).unwrap();
+            let blocks = (0..100).map(|i| HeightEntry { height: i, hash: hash_of(i) });
+            let log: Vec<u8> = blocks.flat_map(|entry| entry.to_bytes()).collect();
+            db.put(&hash_of(100), 100, b"block 100").unwrap();
+            db.sync().unwrap();
+            let mut meta = db.state.read().metadata.to_bytes();
+            drop(db);
+            let log = [log, HeightEntry { height: 100, hash: hash_of(100) }.to_bytes().to_vec()].concat();
+            fs::remove_file(temp_dir.join(HEIGHT_LOG)).unwrap();
+            fs::remove_file(temp_dir.join("adzdb.key")).unwrap();
+            fs::write(&height_path, &log).unwrap();
+            fs::rename(temp_dir.join(segments::segment_name(0)), temp_dir.join("adzdb.dat")).unwrap();
+            let index = fs::read(temp_dir.join("adzdb.idx")).unwrap();
+            let index: Vec<u8> = index.chunks_exact(IndexEntry::SIZE).flat_map(|entry| entry[..56].to_vec()).collect();
+            fs::write(temp_dir.join("adzdb.idx"), &index).unwrap();
+            meta[4..8].copy_from_slice(&5u32.to_le_bytes());
+            meta[96..104].copy_from_slice(&(log.len() as u64).to_le_bytes());
+            meta[104..112].copy_from_slice(&(index.len() as u64).to_le_bytes());
+            fs::write(temp_dir.join("adzdb.meta"), meta).unwrap();
+        }
+
+        let read_only = Database::open(config.clone().with_read_only(true));
+        assert!(matches!(read_only, Err(Error::MigrationRequired(5))));
+
+        let db = Database::open(config).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.latest_height(), 100);
+        assert_eq!(db.get_by_height(100).unwrap(), b"block 100");
+        assert_eq!(db.get_hash_by_height(42).unwrap(), hash_of(42));
+        assert_eq!(db.state.read().metadata.version, VERSION);
+        assert_eq!(fs::metadata(&height_path).unwrap().len(), 101 * 32);
+        assert_eq!(fs::metadata(temp_dir.join(HEIGHT_LOG)).unwrap().len(), 0);
 
         let _ = fs::remove_dir_all(&temp_dir);
     }
-}
 
+    #[test]
+    fn test_in_memory_database() {
+        let config = Config::in_memory();
+        {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            db.put(&[11u8; 32], 1, b"block 1b").unwrap();
+            assert_eq!(db.entry_count(), 3);
+            assert_eq!(db.get_blocks_at_height(1).unwrap(), vec![[1u8; 32], [11u8; 32]]);
+
+            // One writer at a time, as on disk
+            assert!(matches!(Database::open(config.clone()), Err(Error::Locked(_))));
+
+            db.reorg(0, &[[11u8; 32]]).unwrap();
+            db.delete(&[1u8; 32]).unwrap();
+            db.compact().unwrap();
+        }
+
+        // Reopening from a clone of the configuration finds the same blocks
+        let db = Database::open(config.clone()).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1b");
+        assert!(!db.contains(&[1u8; 32]).unwrap());
+        let stats = db.stats();
+        assert_eq!(stats.entry_count, 2);
+        assert_eq!(stats.data_size, 15);
+        assert_eq!(stats.latest_hash, [11u8; 32]);
+        assert_eq!(stats.genesis_hash, [0u8; 32]);
+        assert!(Database::verify(verify::VerifyOptions::from(&config)).unwrap().is_clean());
+        assert!(!config.path.exists());
+
+        // Each in-memory configuration has files of its own
+        let mut other = Database::in_memory().unwrap();
+        assert_eq!(other.entry_count(), 0);
+        other.put(&[0u8; 32], 0, b"another genesis").unwrap();
+        assert_eq!(db.get(&[0u8; 32]).unwrap(), b"genesis");
+    }
+
+    #[test]
+    fn test_block_far_above_the_chain() {
+        let config = Config::in_memory();
+        let mut db = Database::create(config.clone()).unwrap();
+        db.put(&[1u8; 32], 0, b"genesis").unwrap();
+        db.put(&[2u8; 32], MAX_REASONABLE_HEIGHT, b"far").unwrap();
+        db.sync().unwrap();
+        drop(db);
+
+        // The height file holds the empty heights between as a hole, which
+        // the scan skips
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.iter_heights().unwrap().collect::<Vec<_>>(), [0, MAX_REASONABLE_HEIGHT]);
+        assert_eq!(db.get_by_height(MAX_REASONABLE_HEIGHT).unwrap(), b"far");
+        assert!(!db.contains_height(MAX_REASONABLE_HEIGHT / 2).unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+//! Exclusive writer lock on adzdb.lock
+//!
+//! Only one process may append to a database at a time. A writer holds an
+//! advisory lock on adzdb.lock for as long as the database is open: `flock`
+//! on Unix, an open handle that shares nothing on Windows, or whatever the
+//! configured `Vfs` provides. Either way the operating system releases it
+//! when the process exits, so a crash never leaves a stale lock behind.
+//! Read-only opens take no lock. On other platforms there is no lock to
+//! take, so opening a database on the filesystem for writing fails there.
+
+use std::fmt;
+use std::fs::File;
+#[cfg(any(unix, windows))]
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+use crate::vfs::Dir;
+use crate::{Error, Result};
+
+/// Lock file name
+const LOCK_FILE: &str = "adzdb.lock";
+
+/// The lock held by the one process allowed to write a database
+#[derive(Debug)]
+pub(crate) struct WriterLock {
+    _guard: Box<dyn fmt::Debug + Send + Sync>,
+}
+
+impl WriterLock {
+    /// Take the writer lock for the database in `dir`
+    ///
+    /// Fails with `Error::Locked` if another writer holds it, whether in
+    /// this process or another.
+    pub(crate) fn acquire(dir: &Dir) -> Result<Self> {
+        let lock_path = dir.join(LOCK_FILE);
+        let guard = dir.lock(&lock_path).map_err(|e| {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                Error::Locked(lock_path.clone())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        Ok(Self { _guard: guard })
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn open_exclusive(path: &Path) -> io::Result<File> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn flock(fd: c_int, operation: c_int) -> c_int;
+    }
+    const LOCK_EX: c_int = 2;
+    const LOCK_NB: c_int = 4;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // SAFETY: `flock` only reads the descriptor, which `file` keeps open
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+pub(crate) fn open_exclusive(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(0)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(ERROR_SHARING_VIOLATION) => io::ErrorKind::WouldBlock.into(),
+            _ => e,
+        })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn open_exclusive(path: &Path) -> io::Result<File> {
+    // Without a lock a second writer could corrupt the files unnoticed
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot lock {:?}: file locking is not supported on this platform", path),
+    ))
+}
@@ -0,0 +1,778 @@
+//! Format migrations: upgrading databases written by older versions
+//!
+//! `Metadata.version` names the layout of every file in a database
+//! directory. This build reads only `VERSION`; opening anything else fails
+//! with `Error::MigrationRequired` if an upgrade path exists, and with
+//! `Error::UnsupportedVersion` otherwise, rather than misreading it.
+//!
+//! An upgrade is a chain of steps, each from one format version to a later
+//! one. A step writes the files it changes beside the old ones with a
+//! `.migrate` suffix, the metadata carrying the new version last, and
+//! commits them by creating a marker file before renaming them into place.
+//! If a crash interrupts the renames, the next open or upgrade completes
+//! them; without the marker, leftover files are discarded and the step runs
+//! again. Either way the directory holds one complete format version.
+//!
+//! A writable `Database::open` upgrades the directory in place before
+//! loading it. [`upgrade_into`] leaves the original untouched instead, and
+//! writes the upgraded database to a new directory.
+
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::checksum::crc32c;
+use crate::compact::{self, write_synced};
+use crate::heights::{HEIGHT_FILE, HEIGHT_LOG};
+use crate::keyfile::{KeyFile, KEY_FILE, LOG_FILE};
+use crate::lock::WriterLock;
+use crate::segments::{first_segment, segment_name, FIRST_FILE};
+use crate::superblock::{self, META_FILE};
+use crate::vfs::Dir;
+use crate::{read_exact_at, to_hex, Database, Error, HeightEntry, IndexEntry, Metadata, RecordHeader, Result, VERSION};
+
+/// Oldest format version that can be upgraded
+pub const MIN_VERSION: u32 = 1;
+
+/// Files a step may replace, in the order they are renamed; the metadata
+/// goes last, so its version never runs ahead of the other files
+const FILES: [&str; 5] = [DATA_V7_FILE, "adzdb.idx", HEIGHT_FILE, HEIGHT_LOG, META_FILE];
+
+/// Files copied by `upgrade_into` after the data segments, the metadata last
+const COPIED: [&str; 8] = [
+    DATA_V7_FILE,
+    "adzdb.idx",
+    HEIGHT_FILE,
+    HEIGHT_LOG,
+    KEY_FILE,
+    LOG_FILE,
+    FIRST_FILE,
+    META_FILE,
+];
+
+/// The single data file before version 8 split it into segments
+const DATA_V7_FILE: &str = "adzdb.dat";
+
+/// Marker whose presence commits the staged files
+const MARKER: &str = "adzdb.migrate";
+
+/// Size of the metadata before version 4 added `height_len`
+const META_V3_SIZE: usize = 96;
+
+/// Size of the metadata before version 5 added `index_len`
+const META_V4_SIZE: usize = 104;
+
+/// Size of the metadata before version 9 added `logical_size`
+const META_V8_SIZE: usize = 112;
+
+/// Size of the metadata before version 10 added `journal_generation`
+const META_V9_SIZE: usize = 120;
+
+/// Size of an index entry before version 8 added `segment`
+const INDEX_V7_SIZE: usize = 56;
+
+/// First format version keeping the metadata in two slots
+const SLOTTED_VERSION: u32 = 7;
+
+/// First format version keeping the data in segments
+const SEGMENTED_VERSION: u32 = 8;
+
+/// First format version with `logical_size` in the metadata
+const COMPRESSED_VERSION: u32 = 9;
+
+/// First format version with `journal_generation` in the metadata
+const GENERATION_VERSION: u32 = 10;
+
+/// One upgrade from a format version to a later one
+struct Step {
+    from: u32,
+    to: u32,
+    /// Stage the files that change and update the metadata bytes to match;
+    /// the caller has already set the new version in them
+    run: fn(&Dir, &mut Vec<u8>) -> Result<()>,
+}
+
+/// Every upgrade step, oldest first
+const STEPS: [Step; 9] = [
+    Step { from: 1, to: 3, run: frame_bare_values },
+    Step { from: 2, to: 3, run: frame_checked_values },
+    Step { from: 3, to: 4, run: add_height_len },
+    Step { from: 4, to: 5, run: add_index_len },
+    Step { from: 5, to: 6, run: split_height_log },
+    Step { from: 6, to: 7, run: double_buffer_metadata },
+    Step { from: 7, to: 8, run: segment_data_file },
+    Step { from: 8, to: 9, run: add_logical_size },
+    Step { from: 9, to: 10, run: add_journal_generation },
+];
+
+/// Outcome of an upgrade
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Format version the database had
+    pub from_version: u32,
+    /// Format version it has now
+    pub to_version: u32,
+    /// Steps taken, each a pair of format versions
+    pub steps: Vec<(u32, u32)>,
+}
+
+impl MigrationReport {
+    /// Returns true if the database was already current
+    pub fn is_noop(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+/// Read the format version of the database at `path` without opening it
+///
+/// # Errors
+///
+/// Returns `Error::Corruption` if adzdb.meta does not start with the magic
+/// bytes.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use adzdb::migrate;
+///
+/// # fn main() -> adzdb::Result<()> {
+/// let version = migrate::format_version("./blockchain".as_ref())?;
+/// if version < adzdb::VERSION {
+///     println!("format {} needs upgrading", version);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn format_version(path: &Path) -> Result<u32> {
+    version_of(&Dir::os(path))
+}
+
+/// Format version of the database in `dir`
+pub(crate) fn version_of(dir: &Dir) -> Result<u32> {
+    superblock::version(&dir.open_read(&dir.join(META_FILE))?)
+}
+
+/// The error for a format version this build cannot read as it is
+pub(crate) fn version_error(version: u32) -> Error {
+    if (MIN_VERSION..VERSION).contains(&version) {
+        Error::MigrationRequired(version)
+    } else {
+        Error::UnsupportedVersion(version)
+    }
+}
+
+/// Size of the metadata in a slot of adzdb.meta of format `version`
+pub(crate) fn metadata_size(version: u32) -> usize {
+    if version < COMPRESSED_VERSION {
+        META_V8_SIZE
+    } else if version < GENERATION_VERSION {
+        META_V9_SIZE
+    } else {
+        Metadata::SIZE
+    }
+}
+
+/// Upgrade the database at `path` to the current format, in place
+///
+/// Takes the writer lock, so it fails with `Error::Locked` while the
+/// database is open for writing. A database that is already current is left
+/// alone. Steps that rewrite the data file need room for a second copy of it
+/// until they commit.
+///
+/// # Errors
+///
+/// Returns `Error::UnsupportedVersion` for a format this build cannot
+/// upgrade, including any newer than `VERSION`, and `Error::Corruption` if
+/// a record of an old data file fails its checksum.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use adzdb::migrate;
+///
+/// # fn main() -> adzdb::Result<()> {
+/// let report = migrate::upgrade("./blockchain".as_ref())?;
+/// println!("format {} -> {}", report.from_version, report.to_version);
+/// # Ok(())
+/// # }
+/// ```
+pub fn upgrade(path: &Path) -> Result<MigrationReport> {
+    let dir = Dir::os(path);
+    let _lock = WriterLock::acquire(&dir)?;
+    compact::finish_pending(&dir)?;
+    upgrade_locked(&dir)
+}
+
+/// Write an upgraded copy of the database at `source` to `dest`
+///
+/// The files are copied, the metadata last, and the copy is upgraded in
+/// place; `source` is only read. A copy interrupted before its metadata
+/// landed is started over by the next call, and one interrupted while
+/// upgrading is finished by it. Both directories are locked meanwhile.
+///
+/// # Errors
+///
+/// Returns `Error::AlreadyExists` if `dest` already holds a database, and
+/// otherwise the errors of [`upgrade`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use adzdb::{migrate, Config, Database};
+///
+/// # fn main() -> adzdb::Result<()> {
+/// // Keep the old directory until the new one has been checked
+/// migrate::upgrade_into("./blockchain".as_ref(), "./blockchain-v6".as_ref())?;
+/// let db = Database::open(Config::new("./blockchain-v6"))?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn upgrade_into(source: &Path, dest: &Path) -> Result<MigrationReport> {
+    let (source, dest) = (Dir::os(source), Dir::os(dest));
+    let _source_lock = WriterLock::acquire(&source)?;
+    compact::finish_pending(&source)?;
+    finish_pending(&source)?;
+    let version = version_of(&source)?;
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    dest.create_dir_all()?;
+    let _dest_lock = WriterLock::acquire(&dest)?;
+    if dest.exists(&dest.join(META_FILE)) {
+        // A previous call got as far as copying everything
+        if version_of(&dest)? == version || is_pending(&dest) {
+            let mut report = upgrade_locked(&dest)?;
+            report.from_version = version;
+            return Ok(report);
+        }
+        return Err(Error::AlreadyExists);
+    }
+
+    let mut segment = first_segment(&source)?;
+    while source.exists(&source.join(segment_name(segment))) {
+        source.copy(&source.join(segment_name(segment)), &dest.join(segment_name(segment)))?;
+        segment += 1;
+    }
+    for name in COPIED {
+        match source.copy(&source.join(name), &dest.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    dest.sync()?;
+    upgrade_locked(&dest)
+}
+
+/// Upgrade in place with the writer lock already held
+pub(crate) fn upgrade_locked(dir: &Dir) -> Result<MigrationReport> {
+    finish_pending(dir)?;
+    let from_version = version_of(dir)?;
+    let mut report = MigrationReport {
+        from_version,
+        to_version: from_version,
+        steps: Vec::new(),
+    };
+    if from_version > VERSION {
+        return Err(Error::UnsupportedVersion(from_version));
+    }
+
+    while report.to_version != VERSION {
+        let step = STEPS
+            .iter()
+            .find(|step| step.from == report.to_version)
+            .ok_or(Error::UnsupportedVersion(report.to_version))?;
+
+        // Steps work on the metadata itself, taken out of its slot
+        let slotted = step.from >= SLOTTED_VERSION;
+        let mut meta = if slotted {
+            superblock::newest_bytes(&dir.open_read(&dir.join(META_FILE))?)?
+                .ok_or_else(|| Error::Corruption("No valid metadata slot".to_string()))?
+        } else {
+            dir.read(&dir.join(META_FILE))?
+        };
+        meta[4..8].copy_from_slice(&step.to.to_le_bytes());
+        (step.run)(dir, &mut meta)?;
+        if slotted {
+            meta = superblock::encode_bytes(&meta);
+        }
+        write_synced(dir, &staged(dir.path(), META_FILE), &meta)?;
+
+        // Commit point: once the marker exists the step is completed even
+        // if a crash interrupts it
+        Database::replace_file(dir, &dir.join(MARKER), &[])?;
+        dir.sync()?;
+        finish_pending(dir)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!("🗄️  ADZDB migrated format version {} to {}", step.from, step.to);
+
+        report.steps.push((step.from, step.to));
+        report.to_version = step.to;
+    }
+    Ok(report)
+}
+
+/// Whether a committed step is waiting to be renamed into place
+pub(crate) fn is_pending(dir: &Dir) -> bool {
+    dir.exists(&dir.join(MARKER))
+}
+
+/// Complete or discard a step interrupted by a crash
+///
+/// With the marker present the staged files are committed, so any that were
+/// not renamed yet are moved into place. Without it they are leftovers of a
+/// step that never committed.
+///
+/// The step to version 8 stages no data: its commit renames adzdb.dat to
+/// the first segment, before the metadata.
+pub(crate) fn finish_pending(dir: &Dir) -> Result<()> {
+    let marker = dir.join(MARKER);
+    if dir.exists(&marker) {
+        let staged_meta = staged(dir.path(), META_FILE);
+        let data_file = dir.join(DATA_V7_FILE);
+        if dir.exists(&staged_meta)
+            && dir.exists(&data_file)
+            && superblock::version(&dir.open_read(&staged_meta)?)? >= SEGMENTED_VERSION
+        {
+            dir.rename(&data_file, &dir.join(segment_name(0)))?;
+        }
+        for name in FILES {
+            let staged = staged(dir.path(), name);
+            if dir.exists(&staged) {
+                dir.rename(&staged, &dir.join(name))?;
+            }
+        }
+        dir.sync()?;
+        dir.remove_file(&marker)?;
+    } else {
+        for name in FILES {
+            dir.remove_if_exists(&staged(dir.path(), name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the staged replacement for a database file
+fn staged(path: &Path, name: &str) -> std::path::PathBuf {
+    path.join(format!("{}.migrate", name))
+}
+
+/// Versions 1 and 2 to 3: frame every value in a self-describing record
+/// header, and point the index at the new records
+///
+/// Version 1 stored bare values, and version 2 preceded each with its size
+/// and a CRC32C of the value. Records are appended back to back, so a record
+/// cut short at the end of the data file, or a zero-filled index entry, is a
+/// torn write: it is left out with everything after it, as recovery would.
+fn frame_records(dir: &Dir, meta: &mut [u8], framing: u64) -> Result<()> {
+    let source = dir.open_read(&dir.join(DATA_V7_FILE))?;
+    let data_len = source.len()?;
+    let index_file = dir.open_read(&dir.join("adzdb.idx"))?;
+    let (raw_index, _) = Database::read_records::<INDEX_V7_SIZE>(&index_file, 0)?;
+
+    let mut data = BufWriter::new(dir.create(&staged(dir.path(), DATA_V7_FILE))?);
+    let mut entries = Vec::with_capacity(raw_index.len());
+    let mut old_end = 0;
+    let mut offset = 0;
+    for raw in &raw_index {
+        let old = v7_entry(raw);
+        let end = old.offset + framing + old.size as u64;
+        if end > data_len || raw.iter().all(|&b| b == 0) {
+            break;
+        }
+        if old.offset != old_end {
+            return Err(Error::Corruption(format!(
+                "Index entry {} for key {} points to offset {}, expected {}",
+                entries.len(),
+                to_hex(&old.key),
+                old.offset,
+                old_end
+            )));
+        }
+
+        let mut value = vec![0u8; old.size as usize];
+        read_exact_at(&source, &mut value, old.offset + framing)?;
+        if framing > 0 {
+            let mut header = [0u8; 8];
+            read_exact_at(&source, &mut header, old.offset)?;
+            let size = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if size != old.size || checksum != crc32c(&value) {
+                return Err(Error::Corruption(format!(
+                    "Checksum mismatch for key {} at offset {}",
+                    to_hex(&old.key),
+                    old.offset
+                )));
+            }
+        }
+
+        let entry = IndexEntry {
+            key: old.key,
+            offset,
+            size: old.size,
+            height: old.height,
+            ..IndexEntry::default()
+        };
+        data.write_all(&RecordHeader::new(&entry.key, entry.height, &value).to_bytes())?;
+        data.write_all(&value)?;
+        offset = entry.record_end();
+        old_end = end;
+        entries.push(entry);
+    }
+    let data = data.into_inner().map_err(|e| Error::Io(e.into_error()))?;
+    data.sync_all()?;
+
+    let index: Vec<u8> = entries.iter().flat_map(|entry| entry.to_bytes()[..INDEX_V7_SIZE].to_vec()).collect();
+    write_synced(dir, &staged(dir.path(), "adzdb.idx"), &index)?;
+
+    let data_size: u64 = entries.iter().map(|entry| entry.size as u64).sum();
+    meta[8..16].copy_from_slice(&(entries.len() as u64).to_le_bytes());
+    meta[16..24].copy_from_slice(&data_size.to_le_bytes());
+    Ok(())
+}
+
+#[allow(clippy::ptr_arg)] // the signature shared by every step
+fn frame_bare_values(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    frame_records(dir, meta, 0)
+}
+
+#[allow(clippy::ptr_arg)]
+fn frame_checked_values(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    frame_records(dir, meta, 8)
+}
+
+/// Version 3 to 4: record the committed length of the height log, which
+/// then was adzdb.hgt; every whole entry in it was written by a finished put
+fn add_height_len(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    let len = dir.len(&dir.join(HEIGHT_FILE))?;
+    meta.resize(META_V3_SIZE, 0);
+    meta.extend_from_slice(&(len - len % HeightEntry::SIZE as u64).to_le_bytes());
+    Ok(())
+}
+
+/// Version 4 to 5: record the synced length of adzdb.idx
+fn add_index_len(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    let len = dir.len(&dir.join("adzdb.idx"))?;
+    meta.resize(META_V4_SIZE, 0);
+    meta.extend_from_slice(&(len - len % INDEX_V7_SIZE as u64).to_le_bytes());
+    Ok(())
+}
+
+/// Version 5 to 6: make the log of height entries in adzdb.hgt the journal,
+/// replayed in full over an empty, dense adzdb.hgt
+///
+/// The key file, whose checkpoint refers to the log, is removed; the open
+/// that follows rebuilds both. The committed log length carries over as the
+/// committed journal length.
+fn split_height_log(dir: &Dir, _meta: &mut Vec<u8>) -> Result<()> {
+    dir.copy(&dir.join(HEIGHT_FILE), &staged(dir.path(), HEIGHT_LOG))?;
+    write_synced(dir, &staged(dir.path(), HEIGHT_FILE), &[])?;
+    dir.remove_if_exists(&dir.join(KEY_FILE))?;
+    Ok(())
+}
+
+/// Version 6 to 7: move the metadata into the first of two checksummed
+/// slots, so a commit torn by a crash leaves the previous one readable
+fn double_buffer_metadata(_dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    let bytes = meta
+        .get(..META_V8_SIZE)
+        .ok_or_else(|| Error::Corruption("Metadata file too small".to_string()))?;
+    *meta = superblock::encode_bytes(bytes);
+    Ok(())
+}
+
+/// Version 7 to 8: make adzdb.dat the first data segment, and give every
+/// index entry the segment its record is in
+///
+/// Offsets into adzdb.dat are offsets into the first segment, so each entry
+/// only gains a zero segment number. A torn entry at the end of adzdb.idx is
+/// left out. The key file, whose pages and checkpoint hold index entries, is
+/// removed once an interrupted checkpoint has been rolled back; the open
+/// that follows rebuilds it.
+#[allow(clippy::ptr_arg)]
+fn segment_data_file(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    let index_file = dir.open_read(&dir.join("adzdb.idx"))?;
+    let (raw_index, _) = Database::read_records::<INDEX_V7_SIZE>(&index_file, 0)?;
+    let index: Vec<u8> = raw_index.iter().flat_map(|raw| v7_entry(raw).to_bytes()).collect();
+    write_synced(dir, &staged(dir.path(), "adzdb.idx"), &index)?;
+
+    let index_len = u64::from_le_bytes(meta[104..112].try_into().unwrap());
+    let index_len = index_len / INDEX_V7_SIZE as u64 * IndexEntry::SIZE as u64;
+    meta[104..112].copy_from_slice(&index_len.to_le_bytes());
+
+    drop(KeyFile::open(dir)?);
+    dir.remove_if_exists(&dir.join(KEY_FILE))?;
+    Ok(())
+}
+
+/// Version 8 to 9: add `logical_size` to the metadata, the size of the live
+/// values before compression
+///
+/// Every value so far is stored as is, so it starts out equal to the data
+/// size. The key file, whose checkpoint holds it too, is removed once an
+/// interrupted checkpoint has been rolled back; the open that follows
+/// rebuilds it.
+fn add_logical_size(dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    let data_size: [u8; 8] = meta[16..24].try_into().unwrap();
+    meta.truncate(META_V8_SIZE);
+    meta.extend_from_slice(&data_size);
+
+    drop(KeyFile::open(dir)?);
+    dir.remove_if_exists(&dir.join(KEY_FILE))?;
+    Ok(())
+}
+
+/// Version 9 to 10: add `journal_generation` to the metadata, counting from
+/// the journal as it is
+fn add_journal_generation(_dir: &Dir, meta: &mut Vec<u8>) -> Result<()> {
+    meta.resize(META_V9_SIZE, 0);
+    meta.extend_from_slice(&0u64.to_le_bytes());
+    Ok(())
+}
+
+/// Decode an index entry of version 7 or earlier, which had no segment
+fn v7_entry(raw: &[u8; INDEX_V7_SIZE]) -> IndexEntry {
+    let mut bytes = [0u8; IndexEntry::SIZE];
+    bytes[..INDEX_V7_SIZE].copy_from_slice(raw);
+    IndexEntry::from_bytes(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::fs;
+
+    /// Write a database in the version 1 or 2 layout: bare or size and
+    /// checksum framed values, and adzdb.hgt as a log of height entries
+    fn write_old_database(path: &Path, version: u32, blocks: &[([u8; 32], u64, &[u8])]) {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+
+        let (mut data, mut index, mut heights) = (Vec::new(), Vec::new(), Vec::new());
+        for (key, height, value) in blocks {
+            let entry = IndexEntry {
+                key: *key,
+                offset: data.len() as u64,
+                size: value.len() as u32,
+                height: *height,
+                ..IndexEntry::default()
+            };
+            if version == 2 {
+                data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                data.extend_from_slice(&crc32c(value).to_le_bytes());
+            }
+            data.extend_from_slice(value);
+            index.extend_from_slice(&entry.to_bytes()[..INDEX_V7_SIZE]);
+            heights.extend_from_slice(&HeightEntry { height: *height, hash: *key }.to_bytes());
+        }
+
+        let (tip_key, tip_height, _) = blocks.last().unwrap();
+        let metadata = Metadata {
+            version,
+            entry_count: blocks.len() as u64,
+            data_size: blocks.iter().map(|(_, _, value)| value.len() as u64).sum(),
+            latest_height: *tip_height,
+            latest_hash: *tip_key,
+            genesis_hash: blocks[0].0,
+            ..Metadata::default()
+        };
+        fs::write(path.join("adzdb.dat"), &data).unwrap();
+        fs::write(path.join("adzdb.idx"), &index).unwrap();
+        fs::write(path.join(HEIGHT_FILE), &heights).unwrap();
+        fs::write(path.join("adzdb.meta"), &metadata.to_bytes()[..META_V3_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_from_version_1() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-v1");
+        write_old_database(
+            &temp_dir,
+            1,
+            &[([0u8; 32], 0, b"genesis"), ([1u8; 32], 1, b"block 1"), ([2u8; 32], 2, b"block 2")],
+        );
+
+        // Neither a read-only open nor a rebuild may misread the old files
+        let read_only = Database::open(Config::new(&temp_dir).with_read_only(true));
+        assert!(matches!(read_only, Err(Error::MigrationRequired(1))));
+
+        let report = upgrade(&temp_dir).unwrap();
+        assert_eq!((report.from_version, report.to_version), (1, VERSION));
+        assert_eq!(report.steps, vec![(1, 3), (3, 4), (4, 5), (5, 6), (6, 7), (7, 8), (8, 9), (9, 10)]);
+        assert!(upgrade(&temp_dir).unwrap().is_noop());
+        assert!(!temp_dir.join(MARKER).exists());
+        assert!(!temp_dir.join(DATA_V7_FILE).exists());
+        assert!(temp_dir.join(segment_name(0)).exists());
+
+        let mut db = Database::open(Config::new(&temp_dir)).unwrap();
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.entry_count(), 3);
+        assert_eq!(db.latest_hash(), [2u8; 32]);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+        db.put(&[3u8; 32], 3, b"block 3").unwrap();
+        assert_eq!(db.get(&[3u8; 32]).unwrap(), b"block 3");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_upgrade_adds_logical_size() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-v8");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let metadata = {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            db.put(&[1u8; 32], 1, b"block 1").unwrap();
+            let metadata = db.state.read().metadata.clone();
+            metadata
+        };
+
+        // The metadata of version 8 ends before `logical_size`
+        let mut meta = metadata.to_bytes()[..META_V8_SIZE].to_vec();
+        meta[4..8].copy_from_slice(&8u32.to_le_bytes());
+        fs::write(temp_dir.join(META_FILE), superblock::encode_bytes(&meta)).unwrap();
+        let read_only = Database::open(config.clone().with_read_only(true));
+        assert!(matches!(read_only, Err(Error::MigrationRequired(8))));
+
+        let report = upgrade(&temp_dir).unwrap();
+        assert_eq!(report.steps, vec![(8, 9), (9, 10)]);
+        assert!(!temp_dir.join(KEY_FILE).exists());
+
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.stats().data_size, 14);
+        assert_eq!(db.stats().logical_size, 14);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_upgrade_adds_journal_generation() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-v9");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let metadata = {
+            let mut db = Database::create(config.clone()).unwrap();
+            db.put(&[0u8; 32], 0, b"genesis").unwrap();
+            let metadata = db.state.read().metadata.clone();
+            metadata
+        };
+
+        // The metadata of version 9 ends before `journal_generation`
+        let mut meta = metadata.to_bytes()[..META_V9_SIZE].to_vec();
+        meta[4..8].copy_from_slice(&9u32.to_le_bytes());
+        fs::write(temp_dir.join(META_FILE), superblock::encode_bytes(&meta)).unwrap();
+        let read_only = Database::open(config.clone().with_read_only(true));
+        assert!(matches!(read_only, Err(Error::MigrationRequired(9))));
+
+        assert_eq!(upgrade(&temp_dir).unwrap().steps, vec![(9, 10)]);
+        let db = Database::open(config).unwrap();
+        assert_eq!(db.state.read().metadata.journal_generation, 0);
+        assert_eq!(db.get_by_height(0).unwrap(), b"genesis");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_upgrade_into_keeps_source() {
+        let source = std::env::temp_dir().join("adzdb-test-migrate-source");
+        let dest = std::env::temp_dir().join("adzdb-test-migrate-dest");
+        let _ = fs::remove_dir_all(&dest);
+        write_old_database(&source, 2, &[([0u8; 32], 0, b"genesis"), ([1u8; 32], 1, b"block 1")]);
+
+        // A torn index entry at the end is left out
+        let mut index = fs::OpenOptions::new().append(true).open(source.join("adzdb.idx")).unwrap();
+        let torn = IndexEntry { key: [2u8; 32], offset: 1 << 20, ..IndexEntry::default() };
+        index.write_all(&torn.to_bytes()[..INDEX_V7_SIZE]).unwrap();
+        drop(index);
+        let before = fs::read(source.join("adzdb.dat")).unwrap();
+
+        let report = upgrade_into(&source, &dest).unwrap();
+        assert_eq!((report.from_version, report.to_version), (2, VERSION));
+        assert_eq!(fs::read(source.join("adzdb.dat")).unwrap(), before);
+        assert_eq!(format_version(&source).unwrap(), 2);
+        assert!(matches!(upgrade_into(&source, &dest), Err(Error::AlreadyExists)));
+
+        let db = Database::open(Config::new(&dest)).unwrap();
+        assert_eq!(db.entry_count(), 2);
+        assert_eq!(db.get(&[1u8; 32]).unwrap(), b"block 1");
+        assert!(!db.contains(&[2u8; 32]).unwrap());
+
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-unknown");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        drop(Database::create(config.clone()).unwrap());
+
+        // A future format is refused rather than misread
+        let meta_path = temp_dir.join("adzdb.meta");
+        let mut meta = Metadata::default().to_bytes();
+        meta[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&meta_path, superblock::encode_bytes(&meta)).unwrap();
+        assert!(matches!(Metadata::from_bytes(&meta), Err(Error::UnsupportedVersion(_))));
+        assert!(matches!(Database::open(config.clone()), Err(Error::UnsupportedVersion(v)) if v == VERSION + 1));
+        assert!(matches!(upgrade(&temp_dir), Err(Error::UnsupportedVersion(_))));
+
+        // As is a damaged version number, whatever its checksum
+        meta[4..8].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&meta_path, meta).unwrap();
+        assert!(matches!(Database::open(config), Err(Error::UnsupportedVersion(0))));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_interrupted_step_is_completed_on_open() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-crash");
+        write_old_database(&temp_dir, 1, &[([0u8; 32], 0, b"genesis"), ([1u8; 32], 1, b"block 1")]);
+
+        // Staged files without the marker are discarded and the step redone
+        let mut meta = fs::read(temp_dir.join("adzdb.meta")).unwrap();
+        frame_bare_values(&Dir::os(&temp_dir), &mut meta).unwrap();
+        assert!(staged(&temp_dir, "adzdb.dat").exists());
+        assert_eq!(format_version(&temp_dir).unwrap(), 1);
+
+        // With it, a step that crashed after the first rename is completed
+        meta[4..8].copy_from_slice(&3u32.to_le_bytes());
+        write_synced(&Dir::os(&temp_dir), &staged(&temp_dir, "adzdb.meta"), &meta).unwrap();
+        fs::write(temp_dir.join(MARKER), b"").unwrap();
+        fs::rename(staged(&temp_dir, "adzdb.dat"), temp_dir.join("adzdb.dat")).unwrap();
+
+        let db = Database::open(Config::new(&temp_dir)).unwrap();
+        assert!(!temp_dir.join(MARKER).exists());
+        assert!(!staged(&temp_dir, "adzdb.idx").exists());
+        assert_eq!(db.state.read().metadata.version, VERSION);
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_interrupted_segmenting_is_completed_on_open() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-migrate-segments");
+        write_old_database(&temp_dir, 1, &[([0u8; 32], 0, b"genesis"), ([1u8; 32], 1, b"block 1")]);
+        upgrade(&temp_dir).unwrap();
+
+        // The step to version 8 committed, but crashed before adzdb.dat
+        // became the first segment
+        fs::rename(temp_dir.join(segment_name(0)), temp_dir.join(DATA_V7_FILE)).unwrap();
+        fs::copy(temp_dir.join(META_FILE), staged(&temp_dir, META_FILE)).unwrap();
+        fs::write(temp_dir.join(MARKER), b"").unwrap();
+
+        let db = Database::open(Config::new(&temp_dir)).unwrap();
+        assert!(!temp_dir.join(MARKER).exists());
+        assert!(!temp_dir.join(DATA_V7_FILE).exists());
+        assert_eq!(db.get_by_height(1).unwrap(), b"block 1");
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
@@ -0,0 +1,66 @@
+//! Memory-mapped reads of the data segments (`mmap` feature)
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+use memmap2::Mmap;
+
+/// Read-only mappings of the data segments
+///
+/// Records are never rewritten in place, so a mapping stays valid for every
+/// live record it covers. Each segment is mapped separately; when a record
+/// lies past the end of the newest mapping of its segment, because the
+/// segment grew, it is mapped again. Older mappings are kept alive, since
+/// slices borrowed from them may still be in use, until `release` or `clear`
+/// runs with exclusive access.
+///
+/// Only a writable database maps its segments. It holds the lock, so no
+/// other process can truncate a segment under a mapping, which would make
+/// reading it fault. A read-only database may share the directory with a
+/// writer in another process, and reads copies instead.
+#[derive(Default)]
+pub(crate) struct DataMap {
+    /// Mappings of each segment, oldest first
+    maps: Mutex<HashMap<u32, Vec<Mmap>>>,
+}
+
+impl DataMap {
+    /// Borrow `len` bytes of a segment, open as `file`, starting at `offset`
+    pub(crate) fn slice(&self, file: &std::fs::File, segment: u32, offset: u64, len: usize) -> io::Result<&[u8]> {
+        let end = offset + len as u64;
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        let maps = maps.entry(segment).or_default();
+        if maps.last().map_or(true, |map| (map.len() as u64) < end) {
+            // SAFETY: only a writable database maps its segments, and it
+            // holds the lock, so no other process writes them. It never
+            // modifies bytes that are already in a segment while it is
+            // mapped; it only appends, and truncates after `clear` has
+            // dropped every mapping.
+            let map = unsafe { Mmap::map(file)? };
+            if (map.len() as u64) < end {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            maps.push(map);
+        }
+
+        let bytes = &maps.last().unwrap()[offset as usize..end as usize];
+        // SAFETY: the mapping outlives the lock guard. It is only dropped by
+        // `release` or `clear`, which take `&mut self` and so cannot run
+        // while the returned slice borrows `self`.
+        Ok(unsafe { std::slice::from_raw_parts(bytes.as_ptr(), len) })
+    }
+
+    /// Drop every mapping except the newest of each segment
+    pub(crate) fn release(&mut self) {
+        for maps in self.maps.get_mut().unwrap_or_else(|e| e.into_inner()).values_mut() {
+            let stale = maps.len().saturating_sub(1);
+            maps.drain(..stale);
+        }
+    }
+
+    /// Drop every mapping, before the data is truncated
+    pub(crate) fn clear(&mut self) {
+        self.maps.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
@@ -0,0 +1,541 @@
+//! Segmented data files: adzdb.00000.dat, adzdb.00001.dat, ...
+//!
+//! Records are not kept in one file that grows forever, but in numbered
+//! segments. They are appended to the newest segment, the active one, until
+//! the next record would take it past `Config::segment_size`; that record
+//! starts a new segment instead. A record is never split, so one larger than
+//! the limit gets a segment of its own. Every index entry names the segment
+//! its record is in and the offset within it.
+//!
+//! Starting a segment seals the one before it. The sealed segment is synced,
+//! and once a commit covers a record in a later segment it is never written
+//! again: crash recovery only cuts off records no commit covers, and cutting
+//! blocks off the tail stays within the active segment. A sealed segment can
+//! be archived, checksummed or deleted as one unit.
+//!
+//! Pruning deletes the oldest segments once every value in them is pruned.
+//! The segments then no longer start at 0, so the number of the first one is
+//! kept in adzdb.first; without that file they start at 0.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::vfs::{Dir, File, OpenOptions};
+use crate::{to_hex, Database, Error, IndexEntry, Result};
+
+/// File holding the number of the first segment, once pruning has removed
+/// the ones before it
+pub(crate) const FIRST_FILE: &str = "adzdb.first";
+
+/// Name of the file holding a segment
+pub(crate) fn segment_name(segment: u32) -> String {
+    format!("adzdb.{:05}.dat", segment)
+}
+
+/// Number of the first segment of the database in `dir`
+///
+/// # Errors
+///
+/// Returns `Error::Corruption` if adzdb.first does not hold a segment number.
+pub(crate) fn first_segment(dir: &Dir) -> Result<u32> {
+    let path = dir.join(FIRST_FILE);
+    if !dir.exists(&path) {
+        return Ok(0);
+    }
+    let bytes = dir.read(&path)?;
+    let bytes: [u8; 4] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::Corruption(format!("{} holds {} bytes, expected 4", FIRST_FILE, bytes.len())))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// A place in the data: a segment and an offset into it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Position {
+    pub(crate) segment: u32,
+    pub(crate) offset: u64,
+}
+
+impl Position {
+    /// Where a record of `len` bytes appended here goes: right here, or at
+    /// the start of the next segment if this one holds records already and
+    /// the record would take it past `limit`
+    pub(crate) fn place(self, len: u64, limit: u64) -> Self {
+        if self.offset > 0 && self.offset + len > limit {
+            self.next_segment()
+        } else {
+            self
+        }
+    }
+
+    /// Whether a record at `next` can directly follow data ending here:
+    /// records are appended back to back, each where the one before ended or
+    /// at the start of the next segment
+    pub(crate) fn is_followed_by(self, next: Position) -> bool {
+        next == self || next == self.next_segment()
+    }
+
+    fn next_segment(self) -> Self {
+        Self {
+            segment: self.segment.wrapping_add(1),
+            offset: 0,
+        }
+    }
+}
+
+/// Records laid out for appending, grouped into one run of bytes per
+/// segment they go in
+#[derive(Default)]
+pub(crate) struct Records {
+    chunks: Vec<(Position, Vec<u8>)>,
+}
+
+impl Records {
+    /// The buffer to write a record placed at `at` into, which must follow
+    /// the records added before
+    pub(crate) fn at(&mut self, at: Position) -> &mut Vec<u8> {
+        if self.chunks.last().map_or(true, |(start, _)| start.segment != at.segment) {
+            self.chunks.push((at, Vec::new()));
+        }
+        let (_, bytes) = self.chunks.last_mut().expect("a chunk was just pushed");
+        bytes
+    }
+
+    /// Every run of bytes with where it starts, in order
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = (Position, &[u8])> {
+        self.chunks.iter().map(|(at, bytes)| (*at, bytes.as_slice()))
+    }
+}
+
+/// Sealed segments kept open at once; the others are opened again when they
+/// are read, so a database with thousands of segments does not run out of
+/// file descriptors
+const OPEN_SEALED: usize = 64;
+
+/// The segments of a database, oldest first; the last is the active one
+///
+/// The active segment is always open. Sealed segments are opened when they
+/// are read, through a cache of the ones read lately that clones share.
+/// Before this process removes or replaces segment files while a clone
+/// still reads them, every sealed segment is opened for the clones to keep,
+/// so a snapshot keeps reading the segments it was taken with, whatever
+/// happens to them on disk afterwards. Segments replaced by another process
+/// are only found out by the record checks.
+#[derive(Clone)]
+pub(crate) struct Segments {
+    dir: Dir,
+    /// Number of the first segment
+    first: u32,
+    /// Number of the active segment
+    last: u32,
+    active: Arc<File>,
+    sealed: Arc<Mutex<Handles>>,
+    writable: bool,
+}
+
+/// Open sealed segments, shared by a `Segments` and its clones
+#[derive(Default)]
+struct Handles {
+    /// The segments read lately, least recently used first
+    recent: Vec<(u32, Arc<File>)>,
+    /// Every sealed segment, once `retire` has run; no other is opened then
+    retired: Option<HashMap<u32, Arc<File>>>,
+}
+
+/// A segment started after the active one, which `extend` makes active
+pub(crate) struct Started {
+    segment: u32,
+    file: Arc<File>,
+}
+
+impl Segments {
+    /// Create the first segment of a new database
+    pub(crate) fn create(dir: &Dir) -> Result<Self> {
+        let path = dir.join(segment_name(0));
+        let file = dir.open(&path, OpenOptions::new().read(true).create(true).append(true))?;
+        Ok(Self {
+            dir: dir.clone(),
+            first: 0,
+            last: 0,
+            active: Arc::new(file),
+            sealed: Arc::default(),
+            writable: true,
+        })
+    }
+
+    /// Open the database in `dir`, whose segments run from the first up to
+    /// the last one that exists
+    pub(crate) fn open(dir: &Dir, writable: bool) -> Result<Self> {
+        let first = first_segment(dir)?;
+        let mut last = first;
+        while dir.exists(&dir.join(segment_name(last + 1))) {
+            last += 1;
+        }
+        let active = dir.open(&dir.join(segment_name(last)), OpenOptions::new().read(true).append(writable))?;
+        Ok(Self {
+            dir: dir.clone(),
+            first,
+            last,
+            active: Arc::new(active),
+            sealed: Arc::default(),
+            writable,
+        })
+    }
+
+    fn open_file(&self, segment: u32) -> io::Result<File> {
+        self.dir.open(&self.path(segment), OpenOptions::new().read(true).append(self.writable))
+    }
+
+    fn path(&self, segment: u32) -> PathBuf {
+        self.dir.join(segment_name(segment))
+    }
+
+    fn handles(&self) -> MutexGuard<'_, Handles> {
+        self.sealed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Open the last of the segments past the active one, which another
+    /// process has started since, if there are any; `extend` adds them
+    pub(crate) fn open_started(&self) -> Result<Option<Started>> {
+        let mut segment = self.last;
+        while self.dir.exists(&self.path(segment + 1)) {
+            segment += 1;
+        }
+        if segment == self.last {
+            return Ok(None);
+        }
+        let file = Arc::new(self.open_file(segment)?);
+        Ok(Some(Started { segment, file }))
+    }
+
+    /// Add the segments up to one opened by `open_started` or
+    /// `create_next`, which becomes the active one
+    pub(crate) fn extend(&mut self, started: Started) {
+        let sealed = std::mem::replace(&mut self.active, started.file);
+        let mut handles = self.handles();
+        match &mut handles.retired {
+            Some(retired) => {
+                retired.insert(self.last, sealed);
+            }
+            None => {
+                handles.recent.push((self.last, sealed));
+                if handles.recent.len() > OPEN_SEALED {
+                    handles.recent.remove(0);
+                }
+            }
+        }
+        drop(handles);
+        self.last = started.segment;
+    }
+
+    /// Number of the first segment; the ones before it were pruned
+    pub(crate) fn first(&self) -> u32 {
+        self.first
+    }
+
+    /// Number of the active segment
+    pub(crate) fn last(&self) -> u32 {
+        self.last
+    }
+
+    /// The file of a segment, opened if it is sealed and not open, or `None`
+    /// if it is not one of these segments
+    pub(crate) fn file(&self, segment: u32) -> Result<Option<Arc<File>>> {
+        if segment == self.last {
+            return Ok(Some(self.active.clone()));
+        }
+        if segment < self.first || segment > self.last {
+            return Ok(None);
+        }
+
+        let mut handles = self.handles();
+        if let Some(retired) = &handles.retired {
+            return Ok(retired.get(&segment).cloned());
+        }
+        if let Some(at) = handles.recent.iter().position(|(number, _)| *number == segment) {
+            let entry = handles.recent.remove(at);
+            let file = entry.1.clone();
+            handles.recent.push(entry);
+            return Ok(Some(file));
+        }
+        let file = match self.open_file(segment) {
+            Ok(file) => Arc::new(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if handles.recent.len() == OPEN_SEALED {
+            handles.recent.remove(0);
+        }
+        handles.recent.push((segment, file.clone()));
+        Ok(Some(file))
+    }
+
+    /// The file of one of these segments
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::Corruption` if the segment is not there.
+    pub(crate) fn get(&self, segment: u32) -> Result<Arc<File>> {
+        self.file(segment)?
+            .ok_or_else(|| Error::Corruption(format!("{} is missing", segment_name(segment))))
+    }
+
+    /// Every segment number, oldest first
+    pub(crate) fn numbers(&self) -> RangeInclusive<u32> {
+        self.first..=self.last
+    }
+
+    /// Where the data ends: the end of the active segment
+    pub(crate) fn end(&self) -> Result<Position> {
+        Ok(Position {
+            segment: self.last,
+            offset: self.active.len()?,
+        })
+    }
+
+    /// Whether the data reaches `position`; it reached every position in
+    /// the pruned segments
+    pub(crate) fn covers(&self, position: Position) -> Result<bool> {
+        match self.file(position.segment)? {
+            Some(file) => Ok(position.offset <= file.len()?),
+            None => Ok(position.segment < self.first),
+        }
+    }
+
+    /// Whether the whole record of `entry` lies within its segment
+    pub(crate) fn contains(&self, entry: &IndexEntry) -> Result<bool> {
+        match self.file(entry.segment)? {
+            Some(file) => Ok(entry.record_end() <= file.len()?),
+            None => Ok(false),
+        }
+    }
+
+    /// Bytes of data from `position` to the end of the active segment
+    pub(crate) fn len_after(&self, position: Position) -> Result<u64> {
+        let mut len = 0;
+        for segment in position.segment.max(self.first)..=self.last {
+            let start = if segment == position.segment { position.offset } else { 0 };
+            len += self.get(segment)?.len()?.saturating_sub(start);
+        }
+        Ok(len)
+    }
+
+    /// Total size of the segments in bytes
+    pub(crate) fn len(&self) -> Result<u64> {
+        self.len_after(Position {
+            segment: self.first,
+            offset: 0,
+        })
+    }
+
+    /// The file holding the record of `entry`
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::Corruption` if the segment is not there.
+    pub(crate) fn file_of(&self, entry: &IndexEntry) -> Result<Arc<File>> {
+        self.file(entry.segment)?.ok_or_else(|| {
+            Error::Corruption(format!(
+                "Record for key {} is in segment {}, which is missing",
+                to_hex(&entry.key),
+                entry.segment
+            ))
+        })
+    }
+
+    /// Append records to the end of the active segment
+    pub(crate) fn append(&self, segment: u32, records: &[u8]) -> Result<()> {
+        if segment != self.last {
+            return Err(Error::Corruption(format!(
+                "Cannot append to segment {}, which is not the active one",
+                segment
+            )));
+        }
+        (&*self.active).write_all(records)?;
+        Ok(())
+    }
+
+    /// Seal the active segment and create the one after it, which `extend`
+    /// then makes active
+    ///
+    /// The sealed segment is synced, since commits only sync the active one,
+    /// and so is the directory, so the new file outlives a crash once a
+    /// commit covers a record in it.
+    pub(crate) fn create_next(&self) -> Result<Started> {
+        self.active.sync_all()?;
+        let segment = self.last + 1;
+        let file = self.dir.open(&self.path(segment), OpenOptions::new().read(true).create(true).append(true))?;
+        self.dir.sync()?;
+        Ok(Started {
+            segment,
+            file: Arc::new(file),
+        })
+    }
+
+    /// Make the active segment durable; sealed ones were synced when they
+    /// were sealed
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.active.sync_all()?;
+        Ok(())
+    }
+
+    /// Cut the data back to `end`: the segments after its segment are
+    /// removed, and that one becomes the active segment, truncated
+    pub(crate) fn truncate(&mut self, end: Position) -> Result<()> {
+        let last = end.segment.max(self.first);
+        if self.last > last {
+            self.detach()?;
+            let removed = self.last;
+            self.active = Arc::new(self.open_file(last)?);
+            self.last = last;
+            for segment in (last + 1..=removed).rev() {
+                self.dir.remove_if_exists(&self.path(segment))?;
+            }
+        }
+        if self.last == end.segment {
+            self.active.set_len(end.offset)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the segments before `first`, which must not hold a value any
+    /// index entry still needs
+    ///
+    /// adzdb.first is replaced before any segment goes, so a crash leaves at
+    /// worst segments before the first one, which are never opened. They are
+    /// removed lowest first, so any left over by a crash stay numbered right
+    /// before the first segment, where the next call finds them. Clones keep
+    /// the removed segments open, so snapshots can still read them.
+    pub(crate) fn remove_before(&mut self, first: u32) -> Result<()> {
+        let first = first.min(self.last);
+        if first <= self.first {
+            return Ok(());
+        }
+        self.detach()?;
+        Database::replace_file(&self.dir, &self.dir.join(FIRST_FILE), &first.to_le_bytes())?;
+        self.dir.sync()?;
+        self.first = first;
+
+        let mut start = first;
+        while start > 0 && self.dir.exists(&self.path(start - 1)) {
+            start -= 1;
+        }
+        for segment in start..first {
+            self.dir.remove_file(&self.path(segment))?;
+        }
+        self.dir.sync()?;
+        Ok(())
+    }
+
+    /// Before segment files are replaced, open every sealed segment and
+    /// keep it open, for this view and every clone sharing its cache
+    ///
+    /// They then keep reading the files they were opened with until they are
+    /// dropped, rather than open what is there by then.
+    pub(crate) fn retire(&self) -> Result<()> {
+        let mut handles = self.handles();
+        if handles.retired.is_none() {
+            let mut retired: HashMap<u32, Arc<File>> = handles.recent.drain(..).collect();
+            for segment in self.first..self.last {
+                if let Entry::Vacant(entry) = retired.entry(segment) {
+                    entry.insert(Arc::new(self.open_file(segment)?));
+                }
+            }
+            handles.retired = Some(retired);
+        }
+        Ok(())
+    }
+
+    /// Before segment files are removed, leave the cache to the clones
+    /// sharing it, with every sealed segment open, and start another
+    fn detach(&mut self) -> Result<()> {
+        if Arc::strong_count(&self.sealed) == 1 {
+            self.handles().recent.clear();
+            return Ok(());
+        }
+        self.retire()?;
+        self.sealed = Arc::default();
+        Ok(())
+    }
+
+    /// Whether a snapshot or compactor still reads the active segment
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.active) > 1
+    }
+
+    /// Whether the active segment, and every sealed one open, is still the
+    /// file at its path, rather than one a compaction has replaced or
+    /// removed since
+    pub(crate) fn is_current(&self) -> Result<bool> {
+        let handles = self.handles();
+        let sealed = handles.recent.iter().map(|(segment, file)| (*segment, file));
+        for (segment, file) in sealed.chain([(self.last, &self.active)]) {
+            match file.is_same_file(&self.path(segment)) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_exact_at;
+    use crate::vfs::MemVfs;
+    use std::path::Path;
+
+    #[test]
+    fn test_records_roll_over_to_the_next_segment() {
+        let start = Position::default();
+        let at = |segment, offset| Position { segment, offset };
+
+        // An empty segment takes any record, even one past the limit
+        assert_eq!(start.place(500, 100), start);
+        assert_eq!(at(0, 60).place(40, 100), at(0, 60));
+        assert_eq!(at(0, 60).place(41, 100), at(1, 0));
+
+        assert!(at(0, 60).is_followed_by(at(0, 60)));
+        assert!(at(0, 60).is_followed_by(at(1, 0)));
+        assert!(!at(0, 60).is_followed_by(at(0, 61)));
+        assert!(!at(0, 60).is_followed_by(at(1, 60)));
+        assert!(!at(0, 60).is_followed_by(at(2, 0)));
+    }
+
+    #[test]
+    fn test_sealed_segments_open_on_demand() {
+        let dir = Dir::new(Arc::new(MemVfs::new()), Path::new("/segments"));
+        dir.create_dir_all().unwrap();
+        let mut segments = Segments::create(&dir).unwrap();
+        let count = 2 * OPEN_SEALED as u32;
+        for segment in 0..count {
+            segments.append(segment, &[segment as u8; 3]).unwrap();
+            let started = segments.create_next().unwrap();
+            segments.extend(started);
+        }
+        assert_eq!(segments.len().unwrap(), 3 * count as u64);
+        assert_eq!(segments.handles().recent.len(), OPEN_SEALED);
+        assert_eq!(Segments::open(&dir, false).unwrap().last(), count);
+
+        // A clone keeps reading the segments removed after it was taken
+        let snapshot = segments.clone();
+        segments.remove_before(count / 2).unwrap();
+        assert!(segments.file(0).unwrap().is_none());
+        assert!(segments.handles().recent.is_empty());
+        segments.truncate(Position { segment: 1, offset: 0 }).unwrap();
+        assert_eq!(segments.last(), count / 2);
+        let mut byte = [0u8; 1];
+        for segment in [0, count / 2 + 1, count - 1] {
+            read_exact_at(&snapshot.get(segment).unwrap(), &mut byte, 0).unwrap();
+            assert_eq!(byte, [segment as u8]);
+        }
+    }
+}
@@ -0,0 +1,859 @@
+//! Deterministic, fault-injecting in-memory filesystem for simulation tests
+//!
+//! [`SimVfs`] keeps every file in memory and tracks which of its bytes are
+//! durable: what was last synced, plus the writes and truncations made since.
+//! [`SimVfs::crash`] plays a power loss. Each write made since the last sync
+//! of its file survives whole, is lost, is torn at a sector boundary so only
+//! a prefix lands, or lands as zeros, the way a filesystem can extend a file
+//! before persisting its contents. Creating, renaming and removing files is
+//! atomic and survives a crash, as on a journaling filesystem.
+//!
+//! [`Faults`] make operations fail while the database runs: reads, writes
+//! and syncs return I/O errors, and reads return fewer bytes than asked for.
+//! Every choice is drawn from a generator seeded by [`SimVfs::new`], so a
+//! failing run replays exactly from its seed, as in TigerBeetle's simulator.
+//!
+//! # Example
+//!
+//! ```rust
+//! use adzdb::sim::SimVfs;
+//! use adzdb::{Config, Database};
+//! use std::sync::Arc;
+//!
+//! # fn main() -> adzdb::Result<()> {
+//! let vfs = Arc::new(SimVfs::new(7));
+//! let config = Config::new("/sim/chain").with_vfs(vfs.clone()).with_sync_on_write(false);
+//!
+//! let mut db = Database::create(config.clone())?;
+//! db.put(&[1u8; 32], 0, b"genesis")?;
+//! db.sync()?;
+//! db.put(&[2u8; 32], 1, b"block 1")?;
+//! drop(db);
+//!
+//! // The genesis block was synced; block 1 may or may not survive
+//! vfs.crash();
+//! let db = Database::open(config)?;
+//! assert_eq!(db.get(&[1u8; 32])?, b"genesis");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::vfs::{OpenOptions, Vfs, VfsFile};
+
+/// Size of the unit a torn write is cut at
+const SECTOR_SIZE: u64 = 512;
+
+/// How often operations fail while the database runs, each a probability
+/// from 0 to 1 (default: never)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    /// A read fails with an I/O error
+    pub read_error: f64,
+    /// A read returns fewer bytes than asked for, and at least one
+    pub short_read: f64,
+    /// A write fails with an I/O error, after an arbitrary prefix of it
+    /// reached the file
+    pub write_error: f64,
+    /// A sync fails with an I/O error, leaving the file as unsynced as before
+    pub sync_error: f64,
+}
+
+/// An in-memory filesystem that injects faults and simulates crashes
+///
+/// See the [module documentation](self).
+pub struct SimVfs {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    rng: Rng,
+    faults: Faults,
+    /// Incremented by every crash; handles opened before it are dead
+    epoch: u64,
+    names: BTreeMap<PathBuf, u64>,
+    dirs: BTreeSet<PathBuf>,
+    inodes: BTreeMap<u64, Inode>,
+    next_inode: u64,
+    /// Held locks, with the token of the guard holding each
+    locks: BTreeMap<PathBuf, u64>,
+    next_token: u64,
+}
+
+#[derive(Default)]
+struct Inode {
+    /// Contents as the running program sees them
+    data: Vec<u8>,
+    /// Contents as of the last sync
+    durable: Vec<u8>,
+    /// Changes since the last sync, oldest first
+    unsynced: Vec<Change>,
+}
+
+enum Change {
+    Write { offset: u64, bytes: Vec<u8> },
+    SetLen(u64),
+}
+
+impl SimVfs {
+    /// Create an empty filesystem whose faults and crashes are drawn from
+    /// `seed`
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rng: Rng(seed),
+                faults: Faults::default(),
+                epoch: 0,
+                names: BTreeMap::new(),
+                dirs: BTreeSet::new(),
+                inodes: BTreeMap::new(),
+                next_inode: 0,
+                locks: BTreeMap::new(),
+                next_token: 0,
+            })),
+        }
+    }
+
+    /// Set the faults injected from now on
+    pub fn set_faults(&self, faults: Faults) {
+        lock(&self.state).faults = faults;
+    }
+
+    /// Lose power
+    ///
+    /// Every file is reduced to its synced contents plus a random selection
+    /// of the changes made since, some of them torn or zeroed. Handles
+    /// opened before the crash fail from then on, and locks are released.
+    pub fn crash(&self) {
+        let mut state = lock(&self.state);
+        let state = &mut *state;
+        state.epoch += 1;
+        state.locks.clear();
+        let live: BTreeSet<u64> = state.names.values().copied().collect();
+        state.inodes.retain(|ino, _| live.contains(ino));
+        for inode in state.inodes.values_mut() {
+            let mut data = std::mem::take(&mut inode.durable);
+            for change in inode.unsynced.drain(..) {
+                match change {
+                    Change::SetLen(len) => {
+                        if state.rng.below(2) == 0 {
+                            data.resize(len as usize, 0);
+                        }
+                    }
+                    Change::Write { offset, mut bytes } => match state.rng.below(5) {
+                        0 | 1 => write(&mut data, offset, &bytes),
+                        2 => {}
+                        3 => {
+                            // A prefix up to a sector boundary inside the write
+                            let first = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                            let end = offset + bytes.len() as u64;
+                            if first < end {
+                                let sectors = (end - first + SECTOR_SIZE - 1) / SECTOR_SIZE;
+                                let cut = first + state.rng.below(sectors) * SECTOR_SIZE;
+                                bytes.truncate((cut - offset) as usize);
+                                write(&mut data, offset, &bytes);
+                            }
+                        }
+                        _ => {
+                            bytes.fill(0);
+                            write(&mut data, offset, &bytes);
+                        }
+                    },
+                }
+            }
+            inode.durable = data.clone();
+            inode.data = data;
+        }
+    }
+}
+
+impl fmt::Debug for SimVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = lock(&self.state);
+        f.debug_struct("SimVfs")
+            .field("files", &state.names.len())
+            .field("faults", &state.faults)
+            .field("epoch", &state.epoch)
+            .finish()
+    }
+}
+
+impl Vfs for SimVfs {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = lock(&self.state);
+        let ino = match state.names.get(path) {
+            Some(&ino) => ino,
+            None if options.create => {
+                if !path.parent().map_or(true, |parent| state.is_dir(parent)) {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+                let ino = state.next_inode;
+                state.next_inode += 1;
+                state.inodes.insert(ino, Inode::default());
+                state.names.insert(path.to_path_buf(), ino);
+                ino
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let file = SimFile {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+            ino,
+            epoch: state.epoch,
+            writable: options.write || options.append,
+        };
+        if options.truncate {
+            state.inode(ino).set_len(0);
+        }
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        let ino = state.names.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        state.names.insert(to.to_path_buf(), ino);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        state.names.remove(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = lock(&self.state);
+        state.names.contains_key(path) || state.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        if lock(&self.state).is_dir(path) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn fmt::Debug + Send + Sync>> {
+        let mut state = lock(&self.state);
+        if state.locks.contains_key(path) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let token = state.next_token;
+        state.next_token += 1;
+        state.locks.insert(path.to_path_buf(), token);
+        Ok(Box::new(SimLock {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+            token,
+        }))
+    }
+}
+
+impl State {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    fn inode(&mut self, ino: u64) -> &mut Inode {
+        self.inodes.entry(ino).or_default()
+    }
+
+    /// Whether an operation subject to a fault of probability `p` fails
+    fn fails(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.unit() < p
+    }
+}
+
+impl Inode {
+    fn write(&mut self, offset: u64, bytes: &[u8]) {
+        write(&mut self.data, offset, bytes);
+        self.unsynced.push(Change::Write {
+            offset,
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.data.resize(len as usize, 0);
+        self.unsynced.push(Change::SetLen(len));
+    }
+
+    fn sync(&mut self) {
+        self.durable = self.data.clone();
+        self.unsynced.clear();
+    }
+}
+
+/// An open file of a [`SimVfs`]
+struct SimFile {
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+    ino: u64,
+    epoch: u64,
+    writable: bool,
+}
+
+impl SimFile {
+    /// The filesystem, unless it crashed since the file was opened
+    fn state(&self) -> io::Result<MutexGuard<'_, State>> {
+        let state = lock(&self.state);
+        if state.epoch != self.epoch {
+            return Err(io::Error::new(io::ErrorKind::Other, "file handle outlived a simulated crash"));
+        }
+        Ok(state)
+    }
+
+    fn writable_state(&self) -> io::Result<MutexGuard<'_, State>> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file not opened for writing"));
+        }
+        self.state()
+    }
+
+    fn write_at_locked(&self, state: &mut State, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let faults = state.faults;
+        if state.fails(faults.write_error) {
+            let landed = state.rng.below(buf.len() as u64 + 1) as usize;
+            if landed > 0 {
+                state.inode(self.ino).write(offset, &buf[..landed]);
+            }
+            return Err(io_error());
+        }
+        state.inode(self.ino).write(offset, buf);
+        Ok(buf.len())
+    }
+}
+
+impl fmt::Debug for SimFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimFile").field("path", &self.path).field("ino", &self.ino).finish()
+    }
+}
+
+impl VfsFile for SimFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state()?;
+        let faults = state.faults;
+        if state.fails(faults.read_error) {
+            return Err(io_error());
+        }
+        let mut len = {
+            let data = &state.inode(self.ino).data;
+            let start = (offset as usize).min(data.len());
+            buf.len().min(data.len() - start)
+        };
+        if len > 1 && state.fails(faults.short_read) {
+            len = 1 + state.rng.below(len as u64 - 1) as usize;
+        }
+        let data = &state.inode(self.ino).data;
+        let start = (offset as usize).min(data.len());
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.writable_state()?;
+        self.write_at_locked(&mut state, buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.writable_state()?;
+        let offset = state.inode(self.ino).data.len() as u64;
+        self.write_at_locked(&mut state, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.state()?.inode(self.ino).data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.writable_state()?.inode(self.ino).set_len(len);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.state()?;
+        let faults = state.faults;
+        if state.fails(faults.sync_error) {
+            return Err(io_error());
+        }
+        state.inode(self.ino).sync();
+        Ok(())
+    }
+
+    fn is_same_file(&self, path: &Path) -> io::Result<bool> {
+        let state = self.state()?;
+        match state.names.get(path) {
+            Some(&ino) => Ok(ino == self.ino),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+/// A lock held on a [`SimVfs`] path, released when dropped
+struct SimLock {
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+    token: u64,
+}
+
+impl fmt::Debug for SimLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimLock").field("path", &self.path).finish()
+    }
+}
+
+impl Drop for SimLock {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+        // A crash may have released it, and someone else taken it since
+        if state.locks.get(&self.path) == Some(&self.token) {
+            state.locks.remove(&self.path);
+        }
+    }
+}
+
+/// A seeded SplitMix64 generator
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which must not be zero
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// A number in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write(data: &mut Vec<u8>, offset: u64, bytes: &[u8]) {
+    let end = offset as usize + bytes.len();
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    data[offset as usize..end].copy_from_slice(bytes);
+}
+
+fn io_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "simulated I/O error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::VerifyOptions;
+    use crate::{Compression, Config, Database, Error, Hash, Result, WriteBatch, ZERO_HASH};
+
+    /// A block whose key encodes its id and height, with a value derived
+    /// from the id
+    fn block(id: u64, height: u64) -> (Hash, u64, Vec<u8>) {
+        let mut key = [0u8; 32];
+        key[..8].copy_from_slice(&id.to_le_bytes());
+        key[8..16].copy_from_slice(&height.to_le_bytes());
+        let value = (0..id * 37 % 1500).map(|i| (i ^ id) as u8).collect();
+        (key, height, value)
+    }
+
+    /// What the test knows about the database between crashes
+    #[derive(Default)]
+    struct Model {
+        /// Every block ever written, by key
+        blocks: BTreeMap<Hash, (u64, Vec<u8>)>,
+        /// Blocks stored, as acknowledged so far
+        present: BTreeSet<Hash>,
+        /// Blocks stored as of the last acknowledged sync
+        durable: BTreeSet<Hash>,
+        /// Blocks added or removed since, which a crash may or may not keep
+        touched: BTreeSet<Hash>,
+        /// New blocks of batches that failed since, each kept whole or not
+        /// at all
+        failed_batches: Vec<Vec<Hash>>,
+        /// Blocks a prune may have reached, whose values may read as pruned
+        pruned: BTreeSet<Hash>,
+        next_id: u64,
+    }
+
+    impl Model {
+        fn new_block(&mut self, height: u64) -> (Hash, u64, Vec<u8>) {
+            self.next_id += 1;
+            let (key, height, value) = block(self.next_id, height);
+            self.blocks.insert(key, (height, value.clone()));
+            (key, height, value)
+        }
+
+        fn synced(&mut self) {
+            self.durable = self.present.clone();
+            self.touched.clear();
+            self.failed_batches.clear();
+        }
+
+        fn random_present(&self, rng: &mut Rng) -> Option<Hash> {
+            let len = self.present.len() as u64;
+            (len > 0).then(|| *self.present.iter().nth(rng.below(len) as usize).unwrap())
+        }
+    }
+
+    /// Run random operations with `faults` injected, until the budget runs
+    /// out; returns without the database being dropped cleanly
+    ///
+    /// Once an operation fails with an I/O error, the database is checked
+    /// against the model after every operation that follows.
+    fn run_ops(db: &mut Database, vfs: &SimVfs, faults: Faults, model: &mut Model, rng: &mut Rng, sync_on_write: bool) {
+        let mut failed = false;
+        for op in 0..rng.below(24) {
+            let tip = if db.entry_count() == 0 { 0 } else { db.latest_height() + 1 };
+            let result = match rng.below(100) {
+                0..=41 => {
+                    let (key, height, value) = model.new_block(tip);
+                    model.touched.insert(key);
+                    db.put(&key, height, &value).map(|_| {
+                        model.present.insert(key);
+                        sync_on_write
+                    })
+                }
+                42..=51 => {
+                    let (key, height, value) = model.new_block(rng.below(tip + 1));
+                    model.touched.insert(key);
+                    db.put(&key, height, &value).map(|_| {
+                        model.present.insert(key);
+                        sync_on_write
+                    })
+                }
+                52..=63 => {
+                    let Some(key) = model.random_present(rng) else { continue };
+                    match db.get(&key) {
+                        Ok(value) => {
+                            assert_eq!(value, model.blocks[&key].1);
+                            Ok(false)
+                        }
+                        Err(Error::Pruned) if model.pruned.contains(&key) => Ok(false),
+                        Err(e) => Err(e),
+                    }
+                }
+                64..=71 => {
+                    let Some(key) = model.random_present(rng) else { continue };
+                    model.touched.insert(key);
+                    db.delete(&key).map(|_| {
+                        model.present.remove(&key);
+                        sync_on_write
+                    })
+                }
+                72..=76 => {
+                    let height = rng.below(tip + 1);
+                    let above = model.present.iter().filter(|key| model.blocks[*key].0 > height);
+                    model.touched.extend(above.copied().collect::<Vec<_>>());
+                    // Nothing is committed when nothing is removed
+                    db.truncate_to_height(height).map(|removed| {
+                        for key in &removed {
+                            model.present.remove(key);
+                        }
+                        !removed.is_empty()
+                    })
+                }
+                77..=84 => {
+                    let mut batch = WriteBatch::new();
+                    let mut keys = Vec::new();
+                    for height in tip..tip + 1 + rng.below(4) {
+                        let (key, height, value) = model.new_block(height);
+                        batch.put(&key, height, &value);
+                        keys.push(key);
+                    }
+                    model.touched.extend(keys.iter().copied());
+                    match db.write(batch) {
+                        Ok(()) => {
+                            model.present.extend(keys);
+                            Ok(true)
+                        }
+                        Err(e) => {
+                            model.failed_batches.push(keys);
+                            Err(e)
+                        }
+                    }
+                }
+                85..=92 => {
+                    let Some(key) = model.random_present(rng) else { continue };
+                    let height = model.blocks[&key].0;
+                    if height == 0 || db.get_hash_by_height(height).ok() == Some(key) {
+                        continue;
+                    }
+                    match db.reorg(height - 1, &[key]) {
+                        Err(Error::InvalidReorg(_)) => Ok(false),
+                        result => result.map(|_| true),
+                    }
+                }
+                93..=95 => {
+                    let height = rng.below(tip + 1);
+                    let below = model.present.iter().filter(|key| model.blocks[*key].0 < height);
+                    model.pruned.extend(below.copied().collect::<Vec<_>>());
+                    db.prune_below(height).map(|report| report.blocks_pruned > 0 || report.segments_removed > 0)
+                }
+                96..=97 => {
+                    // A read fault while classifying fails the operation like
+                    // any other
+                    let side = model
+                        .present
+                        .iter()
+                        .filter_map(|&key| match db.get_hash_by_height(model.blocks[&key].0) {
+                            Ok(canonical) if canonical == key => None,
+                            Ok(_) | Err(Error::NotFound) => Some(Ok(key)),
+                            Err(e) => Some(Err(e)),
+                        })
+                        .collect::<Result<Vec<Hash>>>();
+                    side.and_then(|side| {
+                        model.touched.extend(side.iter().copied());
+                        db.compact().map(|_| {
+                            for key in side {
+                                model.present.remove(&key);
+                            }
+                            true
+                        })
+                    })
+                }
+                _ => db.sync().map(|_| true),
+            };
+            match result {
+                Ok(true) => model.synced(),
+                Ok(false) => {}
+                Err(Error::Io(_)) => failed = true,
+                // A failed write could not be cut back off the files
+                Err(Error::ReopenRequired) if failed => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+            if failed {
+                vfs.set_faults(Faults::default());
+                check_running(db, model, &format!("op {}", op));
+                vfs.set_faults(faults);
+            }
+        }
+    }
+
+    /// Check the reopened database against what was acknowledged before
+    /// the crash, and reset the model to what it holds
+    fn check_recovered(db: &Database, model: &mut Model, at: &str) {
+        model.present = check(db, model, &model.durable, at);
+        model.synced();
+    }
+
+    /// Check the running database against what was acknowledged so far,
+    /// after a failed operation left it unknown whether the blocks it
+    /// touched are stored, and bring the model up to date
+    fn check_running(db: &Database, model: &mut Model, at: &str) {
+        model.present = check(db, model, &model.present, at);
+    }
+
+    /// Check that the database holds `expected`, give or take the blocks
+    /// touched since the last sync, with consistent heights; returns the
+    /// blocks it holds
+    fn check(db: &Database, model: &Model, expected: &BTreeSet<Hash>, at: &str) -> BTreeSet<Hash> {
+        let mut present = BTreeSet::new();
+        for (key, (_, value)) in &model.blocks {
+            match db.get(key) {
+                Ok(stored) => {
+                    assert_eq!(&stored, value, "{}: block {:?} has the wrong value", at, &key[..16]);
+                    present.insert(*key);
+                }
+                Err(Error::Pruned) if model.pruned.contains(key) => {
+                    present.insert(*key);
+                }
+                Err(Error::NotFound) => {}
+                Err(e) => panic!("{}: reading block {:?}: {}", at, &key[..16], e),
+            }
+            if !model.touched.contains(key) {
+                assert_eq!(
+                    present.contains(key),
+                    expected.contains(key),
+                    "{}: block {:?} was not kept as it was",
+                    at,
+                    &key[..16]
+                );
+            }
+        }
+        for batch in &model.failed_batches {
+            let kept = batch.iter().filter(|key| present.contains(*key)).count();
+            assert!(kept == 0 || kept == batch.len(), "{}: batch partly recovered", at);
+        }
+        assert_eq!(db.entry_count(), present.len() as u64, "{}", at);
+
+        let max_height = model.blocks.values().map(|(height, _)| *height).max().unwrap_or(0);
+        let mut tip = None;
+        for height in 0..=max_height {
+            let blocks = db.get_blocks_at_height(height).unwrap();
+            for key in present.iter().filter(|key| model.blocks[*key].0 == height) {
+                assert!(blocks.contains(key), "{}: block missing from height {}", at, height);
+            }
+            if let Ok(hash) = db.get_hash_by_height(height) {
+                assert!(present.contains(&hash), "{}: height {} names a missing block", at, height);
+                assert_eq!(model.blocks[&hash].0, height, "{}", at);
+                tip = Some((height, hash));
+            }
+        }
+        let (height, hash) = tip.unwrap_or((0, ZERO_HASH));
+        assert_eq!((db.latest_height(), db.latest_hash()), (height, hash), "{}", at);
+        present
+    }
+
+    /// A codec for a cycle, among those enabled; values stored with another
+    /// codec in an earlier cycle still read back
+    fn compression(rng: &mut Rng) -> Compression {
+        match rng.below(3) {
+            #[cfg(feature = "lz4")]
+            0 => Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            1 => Compression::Zstd(3),
+            _ => Compression::None,
+        }
+    }
+
+    #[test]
+    fn test_failed_reads_are_not_absent() {
+        let vfs = Arc::new(SimVfs::new(0));
+        let config = Config::new("/sim/adzdb").with_vfs(vfs.clone());
+        let mut db = Database::create(config).unwrap();
+        for id in 0..10 {
+            let (key, height, value) = block(id + 1, id);
+            db.put(&key, height, &value).unwrap();
+        }
+        // Move the indexes out of memory into the key and height files
+        db.sync().unwrap();
+        let (key, _, _) = block(5, 4);
+
+        vfs.set_faults(Faults {
+            read_error: 1.0,
+            ..Faults::default()
+        });
+        assert!(matches!(db.contains(&key), Err(Error::Io(_))));
+        assert!(matches!(db.contains_height(4), Err(Error::Io(_))));
+        assert!(matches!(db.get_blocks_at_height(4), Err(Error::Io(_))));
+        assert!(db.iter_heights().is_err());
+        vfs.set_faults(Faults::default());
+
+        assert!(db.contains(&key).unwrap());
+        assert!(db.contains_height(4).unwrap());
+        assert_eq!(db.get_blocks_at_height(4).unwrap(), vec![key]);
+        assert_eq!(db.iter_heights().unwrap().count(), 10);
+    }
+
+    #[test]
+    fn test_failed_writes_are_cut_back() {
+        for seed in 0..50 {
+            let vfs = Arc::new(SimVfs::new(seed));
+            let config = Config::new("/sim/adzdb").with_vfs(vfs.clone()).with_sync_on_write(false);
+            let mut db = Database::create(config.clone()).unwrap();
+            let mut stored = Vec::new();
+            for id in 0..60 {
+                let (key, height, value) = block(id + 1, id);
+                let faulty = (20..40).contains(&id);
+                if faulty {
+                    vfs.set_faults(Faults {
+                        write_error: 0.2,
+                        ..Faults::default()
+                    });
+                }
+                let result = db.put(&key, height, &value);
+                vfs.set_faults(Faults::default());
+                match result {
+                    Ok(()) => stored.push(key),
+                    Err(Error::Io(_)) if faulty => {}
+                    Err(e) => panic!("seed {}: put {}: {}", seed, id, e),
+                }
+            }
+            // A delete whose tombstone fails leaves the block in place
+            vfs.set_faults(Faults {
+                write_error: 1.0,
+                ..Faults::default()
+            });
+            assert!(matches!(db.delete(&stored[0]), Err(Error::Io(_))));
+            vfs.set_faults(Faults::default());
+            db.delete(&stored[1]).unwrap();
+            db.sync().unwrap();
+            drop(db);
+
+            let db = Database::open(config).unwrap_or_else(|e| panic!("seed {}: {}", seed, e));
+            for (i, key) in stored.iter().enumerate() {
+                assert_eq!(db.contains(key).unwrap(), i != 1, "seed {}: block {}", seed, i);
+            }
+            assert_eq!(db.entry_count(), stored.len() as u64 - 1);
+            assert_eq!(db.latest_height(), 59);
+        }
+    }
+
+    #[test]
+    fn test_recovery_survives_random_crashes() {
+        let mut crashes = 0;
+        for seed in 0..500 {
+            let vfs = Arc::new(SimVfs::new(seed));
+            let mut rng = Rng(seed ^ 0xADDB);
+            let mut model = Model::default();
+            let path = Path::new("/sim/adzdb");
+
+            for cycle in 0..4 {
+                let sync_on_write = rng.below(2) == 0;
+                // Segments a few records long, so crashes also hit rollovers
+                let config = Config::new(path)
+                    .with_vfs(vfs.clone())
+                    .with_sync_on_write(sync_on_write)
+                    .with_segment_size(4096)
+                    .with_compression(compression(&mut rng));
+                let mut db = if cycle == 0 {
+                    Database::create(config.clone()).unwrap()
+                } else {
+                    Database::open(config.clone())
+                        .unwrap_or_else(|e| panic!("seed {} cycle {}: reopening failed: {}", seed, cycle, e))
+                };
+                if cycle > 0 {
+                    let at = format!("seed {} cycle {}", seed, cycle);
+                    check_recovered(&db, &mut model, &at);
+                    db.sync().unwrap();
+                    let report = Database::verify(VerifyOptions::from(&config)).unwrap();
+                    assert!(report.is_clean(), "{}: {:?}", at, report.problems);
+                }
+
+                let faults = if rng.below(2) == 0 {
+                    Faults {
+                        read_error: 0.002,
+                        short_read: 0.05,
+                        write_error: 0.002,
+                        sync_error: 0.005,
+                    }
+                } else {
+                    Faults::default()
+                };
+                vfs.set_faults(faults);
+                run_ops(&mut db, &vfs, faults, &mut model, &mut rng, sync_on_write);
+                drop(db);
+                vfs.set_faults(Faults::default());
+                vfs.crash();
+                crashes += 1;
+            }
+        }
+        assert!(crashes >= 2000);
+    }
+}
@@ -0,0 +1,232 @@
+//! Double-buffered metadata: two checksummed copies in adzdb.meta
+//!
+//! adzdb.meta holds two slots, each a copy of the metadata followed by a
+//! sequence number and a CRC32C of both. A commit writes the copy with the
+//! next sequence number into the slot that does not hold the newest one, so
+//! a write torn by a crash only ever damages the slot being written, and the
+//! previous commit is still in the other. Opening picks the valid slot with
+//! the highest sequence number, as TigerBeetle does with its superblock
+//! copies.
+//!
+//! The second slot starts a page after the first, so the two never share a
+//! sector or a page of the cache. The metadata length depends on the format
+//! version, which every version keeps in its first bytes, so a slot is read
+//! in two parts: the version, then the rest.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::checksum::crc32c;
+use crate::vfs::{Dir, File, OpenOptions};
+use crate::{migrate, read_exact_at, Error, Metadata, Result, MAGIC, VERSION};
+
+/// Metadata file name
+pub(crate) const META_FILE: &str = "adzdb.meta";
+
+/// Offset of the second slot
+const SLOT_STRIDE: u64 = 4096;
+
+/// Bytes after the metadata in a slot: the sequence number, then a checksum
+/// covering everything before it
+const SLOT_TAIL: usize = 12;
+
+/// adzdb.meta, open for reading the newest commit and writing the next
+pub(crate) struct Superblock {
+    file: File,
+    /// Sequence number of the newest valid slot
+    sequence: u64,
+}
+
+impl Superblock {
+    /// Create adzdb.meta holding `metadata` in the first slot
+    pub(crate) fn create(dir: &Dir, metadata: &Metadata) -> Result<Self> {
+        let mut file = dir.open(
+            &dir.join(META_FILE),
+            OpenOptions::new().read(true).write(true).create(true).truncate(true),
+        )?;
+        file.write_all(&encode(metadata))?;
+        file.sync_all()?;
+        Ok(Self { file, sequence: 0 })
+    }
+
+    /// Open adzdb.meta and read the newest valid copy of the metadata
+    pub(crate) fn open(dir: &Dir, writable: bool) -> Result<(Self, Metadata)> {
+        let file = dir.open(&dir.join(META_FILE), OpenOptions::new().read(true).write(writable))?;
+        let mut superblock = Self { file, sequence: 0 };
+        let metadata = superblock.read()?;
+        Ok((superblock, metadata))
+    }
+
+    /// Read the newest valid copy of the metadata
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::MigrationRequired` or `Error::UnsupportedVersion` for
+    /// the metadata file of another format version, and `Error::Corruption`
+    /// if neither slot is valid.
+    pub(crate) fn read(&mut self) -> Result<Metadata> {
+        match newest(&self.file)? {
+            Some((sequence, bytes)) => {
+                let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+                if version != VERSION {
+                    return Err(migrate::version_error(version));
+                }
+                let metadata = Metadata::from_bytes(bytes[..].try_into().unwrap())?;
+                self.sequence = sequence;
+                Ok(metadata)
+            }
+            None => match prefix_version(&self.file) {
+                Ok(version) if version != VERSION => Err(migrate::version_error(version)),
+                _ => Err(Error::Corruption("No valid metadata slot".to_string())),
+            },
+        }
+    }
+
+    /// Commit `metadata` to the older slot and make it durable
+    pub(crate) fn write(&mut self, metadata: &Metadata) -> Result<()> {
+        let sequence = self.sequence + 1;
+        self.file.seek(SeekFrom::Start(offset(sequence)))?;
+        self.file.write_all(&slot(&metadata.to_bytes(), sequence))?;
+        self.file.sync_all()?;
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+/// Contents of a new adzdb.meta holding `metadata`
+pub(crate) fn encode(metadata: &Metadata) -> Vec<u8> {
+    encode_bytes(&metadata.to_bytes())
+}
+
+/// Contents of a new adzdb.meta holding serialized metadata, of whichever
+/// format version
+pub(crate) fn encode_bytes(metadata: &[u8]) -> Vec<u8> {
+    slot(metadata, 0)
+}
+
+/// Serialized metadata in the newest valid slot of adzdb.meta, of whichever
+/// format version, or `None` if neither slot is valid
+pub(crate) fn newest_bytes(file: &File) -> Result<Option<Vec<u8>>> {
+    Ok(newest(file)?.map(|(_, bytes)| bytes))
+}
+
+/// Format version of the metadata in adzdb.meta, from its newest valid slot,
+/// or else from the first bytes of the file, where every format version
+/// keeps its magic and version
+pub(crate) fn version(file: &File) -> Result<u32> {
+    match newest(file)? {
+        Some((_, bytes)) => Ok(u32::from_le_bytes(bytes[4..8].try_into().unwrap())),
+        None => prefix_version(file),
+    }
+}
+
+fn prefix_version(file: &File) -> Result<u32> {
+    let mut prefix = [0u8; 8];
+    read_exact_at(file, &mut prefix, 0).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Corruption("Metadata file too small".to_string())
+        } else {
+            Error::Io(e)
+        }
+    })?;
+    if &prefix[0..4] != MAGIC {
+        return Err(Error::Corruption("Invalid magic bytes".to_string()));
+    }
+    Ok(u32::from_le_bytes(prefix[4..8].try_into().unwrap()))
+}
+
+/// The sequence number and metadata of the valid slot with the highest
+/// sequence number, if any
+fn newest(file: &File) -> Result<Option<(u64, Vec<u8>)>> {
+    let mut newest: Option<(u64, Vec<u8>)> = None;
+    for start in [0, SLOT_STRIDE] {
+        // A damaged version reads a slot of the wrong length, which fails
+        // its checksum
+        let mut prefix = [0u8; 8];
+        if !read_slot(file, &mut prefix, start)? {
+            continue;
+        }
+        let version = u32::from_le_bytes(prefix[4..8].try_into().unwrap());
+        let mut slot = vec![0u8; migrate::metadata_size(version) + SLOT_TAIL];
+        if !read_slot(file, &mut slot, start)? {
+            continue;
+        }
+        let checksum_at = slot.len() - 4;
+        let sequence_at = checksum_at - 8;
+        let checksum = u32::from_le_bytes(slot[checksum_at..].try_into().unwrap());
+        let sequence = u64::from_le_bytes(slot[sequence_at..checksum_at].try_into().unwrap());
+        // A slot of the wrong parity was written somewhere else
+        if checksum != crc32c(&slot[..checksum_at]) || offset(sequence) != start {
+            continue;
+        }
+        if newest.as_ref().map_or(true, |(newest, _)| sequence > *newest) {
+            slot.truncate(sequence_at);
+            newest = Some((sequence, slot));
+        }
+    }
+    Ok(newest)
+}
+
+/// Fill `buf` from a slot, or return false if the file ends first
+fn read_slot(file: &File, buf: &mut [u8], start: u64) -> Result<bool> {
+    match read_exact_at(file, buf, start) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Offset of the slot that holds a sequence number
+fn offset(sequence: u64) -> u64 {
+    (sequence % 2) * SLOT_STRIDE
+}
+
+fn slot(metadata: &[u8], sequence: u64) -> Vec<u8> {
+    let mut slot = Vec::with_capacity(metadata.len() + SLOT_TAIL);
+    slot.extend_from_slice(metadata);
+    slot.extend_from_slice(&sequence.to_le_bytes());
+    let checksum = crc32c(&slot);
+    slot.extend_from_slice(&checksum.to_le_bytes());
+    slot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_torn_write_falls_back_to_previous_slot() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-superblock");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let dir = Dir::os(&temp_dir);
+
+        let mut superblock = Superblock::create(&dir, &Metadata::default()).unwrap();
+        for height in 1..=3 {
+            superblock.write(&Metadata { latest_height: height, ..Metadata::default() }).unwrap();
+        }
+        let (_, metadata) = Superblock::open(&dir, false).unwrap();
+        assert_eq!(metadata.latest_height, 3);
+
+        // Sequence 4 goes to the first slot; tear it part-way through
+        superblock.write(&Metadata { latest_height: 4, ..Metadata::default() }).unwrap();
+        let path = temp_dir.join(META_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[24..Metadata::SIZE].fill(0);
+        fs::write(&path, &bytes).unwrap();
+
+        let (mut superblock, metadata) = Superblock::open(&dir, true).unwrap();
+        assert_eq!(metadata.latest_height, 3);
+
+        // The next commit overwrites the torn slot, not the surviving one
+        superblock.write(&Metadata { latest_height: 5, ..Metadata::default() }).unwrap();
+        assert_eq!(Superblock::open(&dir, false).unwrap().1.latest_height, 5);
+        assert_eq!(version(&dir.open_read(&path).unwrap()).unwrap(), VERSION);
+
+        // With both slots damaged nothing is trusted
+        fs::write(&path, vec![0xAB; SLOT_STRIDE as usize + Metadata::SIZE + SLOT_TAIL]).unwrap();
+        assert!(matches!(Superblock::open(&dir, false), Err(Error::Corruption(_))));
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
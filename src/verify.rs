@@ -0,0 +1,591 @@
+//! Offline integrity verification
+//!
+//! [`Database::verify`] reads every file of a closed database and
+//! cross-checks them without repairing anything. Where `open` quietly repairs
+//! what a crash leaves behind and stops at the first real inconsistency, the
+//! verifier carries on and reports every problem it finds, each with the file
+//! and byte offset where it lies.
+//!
+//! The files are checked in two passes. The first walks adzdb.idx, the
+//! height file and the committed height journal entry by entry: every index
+//! entry must point at a record inside its data segment that matches it,
+//! passes its checksum and decompresses, unless pruning deleted the segment,
+//! and every height entry must name a block indexed at that height. The
+//! second opens the database read-only and compares the chain it sees with
+//! the metadata, and, given a block decoder, checks that each canonical block
+//! names the one below it as its parent.
+//!
+//! Nothing is locked, so a writer appending meanwhile shows up as problems at
+//! the file tails; verify a database no process is writing to.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::hasher::Hasher;
+use crate::heights::{HeightFile, HEIGHT_FILE, HEIGHT_LOG};
+use crate::segments::{segment_name, Position, Segments};
+use crate::superblock::{Superblock, META_FILE};
+use crate::vfs::{Dir, OsVfs, Vfs};
+use crate::{
+    compact, compress, migrate, to_hex, Config, Database, Error, Hash, HeightEntry, IndexEntry, Metadata, Result,
+};
+
+const INDEX_FILE: &str = "adzdb.idx";
+
+/// Block decoder returning the parent hash a block names, or `None` if the
+/// block cannot be decoded
+pub type ParentFn = Box<dyn Fn(&[u8]) -> Option<Hash> + Send + Sync>;
+
+/// Options for [`Database::verify`]
+///
+/// # Example
+///
+/// ```rust
+/// # #[cfg(feature = "sha2")] {
+/// use adzdb::hasher::DoubleSha256;
+/// use adzdb::verify::VerifyOptions;
+///
+/// let options = VerifyOptions::new("./blockchain")
+///     .with_hasher(DoubleSha256)
+///     .with_block_decoder(|block| block.get(4..36)?.try_into().ok());
+/// # }
+/// ```
+pub struct VerifyOptions {
+    /// Directory of the database to verify
+    pub path: PathBuf,
+    /// Check that every stored value hashes to its key (default: none)
+    pub hasher: Option<Arc<dyn Hasher>>,
+    /// Check that every canonical block names the one below it as its
+    /// parent (default: none)
+    pub parent_of: Option<ParentFn>,
+    /// Filesystem the database is on (default: `OsVfs`)
+    pub vfs: Arc<dyn Vfs>,
+}
+
+impl VerifyOptions {
+    /// Create options that check the files only
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            hasher: None,
+            parent_of: None,
+            vfs: Arc::new(OsVfs),
+        }
+    }
+
+    /// Set the hash function that keys must match
+    pub fn with_hasher<H: Hasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Some(Arc::new(hasher));
+        self
+    }
+
+    /// Set a decoder that extracts the parent hash from a block
+    pub fn with_block_decoder<F>(mut self, parent_of: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Hash> + Send + Sync + 'static,
+    {
+        self.parent_of = Some(Box::new(parent_of));
+        self
+    }
+}
+
+impl From<&Config> for VerifyOptions {
+    /// Options for the database a configuration opens, with its hasher and
+    /// filesystem
+    fn from(config: &Config) -> Self {
+        Self {
+            path: config.path.clone(),
+            hasher: config.hasher.clone(),
+            parent_of: None,
+            vfs: config.vfs.clone(),
+        }
+    }
+}
+
+/// A problem found by [`Database::verify`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// File the problem lies in
+    pub file: String,
+    /// Byte offset in that file
+    pub offset: u64,
+    /// What is wrong
+    pub description: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}: {}", self.file, self.offset, self.description)
+    }
+}
+
+/// Outcome of [`Database::verify`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Every problem found, in the order the files were checked
+    pub problems: Vec<Problem>,
+    /// Index entries checked
+    pub entries_checked: u64,
+    /// Height file slots and journal entries checked
+    pub heights_checked: u64,
+    /// Parent links checked with the block decoder
+    pub links_checked: u64,
+}
+
+impl VerifyReport {
+    /// Returns true if no problem was found
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, file: &str, offset: u64, description: String) {
+        self.problems.push(Problem {
+            file: file.to_string(),
+            offset,
+            description,
+        });
+    }
+}
+
+impl Database {
+    /// Check every file of a database and report each problem found
+    ///
+    /// Nothing is modified or repaired, and the writer lock is not taken.
+    /// See the [`verify`](crate::verify) module for what is checked.
+    ///
+    /// # Errors
+    ///
+    /// Problems with the contents of the files go in the report. Errors are
+    /// returned for files that cannot be read at all, for a database in
+    /// another format version, and with `Error::CompactionInProgress` while
+    /// a compaction is being swapped in.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use adzdb::Database;
+    /// use adzdb::verify::VerifyOptions;
+    ///
+    /// # fn main() -> adzdb::Result<()> {
+    /// let report = Database::verify(VerifyOptions::new("./blockchain"))?;
+    /// for problem in &report.problems {
+    ///     eprintln!("{}", problem);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verify(options: VerifyOptions) -> Result<VerifyReport> {
+        let dir = Dir::new(options.vfs.clone(), &options.path);
+        if compact::is_pending(&dir) {
+            return Err(Error::CompactionInProgress);
+        }
+        if migrate::is_pending(&dir) {
+            return Err(Error::MigrationRequired(migrate::version_of(&dir)?));
+        }
+
+        let mut report = VerifyReport::default();
+        let metadata = match Superblock::open(&dir, false) {
+            Ok((_, metadata)) => Some(metadata),
+            Err(Error::Corruption(message)) => {
+                report.problem(META_FILE, 0, message);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Pass one: the files, entry by entry
+        let stored = verify_records(&dir, &options, &mut report)?;
+        let committed = metadata.as_ref().map_or(0, |metadata| metadata.height_len);
+        verify_heights(&dir, &stored, committed, &mut report)?;
+
+        let height_log_len = dir.len(&dir.join(HEIGHT_LOG))?;
+        let index_len = dir.len(&dir.join(INDEX_FILE))?;
+        if let Some(metadata) = &metadata {
+            if metadata.height_len > height_log_len {
+                let description = format!(
+                    "Committed height journal length {} exceeds {} ({} bytes)",
+                    metadata.height_len, HEIGHT_LOG, height_log_len
+                );
+                report.problem(META_FILE, 0, description);
+            }
+            if metadata.index_len > index_len {
+                let description = format!(
+                    "Synced index length {} exceeds {} ({} bytes)",
+                    metadata.index_len, INDEX_FILE, index_len
+                );
+                report.problem(META_FILE, 0, description);
+            }
+        }
+
+        // Pass two: the chain the files add up to
+        let config = Config::new(&options.path).with_vfs(options.vfs.clone()).with_read_only(true);
+        let db = match Database::open(config) {
+            Ok(db) => db,
+            Err(Error::Corruption(message)) => {
+                report.problem(META_FILE, 0, format!("Database cannot be opened: {}", message));
+                return Ok(report);
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(metadata) = &metadata {
+            verify_metadata(&db, metadata, &mut report);
+        }
+        if let Some(parent_of) = &options.parent_of {
+            verify_links(&db, parent_of, &stored, &mut report)?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Check every index entry against its record in the data segments, and the
+/// values against their keys; returns the last entry indexed for each key,
+/// a tombstone if the block was deleted
+fn verify_records(dir: &Dir, options: &VerifyOptions, report: &mut VerifyReport) -> Result<HashMap<Hash, IndexEntry>> {
+    let segments = Segments::open(dir, false)?;
+    let index_file = dir.open_read(&dir.join(INDEX_FILE))?;
+    let (raw_index, index_torn) = Database::read_records::<{ IndexEntry::SIZE }>(&index_file, 0)?;
+
+    let mut stored = HashMap::new();
+    let mut data_end = Position {
+        segment: segments.first(),
+        offset: 0,
+    };
+    // End of the last record indexed in each segment
+    let mut segment_ends = HashMap::new();
+    for (i, raw) in raw_index.iter().enumerate() {
+        let offset = (i * IndexEntry::SIZE) as u64;
+        let entry = IndexEntry::from_bytes(raw);
+        let data_file = segment_name(entry.segment);
+        report.entries_checked += 1;
+
+        // Records in pruned segments are gone; later entries replace these
+        if entry.segment < segments.first() {
+            data_end = entry.end();
+            stored.insert(entry.key, entry);
+            continue;
+        }
+
+        // Records are appended back to back, in the order of their entries
+        if !data_end.is_followed_by(entry.position()) {
+            let description = format!(
+                "Entry for key {} points to offset {} of {}, expected offset {} of {}",
+                to_hex(&entry.key),
+                entry.offset,
+                data_file,
+                data_end.offset,
+                segment_name(data_end.segment)
+            );
+            report.problem(INDEX_FILE, offset, description);
+        }
+        data_end = entry.end();
+        segment_ends.insert(entry.segment, entry.record_end());
+        if !segments.contains(&entry)? {
+            let description = match segments.file(entry.segment)? {
+                Some(file) => format!(
+                    "Entry for key {} ends at offset {}, past the end of {} ({} bytes)",
+                    to_hex(&entry.key),
+                    entry.record_end(),
+                    data_file,
+                    file.len()?
+                ),
+                None => format!("Entry for key {} points into {}, which is missing", to_hex(&entry.key), data_file),
+            };
+            report.problem(INDEX_FILE, offset, description);
+            continue;
+        }
+
+        match Database::read_value(&segments, &entry).and_then(|stored| compress::decode(&entry, stored)) {
+            Ok(value) => {
+                if let (Some(hasher), false) = (&options.hasher, entry.is_tombstone() || entry.is_pruned()) {
+                    let actual = hasher.hash(&value);
+                    if actual != entry.key {
+                        let description =
+                            format!("Value of key {} hashes to {}", to_hex(&entry.key), to_hex(&actual));
+                        report.problem(&data_file, entry.offset, description);
+                    }
+                }
+            }
+            Err(Error::Corruption(message)) => report.problem(&data_file, entry.offset, message),
+            Err(e) => return Err(e),
+        }
+        stored.insert(entry.key, entry);
+    }
+
+    if index_torn > 0 {
+        let offset = (raw_index.len() * IndexEntry::SIZE) as u64;
+        report.problem(INDEX_FILE, offset, format!("{} trailing bytes do not form an entry", index_torn));
+    }
+    for segment in segments.numbers() {
+        let end = segment_ends.get(&segment).copied().unwrap_or(0);
+        let len = segments.get(segment)?.len()?;
+        if end < len {
+            let description = format!("{} bytes follow the last indexed record", len - end);
+            report.problem(&segment_name(segment), end, description);
+        }
+    }
+    Ok(stored)
+}
+
+/// Check that every height file slot and committed journal entry names a
+/// block indexed at its height
+fn verify_heights(
+    dir: &Dir,
+    stored: &HashMap<Hash, IndexEntry>,
+    committed: u64,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let unknown = |hash: &Hash, height: u64| match stored.get(hash) {
+        None => Some(format!("Height {} refers to unknown block {}", height, to_hex(hash))),
+        Some(entry) if entry.is_tombstone() => {
+            Some(format!("Height {} refers to deleted block {}", height, to_hex(hash)))
+        }
+        Some(entry) if entry.height != height => Some(format!(
+            "Height {} refers to block {}, stored at height {}",
+            height,
+            to_hex(hash),
+            entry.height
+        )),
+        Some(_) => None,
+    };
+
+    let slots = HeightFile::open(dir, false)?;
+    for (height, hash) in slots.canonical_from(0)? {
+        report.heights_checked += 1;
+        if let Some(description) = unknown(&hash, height) {
+            report.problem(HEIGHT_FILE, height * 32, description);
+        }
+    }
+    let slots_len = dir.len(&dir.join(HEIGHT_FILE))?;
+    if slots_len % 32 != 0 {
+        let description = format!("{} trailing bytes do not form a slot", slots_len % 32);
+        report.problem(HEIGHT_FILE, slots_len - slots_len % 32, description);
+    }
+
+    // Entries past the committed length are discarded on open, and only the
+    // last entry for a height counts: earlier ones may name blocks deleted
+    // since
+    let height_log = dir.open_read(&dir.join(HEIGHT_LOG))?;
+    let (entries, _) = Database::read_records::<{ HeightEntry::SIZE }>(&height_log, 0)?;
+    let committed = (committed / HeightEntry::SIZE as u64) as usize;
+    let entries: Vec<HeightEntry> = entries.iter().take(committed).map(HeightEntry::from_bytes).collect();
+    let last: HashMap<u64, usize> = entries.iter().enumerate().map(|(i, entry)| (entry.target_height(), i)).collect();
+    for (i, entry) in entries.iter().enumerate() {
+        report.heights_checked += 1;
+        if entry.is_cleared() || last[&entry.target_height()] != i {
+            continue;
+        }
+        if let Some(description) = unknown(&entry.hash, entry.height) {
+            report.problem(HEIGHT_LOG, (i * HeightEntry::SIZE) as u64, description);
+        }
+    }
+    Ok(())
+}
+
+/// Check the counts and chain state in the metadata against the database
+fn verify_metadata(db: &Database, metadata: &Metadata, report: &mut VerifyReport) {
+    let actual = db.state.read().metadata.clone();
+    let mut mismatch = |field: &str, claimed: String, actual: String| {
+        if claimed != actual {
+            let description = format!("Metadata {} is {}, but the files hold {}", field, claimed, actual);
+            report.problem(META_FILE, 0, description);
+        }
+    };
+    mismatch("entry_count", metadata.entry_count.to_string(), actual.entry_count.to_string());
+    mismatch("data_size", metadata.data_size.to_string(), actual.data_size.to_string());
+    mismatch("logical_size", metadata.logical_size.to_string(), actual.logical_size.to_string());
+    mismatch("latest_height", metadata.latest_height.to_string(), actual.latest_height.to_string());
+    mismatch("latest_hash", to_hex(&metadata.latest_hash), to_hex(&actual.latest_hash));
+    mismatch("genesis_hash", to_hex(&metadata.genesis_hash), to_hex(&actual.genesis_hash));
+}
+
+/// Check that every canonical block above the lowest names the canonical
+/// block below it as its parent
+fn verify_links(
+    db: &Database,
+    parent_of: &ParentFn,
+    stored: &HashMap<Hash, IndexEntry>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let snapshot = db.snapshot();
+    let mut below: Option<(u64, Hash)> = None;
+    for height in snapshot.iter_heights()? {
+        let Ok(hash) = snapshot.get_hash_by_height(height) else {
+            continue;
+        };
+        let (data_file, offset) = stored
+            .get(&hash)
+            .map_or((segment_name(0), 0), |entry| (segment_name(entry.segment), entry.offset));
+        let block = match snapshot.get(&hash) {
+            Ok(block) => block,
+            // Pruned, or already reported as a record problem
+            Err(_) => {
+                below = Some((height, hash));
+                continue;
+            }
+        };
+
+        if let Some((below_height, below_hash)) = below {
+            report.links_checked += 1;
+            match parent_of(&block) {
+                None => {
+                    let description = format!("Block {} at height {} cannot be decoded", to_hex(&hash), height);
+                    report.problem(&data_file, offset, description);
+                }
+                Some(_) if below_height + 1 != height => {
+                    let description = format!(
+                        "Block {} at height {} has no canonical parent at height {}",
+                        to_hex(&hash),
+                        height,
+                        height - 1
+                    );
+                    report.problem(&data_file, offset, description);
+                }
+                Some(parent) if parent != below_hash => {
+                    let description = format!(
+                        "Block {} at height {} names parent {}, but block {} is canonical below it",
+                        to_hex(&hash),
+                        height,
+                        to_hex(&parent),
+                        to_hex(&below_hash)
+                    );
+                    report.problem(&data_file, offset, description);
+                }
+                Some(_) => {}
+            }
+        }
+        below = Some((height, hash));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::crc32c;
+    use crate::ZERO_HASH;
+    use std::fs;
+
+    /// Keys values by their CRC32C, which needs no hasher feature
+    #[derive(Debug)]
+    struct Crc32c;
+
+    impl Hasher for Crc32c {
+        fn hash(&self, data: &[u8]) -> Hash {
+            let mut hash = [0u8; 32];
+            hash[..4].copy_from_slice(&crc32c(data).to_le_bytes());
+            hash
+        }
+    }
+
+    /// A block naming its parent in its first 32 bytes
+    fn block(parent: &Hash, body: &[u8]) -> (Hash, Vec<u8>) {
+        let block = [&parent[..], body].concat();
+        (Crc32c.hash(&block), block)
+    }
+
+    fn parent_of(block: &[u8]) -> Option<Hash> {
+        block.get(..32)?.try_into().ok()
+    }
+
+    #[test]
+    fn test_verify_clean_database() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-verify-clean");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir).with_hasher(Crc32c);
+        let mut db = Database::create(config.clone()).unwrap();
+        let mut parent = ZERO_HASH;
+        for height in 0..10 {
+            let (hash, value) = block(&parent, &[height as u8]);
+            db.put(&hash, height, &value).unwrap();
+            parent = hash;
+        }
+        let (side, value) = block(&db.get_hash_by_height(4).unwrap(), b"side");
+        db.put(&side, 5, &value).unwrap();
+        db.delete(&side).unwrap();
+        drop(db);
+
+        let options = VerifyOptions::from(&config).with_block_decoder(parent_of);
+        let report = Database::verify(options).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.entries_checked, 12);
+        assert_eq!(report.links_checked, 9);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_verify_reports_every_problem() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-verify-damaged");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        let mut parent = ZERO_HASH;
+        for height in 0..5 {
+            let (hash, value) = block(&parent, &[height as u8; 8]);
+            db.put(&hash, height, &value).unwrap();
+            parent = hash;
+        }
+        // A block whose parent is not the canonical block below it
+        let (orphan, value) = block(&[0xAB; 32], b"orphan");
+        db.put(&orphan, 5, &value).unwrap();
+        db.sync().unwrap();
+        drop(db);
+
+        // Flip a byte in the value of block 1, and in the key of block 3
+        let record = |height: usize| (height * (crate::RecordHeader::SIZE + 40)) as u64;
+        let data_file = segment_name(0);
+        let mut data = fs::read(temp_dir.join(&data_file)).unwrap();
+        data[record(1) as usize + crate::RecordHeader::SIZE + 35] ^= 0xFF;
+        data[record(3) as usize + 8] ^= 0xFF;
+        fs::write(temp_dir.join(&data_file), &data).unwrap();
+
+        // Point height 2 at a block that was never stored
+        let mut slots = fs::read(temp_dir.join(HEIGHT_FILE)).unwrap();
+        slots[2 * 32] ^= 0xFF;
+        fs::write(temp_dir.join(HEIGHT_FILE), &slots).unwrap();
+
+        let options = VerifyOptions::new(&temp_dir).with_hasher(Crc32c).with_block_decoder(parent_of);
+        let report = Database::verify(options).unwrap();
+        let at = |file: &str, offset: u64| report.problems.iter().any(|p| p.file == file && p.offset == offset);
+        assert!(at(&data_file, record(1)), "{:?}", report.problems);
+        assert!(at(&data_file, record(3)));
+        assert!(at(HEIGHT_FILE, 2 * 32));
+        assert!(at(&data_file, record(5)));
+        assert!(!at(&data_file, record(4)));
+        assert_eq!(report.entries_checked, 6);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_verify_catches_height_of_deleted_block() {
+        let temp_dir = std::env::temp_dir().join("adzdb-test-verify-deleted");
+        let _ = fs::remove_dir_all(&temp_dir);
+
+        let config = Config::new(&temp_dir);
+        let mut db = Database::create(config.clone()).unwrap();
+        for i in 0..4u8 {
+            db.put(&[i; 32], i as u64, &[i; 16]).unwrap();
+        }
+        db.delete(&[2u8; 32]).unwrap();
+        db.sync().unwrap();
+        drop(db);
+        let report = Database::verify(VerifyOptions::new(&temp_dir)).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // Point height 2 back at the block deleted from it
+        let mut slots = fs::read(temp_dir.join(HEIGHT_FILE)).unwrap();
+        slots[2 * 32..3 * 32].copy_from_slice(&[2u8; 32]);
+        fs::write(temp_dir.join(HEIGHT_FILE), &slots).unwrap();
+
+        let report = Database::verify(VerifyOptions::new(&temp_dir)).unwrap();
+        assert!(report.problems.iter().any(|p| p.file == HEIGHT_FILE && p.offset == 2 * 32), "{:?}", report.problems);
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+}
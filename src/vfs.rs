@@ -0,0 +1,810 @@
+//! Storage abstraction: every file operation of a database goes through a
+//! [`Vfs`]
+//!
+//! The database never touches `std::fs` directly. It opens, reads, writes,
+//! syncs, truncates, renames and removes its files through the `Vfs` in its
+//! [`Config`](crate::Config), which is [`OsVfs`], the real filesystem, unless
+//! another is configured. [`MemVfs`] keeps the files in memory, for tests and
+//! nodes that need no persistence. [`SimVfs`](crate::sim::SimVfs) keeps them
+//! in memory too and injects faults, so tests can crash a database at any
+//! point and check what it recovers.
+//!
+//! Files are read and written at explicit offsets; the cursor, and the
+//! `Read`, `Write` and `Seek` impls that use it, are provided on top by the
+//! crate's own file handle.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A filesystem holding database directories
+pub trait Vfs: fmt::Debug + Send + Sync {
+    /// Open a file
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Rename a file, replacing any file at `to` atomically
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Whether a file or directory exists at `path`
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Create a directory and its missing parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Make the renames, creations and removals in a directory durable
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Take an exclusive lock on the file at `path`, held until the returned
+    /// guard is dropped; fails with `io::ErrorKind::WouldBlock` if it is
+    /// held already
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn fmt::Debug + Send + Sync>>;
+}
+
+/// An open file of a [`Vfs`]
+pub trait VfsFile: fmt::Debug + Send + Sync {
+    /// Read at `offset`, returning how many bytes were read; 0 at the end
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Write at `offset`, returning how many bytes were written
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// Write at the end of the file
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, self.len()?)
+    }
+
+    /// Length of the file in bytes
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns true if the file is empty
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncate or extend the file with zeros
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Make the contents and length of the file durable
+    fn sync(&self) -> io::Result<()>;
+
+    /// Whether this is still the file at `path`, rather than one a rename
+    /// has since replaced
+    fn is_same_file(&self, path: &Path) -> io::Result<bool>;
+
+    /// Offset of the first byte at or after `offset` that may not be zero,
+    /// so scans can skip holes; `offset` itself unless the file knows better
+    fn data_from(&self, offset: u64) -> io::Result<u64> {
+        Ok(offset)
+    }
+
+    /// The underlying operating system file, if there is one
+    fn as_std(&self) -> Option<&std::fs::File> {
+        None
+    }
+}
+
+/// How to open a file, as `std::fs::OpenOptions`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Open for reading
+    pub read: bool,
+    /// Open for writing
+    pub write: bool,
+    /// Open for writing at the end of the file
+    pub append: bool,
+    /// Create the file if it does not exist
+    pub create: bool,
+    /// Empty the file when opening it
+    pub truncate: bool,
+}
+
+impl OpenOptions {
+    /// Options that open nothing, until set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set read access
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Set write access
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Set append mode
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Set whether a missing file is created
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Set whether the file is emptied
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+}
+
+/// The operating system's filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsVfs;
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn VfsFile>> {
+        let file = std::fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create(options.create)
+            .truncate(options.truncate)
+            .open(path)?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        std::fs::File::open(path)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn fmt::Debug + Send + Sync>> {
+        Ok(Box::new(crate::lock::open_exclusive(path)?))
+    }
+}
+
+/// A file of the operating system's filesystem
+#[derive(Debug)]
+struct OsFile(std::fs::File);
+
+impl VfsFile for OsFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::read_at(&self.0, buf, offset)
+        }
+        #[cfg(windows)]
+        {
+            // `seek_read` moves the cursor, but nothing relies on it: the
+            // crate's file handle keeps its own
+            std::os::windows::fs::FileExt::seek_read(&self.0, buf, offset)
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::write_at(&self.0, buf, offset)
+        }
+        #[cfg(windows)]
+        {
+            std::os::windows::fs::FileExt::seek_write(&self.0, buf, offset)
+        }
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        (&self.0).write(buf)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+
+    fn is_same_file(&self, path: &Path) -> io::Result<bool> {
+        let (open, current) = (self.0.metadata()?, std::fs::metadata(path)?);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(open.dev() == current.dev() && open.ino() == current.ino())
+        }
+        #[cfg(not(unix))]
+        {
+            // A renamed file keeps its own creation time
+            Ok(match (open.created(), current.created()) {
+                (Ok(open), Ok(current)) => open == current,
+                _ => true,
+            })
+        }
+    }
+
+    fn as_std(&self) -> Option<&std::fs::File> {
+        Some(&self.0)
+    }
+}
+
+/// A filesystem that keeps every file in memory
+///
+/// Clones share the same files, which are freed with the last clone. Syncs
+/// do nothing, since there is nothing to make durable: what a database
+/// writes is exactly what a later one opened on the same `MemVfs` reads.
+/// Files are sparse, so a block stored far above the rest of the chain
+/// costs no memory for the empty heights below it.
+///
+/// # Example
+///
+/// ```rust
+/// use adzdb::vfs::MemVfs;
+/// use adzdb::{Config, Database};
+/// use std::sync::Arc;
+///
+/// # fn main() -> adzdb::Result<()> {
+/// let config = Config::new("/chain").with_vfs(Arc::new(MemVfs::new()));
+/// let mut db = Database::create(config.clone())?;
+/// db.put(&[1u8; 32], 0, b"genesis")?;
+/// drop(db);
+///
+/// let db = Database::open(config)?;
+/// assert_eq!(db.get(&[1u8; 32])?, b"genesis");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemVfs {
+    state: Arc<Mutex<MemState>>,
+}
+
+#[derive(Default)]
+struct MemState {
+    files: BTreeMap<PathBuf, Arc<RwLock<MemData>>>,
+    dirs: BTreeSet<PathBuf>,
+    locks: BTreeSet<PathBuf>,
+}
+
+impl MemVfs {
+    /// Create an empty filesystem
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MemState {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+}
+
+impl fmt::Debug for MemVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemVfs").field("files", &self.state().files.len()).finish()
+    }
+}
+
+impl Vfs for MemVfs {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state();
+        let data = match state.files.get(path) {
+            Some(data) => data.clone(),
+            None if options.create => {
+                if !path.parent().map_or(true, |parent| state.is_dir(parent)) {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+                let data = Arc::new(RwLock::new(MemData::default()));
+                state.files.insert(path.to_path_buf(), data.clone());
+                data
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let file = MemFile {
+            vfs: self.clone(),
+            data,
+            writable: options.write || options.append,
+        };
+        if options.truncate {
+            file.set_len(0)?;
+        }
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let data = state.files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state().files.remove(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state();
+        state.files.contains_key(path) || state.is_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        if self.state().is_dir(path) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn fmt::Debug + Send + Sync>> {
+        if !self.state().locks.insert(path.to_path_buf()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(MemLock {
+            vfs: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+/// Size of the chunks a [`MemVfs`] file is stored in
+const CHUNK_SIZE: u64 = 4096;
+
+/// The contents of a [`MemVfs`] file
+///
+/// Only chunks holding a non-zero byte are stored; the rest of the file,
+/// like a hole in a file on disk, reads as zeros.
+#[derive(Default)]
+struct MemData {
+    len: u64,
+    chunks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl MemData {
+    /// Split a byte range starting at `offset` at chunk boundaries, as
+    /// (chunk, start within the chunk, position in the range, length)
+    fn spans(offset: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+            let pos = offset + done as u64;
+            let start = (pos % CHUNK_SIZE) as usize;
+            let n = (len - done).min(CHUNK_SIZE as usize - start);
+            let span = (pos / CHUNK_SIZE, start, done, n);
+            done += n;
+            Some(span)
+        })
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> usize {
+        let len = buf.len().min(self.len.saturating_sub(offset) as usize);
+        for (index, start, at, n) in Self::spans(offset, len) {
+            let out = &mut buf[at..at + n];
+            match self.chunks.get(&index) {
+                Some(chunk) => out.copy_from_slice(&chunk[start..start + n]),
+                None => out.fill(0),
+            }
+        }
+        len
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) {
+        for (index, start, at, n) in Self::spans(offset, buf.len()) {
+            let bytes = &buf[at..at + n];
+            match self.chunks.get_mut(&index) {
+                Some(chunk) => chunk[start..start + n].copy_from_slice(bytes),
+                None if bytes.iter().all(|&b| b == 0) => {}
+                None => {
+                    let mut chunk = vec![0u8; CHUNK_SIZE as usize].into_boxed_slice();
+                    chunk[start..start + n].copy_from_slice(bytes);
+                    self.chunks.insert(index, chunk);
+                }
+            }
+        }
+        self.len = self.len.max(offset + buf.len() as u64);
+    }
+
+    fn set_len(&mut self, len: u64) {
+        if len < self.len {
+            // Zero the tail cut off the last chunk, so it reads as zeros if
+            // the file grows again
+            self.chunks.split_off(&((len + CHUNK_SIZE - 1) / CHUNK_SIZE));
+            if let Some(chunk) = self.chunks.get_mut(&(len / CHUNK_SIZE)) {
+                chunk[(len % CHUNK_SIZE) as usize..].fill(0);
+            }
+        }
+        self.len = len;
+    }
+
+    fn data_from(&self, offset: u64) -> u64 {
+        match self.chunks.range(offset / CHUNK_SIZE..).next() {
+            Some((&index, _)) => offset.max(index * CHUNK_SIZE),
+            None => offset.max(self.len),
+        }
+    }
+}
+
+/// An open file of a [`MemVfs`]
+struct MemFile {
+    vfs: MemVfs,
+    data: Arc<RwLock<MemData>>,
+    writable: bool,
+}
+
+impl MemFile {
+    fn data(&self) -> RwLockReadGuard<'_, MemData> {
+        self.data.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn data_mut(&self) -> io::Result<RwLockWriteGuard<'_, MemData>> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file not opened for writing"));
+        }
+        Ok(self.data.write().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl fmt::Debug for MemFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemFile").field("len", &self.data().len).finish()
+    }
+}
+
+impl VfsFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        Ok(self.data().read(buf, offset))
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.data_mut()?.write(buf, offset);
+        Ok(buf.len())
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data_mut()?;
+        let len = data.len;
+        data.write(buf, len);
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data().len)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data_mut()?.set_len(len);
+        Ok(())
+    }
+
+    fn data_from(&self, offset: u64) -> io::Result<u64> {
+        Ok(self.data().data_from(offset))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_same_file(&self, path: &Path) -> io::Result<bool> {
+        match self.vfs.state().files.get(path) {
+            Some(data) => Ok(Arc::ptr_eq(data, &self.data)),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+/// A lock held on a [`MemVfs`] path, released when dropped
+struct MemLock {
+    vfs: MemVfs,
+    path: PathBuf,
+}
+
+impl fmt::Debug for MemLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemLock").field("path", &self.path).finish()
+    }
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.vfs.state().locks.remove(&self.path);
+    }
+}
+
+/// A database directory on a [`Vfs`]
+///
+/// Paths given to its methods are full paths, usually built with
+/// [`Dir::join`].
+#[derive(Debug, Clone)]
+pub(crate) struct Dir {
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+}
+
+impl Dir {
+    pub(crate) fn new(vfs: Arc<dyn Vfs>, path: &Path) -> Self {
+        Self {
+            vfs,
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// A directory on the operating system's filesystem
+    pub(crate) fn os(path: &Path) -> Self {
+        Self::new(Arc::new(OsVfs), path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of a file in the directory
+    pub(crate) fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.path.join(name)
+    }
+
+    pub(crate) fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<File> {
+        let inner = self.vfs.open(path, options)?;
+        Ok(File {
+            inner,
+            pos: AtomicU64::new(0),
+            append: options.append,
+        })
+    }
+
+    /// Open a file for reading, as `std::fs::File::open`
+    pub(crate) fn open_read(&self, path: &Path) -> io::Result<File> {
+        self.open(path, OpenOptions::new().read(true))
+    }
+
+    /// Create or empty a file for writing, as `std::fs::File::create`
+    pub(crate) fn create(&self, path: &Path) -> io::Result<File> {
+        self.open(path, OpenOptions::new().write(true).create(true).truncate(true))
+    }
+
+    /// Read a whole file, as `std::fs::read`
+    pub(crate) fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open_read(path)?;
+        let mut contents = vec![0u8; file.len()? as usize];
+        file.read_exact_at(&mut contents, 0)?;
+        Ok(contents)
+    }
+
+    /// Length of a file, as `std::fs::metadata(path)?.len()`
+    pub(crate) fn len(&self, path: &Path) -> io::Result<u64> {
+        self.open_read(path)?.len()
+    }
+
+    /// Copy a file and make the copy durable, as `std::fs::copy` followed
+    /// by a sync
+    pub(crate) fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let source = self.open_read(from)?;
+        let dest = self.create(to)?;
+        io::copy(&mut &source, &mut &dest)?;
+        dest.sync_all()
+    }
+
+    pub(crate) fn exists(&self, path: &Path) -> bool {
+        self.vfs.exists(path)
+    }
+
+    pub(crate) fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.vfs.rename(from, to)
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.vfs.remove_file(path)
+    }
+
+    /// Remove a file if it exists
+    pub(crate) fn remove_if_exists(&self, path: &Path) -> io::Result<()> {
+        match self.vfs.remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn create_dir_all(&self) -> io::Result<()> {
+        self.vfs.create_dir_all(&self.path)
+    }
+
+    /// Make renames in the directory durable
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.vfs.sync_dir(&self.path)
+    }
+
+    pub(crate) fn lock(&self, path: &Path) -> io::Result<Box<dyn fmt::Debug + Send + Sync>> {
+        self.vfs.lock(path)
+    }
+}
+
+/// An open file, with a cursor for `Read`, `Write` and `Seek`
+///
+/// Like `std::fs::File`, `&File` reads, writes and seeks too, sharing the
+/// one cursor. Positioned reads leave the cursor alone.
+#[derive(Debug)]
+pub(crate) struct File {
+    inner: Box<dyn VfsFile>,
+    pos: AtomicU64,
+    append: bool,
+}
+
+impl File {
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    /// Read exactly `buf.len()` bytes at `offset` without using the cursor
+    pub(crate) fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.inner.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_same_file(&self, path: &Path) -> io::Result<bool> {
+        self.inner.is_same_file(path)
+    }
+
+    /// Offset of the first byte at or after `offset` that may not be zero
+    pub(crate) fn data_from(&self, offset: u64) -> io::Result<u64> {
+        self.inner.data_from(offset)
+    }
+
+    #[cfg(feature = "mmap")]
+    pub(crate) fn as_std(&self) -> Option<&std::fs::File> {
+        self.inner.as_std()
+    }
+}
+
+impl Read for &File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.pos.load(Ordering::Relaxed);
+        let n = self.inner.read_at(buf, pos)?;
+        self.pos.store(pos + n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl Write for &File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.append {
+            let n = self.inner.append(buf)?;
+            self.pos.store(self.inner.len()?, Ordering::Relaxed);
+            return Ok(n);
+        }
+        let pos = self.pos.load(Ordering::Relaxed);
+        let n = self.inner.write_at(buf, pos)?;
+        self.pos.store(pos + n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for &File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(delta) => (self.inner.len()?, delta),
+            SeekFrom::Current(delta) => (self.pos.load(Ordering::Relaxed), delta),
+        };
+        let pos = base
+            .checked_add_signed(delta)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        self.pos.store(pos, Ordering::Relaxed);
+        Ok(pos)
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        (&*self).seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_files_are_sparse() {
+        let vfs = MemVfs::new();
+        let path = Path::new("/sparse");
+        vfs.create_dir_all(Path::new("/")).unwrap();
+        let file = vfs.open(path, OpenOptions::new().read(true).write(true).create(true)).unwrap();
+
+        // Only the chunk written to is stored; zeros written elsewhere are not
+        let far = 1u64 << 30;
+        file.write_at(b"far", far).unwrap();
+        file.write_at(&[0u8; 8192], 0).unwrap();
+        assert_eq!(file.len().unwrap(), far + 3);
+        assert_eq!(vfs.state().files[path].read().unwrap().chunks.len(), 1);
+        assert_eq!(file.data_from(0).unwrap(), far / CHUNK_SIZE * CHUNK_SIZE);
+
+        let mut buf = [1u8; 8];
+        assert_eq!(file.read_at(&mut buf, far - 5).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0\0\0far");
+
+        // Bytes cut off and then grown back read as zeros
+        file.set_len(far + 1).unwrap();
+        file.set_len(far + 3).unwrap();
+        assert_eq!(file.read_at(&mut buf, far).unwrap(), 3);
+        assert_eq!(&buf[..3], b"f\0\0");
+        file.set_len(far).unwrap();
+        assert_eq!(vfs.state().files[path].read().unwrap().chunks.len(), 0);
+        assert_eq!(file.data_from(0).unwrap(), far);
+    }
+}